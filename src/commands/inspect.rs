@@ -4,7 +4,9 @@ use clap::Args;
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::utils::load_values::{get_value_files_as_refs, load_yaml_files};
+use crate::utils::load_values::{
+    expand_path, guarded_values_file, is_remote_values_file, load_yaml_files_owned,
+};
 use crate::utils::storage::models::{ApplicationState, PersistedApplication};
 use crate::utils::storage::read_from::get_application_by_id;
 
@@ -31,9 +33,19 @@ impl Inspect {
         let entries: Vec<ValueFileEntry> = app
             .value_files
             .iter()
-            .map(|p| ValueFileEntry {
-                path: p.clone(),
-                missing: !Path::new(p).exists(),
+            .map(|p| {
+                // Files are recorded with their `if:VAR:` guard and `?` prefix. Remote
+                // files are only checked when they are fetched.
+                let path = match guarded_values_file(p) {
+                    Ok(Some((_, path))) => path,
+                    _ => p.as_str(),
+                };
+                let path = path.strip_prefix('?').unwrap_or(path);
+                ValueFileEntry {
+                    path: p.clone(),
+                    missing: !is_remote_values_file(path)
+                        && !expand_path(path).is_ok_and(|path| Path::new(&path).exists()),
+                }
             })
             .collect();
 
@@ -55,8 +67,7 @@ impl Inspect {
         let merged: Option<serde_yaml::Value> = if present_paths.is_empty() {
             None
         } else {
-            let refs = get_value_files_as_refs(&present_paths);
-            Some(load_yaml_files(&refs)?)
+            Some(load_yaml_files_owned(&present_paths)?)
         };
 
         let output = if self.json {
@@ -1,6 +1,6 @@
 use crate::app;
 use crate::utils::copy_file_utils::{copy_files_with_ignorefile, get_composer_directory};
-use crate::utils::load_values::{get_value_files_as_refs, load_yaml_files};
+use crate::utils::load_values::load_yaml_files_owned;
 use crate::utils::walk::{get_files_with_extensions, get_files_with_names};
 use anyhow::anyhow;
 
@@ -112,8 +112,7 @@ pub fn add_application(
             ));
     }
 
-    let values = get_value_files_as_refs(values_files);
-    let consolidated_values = load_yaml_files(&values)?;
+    let consolidated_values = load_yaml_files_owned(values_files)?;
     trace!(
         "Consolidated values: \n```\n{}\n```\n",
         serde_yaml::to_string(&consolidated_values).unwrap()
@@ -1,7 +1,7 @@
 use anyhow::anyhow;
 
-use crate::utils::load_values::{get_value_files_as_refs, load_yaml_files};
-use crate::utils::template::render_template;
+use crate::utils::load_values::load_yaml_files_owned;
+use crate::utils::template::render_compose_dry_run;
 
 use clap::Args;
 
@@ -34,8 +34,7 @@ impl Template {
             ));
         }
 
-        let values: Vec<&str> = get_value_files_as_refs(&self.value_files);
-        let consolidated_values: serde_yaml::Value = load_yaml_files(&values)?;
+        let consolidated_values: serde_yaml::Value = load_yaml_files_owned(&self.value_files)?;
 
         trace!(
             "Consolidated values: \n```\n{}\n```\n",
@@ -47,7 +46,7 @@ impl Template {
             .to_str()
             .expect("Could not get path to template file.");
 
-        let rendered_template = render_template(template_path_str, consolidated_values)?;
+        let rendered_template = render_compose_dry_run(template_path_str, consolidated_values)?;
 
         if self.output_file.is_empty() {
             // Print output to console
@@ -66,7 +65,9 @@ mod tests {
     use relative_path::RelativePath;
     use tempfile::{tempdir, TempDir};
 
+    use crate::commands::cli::Cli;
     use crate::commands::template::Template;
+    use clap::Parser;
     use serial_test::serial;
     use std::env::current_dir;
     use std::path::PathBuf;
@@ -165,4 +166,77 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn test_template_command_writes_rendered_compose() -> anyhow::Result<()> {
+        trace!("Running test_template_command_writes_rendered_compose.");
+
+        let temp_dir: TempDir = tempdir()?;
+        let template_path: PathBuf = temp_dir.path().join("docker-compose.jinja2");
+        let values_path: PathBuf = temp_dir.path().join("values.yaml");
+        let output_path: PathBuf = temp_dir.path().join("docker-compose.yaml");
+        std::fs::write(
+            &template_path,
+            "services:\n  web:\n    image: nginx:{{ tag }}\n",
+        )?;
+        std::fs::write(&values_path, "tag: '1.25'\n")?;
+
+        let cli = Cli::try_parse_from([
+            "composer",
+            "template",
+            "-t",
+            template_path.to_str().unwrap(),
+            "-v",
+            values_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+        ])?;
+        cli.run()?;
+
+        assert_eq!(
+            std::fs::read_to_string(&output_path)?,
+            "services:\n  web:\n    image: nginx:1.25"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_template_command_rejects_invalid_compose() -> anyhow::Result<()> {
+        trace!("Running test_template_command_rejects_invalid_compose.");
+
+        let temp_dir: TempDir = tempdir()?;
+        let template_path: PathBuf = temp_dir.path().join("docker-compose.jinja2");
+        let values_path: PathBuf = temp_dir.path().join("values.yaml");
+        let output_path: PathBuf = temp_dir.path().join("docker-compose.yaml");
+        std::fs::write(&template_path, "services:\n  web: {{ web }}\n")?;
+        std::fs::write(&values_path, "web: '[unclosed'\n")?;
+
+        let cli = Cli::try_parse_from([
+            "composer",
+            "template",
+            "-t",
+            template_path.to_str().unwrap(),
+            "-v",
+            values_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+        ])?;
+        let err = cli.run().err().unwrap().to_string();
+
+        assert!(
+            err.starts_with(&format!(
+                "Compose template {} did not render to valid YAML",
+                template_path.display()
+            )),
+            "{}",
+            err
+        );
+        // Nothing is written when the compose file is invalid
+        assert!(!output_path.exists());
+
+        Ok(())
+    }
 }
@@ -3,7 +3,7 @@ use crate::utils::copy_file_utils::get_composer_directory;
 use crate::utils::docker_compose::{
     compose_down_with, CommandRunner, RealCommandRunner, COMPOSE_FILE_NAMES,
 };
-use crate::utils::load_values::{get_value_files_as_refs, load_yaml_files};
+use crate::utils::load_values::load_yaml_files_owned;
 use crate::utils::storage::read_from::get_application_by_id;
 use crate::utils::walk::get_files_with_names;
 use anyhow::anyhow;
@@ -127,7 +127,7 @@ impl Upgrade {
             )));
         }
         verify_required_files(&self.directory)?;
-        load_yaml_files(&get_value_files_as_refs(&value_files))?;
+        load_yaml_files_owned(&value_files)?;
 
         // Stop containers/networks before removing the directory. By default
         // only compose files absent from the new template version are downed;
@@ -0,0 +1,152 @@
+use crate::utils::flatten::flatten;
+use serde_yaml::Value;
+use std::fmt;
+
+/// A single leaf-level difference between two values trees.
+///
+/// Paths use the same `.` / `[n]` scheme as `flatten`, and values are the rendered leaf
+/// strings it produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added {
+        path: String,
+        new: String,
+    },
+    Removed {
+        path: String,
+        old: String,
+    },
+    Modified {
+        path: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl Change {
+    #[allow(dead_code)]
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { path, new } => write!(f, "+ {}: {}", path, new),
+            Change::Removed { path, old } => write!(f, "- {}: {}", path, old),
+            Change::Modified { path, old, new } => write!(f, "~ {}: {} -> {}", path, old, new),
+        }
+    }
+}
+
+/// Compares two values trees leaf by leaf and returns every added, removed or modified
+/// path, sorted by path.
+///
+/// This works on the values as given, so pass resolved trees to compare final output.
+/// A leaf that turns into a mapping or sequence (or the reverse) shows up as the old
+/// leaf being removed and the new leaves being added.
+///
+/// # Examples
+///
+/// ```
+/// let old: serde_yaml::Value = serde_yaml::from_str("image: nginx\nports: [80]")?;
+/// let new: serde_yaml::Value = serde_yaml::from_str("image: nginx\nports: [8080, 443]")?;
+///
+/// let changes = diff_values(&old, &new);
+///
+/// assert_eq!(changes[0].to_string(), "~ ports[0]: 80 -> 8080");
+/// assert_eq!(changes[1].to_string(), "+ ports[1]: 443");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn diff_values(old: &Value, new: &Value) -> Vec<Change> {
+    let old_flat = flatten(old);
+    let mut new_flat = flatten(new);
+    let mut changes = Vec::new();
+
+    for (path, old_value) in old_flat {
+        match new_flat.remove(&path) {
+            Some(new_value) if new_value != old_value => changes.push(Change::Modified {
+                path,
+                old: old_value,
+                new: new_value,
+            }),
+            Some(_) => {}
+            None => changes.push(Change::Removed {
+                path,
+                old: old_value,
+            }),
+        }
+    }
+    changes.extend(new_flat.into_iter().map(|(path, new_value)| Change::Added {
+        path,
+        new: new_value,
+    }));
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml::from_str;
+
+    #[test]
+    fn test_diff_values_reports_added_removed_and_modified() -> anyhow::Result<()> {
+        let old: Value = from_str("a:\n  b: 1\n  c: keep\nremoved: true\nlist: [x, y]")?;
+        let new: Value = from_str("a:\n  b: 2\n  c: keep\nadded: new\nlist: [x]")?;
+
+        let changes = diff_values(&old, &new);
+
+        assert_eq!(
+            vec![
+                Change::Modified {
+                    path: "a.b".to_string(),
+                    old: "1".to_string(),
+                    new: "2".to_string(),
+                },
+                Change::Added {
+                    path: "added".to_string(),
+                    new: "new".to_string(),
+                },
+                Change::Removed {
+                    path: "list[1]".to_string(),
+                    old: "y".to_string(),
+                },
+                Change::Removed {
+                    path: "removed".to_string(),
+                    old: "true".to_string(),
+                },
+            ],
+            changes
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_values_identical_trees_is_empty() -> anyhow::Result<()> {
+        let values: Value = from_str("a:\n  b: [1, {c: d}]\nempty: {}")?;
+        assert!(diff_values(&values, &values.clone()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_values_leaf_replaced_by_mapping() -> anyhow::Result<()> {
+        let old: Value = from_str("db: sqlite")?;
+        let new: Value = from_str("db:\n  host: localhost")?;
+
+        let rendered: Vec<String> = diff_values(&old, &new)
+            .iter()
+            .map(Change::to_string)
+            .collect();
+
+        assert_eq!(vec!["- db: sqlite", "+ db.host: localhost"], rendered);
+        Ok(())
+    }
+}
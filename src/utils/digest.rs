@@ -0,0 +1,158 @@
+use serde_yaml::Value;
+use sha2::{Digest, Sha256};
+
+/// Serializes values to bytes that only depend on what the values mean, not on how they
+/// were written: mapping keys are sorted, strings are escaped as JSON strings and numbers
+/// are written in one fixed form, so `0x10` and `16` or `1.50` and `1.5` encode the same.
+/// Integers and floats stay distinct (`1` and `1.0` differ), as do strings and numbers.
+///
+/// The encoding is compact JSON-like text, but is only meant to be hashed or compared.
+///
+/// # Examples
+///
+/// ```
+/// let a: serde_yaml::Value = serde_yaml::from_str("b: 1\na: [x, 2.0]")?;
+/// assert_eq!(canonical_bytes(&a), br#"{"a":["x",2.0],"b":1}"#.to_vec());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn canonical_bytes(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+/// The hex encoded SHA-256 of `canonical_bytes(value)`. Equal values always have the same
+/// digest, so comparing digests between runs tells whether merged and resolved values
+/// changed, e.g. to skip regenerating a compose file when nothing did.
+#[allow(dead_code)]
+pub fn value_digest(value: &Value) -> String {
+    Sha256::digest(canonical_bytes(value))
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn write_canonical(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.extend_from_slice(b"null"),
+        Value::Bool(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+        Value::Number(n) => {
+            let formatted = if let Some(i) = n.as_i64() {
+                i.to_string()
+            } else if let Some(u) = n.as_u64() {
+                u.to_string()
+            } else {
+                match n.as_f64() {
+                    Some(f) if f.is_nan() => ".nan".to_string(),
+                    Some(f) if f.is_infinite() && f > 0.0 => ".inf".to_string(),
+                    Some(f) if f.is_infinite() => "-.inf".to_string(),
+                    // Debug keeps the `.0` on whole floats, so they stay apart from integers
+                    Some(f) => format!("{:?}", f),
+                    None => n.to_string(),
+                }
+            };
+            out.extend_from_slice(formatted.as_bytes());
+        }
+        Value::String(s) => write_string(s, out),
+        Value::Sequence(items) => {
+            out.push(b'[');
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    out.push(b',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(b']');
+        }
+        Value::Mapping(map) => {
+            // Keys may be any value, so they are sorted by their own canonical form
+            let mut entries: Vec<(Vec<u8>, &Value)> = map
+                .iter()
+                .map(|(key, value)| (canonical_bytes(key), value))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            out.push(b'{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(&key);
+                out.push(b':');
+                write_canonical(value, out);
+            }
+            out.push(b'}');
+        }
+        Value::Tagged(tagged) => {
+            out.push(b'!');
+            write_string(&tagged.tag.to_string(), out);
+            write_canonical(&tagged.value, out);
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut Vec<u8>) {
+    let escaped = serde_json::to_string(s).expect("strings always serialize to JSON");
+    out.extend_from_slice(escaped.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml::from_str;
+
+    #[test]
+    fn test_reordered_values_have_the_same_digest() -> anyhow::Result<()> {
+        let a: Value = from_str(
+            "
+name: app
+services:
+  web: {image: nginx, ports: [80, 443]}
+  db: {image: postgres}
+",
+        )?;
+        let b: Value = from_str(
+            "
+services:
+  db:
+    image: 'postgres'
+  web:
+    ports:
+      - 0x50
+      - 443
+    image: \"nginx\"
+name: app # comments do not count
+",
+        )?;
+        assert_eq!(canonical_bytes(&a), canonical_bytes(&b));
+        assert_eq!(value_digest(&a), value_digest(&b));
+        assert_eq!(value_digest(&a).len(), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_different_values_have_different_digests() -> anyhow::Result<()> {
+        let base: Value = from_str("ports: [80, 443]\ntag: '1'")?;
+        let cases = [
+            "ports: [443, 80]\ntag: '1'",
+            "ports: [80, 443]\ntag: 1",
+            "ports: [80, 443]\ntag: 1.0",
+            "ports: [80, 443]\ntag: '1'\nextra: ~",
+        ];
+        for case in cases {
+            let other: Value = from_str(case)?;
+            assert_ne!(value_digest(&base), value_digest(&other), "{}", case);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_bytes_normalizes_scalars() -> anyhow::Result<()> {
+        let value: Value = from_str("f: 1.50\ni: +7\ns: \"a \\\"quoted\\\" word\"\nz: .inf")?;
+        assert_eq!(
+            String::from_utf8(canonical_bytes(&value))?,
+            r#"{"f":1.5,"i":7,"s":"a \"quoted\" word","z":.inf}"#
+        );
+        Ok(())
+    }
+}
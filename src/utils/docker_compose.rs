@@ -15,6 +15,17 @@ pub(crate) const COMPOSE_FILE_NAMES: [&str; 2] = ["docker-compose.jinja2", "dock
 #[cfg_attr(test, mockall::automock)]
 pub trait CommandRunner {
     fn run_unbuffered(&self, args: Vec<String>) -> i32;
+
+    /// Runs a command to completion and returns its exit code and output.
+    fn run_captured(&self, args: Vec<String>) -> anyhow::Result<CapturedOutput>;
+}
+
+/// Exit code and output of a command run with `CommandRunner::run_captured`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapturedOutput {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
 }
 
 pub struct RealCommandRunner;
@@ -24,6 +35,18 @@ impl CommandRunner for RealCommandRunner {
         let arg_refs: Vec<&str> = args.iter().map(String::as_str).collect();
         unbuffered_command(&arg_refs)
     }
+
+    fn run_captured(&self, args: Vec<String>) -> anyhow::Result<CapturedOutput> {
+        let arg_refs: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = silent_run(&arg_refs)
+            .output()
+            .map_err(|e| anyhow::anyhow!("Failed to run command {:?}: {}", args, e))?;
+        Ok(CapturedOutput {
+            exit_code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
 }
 
 pub fn unbuffered_command(command_line_args: &[&str]) -> i32 {
@@ -79,6 +102,20 @@ fn build_compose_down_args(path: &str) -> Vec<String> {
         .collect()
 }
 
+#[allow(dead_code)]
+fn build_compose_down_all_args(paths: &[&str], project: &str) -> Vec<String> {
+    let mut args: Vec<String> = ["docker", "compose", "-p", project]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    for path in paths {
+        args.push("-f".to_string());
+        args.push(path.to_string());
+    }
+    args.extend(["down".to_string(), "--remove-orphans".to_string()]);
+    args
+}
+
 fn build_compose_pull_args(path: &str) -> Vec<String> {
     ["docker", "compose", "-f", path, "pull", "--ignore-pull-failures"]
         .iter()
@@ -86,6 +123,156 @@ fn build_compose_pull_args(path: &str) -> Vec<String> {
         .collect()
 }
 
+fn build_compose_ps_args(application_id: &str) -> Vec<String> {
+    [
+        "docker",
+        "compose",
+        "-p",
+        application_id,
+        "ps",
+        "--all",
+        "--format",
+        "json",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+/// Turns an application id into a valid docker compose project name. Docker only allows
+/// lowercase letters, digits, `-` and `_`, starting with a letter or digit, so the id is
+/// lowercased and anything else is dropped. This is the same normalization docker applies
+/// to the directory name it otherwise derives the project name from, so the result names
+/// the project docker created. Ids with nothing usable left are rejected.
+pub fn normalize_project_id(id: &str) -> anyhow::Result<String> {
+    let normalized: String = id
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-' || *c == '_')
+        .collect();
+    let normalized = normalized.trim_start_matches(['-', '_']).to_string();
+    if normalized.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Application id '{}' cannot be used as a docker compose project name. Project names may only contain lowercase letters, digits, '-' and '_', and must start with a letter or digit.",
+            id
+        ));
+    }
+    if normalized != id {
+        trace!(
+            "Using docker compose project name '{}' for application id '{}'.",
+            normalized,
+            id
+        );
+    }
+    Ok(normalized)
+}
+
+/// State of one service container in a composed application, as reported by
+/// `docker compose ps`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ServiceStatus {
+    #[serde(rename = "Service")]
+    pub service: String,
+    /// Container name.
+    #[serde(rename = "Name")]
+    pub name: String,
+    /// Container state, e.g. `running`, `exited` or `restarting`.
+    #[serde(rename = "State")]
+    pub state: String,
+    /// Health check status (`healthy`, `unhealthy` or `starting`), if the service has one.
+    #[serde(rename = "Health", default, deserialize_with = "empty_as_none")]
+    pub health: Option<String>,
+    #[serde(rename = "ExitCode", default)]
+    pub exit_code: Option<i32>,
+}
+
+impl ServiceStatus {
+    #[allow(dead_code)]
+    pub fn is_running(&self) -> bool {
+        self.state == "running"
+    }
+}
+
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.filter(|s| !s.is_empty()))
+}
+
+/// Queries the state of every service container of the application composed under the
+/// project name `application_id`. Stopped containers are included.
+#[allow(dead_code)]
+pub fn compose_status(application_id: &str) -> anyhow::Result<Vec<ServiceStatus>> {
+    compose_status_with(&RealCommandRunner, application_id)
+}
+
+fn compose_status_with(
+    runner: &impl CommandRunner,
+    application_id: &str,
+) -> anyhow::Result<Vec<ServiceStatus>> {
+    let project_id = normalize_project_id(application_id)?;
+    trace!("[EXEC] docker compose ps {}", project_id);
+    let output = runner
+        .run_captured(build_compose_ps_args(&project_id))
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to query status of app {}: {}. Check that docker is installed and on your PATH.",
+                application_id,
+                e
+            )
+        })?;
+    if output.exit_code != 0 {
+        return Err(anyhow::anyhow!(
+            "docker compose ps failed for app {} with exit code {}: {}",
+            application_id,
+            output.exit_code,
+            output.stderr.trim()
+        ));
+    }
+    parse_compose_ps_output(&output.stdout).map_err(|e| {
+        anyhow::anyhow!(
+            "Could not parse docker compose ps output for app {}: {}",
+            application_id,
+            e
+        )
+    })
+}
+
+/// Whether at least one container of the application composed under the project name
+/// `application_id` is running. Together with `if_application_exists` this tells an app
+/// that is installed but down apart from one composer does not know about.
+#[allow(dead_code)]
+pub fn is_application_running(application_id: &str) -> anyhow::Result<bool> {
+    is_application_running_with(&RealCommandRunner, application_id)
+}
+
+fn is_application_running_with(
+    runner: &impl CommandRunner,
+    application_id: &str,
+) -> anyhow::Result<bool> {
+    let statuses = compose_status_with(runner, application_id)?;
+    Ok(statuses.iter().any(ServiceStatus::is_running))
+}
+
+/// Parses `docker compose ps --format json`, which is a JSON array in older compose
+/// releases and one JSON object per line in newer ones.
+fn parse_compose_ps_output(stdout: &str) -> anyhow::Result<Vec<ServiceStatus>> {
+    let trimmed = stdout.trim();
+    if trimmed.is_empty() {
+        return Ok(Vec::new());
+    }
+    if trimmed.starts_with('[') {
+        return Ok(serde_json::from_str(trimmed)?);
+    }
+    trimmed
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
 pub fn compose_up(path: &str, application_id: &str) -> anyhow::Result<()> {
     compose_up_with(&RealCommandRunner, path, application_id)
 }
@@ -95,6 +282,8 @@ fn compose_up_with(
     path: &str,
     application_id: &str,
 ) -> anyhow::Result<()> {
+    // Catch ids docker cannot use as a project name before it fails with a vaguer error
+    normalize_project_id(application_id)?;
     // A compose file is invalid if its empty or invalid yaml
     check_compose_is_valid(path)?;
     if compose_has_no_services(path) {
@@ -210,6 +399,73 @@ pub(crate) fn compose_down_with(runner: &impl CommandRunner, path: &str, applica
     }
 }
 
+/// Stops an application made of several compose files with a single
+/// `docker compose -p <id> -f a -f b down`, so docker sees them as one project and removes
+/// everything it started, including networks shared between the files. Files with no
+/// services are left out. Only containers started under the same `-p <id>` project are
+/// stopped; `compose_up` names each project after its file's directory instead.
+///
+/// # Errors
+///
+/// Returns an error listing every compose file that is missing, empty or not valid YAML,
+/// without running anything, or an error if `docker compose down` fails.
+#[allow(dead_code)]
+pub fn compose_down_all(compose_files: &[String], application_id: &str) -> anyhow::Result<()> {
+    compose_down_all_with(&RealCommandRunner, compose_files, application_id)
+}
+
+#[allow(dead_code)]
+pub(crate) fn compose_down_all_with(
+    runner: &impl CommandRunner,
+    compose_files: &[String],
+    application_id: &str,
+) -> anyhow::Result<()> {
+    let project = normalize_project_id(application_id)?;
+    let invalid: Vec<String> = compose_files
+        .iter()
+        .filter_map(|path| check_compose_is_valid(path).err())
+        .map(|e| format!("  {}", e))
+        .collect();
+    if !invalid.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Cannot stop app {}, {} compose file(s) are invalid:\n{}",
+            application_id,
+            invalid.len(),
+            invalid.join("\n")
+        ));
+    }
+
+    let paths: Vec<&str> = compose_files
+        .iter()
+        .map(String::as_str)
+        .filter(|path| !compose_has_no_services(path))
+        .collect();
+    if paths.is_empty() {
+        trace!(
+            "Compose down for app {} has been skipped as no file defines services.",
+            application_id
+        );
+        return Ok(());
+    }
+    trace!("[EXEC] docker compose -p {} down {:?}", project, paths);
+    let exit_code = runner.run_unbuffered(build_compose_down_all_args(&paths, &project));
+
+    if exit_code != 0 {
+        if let Err(e) = update_application_state(application_id, ApplicationState::Error) {
+            error!(
+                "Could not update application state for app {}: {}",
+                application_id, e
+            );
+        }
+        return Err(anyhow::anyhow!(
+            "docker compose down failed for app {} with exit code {}. Some containers may still persist.",
+            application_id,
+            exit_code
+        ));
+    }
+    Ok(())
+}
+
 pub fn is_compose_installed() -> bool {
     match silent_run(&["docker", "compose", "version"]).status() {
         Ok(status) => {
@@ -304,6 +560,206 @@ mod tests {
         assert_eq!(expected, build_compose_pull_args("compose.yaml"));
     }
 
+    #[test]
+    fn test_build_compose_ps_args() {
+        let expected = vec![
+            "docker", "compose", "-p", "my_app", "ps", "--all", "--format", "json",
+        ];
+        assert_eq!(expected, build_compose_ps_args("my_app"));
+    }
+
+    #[test]
+    fn test_normalize_project_id_keeps_valid_ids() -> anyhow::Result<()> {
+        for id in ["my_app", "happy-blue-cat", "app2", "0-day"] {
+            assert_eq!(normalize_project_id(id)?, id);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_project_id_normalizes_invalid_characters() -> anyhow::Result<()> {
+        assert_eq!(normalize_project_id("My_App")?, "my_app");
+        assert_eq!(normalize_project_id("web.api v2")?, "webapiv2");
+        assert_eq!(normalize_project_id("_-internal")?, "internal");
+        assert_eq!(normalize_project_id("café")?, "caf");
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_project_id_rejects_ids_with_nothing_usable() {
+        for id in ["", "...", "-_-", "日本"] {
+            let err = normalize_project_id(id).unwrap_err().to_string();
+            assert!(err.contains(&format!("'{}'", id)), "{}", err);
+            assert!(
+                err.contains("lowercase letters, digits, '-' and '_'"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_compose_status_uses_normalized_project_id() -> anyhow::Result<()> {
+        let expected_args = build_compose_ps_args("my_app");
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run_captured()
+            .withf(move |args| *args == expected_args)
+            .times(1)
+            .returning(|_| Ok(CapturedOutput::default()));
+        assert!(compose_status_with(&runner, "My_App")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_up_rejects_unusable_id_without_running() -> anyhow::Result<()> {
+        let file = temp_compose_file(COMPOSE_WITH_SERVICES)?;
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run_unbuffered().times(0);
+        assert!(compose_up_with(&runner, &path_str(&file), "...").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_status_parses_line_delimited_output() -> anyhow::Result<()> {
+        let stdout = concat!(
+            r#"{"Service":"web","Name":"my_app-web-1","State":"running","Health":"healthy","ExitCode":0}"#,
+            "\n",
+            r#"{"Service":"worker","Name":"my_app-worker-1","State":"exited","Health":"","ExitCode":1}"#,
+            "\n",
+        );
+        let expected_args = build_compose_ps_args("my_app");
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run_captured()
+            .withf(move |args| *args == expected_args)
+            .times(1)
+            .returning(move |_| {
+                Ok(CapturedOutput {
+                    exit_code: 0,
+                    stdout: stdout.to_string(),
+                    stderr: String::new(),
+                })
+            });
+
+        let statuses = compose_status_with(&runner, "my_app")?;
+
+        assert_eq!(2, statuses.len());
+        assert!(statuses[0].is_running());
+        assert_eq!(Some("healthy".to_string()), statuses[0].health);
+        assert_eq!("exited", statuses[1].state);
+        assert_eq!(None, statuses[1].health);
+        assert_eq!(Some(1), statuses[1].exit_code);
+        Ok(())
+    }
+
+    fn runner_with_ps_output(stdout: &'static str) -> MockCommandRunner {
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run_captured().returning(move |_| {
+            Ok(CapturedOutput {
+                exit_code: 0,
+                stdout: stdout.to_string(),
+                stderr: String::new(),
+            })
+        });
+        runner
+    }
+
+    #[test]
+    fn test_is_application_running_with_a_running_container() -> anyhow::Result<()> {
+        let runner = runner_with_ps_output(concat!(
+            r#"{"Service":"web","Name":"app-web-1","State":"running"}"#,
+            "\n",
+            r#"{"Service":"worker","Name":"app-worker-1","State":"exited","ExitCode":1}"#,
+        ));
+        assert!(is_application_running_with(&runner, "app")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_application_running_when_stopped_or_unknown() -> anyhow::Result<()> {
+        let stopped = runner_with_ps_output(
+            r#"[{"Service":"web","Name":"app-web-1","State":"exited","ExitCode":137}]"#,
+        );
+        assert!(!is_application_running_with(&stopped, "app")?);
+
+        // Projects docker has never seen have no containers
+        let unknown = runner_with_ps_output("");
+        assert!(!is_application_running_with(&unknown, "app")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_application_running_without_docker_errors_clearly() {
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run_captured()
+            .returning(|_| Err(anyhow::anyhow!("No such file or directory (os error 2)")));
+
+        let err = is_application_running_with(&runner, "app")
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            "Failed to query status of app app: No such file or directory (os error 2). Check that docker is installed and on your PATH.",
+            err
+        );
+    }
+
+    #[test]
+    fn test_parse_compose_ps_output_accepts_json_array_and_empty() -> anyhow::Result<()> {
+        let array = r#"[{"Service":"db","Name":"app-db-1","State":"running"}]"#;
+        let statuses = parse_compose_ps_output(array)?;
+        assert_eq!("db", statuses[0].service);
+        assert_eq!(None, statuses[0].health);
+
+        assert!(parse_compose_ps_output("  \n")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_status_failure_names_app_and_stderr() {
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run_captured().returning(|_| {
+            Ok(CapturedOutput {
+                exit_code: 1,
+                stdout: String::new(),
+                stderr: "Cannot connect to the Docker daemon\n".to_string(),
+            })
+        });
+
+        let err = compose_status_with(&runner, "my_app")
+            .unwrap_err()
+            .to_string();
+
+        assert_eq!(
+            "docker compose ps failed for app my_app with exit code 1: Cannot connect to the Docker daemon",
+            err
+        );
+    }
+
+    #[test]
+    fn test_compose_status_parse_error_names_app() {
+        let mut runner = MockCommandRunner::new();
+        runner.expect_run_captured().returning(|_| {
+            Ok(CapturedOutput {
+                exit_code: 0,
+                stdout: "not json".to_string(),
+                stderr: String::new(),
+            })
+        });
+
+        let err = compose_status_with(&runner, "my_app")
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.starts_with("Could not parse docker compose ps output for app my_app"),
+            "{}",
+            err
+        );
+    }
+
     #[test]
     fn test_compose_up_runs_command_for_valid_file() -> anyhow::Result<()> {
         let file = temp_compose_file(COMPOSE_WITH_SERVICES)?;
@@ -464,6 +920,61 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compose_down_all_runs_one_command_for_every_file() -> anyhow::Result<()> {
+        let web = temp_compose_file(COMPOSE_WITH_SERVICES)?;
+        let db = temp_compose_file("services:\n  db:\n    image: postgres\n")?;
+        let empty = temp_compose_file("services: {}\n")?;
+        let files = vec![path_str(&web), path_str(&empty), path_str(&db)];
+        let expected_args: Vec<String> = [
+            "docker",
+            "compose",
+            "-p",
+            "my_app",
+            "-f",
+            &files[0],
+            "-f",
+            &files[2],
+            "down",
+            "--remove-orphans",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let mut runner = MockCommandRunner::new();
+        runner
+            .expect_run_unbuffered()
+            .withf(move |args| *args == expected_args)
+            .times(1)
+            .returning(|_| 0);
+
+        compose_down_all_with(&runner, &files, "My_App")
+    }
+
+    #[test]
+    fn test_compose_down_all_reports_every_invalid_file() -> anyhow::Result<()> {
+        let valid = temp_compose_file(COMPOSE_WITH_SERVICES)?;
+        let empty = temp_compose_file("  \n")?;
+        let files = vec![
+            path_str(&valid),
+            "/nonexistent/compose.yaml".to_string(),
+            path_str(&empty),
+        ];
+        let runner = MockCommandRunner::new();
+
+        let err = compose_down_all_with(&runner, &files, "test_app")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Cannot stop app test_app, 2 compose file(s) are invalid"),
+            "{}",
+            err
+        );
+        assert!(err.contains("does not exist"), "{}", err);
+        assert!(err.contains("is empty"), "{}", err);
+        Ok(())
+    }
+
     #[test]
     fn test_compose_pull_runs_command() {
         let expected_args = build_compose_pull_args("compose.yaml");
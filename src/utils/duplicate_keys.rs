@@ -0,0 +1,159 @@
+use serde::de::{
+    DeserializeSeed, Deserializer, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use serde_yaml::value::{Tag, TaggedValue};
+use serde_yaml::{Mapping, Number, Sequence, Value};
+use std::fmt;
+
+/// Parses YAML into a `Value` like `serde_yaml::from_str`, except that a key repeated
+/// within a mapping is not an error. The first or the last occurrence is kept, depending
+/// on `keep_first`, at the position the key first appeared.
+pub(crate) fn from_str_allowing_duplicates(
+    contents: &str,
+    keep_first: bool,
+) -> Result<Value, serde_yaml::Error> {
+    ValueSeed { keep_first }.deserialize(serde_yaml::Deserializer::from_str(contents))
+}
+
+#[derive(Clone, Copy)]
+struct ValueSeed {
+    keep_first: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed {
+    type Value = Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any YAML value")
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Bool(b))
+    }
+
+    fn visit_i64<E>(self, i: i64) -> Result<Value, E> {
+        Ok(Value::Number(i.into()))
+    }
+
+    fn visit_u64<E>(self, u: u64) -> Result<Value, E> {
+        Ok(Value::Number(u.into()))
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<Value, E> {
+        Ok(Value::Number(Number::from(f)))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::String(s.to_owned()))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Value, E> {
+        Ok(Value::String(s))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut data: A) -> Result<Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut sequence = Sequence::new();
+        while let Some(item) = data.next_element_seed(self)? {
+            sequence.push(item);
+        }
+        Ok(Value::Sequence(sequence))
+    }
+
+    fn visit_map<A>(self, mut data: A) -> Result<Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut mapping = Mapping::new();
+        while let Some(key) = data.next_key_seed(self)? {
+            let value = data.next_value_seed(self)?;
+            if self.keep_first && mapping.contains_key(&key) {
+                continue;
+            }
+            mapping.insert(key, value);
+        }
+        Ok(Value::Mapping(mapping))
+    }
+
+    fn visit_enum<A>(self, data: A) -> Result<Value, A::Error>
+    where
+        A: EnumAccess<'de>,
+    {
+        let (tag, contents) = data.variant::<String>()?;
+        if tag.is_empty() {
+            return Err(serde::de::Error::custom("empty YAML tag is not allowed"));
+        }
+        let value = contents.newtype_variant_seed(self)?;
+        Ok(Value::Tagged(Box::new(TaggedValue {
+            tag: Tag::new(tag),
+            value,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_serde_yaml_without_duplicates() -> anyhow::Result<()> {
+        let contents = r#"
+name: app
+count: 3
+ratio: 0.5
+big: 18446744073709551615
+enabled: true
+empty: ~
+list: [a, {b: c}]
+tagged: !raw "{{ x }}"
+base: &base {port: 80}
+copy: *base
+"#;
+        let expected: Value = serde_yaml::from_str(contents)?;
+        assert_eq!(from_str_allowing_duplicates(contents, false)?, expected);
+        assert_eq!(from_str_allowing_duplicates("", false)?, Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keeps_first_or_last_duplicate() -> anyhow::Result<()> {
+        let contents = "a: 1\nb: {x: 1, x: 2}\na: 3\n";
+
+        let last = from_str_allowing_duplicates(contents, false)?;
+        let expected: Value = serde_yaml::from_str("a: 3\nb: {x: 2}")?;
+        assert_eq!(last, expected);
+
+        let first = from_str_allowing_duplicates(contents, true)?;
+        let expected: Value = serde_yaml::from_str("a: 1\nb: {x: 1}")?;
+        assert_eq!(first, expected);
+        Ok(())
+    }
+}
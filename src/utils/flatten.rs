@@ -0,0 +1,446 @@
+use crate::utils::value_resolver::get_value_at_path;
+use anyhow::{anyhow, Context};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Flattens a values tree into `path -> value` pairs, one per leaf.
+///
+/// Paths use the same scheme as value references: mapping keys are joined with `.` and
+/// sequence elements are addressed as `[n]`, e.g. `services.web.ports[0]`. Scalars are
+/// rendered as plain text, and empty mappings or sequences are kept as `{}` / `[]` so they
+/// are not silently dropped.
+///
+/// The result is meant for reading, not for turning back into `x.y.z=foo` overrides:
+/// the string `"80"` and the number `80` both flatten to `80`, which an override reads as
+/// a number, and a key containing `.` reads as a longer path.
+///
+/// # Examples
+///
+/// ```
+/// let values: serde_yaml::Value = serde_yaml::from_str("a:\n  b: [1, two]")?;
+/// let flat = flatten(&values);
+/// assert_eq!(flat["a.b[0]"], "1");
+/// assert_eq!(flat["a.b[1]"], "two");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn flatten(value: &Value) -> BTreeMap<String, String> {
+    let mut flat = BTreeMap::new();
+    flatten_into(value, "", &mut flat);
+    flat
+}
+
+fn flatten_into(value: &Value, current_path: &str, flat: &mut BTreeMap<String, String>) {
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (key, val) in map {
+                let key_str = scalar_to_string(key);
+                let new_path = if current_path.is_empty() {
+                    key_str
+                } else {
+                    format!("{}.{}", current_path, key_str)
+                };
+                flatten_into(val, &new_path, flat);
+            }
+        }
+        Value::Sequence(seq) if !seq.is_empty() => {
+            for (idx, val) in seq.iter().enumerate() {
+                let new_path = format!("{}[{}]", current_path, idx);
+                flatten_into(val, &new_path, flat);
+            }
+        }
+        Value::Tagged(tagged) => flatten_into(&tagged.value, current_path, flat),
+        _ => {
+            flat.insert(current_path.to_string(), scalar_to_string(value));
+        }
+    }
+}
+
+/// Flattens a values tree into environment variable assignments, e.g. for an `env_file`.
+///
+/// Each leaf becomes one `(name, value)` pair, in document order. The name is `prefix`
+/// followed by every mapping key and sequence index on the way to the leaf, joined with
+/// `_` and uppercased, with anything other than letters, digits and `_` replaced by `_`:
+/// `services.web-app.ports[0]` under prefix `app` becomes `APP_SERVICES_WEB_APP_PORTS_0`.
+/// An empty prefix adds nothing, and a name that would start with a digit gets a leading
+/// `_`. Strings are used as they are, `null` becomes an empty string, and empty mappings
+/// or sequences become JSON (`{}` / `[]`). Different paths can sanitize to the same name;
+/// both pairs are returned, so the later one wins when loaded as an env file.
+///
+/// # Examples
+///
+/// ```
+/// let values: serde_yaml::Value = serde_yaml::from_str("db:\n  host: x\nports: [80]")?;
+/// let env = to_env(&values, "app");
+/// assert_eq!(env[0], ("APP_DB_HOST".to_string(), "x".to_string()));
+/// assert_eq!(env[1], ("APP_PORTS_0".to_string(), "80".to_string()));
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn to_env(value: &Value, prefix: &str) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    to_env_into(value, &env_name_part(prefix), &mut env);
+    env
+}
+
+fn to_env_into(value: &Value, current_name: &str, env: &mut Vec<(String, String)>) {
+    let child_name = |part: &str| {
+        let part = env_name_part(part);
+        if current_name.is_empty() {
+            part
+        } else {
+            format!("{}_{}", current_name, part)
+        }
+    };
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (key, val) in map {
+                to_env_into(val, &child_name(&scalar_to_string(key)), env);
+            }
+        }
+        Value::Sequence(seq) if !seq.is_empty() => {
+            for (idx, val) in seq.iter().enumerate() {
+                to_env_into(val, &child_name(&idx.to_string()), env);
+            }
+        }
+        Value::Tagged(tagged) => to_env_into(&tagged.value, current_name, env),
+        _ => {
+            let name = if current_name.starts_with(|c: char| c.is_ascii_digit()) {
+                format!("_{}", current_name)
+            } else {
+                current_name.to_string()
+            };
+            let value = match value {
+                Value::Null => String::new(),
+                Value::Sequence(_) | Value::Mapping(_) => {
+                    serde_json::to_string(value).unwrap_or_default()
+                }
+                other => scalar_to_string(other),
+            };
+            env.push((name, value));
+        }
+    }
+}
+
+/// Writes the mapping at `service_path` (e.g. `services.web.environment`) to `out` as a
+/// dotenv file for compose's `env_file`, one `KEY=VALUE` line per entry in mapping order.
+///
+/// Values that compose would read differently unquoted, such as ones containing spaces,
+/// `#`, quotes or `$`, are quoted. Single quotes are used where possible, as compose
+/// reads them literally; values containing a `'` or a line break are double quoted, with
+/// `\`, `"`, line breaks and tabs escaped and `$` written as `$$`. A `null` value is
+/// written as a bare `KEY`, which compose fills in from the shell like `KEY:` in
+/// `environment`.
+///
+/// # Errors
+///
+/// Returns an error if there is nothing at `service_path`, if it is not a mapping, if a
+/// key is not a valid variable name, if a value is a mapping or sequence, or if `out`
+/// cannot be written.
+#[allow(dead_code)]
+pub fn write_env_file(value: &Value, service_path: &str, out: &Path) -> anyhow::Result<()> {
+    let contents = env_file_contents(value, service_path)?;
+    std::fs::write(out, contents)
+        .with_context(|| format!("Failed to write env file {}", out.display()))
+}
+
+fn env_file_contents(value: &Value, service_path: &str) -> anyhow::Result<String> {
+    let mapping = match get_value_at_path(value, service_path) {
+        Some(Value::Mapping(mapping)) => mapping,
+        Some(_) => return Err(anyhow!("'{}' is not a mapping", service_path)),
+        None => return Err(anyhow!("No values found at '{}'", service_path)),
+    };
+    let mut contents = String::new();
+    for (key, val) in mapping {
+        let name = scalar_to_string(key);
+        let is_valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_valid_name {
+            return Err(anyhow!(
+                "'{}.{}' is not a valid environment variable name",
+                service_path,
+                name
+            ));
+        }
+        match val {
+            Value::Null => contents.push_str(&format!("{}\n", name)),
+            Value::Bool(_) | Value::Number(_) | Value::String(_) => {
+                let line = format!("{}={}\n", name, quote_env_value(&scalar_to_string(val)));
+                contents.push_str(&line)
+            }
+            _ => {
+                return Err(anyhow!(
+                    "'{}.{}' is not a scalar, only scalars can be written to an env file",
+                    service_path,
+                    name
+                ))
+            }
+        }
+    }
+    Ok(contents)
+}
+
+/// Quotes a dotenv value if compose would not read it back as it is unquoted.
+fn quote_env_value(value: &str) -> String {
+    let needs_quotes = value.trim() != value
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '#' | '"' | '\'' | '\\' | '$' | '`'));
+    if !needs_quotes {
+        return value.to_string();
+    }
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' => quoted.push_str("$$"),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Uppercases `part` and replaces anything that cannot appear in an environment variable
+/// name with `_`.
+fn env_name_part(part: &str) -> String {
+    part.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Renders a leaf the way it would be written on the right-hand side of an override.
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        Value::Sequence(seq) if seq.is_empty() => "[]".to_string(),
+        Value::Mapping(map) if map.is_empty() => "{}".to_string(),
+        Value::Tagged(tagged) => scalar_to_string(&tagged.value),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::load_values::load_yaml_files;
+    use serde_yaml::from_str;
+
+    #[test]
+    fn test_flatten_nested_mappings_and_sequences() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+hello: true
+count: 3
+foo:
+  bar: "hi"
+  list:
+    - first
+    - nested: value
+"#,
+        )?;
+        let flat = flatten(&values);
+
+        let expected: BTreeMap<String, String> = [
+            ("count", "3"),
+            ("foo.bar", "hi"),
+            ("foo.list[0]", "first"),
+            ("foo.list[1].nested", "value"),
+            ("hello", "true"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(expected, flat);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_keeps_empty_collections_and_nulls() -> anyhow::Result<()> {
+        let values: Value = from_str("empty_map: {}\nempty_list: []\nnothing: ~")?;
+        let flat = flatten(&values);
+        assert_eq!(flat["empty_map"], "{}");
+        assert_eq!(flat["empty_list"], "[]");
+        assert_eq!(flat["nothing"], "null");
+        Ok(())
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_to_env_nested_mappings_and_sequences() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+database:
+  host: db
+  port: 5432
+ports: [80, 443]
+servers:
+  - name: a
+    tags: [x]
+debug: true
+"#,
+        )?;
+        assert_eq!(
+            to_env(&values, "app"),
+            pairs(&[
+                ("APP_DATABASE_HOST", "db"),
+                ("APP_DATABASE_PORT", "5432"),
+                ("APP_PORTS_0", "80"),
+                ("APP_PORTS_1", "443"),
+                ("APP_SERVERS_0_NAME", "a"),
+                ("APP_SERVERS_0_TAGS_0", "x"),
+                ("APP_DEBUG", "true"),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_env_sanitizes_names() -> anyhow::Result<()> {
+        let values: Value = from_str("web-app:\n  log.level: info\n  \"max conns\": 10\n")?;
+        assert_eq!(
+            to_env(&values, "my-prefix"),
+            pairs(&[
+                ("MY_PREFIX_WEB_APP_LOG_LEVEL", "info"),
+                ("MY_PREFIX_WEB_APP_MAX_CONNS", "10"),
+            ])
+        );
+
+        // Without a prefix, a leading digit is escaped
+        let values: Value = from_str("[first]")?;
+        assert_eq!(to_env(&values, ""), pairs(&[("_0", "first")]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_env_leaves_that_are_not_scalars() -> anyhow::Result<()> {
+        let values: Value = from_str("nothing: ~\nempty_map: {}\nempty_list: []\ntext: 'a \"b\"'")?;
+        assert_eq!(
+            to_env(&values, "X"),
+            pairs(&[
+                ("X_NOTHING", ""),
+                ("X_EMPTY_MAP", "{}"),
+                ("X_EMPTY_LIST", "[]"),
+                ("X_TEXT", "a \"b\""),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_round_trips_through_overrides() -> anyhow::Result<()> {
+        let values: Value = from_str("a:\n  b:\n    c: text\n  d: 42\nflag: false")?;
+        let overrides: Vec<String> = flatten(&values)
+            .into_iter()
+            .map(|(path, value)| format!("{}={}", path, value))
+            .collect();
+        let refs: Vec<&str> = overrides.iter().map(String::as_str).collect();
+
+        assert_eq!(values, load_yaml_files(&refs)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_file_quoting() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+services:
+  web:
+    environment:
+      PLAIN: value
+      NUMBER: 8080
+      ENABLED: true
+      EMPTY: ""
+      SPACES: hello world
+      PADDED: " padded "
+      HASH: "a#b"
+      DOLLAR: "pa$word"
+      APOSTROPHE: "it's"
+      DOUBLE: 'say "hi"'
+      MULTILINE: "line one\nline two"
+      BACKSLASH: "C:\\dir it's"
+      INHERITED: null
+"#,
+        )?;
+
+        let contents = env_file_contents(&values, "services.web.environment")?;
+
+        let expected = r#"PLAIN=value
+NUMBER=8080
+ENABLED=true
+EMPTY=
+SPACES='hello world'
+PADDED=' padded '
+HASH='a#b'
+DOLLAR='pa$word'
+APOSTROPHE="it's"
+DOUBLE='say "hi"'
+MULTILINE="line one\nline two"
+BACKSLASH="C:\\dir it's"
+INHERITED
+"#;
+        assert_eq!(contents, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_file_rejects_what_it_cannot_write() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+env:
+  NESTED:
+    a: 1
+bad_name:
+  1ST: x
+list: [a, b]
+"#,
+        )?;
+
+        let err = env_file_contents(&values, "env").unwrap_err();
+        assert!(err.to_string().contains("'env.NESTED' is not a scalar"));
+        let err = env_file_contents(&values, "bad_name").unwrap_err();
+        assert!(err.to_string().contains("'bad_name.1ST' is not a valid"));
+        let err = env_file_contents(&values, "list").unwrap_err();
+        assert!(err.to_string().contains("'list' is not a mapping"));
+        let err = env_file_contents(&values, "missing").unwrap_err();
+        assert!(err.to_string().contains("No values found at 'missing'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_env_file() -> anyhow::Result<()> {
+        let values: Value = from_str("app:\n  env:\n    A: one\n    B: two words")?;
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("app.env");
+
+        write_env_file(&values, "app.env", &out)?;
+
+        assert_eq!(std::fs::read_to_string(&out)?, "A=one\nB='two words'\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::anyhow;
+
+/// Splits an RFC 6901 JSON Pointer such as `/config/greeting` into its unescaped
+/// reference tokens. `~1` decodes to `/` and `~0` to `~`, so `/a~1b/c~0d` becomes
+/// `["a/b", "c~d"]`. The empty pointer refers to the whole document and has no tokens.
+///
+/// # Errors
+///
+/// Returns an error if a non-empty pointer does not start with `/`, or if a `~` is not
+/// followed by `0` or `1`.
+///
+/// # Examples
+///
+/// ```
+/// let tokens = parse_json_pointer("/services/web.app/ports/0")?;
+/// assert_eq!(tokens, vec!["services", "web.app", "ports", "0"]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn parse_json_pointer(pointer: &str) -> anyhow::Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(anyhow!(
+            "Invalid JSON Pointer '{}': must start with '/'",
+            pointer
+        ));
+    };
+    rest.split('/')
+        .map(|token| unescape_token(token, pointer))
+        .collect()
+}
+
+fn unescape_token(token: &str, pointer: &str) -> anyhow::Result<String> {
+    let mut unescaped = String::with_capacity(token.len());
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => unescaped.push('~'),
+            Some('1') => unescaped.push('/'),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid JSON Pointer '{}': '~' must be followed by '0' or '1'",
+                    pointer
+                ))
+            }
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Whether a reference token addresses a sequence element: a non-negative integer
+/// without leading zeros, as RFC 6901 defines array indices.
+pub fn is_index_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.chars().all(|c| c.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json_pointer_splits_tokens() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_json_pointer("/config/greeting")?,
+            vec!["config", "greeting"]
+        );
+        assert_eq!(parse_json_pointer("/a.b/0")?, vec!["a.b", "0"]);
+        assert_eq!(parse_json_pointer("/")?, vec![""]);
+        assert!(parse_json_pointer("")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_json_pointer_unescapes_tokens() -> anyhow::Result<()> {
+        assert_eq!(parse_json_pointer("/a~1b/c~0d")?, vec!["a/b", "c~d"]);
+        // `~01` is `~` followed by `1`, not `/`
+        assert_eq!(parse_json_pointer("/~01")?, vec!["~1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_json_pointer_rejects_invalid_pointers() {
+        assert!(parse_json_pointer("config/greeting").is_err());
+        assert!(parse_json_pointer("/a~2b").is_err());
+        assert!(parse_json_pointer("/trailing~").is_err());
+    }
+
+    #[test]
+    fn test_is_index_token() {
+        assert!(is_index_token("0"));
+        assert!(is_index_token("42"));
+        assert!(!is_index_token("01"));
+        assert!(!is_index_token("-1"));
+        assert!(!is_index_token(""));
+        assert!(!is_index_token("a1"));
+    }
+}
@@ -1,32 +1,789 @@
 use serde_yaml::{Mapping, Value};
 
-use crate::utils::value_resolver::resolve_value_references;
-use crate::utils::yaml_string_parser::parse_yaml_string;
+use crate::utils::duplicate_keys::from_str_allowing_duplicates;
+use crate::utils::flatten::flatten;
+use crate::utils::value_resolver::{
+    coerce_values, contains_references_with, find_unresolved_except, is_within, raw_paths,
+    resolve_value_references_with, too_deep, value_references, ResolveOptions, ScalarType,
+    DEFAULT_MAX_DEPTH,
+};
+use crate::utils::walk::{get_files_with_extensions, get_files_with_names};
+use crate::utils::yaml_string_parser::{
+    override_forces_string, override_key_path, override_keys, parse_yaml_string, APPEND_TAG,
+};
 use anyhow::Context;
+use serde::de::DeserializeOwned;
 use serde_yaml::mapping::Entry;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
 
-fn merge_maps(existing_map: &mut Mapping, new_map: Mapping) {
+fn merge_maps(
+    existing_map: &mut Mapping,
+    new_map: Mapping,
+    options: &MergeOptions,
+) -> anyhow::Result<()> {
+    merge_maps_at(existing_map, new_map, "", 1, options)
+}
+
+/// Merges `new_map` into `existing_map`, which sits `depth` levels below the root.
+fn merge_maps_at(
+    existing_map: &mut Mapping,
+    new_map: Mapping,
+    parent_path: &str,
+    depth: usize,
+    options: &MergeOptions,
+) -> anyhow::Result<()> {
+    if depth > options.max_depth {
+        return Err(too_deep(parent_path));
+    }
+    if options.strategy == MergeStrategy::JsonMergePatch {
+        return merge_patch_at(existing_map, new_map, parent_path, depth, options);
+    }
+    // Keys the new document adds are checked against the existing keys; collisions within
+    // the new document itself were already reported by `check_key_case_within`
+    let existing_keys = (options.key_case_collisions != KeyCaseCollision::Allow)
+        .then(|| lowercase_key_index(existing_map));
     for (new_key, new_value) in new_map {
-        let new_value_clone = new_value.clone();
+        let path = child_path(parent_path, &new_key);
+        if let (Some(existing_keys), Value::String(key)) = (&existing_keys, &new_key) {
+            if let Some(existing_key) = existing_keys.get(&key.to_lowercase()) {
+                if existing_key != key {
+                    options.report_key_case_collision(parent_path, existing_key, key)?;
+                }
+            }
+        }
         match existing_map.entry(new_key) {
-            Entry::Occupied(mut entry) => match (entry.get_mut(), &new_value) {
-                (Value::Mapping(existing_inner), Value::Mapping(new_inner)) => {
-                    merge_maps(existing_inner, new_inner.clone());
+            Entry::Occupied(mut entry) => {
+                if options.precedence == Precedence::EarlierWins {
+                    if let (Value::Mapping(existing_inner), Value::Mapping(new_inner)) =
+                        (entry.get_mut(), new_value)
+                    {
+                        merge_maps_at(existing_inner, new_inner, &path, depth + 1, options)?;
+                    }
+                    continue;
                 }
-                (Value::Sequence(existing_list), Value::Sequence(new_list)) => {
-                    existing_list.extend(new_list.clone());
+                if options.blocks_override(&path, entry.get(), &new_value) {
+                    options.reject_pinned_override(&path)?;
+                    continue;
                 }
-                _ => {
-                    entry.insert(new_value_clone);
+                // Values are moved out of the new document rather than cloned, so merging
+                // large files does not hold two copies of them in memory
+                match (entry.get_mut(), new_value) {
+                    (existing, Value::Tagged(tagged)) if tagged.tag == APPEND_TAG => {
+                        match (existing, tagged.value) {
+                            (Value::Sequence(existing_list), Value::Sequence(new_items)) => {
+                                existing_list.extend(new_items)
+                            }
+                            (existing, new_items) => *existing = new_items,
+                        }
+                    }
+                    (Value::Mapping(existing_inner), Value::Mapping(new_inner)) => {
+                        merge_maps_at(existing_inner, new_inner, &path, depth + 1, options)?;
+                    }
+                    (Value::Sequence(existing_list), Value::Sequence(new_list)) => {
+                        merge_sequences(existing_list, new_list, &path, depth + 1, options)?;
+                    }
+                    (existing, new_value) => {
+                        if options.scalar_conflict.replaces(existing, &new_value) {
+                            entry.insert(new_value);
+                        }
+                    }
                 }
-            },
+            }
             Entry::Vacant(entry) => {
-                entry.insert(new_value);
+                entry.insert(without_append_tags(new_value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies `patch` to `target` as an RFC 7386 JSON Merge Patch.
+fn merge_patch_at(
+    target: &mut Mapping,
+    patch: Mapping,
+    parent_path: &str,
+    depth: usize,
+    options: &MergeOptions,
+) -> anyhow::Result<()> {
+    if depth > options.max_depth {
+        return Err(too_deep(parent_path));
+    }
+    for (key, value) in patch {
+        let path = child_path(parent_path, &key);
+        match value {
+            Value::Null => {
+                target.shift_remove(&key);
+            }
+            Value::Mapping(inner_patch) => {
+                let inner_target = match target.entry(key) {
+                    Entry::Occupied(entry) => {
+                        let existing = entry.into_mut();
+                        if !existing.is_mapping() {
+                            *existing = Value::Mapping(Mapping::new());
+                        }
+                        existing
+                    }
+                    Entry::Vacant(entry) => entry.insert(Value::Mapping(Mapping::new())),
+                };
+                if let Value::Mapping(inner_target) = inner_target {
+                    merge_patch_at(inner_target, inner_patch, &path, depth + 1, options)?;
+                }
+            }
+            // `!append` overrides replace the list like any other value
+            value => {
+                target.insert(key, without_append_tags(value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unwraps `!append` sequences in mappings that had nothing to append to, so appending to
+/// a missing list creates it.
+fn without_append_tags(value: Value) -> Value {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == APPEND_TAG => tagged.value,
+        Value::Mapping(map) => Value::Mapping(
+            map.into_iter()
+                .map(|(key, value)| (key, without_append_tags(value)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Maps the lowercased form of each string key in `map` to the key itself.
+fn lowercase_key_index(map: &Mapping) -> HashMap<String, String> {
+    map.keys()
+        .filter_map(Value::as_str)
+        .map(|key| (key.to_lowercase(), key.to_string()))
+        .collect()
+}
+
+/// Reports every pair of keys in the same mapping of `value` that differ only by case.
+fn check_key_case_within(value: &Value, path: &str, options: &MergeOptions) -> anyhow::Result<()> {
+    match value {
+        Value::Mapping(map) => {
+            let mut seen: HashMap<String, &str> = HashMap::new();
+            for (key, inner) in map {
+                if let Value::String(key) = key {
+                    if let Some(earlier) = seen.insert(key.to_lowercase(), key) {
+                        options.report_key_case_collision(path, earlier, key)?;
+                    }
+                }
+                check_key_case_within(inner, &child_path(path, key), options)?;
+            }
+        }
+        Value::Sequence(items) => {
+            for (index, item) in items.iter().enumerate() {
+                check_key_case_within(item, &format!("{}[{}]", path, index), options)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Builds the dotted path of `key` under `parent_path`.
+fn child_path(parent_path: &str, key: &Value) -> String {
+    let key = match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default(),
+    };
+    if parent_path.is_empty() {
+        key
+    } else {
+        format!("{}.{}", parent_path, key)
+    }
+}
+
+fn merge_sequences(
+    existing_list: &mut Vec<Value>,
+    new_list: Vec<Value>,
+    path: &str,
+    depth: usize,
+    options: &MergeOptions,
+) -> anyhow::Result<()> {
+    if depth > options.max_depth {
+        return Err(too_deep(path));
+    }
+    let is_scalar = |value: &Value| {
+        matches!(
+            value,
+            Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_)
+        )
+    };
+    if options.strategy == MergeStrategy::UnionScalarSequences
+        && existing_list.iter().chain(&new_list).all(is_scalar)
+    {
+        existing_list.extend(new_list);
+        existing_list.sort_by(compare_scalars);
+        existing_list.dedup();
+        return Ok(());
+    }
+    match options.sequence_merge {
+        SequenceMerge::Append => append_items(existing_list, new_list, options),
+        SequenceMerge::ByIndex => {
+            merge_items_by_index(existing_list, new_list, path, depth, options)?
+        }
+    }
+    Ok(())
+}
+
+/// A total order over scalars: null first, then booleans, numbers and strings. Numbers
+/// compare by value, falling back to their text for integers too large to tell apart
+/// as floats, so sorting gives the same result whatever order the items came in.
+fn compare_scalars(a: &Value, b: &Value) -> std::cmp::Ordering {
+    let rank = |value: &Value| match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        _ => 4,
+    };
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let as_float = |number: &serde_yaml::Number| number.as_f64().unwrap_or(f64::NAN);
+            as_float(a)
+                .total_cmp(&as_float(b))
+                .then_with(|| a.to_string().cmp(&b.to_string()))
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Merges each new item into the existing item at the same index, appending any new items
+/// beyond the end of the existing list.
+fn merge_items_by_index(
+    existing_list: &mut Vec<Value>,
+    new_list: Vec<Value>,
+    path: &str,
+    depth: usize,
+    options: &MergeOptions,
+) -> anyhow::Result<()> {
+    for (index, new_item) in new_list.into_iter().enumerate() {
+        let Some(existing_item) = existing_list.get_mut(index) else {
+            existing_list.push(new_item);
+            continue;
+        };
+        let item_path = format!("{}[{}]", path, index);
+        match (existing_item, new_item) {
+            (Value::Mapping(existing_inner), Value::Mapping(new_inner)) => {
+                merge_maps_at(existing_inner, new_inner, &item_path, depth + 1, options)?;
+            }
+            (Value::Sequence(existing_inner), Value::Sequence(new_inner)) => {
+                merge_sequences(existing_inner, new_inner, &item_path, depth + 1, options)?;
             }
+            (existing_item, new_item) => {
+                if options.scalar_conflict.replaces(existing_item, &new_item) {
+                    *existing_item = new_item;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn append_items(existing_list: &mut Vec<Value>, new_list: Vec<Value>, options: &MergeOptions) {
+    if !options.dedup_sequence_items {
+        existing_list.extend(new_list);
+        return;
+    }
+    let existing_len = existing_list.len();
+    for item in new_list {
+        if !existing_list[..existing_len].contains(&item) {
+            existing_list.push(item);
+        }
+    }
+}
+
+/// Options controlling how two values documents are merged together.
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    /// When appending one sequence onto another, skip items that are structurally
+    /// identical to an item already in the sequence. This keeps blocks shared through
+    /// YAML anchors from being repeated once per file that aliases them. Duplicates
+    /// within a single file are left alone. Only applies to `SequenceMerge::Append`.
+    /// Defaults to `false`.
+    pub dedup_sequence_items: bool,
+    /// The rules used to merge one document into another. Defaults to
+    /// `MergeStrategy::Deep`.
+    pub strategy: MergeStrategy,
+    /// Which document wins when both set the same key. Defaults to
+    /// `Precedence::LaterWins`.
+    pub precedence: Precedence,
+    /// How a sequence is merged into a sequence at the same key.
+    pub sequence_merge: SequenceMerge,
+    /// Dotted key paths (e.g. `database.host`) that keep the first value they are given.
+    /// Later documents may repeat the same value, but any other value is handled according
+    /// to `on_pinned_override`. Pinning a mapping pins everything beneath it.
+    pub pinned_paths: Vec<String>,
+    /// What to do when a later document tries to change a pinned path.
+    pub on_pinned_override: PinnedOverride,
+    /// Which value wins when both documents set the same key to a scalar (including null).
+    pub scalar_conflict: ScalarConflict,
+    /// What to do when two keys in the same mapping differ only by case, such as `Path`
+    /// and `path`. YAML keys are case sensitive, but some consumers (like environment
+    /// variables on Windows) are not.
+    pub key_case_collisions: KeyCaseCollision,
+    /// Fail if the merge has to descend more than this many levels into nested mappings
+    /// and sequences. Defaults to `DEFAULT_MAX_DEPTH` (128).
+    pub max_depth: usize,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            dedup_sequence_items: false,
+            strategy: MergeStrategy::default(),
+            precedence: Precedence::default(),
+            sequence_merge: SequenceMerge::default(),
+            pinned_paths: Vec::new(),
+            on_pinned_override: PinnedOverride::default(),
+            scalar_conflict: ScalarConflict::default(),
+            key_case_collisions: KeyCaseCollision::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+/// How a merge reacts to keys in the same mapping that differ only by case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum KeyCaseCollision {
+    /// Treat them as different keys, as YAML does.
+    #[default]
+    Allow,
+    /// Keep both keys and log a warning naming them.
+    Warn,
+    /// Fail the merge.
+    Error,
+}
+
+/// The rules a merge follows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum MergeStrategy {
+    /// Mappings are merged key by key and sequences are appended, as adjusted by the other
+    /// merge options.
+    #[default]
+    Deep,
+    /// [RFC 7386](https://datatracker.ietf.org/doc/html/rfc7386) JSON Merge Patch: mappings
+    /// are merged key by key, a null in the later document deletes the key, and anything
+    /// else, including a sequence, replaces the earlier value wholesale. The other merge
+    /// options, apart from `max_depth`, have no effect.
+    JsonMergePatch,
+    /// Same as `Deep`, except that lists of scalars such as tags or labels become the
+    /// sorted, unique union of both lists, so the result does not depend on which file
+    /// listed an item first. Lists holding a mapping, a sequence or a tagged value are
+    /// merged as `sequence_merge` says, appended by default. Unlike
+    /// `MergeOptions::dedup_sequence_items`, duplicates within one list are removed too.
+    UnionScalarSequences,
+}
+
+/// Which of two documents wins when both set the same key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Precedence {
+    /// The later document overrides the earlier one, as described by the other merge
+    /// options. Files listed last take precedence.
+    #[default]
+    LaterWins,
+    /// The later document only fills in keys the earlier one does not have, so files
+    /// listed first take precedence. Nested mappings are still merged key by key, but any
+    /// other existing value, including a sequence, is kept as it is. `sequence_merge`,
+    /// `scalar_conflict` and `pinned_paths` have no effect, since nothing is replaced.
+    EarlierWins,
+}
+
+/// How a merge combines two sequences at the same key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum SequenceMerge {
+    /// Append the later items after the earlier ones.
+    #[default]
+    Append,
+    /// Merge items at the same index, so `containers[0].env` can be overridden without
+    /// repeating the whole list. Mappings are deep merged, other items are replaced, and
+    /// later items past the end of the earlier list are appended.
+    ByIndex,
+}
+
+/// How a merge settles two scalar values (strings, numbers, booleans or null) at the
+/// same key. Mappings and sequences are always merged, and a scalar replacing a
+/// collection or the other way around is always taken from the later document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ScalarConflict {
+    /// The later document wins.
+    #[default]
+    Overwrite,
+    /// The earlier document wins, treating the base values as authoritative.
+    KeepExisting,
+    /// The later document wins unless its value is null.
+    PreferNonNull,
+}
+
+impl ScalarConflict {
+    /// Whether `new_value` should replace `existing` at the same key.
+    fn replaces(self, existing: &Value, new_value: &Value) -> bool {
+        let is_scalar = |value: &Value| !value.is_mapping() && !value.is_sequence();
+        if !is_scalar(existing) || !is_scalar(new_value) {
+            return true;
+        }
+        match self {
+            ScalarConflict::Overwrite => true,
+            ScalarConflict::KeepExisting => false,
+            ScalarConflict::PreferNonNull => !new_value.is_null(),
+        }
+    }
+}
+
+/// How a merge reacts to an attempt to change a pinned path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum PinnedOverride {
+    /// Keep the pinned value and log a warning.
+    #[default]
+    Ignore,
+    /// Fail the merge.
+    Error,
+}
+
+impl MergeOptions {
+    /// Whether merging `new_value` over `existing` at `path` would change a pinned value.
+    /// Mappings merged into mappings are allowed through so pins further down are checked
+    /// at their own level.
+    fn blocks_override(&self, path: &str, existing: &Value, new_value: &Value) -> bool {
+        if existing == new_value {
+            return false;
+        }
+        self.pinned_paths.iter().any(|pinned| {
+            let is_ancestor = pinned
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('.'));
+            pinned == path || (is_ancestor && !(existing.is_mapping() && new_value.is_mapping()))
+        })
+    }
+
+    fn report_key_case_collision(
+        &self,
+        parent_path: &str,
+        earlier: &str,
+        later: &str,
+    ) -> anyhow::Result<()> {
+        let location = if parent_path.is_empty() {
+            "at the top level".to_string()
+        } else {
+            format!("in '{}'", parent_path)
+        };
+        let message = format!(
+            "Keys '{}' and '{}' {} differ only by case",
+            earlier, later, location
+        );
+        match self.key_case_collisions {
+            KeyCaseCollision::Allow => Ok(()),
+            KeyCaseCollision::Warn => {
+                warn!("{}.", message);
+                Ok(())
+            }
+            KeyCaseCollision::Error => Err(anyhow::anyhow!(
+                "{}. Rename one of them so they cannot shadow each other.",
+                message
+            )),
+        }
+    }
+
+    fn reject_pinned_override(&self, path: &str) -> anyhow::Result<()> {
+        match self.on_pinned_override {
+            PinnedOverride::Ignore => {
+                warn!("Ignoring override of pinned value '{}'.", path);
+                Ok(())
+            }
+            PinnedOverride::Error => Err(anyhow::anyhow!(
+                "Cannot override pinned value '{}': it was already set by an earlier values file.",
+                path
+            )),
+        }
+    }
+}
+
+/// Prefix marking a values file as optional, e.g. `?values.local.yaml`.
+const OPTIONAL_FILE_PREFIX: char = '?';
+
+/// Prefix of a values file merged only when an environment variable is set, e.g.
+/// `if:DEBUG:values.debug.yaml`.
+const GUARDED_FILE_PREFIX: &str = "if:";
+
+/// Splits a guarded values file argument into whether its guard holds and the argument it
+/// guards. Returns `None` for arguments without a guard. The guard holds when the variable
+/// is set to anything other than an empty string, `0`, `false`, `no` or `off`.
+pub(crate) fn guarded_values_file(arg: &str) -> anyhow::Result<Option<(bool, &str)>> {
+    let Some(guarded) = arg.strip_prefix(GUARDED_FILE_PREFIX) else {
+        return Ok(None);
+    };
+    let (variable, path) = guarded
+        .split_once(':')
+        .filter(|(variable, path)| !variable.is_empty() && !path.is_empty())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid guarded values file '{}'. Expected if:VARIABLE:path, e.g. if:DEBUG:values.debug.yaml.",
+                arg
+            )
+        })?;
+    let enabled = std::env::var(variable).is_ok_and(|value| {
+        !matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "" | "0" | "false" | "no" | "off"
+        )
+    });
+    Ok(Some((enabled, path)))
+}
+
+/// Options controlling how `load_yaml_files_with` loads, merges and resolves values.
+/// The defaults match the behaviour of `load_yaml_files`. Fields can be set directly or
+/// through the chained setters:
+///
+/// ```
+/// let options = LoadOptions::new()
+///     .sequence_merge(SequenceMerge::ByIndex)
+///     .strict_undefined(true)
+///     .expand_env(false);
+/// let values = load_yaml_files_with(&["values.yaml"], &options)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Fail if any value still contains template syntax once references have been
+    /// resolved, rather than passing the literal `{{ }}` through. Defaults to `false`.
+    pub fail_on_unresolved: bool,
+    /// How each file is merged onto the ones before it.
+    pub merge: MergeOptions,
+    /// What to do when two `x.y.z=foo` overrides set the same path to different values.
+    pub override_conflicts: OverrideConflict,
+    /// How value references are resolved once every file is merged.
+    pub resolve: ResolveOptions,
+    /// Expand `$VAR`, `${VAR}` and a leading `~` in values file paths. When `false`,
+    /// paths are read exactly as given. Defaults to `true`.
+    pub expand_env: bool,
+    /// Values files, written exactly as they are passed in, whose values are already
+    /// resolved. Strings they set are kept as they are, `{{ }}` included, and references
+    /// from other files read them as plain values. A value a later file overrides is
+    /// resolved as usual. Defaults to none.
+    pub frozen_files: Vec<String>,
+    /// Value paths whose resolved values are converted to a type, e.g. `debug` to
+    /// `ScalarType::Bool` when it is set by a reference rendering `"true"`. See
+    /// `coerce_values`. Defaults to none.
+    pub coerce: BTreeMap<String, ScalarType>,
+    /// Fail if a values file is empty, holds only comments or `~`, or is an empty mapping
+    /// such as a JSON `{}`, which usually means it was truncated or badly generated,
+    /// rather than skipping it. Defaults to `false`.
+    pub fail_on_empty: bool,
+    /// What to do when a key appears more than once within a mapping of a YAML values
+    /// file. Defaults to `DuplicateKeyPolicy::Error`.
+    pub duplicate_keys: DuplicateKeyPolicy,
+    /// Fail once more than this many values files are read in one load, e.g. when a
+    /// directory holding far more files than expected is passed. Overrides are not
+    /// counted. Defaults to no limit.
+    pub max_files: Option<usize>,
+    /// Fail once the local values files read in one load add up to more than this many
+    /// bytes. Each file's size is checked before it is read. Defaults to no limit.
+    pub max_total_bytes: Option<u64>,
+    /// Fail if an `x.y.z=foo` override sets a path to a different type of value than the
+    /// one already there, e.g. `replicas=[1,2]` when `replicas` is a number, instead of
+    /// replacing it. Use `x.y.z:=foo` to set a string whatever the type there. Defaults
+    /// to `false`.
+    pub strict_override_types: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            fail_on_unresolved: false,
+            merge: MergeOptions::default(),
+            override_conflicts: OverrideConflict::default(),
+            resolve: ResolveOptions::default(),
+            expand_env: true,
+            frozen_files: Vec::new(),
+            coerce: BTreeMap::new(),
+            fail_on_empty: false,
+            duplicate_keys: DuplicateKeyPolicy::default(),
+            max_files: None,
+            max_total_bytes: None,
+            strict_override_types: false,
+        }
+    }
+}
+
+impl LoadOptions {
+    /// The default options, as used by `load_yaml_files`.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces every merge option. Defaults to `MergeOptions::default()`.
+    #[allow(dead_code)]
+    pub fn merge(mut self, merge: MergeOptions) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    /// The rules used to merge one document into another. Defaults to
+    /// `MergeStrategy::Deep`.
+    #[allow(dead_code)]
+    pub fn merge_strategy(mut self, strategy: MergeStrategy) -> Self {
+        self.merge.strategy = strategy;
+        self
+    }
+
+    /// How sequences at the same key are combined. Defaults to `SequenceMerge::Append`.
+    #[allow(dead_code)]
+    pub fn sequence_merge(mut self, sequence_merge: SequenceMerge) -> Self {
+        self.merge.sequence_merge = sequence_merge;
+        self
+    }
+
+    /// Fail when a value reference points at a value that does not exist, instead of
+    /// rendering it as empty. Defaults to `false`.
+    #[allow(dead_code)]
+    pub fn strict_undefined(mut self, strict: bool) -> Self {
+        self.resolve.fail_on_undefined = strict;
+        self
+    }
+
+    /// Remove mapping keys whose template rendered to an empty string, see
+    /// `ResolveOptions::omit_empty`. Defaults to `false`.
+    #[allow(dead_code)]
+    pub fn omit_empty(mut self, omit: bool) -> Self {
+        self.resolve.omit_empty = omit;
+        self
+    }
+
+    /// Fail when template syntax is left over after resolution. Defaults to `false`.
+    #[allow(dead_code)]
+    pub fn fail_on_unresolved(mut self, fail: bool) -> Self {
+        self.fail_on_unresolved = fail;
+        self
+    }
+
+    /// What to do when overrides set the same path to different values. Defaults to
+    /// `OverrideConflict::Allow`.
+    #[allow(dead_code)]
+    pub fn override_conflicts(mut self, policy: OverrideConflict) -> Self {
+        self.override_conflicts = policy;
+        self
+    }
+
+    /// Replaces every resolve option. Defaults to `ResolveOptions::default()`.
+    #[allow(dead_code)]
+    pub fn resolve(mut self, resolve: ResolveOptions) -> Self {
+        self.resolve = resolve;
+        self
+    }
+
+    /// Whether environment variables and `~` are expanded in values file paths.
+    /// Defaults to `true`.
+    #[allow(dead_code)]
+    pub fn expand_env(mut self, expand: bool) -> Self {
+        self.expand_env = expand;
+        self
+    }
+
+    /// Marks `path` as already resolved, see `frozen_files`.
+    #[allow(dead_code)]
+    pub fn frozen_file(mut self, path: &str) -> Self {
+        self.frozen_files.push(path.to_string());
+        self
+    }
+
+    /// Fail when a values file is empty or `{}` instead of skipping it. Defaults to `false`.
+    #[allow(dead_code)]
+    pub fn fail_on_empty(mut self, fail: bool) -> Self {
+        self.fail_on_empty = fail;
+        self
+    }
+
+    /// What to do when a YAML values file repeats a key. Defaults to
+    /// `DuplicateKeyPolicy::Error`.
+    #[allow(dead_code)]
+    pub fn duplicate_keys(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.duplicate_keys = policy;
+        self
+    }
+
+    /// The most values files one load may read. Defaults to no limit.
+    #[allow(dead_code)]
+    pub fn max_files(mut self, max: usize) -> Self {
+        self.max_files = Some(max);
+        self
+    }
+
+    /// The most bytes the local values files of one load may add up to. Defaults to no
+    /// limit.
+    #[allow(dead_code)]
+    pub fn max_total_bytes(mut self, max: u64) -> Self {
+        self.max_total_bytes = Some(max);
+        self
+    }
+
+    /// Fail when an override changes the type of the value it sets, see
+    /// `strict_override_types`. Defaults to `false`.
+    #[allow(dead_code)]
+    pub fn strict_override_types(mut self, strict: bool) -> Self {
+        self.strict_override_types = strict;
+        self
+    }
+
+    /// Converts the resolved value at `path` to `scalar_type`, see `coerce`.
+    #[allow(dead_code)]
+    pub fn coerce(mut self, path: &str, scalar_type: ScalarType) -> Self {
+        self.coerce.insert(path.to_string(), scalar_type);
+        self
+    }
+
+    fn values_file_path(&self, path: &str) -> anyhow::Result<String> {
+        if self.expand_env {
+            expand_path(path)
+        } else {
+            Ok(path.to_string())
         }
     }
 }
 
+/// How loading reacts to several overrides setting the same path in one call. Unlike
+/// files overriding each other, this is almost always a mistake on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum OverrideConflict {
+    /// Keep the last override, as files do.
+    #[default]
+    Allow,
+    /// Keep the last override and log a warning naming both.
+    Warn,
+    /// Fail the load.
+    Error,
+}
+
+/// How a YAML values file that repeats a key within a mapping is read. Only the file
+/// itself is affected; keys set again by later files are merged as usual.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum DuplicateKeyPolicy {
+    /// Fail, naming the key and the lines that define it.
+    #[default]
+    Error,
+    /// Keep the value of the last occurrence, as most YAML parsers do.
+    LastWins,
+    /// Keep the value of the first occurrence.
+    FirstWins,
+}
+
 /// Loads one or more YAML files or key-value string(s) into a single `serde_yaml::Value` object.
 ///
 /// This function takes a vector of YAML file paths or key-value strings in the format of "x.y.z=foo", and
@@ -34,6 +791,7 @@ fn merge_maps(existing_map: &mut Mapping, new_map: Mapping) {
 /// a YAML mapping using the `parse_yaml_string` function. If a file path is provided, the file is read and
 /// deserialized into a YAML mapping using the `read_yaml_file` function. The resulting mappings are then merged
 /// into a single mapping, with any conflicting values being overwritten by the last value encountered.
+/// A file path prefixed with `?` is optional and is skipped if the file does not exist.
 ///
 /// # Errors
 ///
@@ -68,107 +826,998 @@ fn merge_maps(existing_map: &mut Mapping, new_map: Mapping) {
 /// # Returns
 ///
 /// A `serde_yaml::Value` object representing the merged YAML mappings loaded from the input files or strings.
+#[allow(dead_code)]
 pub fn load_yaml_files(yaml_files: &Vec<&str>) -> anyhow::Result<Value> {
-    let mut yaml_values = Mapping::new();
-
-    for yaml_file in yaml_files {
-        let yaml = if yaml_file.contains("=") {
-            parse_yaml_string(yaml_file)?
-        } else {
-            read_yaml_file(yaml_file)?
-        };
+    load_yaml_files_with(yaml_files, &LoadOptions::default())
+}
 
-        // Start merging here, whether it's a map or not
-        match &yaml {
-            Value::Mapping(map) => {
-                for (key, value) in map {
-                    match yaml_values.entry(key.clone()) {
-                        Entry::Occupied(mut entry) => match (entry.get_mut(), value) {
-                            (Value::Mapping(existing_inner), Value::Mapping(new_inner)) => {
-                                merge_maps(existing_inner, new_inner.clone());
-                            }
-                            (Value::Sequence(existing_list), Value::Sequence(new_list)) => {
-                                existing_list.extend(new_list.clone());
-                            }
-                            _ => {
-                                entry.insert(value.clone());
-                            }
-                        },
-                        Entry::Vacant(entry) => {
-                            entry.insert(value.clone());
-                        }
-                    }
-                }
-            }
-            // In case top-level structure is not a map
-            _ => {
-                return Err(anyhow::anyhow!(
-                    "Expected top-level YAML structure to be a mapping."
-                ));
-            }
-        }
-    }
+/// Loads values the same way as `load_yaml_files`, taking owned paths such as those parsed from
+/// the command line.
+pub fn load_yaml_files_owned(yaml_files: &[String]) -> anyhow::Result<Value> {
+    let refs: Vec<&str> = yaml_files.iter().map(String::as_str).collect();
+    load_yaml_files_with(&refs, &LoadOptions::default())
+}
 
-    // Resolve value references after all files are merged
-    let merged_values = Value::Mapping(yaml_values);
-    let resolved_values = resolve_value_references(merged_values)
-        .with_context(|| "Failed to resolve value references in YAML files")?;
+/// Loads values the same way as `load_yaml_files`, but with the precedence reversed: each
+/// file or override only fills in keys that no earlier one set. List the most specific
+/// values first and the defaults last.
+///
+/// # Examples
+///
+/// ```
+/// let values = load_yaml_files_fill_defaults(&["replicas=3", "replicas=1", "image=nginx"])?;
+///
+/// assert_eq!(values["replicas"], 3);
+/// assert_eq!(values["image"], "nginx");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn load_yaml_files_fill_defaults(yaml_files: &[&str]) -> anyhow::Result<Value> {
+    let options = LoadOptions {
+        merge: MergeOptions {
+            precedence: Precedence::EarlierWins,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    load_yaml_files_with(yaml_files, &options)
+}
 
-    Ok(resolved_values)
+/// Loads values the same way as `load_yaml_files`, then deserializes the result into `T`.
+///
+/// # Errors
+///
+/// Returns an error if loading fails, or if the merged values do not match `T`. The latter
+/// names every file and override that contributed to the values.
+///
+/// # Examples
+///
+/// The same example runs as `test_load_yaml_files_as_example`, since doc examples are
+/// not run for a binary crate.
+///
+/// ```
+/// use crate::utils::load_values::load_yaml_files_as;
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct Database {
+///     host: String,
+///     port: u16,
+/// }
+///
+/// let database: Database = load_yaml_files_as(&["host=localhost", "port=5432"])?;
+///
+/// assert_eq!(database.host, "localhost");
+/// assert_eq!(database.port, 5432);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn load_yaml_files_as<T: DeserializeOwned>(yaml_files: &[&str]) -> anyhow::Result<T> {
+    load_yaml_files_as_with(yaml_files, &LoadOptions::default())
 }
 
-pub fn get_value_files_as_refs(strings: &[String]) -> Vec<&str> {
-    strings.iter().map(|s| s.as_ref()).collect()
+/// Same as `load_yaml_files_as`, with the behaviour adjusted by `options`. Use
+/// `LoadOptions::coerce` for fields of `T` set by references, which always render strings:
+///
+/// ```
+/// let options = LoadOptions::new().coerce("debug", ScalarType::Bool);
+/// let config: Config = load_yaml_files_as_with(&["values.yaml"], &options)?;
+/// ```
+#[allow(dead_code)]
+pub fn load_yaml_files_as_with<T: DeserializeOwned>(
+    yaml_files: &[&str],
+    options: &LoadOptions,
+) -> anyhow::Result<T> {
+    let values = load_yaml_files_with(yaml_files, options)?;
+    serde_yaml::from_value(values).with_context(|| {
+        format!(
+            "Values loaded from [{}] do not match the expected structure",
+            yaml_files.join(", ")
+        )
+    })
 }
 
-pub fn read_yaml_file(path: &str) -> anyhow::Result<Value> {
-    trace!("Loading file: {}", path);
-    let contents = std::fs::read_to_string(path)
-        .with_context(|| format!("Failed to read values file '{}'", path))?;
-    parse_values_yaml(&contents, path)
+/// Loads `values.yaml` from `dir`, merged with `values.<env>.yaml` if that file exists.
+///
+/// Only files directly in `dir` are used. A missing environment file is not an error, so
+/// environments without overrides use the base values as they are.
+///
+/// # Errors
+///
+/// Returns an error if `dir` has no `values.yaml`, or if loading either file fails.
+#[allow(dead_code)]
+pub fn load_yaml_files_for_env(dir: &str, env: &str) -> anyhow::Result<Value> {
+    let base_name = "values.yaml";
+    let env_name = format!("values.{}.yaml", env);
+    let found = get_files_with_names(dir, &[base_name, env_name.as_str()]);
+    let find = |name: &str| {
+        found
+            .iter()
+            .find(|file| Path::new(file) == Path::new(dir).join(name))
+            .map(String::as_str)
+    };
+
+    let base = find(base_name)
+        .ok_or_else(|| anyhow::anyhow!("No base values file '{}' found in '{}'", base_name, dir))?;
+    let mut files = vec![base];
+    match find(&env_name) {
+        Some(env_file) => files.push(env_file),
+        None => trace!(
+            "No '{}' found in '{}', using base values only.",
+            env_name,
+            dir
+        ),
+    }
+    load_yaml_files_with(&files, &LoadOptions::default())
 }
 
-fn parse_values_yaml(contents: &str, path: &str) -> anyhow::Result<Value> {
-    serde_yaml::from_str(contents).map_err(|err| describe_yaml_error(&err, contents, path))
+/// Loads every `.yaml`, `.yml` and `.json` file in `dir`, merged in order of their paths
+/// and then resolved, as if they had been passed to `load_yaml_files` one by one. Other
+/// files are ignored. With `recursive`, files in subdirectories are loaded too, each
+/// directory's files sorting with its name (so `base/x.yaml` comes before `db.yaml`).
+///
+/// # Errors
+///
+/// Returns an error if `dir` is not a directory, or if loading any of the files fails.
+#[allow(dead_code)]
+pub fn load_yaml_dir(dir: &str, recursive: bool) -> anyhow::Result<Value> {
+    load_yaml_dir_with(dir, recursive, &LoadOptions::default())
 }
 
-/// Builds a self-contained parse error naming the file, the offending line and the
-/// underlying cause, so the top-level error display is actionable on its own.
-fn describe_yaml_error(err: &serde_yaml::Error, contents: &str, path: &str) -> anyhow::Error {
-    if let Some(key) = duplicate_key_in(err) {
-        return describe_duplicate_key_error(&key, contents, path);
-    }
-    let mut message = format!("Invalid YAML in values file '{}': {}", path, err);
-    if let Some(location) = err.location() {
-        if let Some(line) = contents.lines().nth(location.line().saturating_sub(1)) {
-            message.push_str(&format!("\n  line {} | {}", location.line(), line));
-        }
-    }
-    if let Some(tab_line) = first_tab_indented_line(contents) {
-        message.push_str(&format!(
-            "\nHint: YAML does not allow tabs in indentation. Replace the tab(s) on line {} with spaces.",
-            tab_line
+/// Same as `load_yaml_dir`, with the behaviour adjusted by `options`, e.g. to cap how
+/// many files the directory may hold with `LoadOptions::max_files`.
+#[allow(dead_code)]
+pub fn load_yaml_dir_with(
+    dir: &str,
+    recursive: bool,
+    options: &LoadOptions,
+) -> anyhow::Result<Value> {
+    if !Path::new(dir).is_dir() {
+        return Err(anyhow::anyhow!(
+            "Values directory '{}' is not a directory",
+            dir
         ));
     }
-    anyhow::anyhow!(message)
+    let mut files = get_files_with_extensions(dir, &["yaml", "yml", "json"]);
+    if !recursive {
+        files.retain(|file| Path::new(file).parent() == Some(Path::new(dir)));
+    }
+    files.sort_by(|a, b| Path::new(a).cmp(Path::new(b)));
+    trace!("Loading values from '{}': {:?}", dir, files);
+
+    let files: Vec<&str> = files.iter().map(String::as_str).collect();
+    load_yaml_files_with(&files, options)
 }
 
-/// Extracts the key name from serde_yaml's `duplicate entry with key "x"` message.
-/// Returns None for any other kind of parse error.
-fn duplicate_key_in(err: &serde_yaml::Error) -> Option<String> {
-    let display = err.to_string();
-    let marker = "duplicate entry with key \"";
-    let start = display.find(marker)? + marker.len();
-    let rest = &display[start..];
-    let end = rest.find('"')?;
-    Some(rest[..end].to_string())
+/// Same as `load_yaml_files`, with the behaviour adjusted by `options`.
+pub fn load_yaml_files_with(yaml_files: &[&str], options: &LoadOptions) -> anyhow::Result<Value> {
+    load_and_merge(yaml_files, options, None, &mut |_| {})
 }
 
-fn describe_duplicate_key_error(key: &str, contents: &str, path: &str) -> anyhow::Error {
-    let mut message = format!(
-        "Duplicate key \"{}\" in values file '{}': the same key appears more than once within a mapping.",
-        key, path
-    );
+/// Loads values the same way as `load_yaml_files`, calling `on_file` with the path of each
+/// values file once it has been read, so callers can report progress. Key-value overrides,
+/// and optional or guarded files that are skipped, are not reported.
+///
+/// # Examples
+///
+/// ```
+/// let values = load_yaml_files_with_progress(&["values.yaml", "replicas=3"], &mut |path| {
+///     println!("Loaded {}", path);
+/// })?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn load_yaml_files_with_progress(
+    yaml_files: &[&str],
+    on_file: &mut dyn FnMut(&str),
+) -> anyhow::Result<Value> {
+    load_and_merge(yaml_files, &LoadOptions::default(), None, on_file)
+}
+
+/// Fails when an override would nest under an existing value that is not a mapping, such as
+/// `foo.baz=x` when `foo` is a string. Merging would otherwise silently replace that value.
+fn check_override_ancestors(values: &Value, keys: &[String]) -> anyhow::Result<()> {
+    let mut current = values;
+    for (index, key) in keys.iter().enumerate().take(keys.len().saturating_sub(1)) {
+        let Some(child) = current
+            .as_mapping()
+            .and_then(|mapping| mapping.get(key.as_str()))
+        else {
+            return Ok(());
+        };
+        match child {
+            Value::Mapping(_) => {
+                current = child;
+                continue;
+            }
+            Value::Null => return Ok(()),
+            _ => {}
+        }
+        return Err(anyhow::anyhow!(
+            "Cannot set {}: {} is a {}",
+            keys.join("."),
+            keys[..=index].join("."),
+            kind_name(child)
+        ));
+    }
+    Ok(())
+}
+
+/// Fails when the override `yaml_file`, parsed into `yaml`, sets `keys` to a different
+/// type of value than the one already there, such as `replicas=[1,2]` when `replicas` is
+/// a number. A null on either side is not a mismatch.
+fn check_override_type(
+    values: &Value,
+    yaml: &Value,
+    keys: &[String],
+    yaml_file: &str,
+) -> anyhow::Result<()> {
+    fn value_at<'a>(root: &'a Value, keys: &[String]) -> Option<&'a Value> {
+        keys.iter().try_fold(root, |current, key| {
+            current
+                .as_mapping()
+                .and_then(|mapping| mapping.get(key.as_str()))
+        })
+    }
+    let (Some(existing), Some(new)) = (value_at(values, keys), value_at(yaml, keys)) else {
+        return Ok(());
+    };
+    if existing.is_null() || new.is_null() || kind_name(existing) == kind_name(new) {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Override '{}' sets {} to a {}, but it is a {}",
+        yaml_file,
+        keys.join("."),
+        kind_name(new),
+        kind_name(existing)
+    ))
+}
+
+fn kind_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "sequence",
+        Value::Mapping(_) => "mapping",
+        Value::Tagged(_) => "tagged value",
+    }
+}
+
+/// Source recorded by `load_yaml_files_with_provenance` for `x.y.z=foo` overrides.
+const OVERRIDE_SOURCE: &str = "--set";
+
+/// Loads values the same way as `load_yaml_files`, additionally returning which input last
+/// set each leaf path. Paths use the `flatten` scheme (`a.b[0]`). Files are named as given,
+/// without the optional `?` prefix, and key-value overrides are recorded as
+/// `--set x.y.z=foo`.
+///
+/// A leaf is attributed to a file if that file added it, changed its value, or set it again
+/// to the same value. Items appended to a sequence are attributed to the file they came from.
+#[allow(dead_code)]
+pub fn load_yaml_files_with_provenance(
+    yaml_files: &[&str],
+) -> anyhow::Result<(Value, HashMap<String, String>)> {
+    let mut provenance = HashMap::new();
+    let values = load_and_merge(
+        yaml_files,
+        &LoadOptions::default(),
+        Some(&mut provenance),
+        &mut |_| {},
+    )?;
+    Ok((values, provenance))
+}
+
+/// A reference whose target was set by a different input than the value holding it,
+/// found by `cross_file_references`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrossFileReference {
+    /// Path of the value containing the reference, e.g. `message`.
+    pub path: String,
+    /// Path the reference points at, e.g. `config.greeting`.
+    pub target: String,
+    /// Input that last set the value containing the reference.
+    pub source: String,
+    /// Input that last set the target, or part of it if the target is a mapping or list.
+    pub target_source: String,
+}
+
+impl std::fmt::Display for CrossFileReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' (set in {}) references '{}', which was last set in {}",
+            self.path, self.source, self.target, self.target_source
+        )
+    }
+}
+
+/// Lints layered values for references that may not read what their author expected: a
+/// template in one file pointing at a value that another file set or replaced, such as
+/// `message: "{{ config.greeting }}"` in `base.yaml` after `prod.yaml` replaced `config`.
+/// Inputs are named as in `load_yaml_files_with_provenance`.
+///
+/// This is a heuristic, so the results are meant as warnings: a file referencing a value
+/// it expects another file to provide is often intended. A target set by several inputs,
+/// such as a mapping, gives one result for each input other than the referencing one.
+/// References to values that do not exist are left to `missing_references`.
+///
+/// # Errors
+///
+/// Returns an error if loading or merging the files fails. References are not resolved.
+#[allow(dead_code)]
+pub fn cross_file_references(yaml_files: &[&str]) -> anyhow::Result<Vec<CrossFileReference>> {
+    let mut provenance = HashMap::new();
+    let (values, _) = merge_files(
+        yaml_files,
+        &LoadOptions::default(),
+        Some(&mut provenance),
+        &mut |_| {},
+    )?;
+
+    let mut found = Vec::new();
+    for (path, targets) in value_references(&values)? {
+        let Some(source) = provenance.get(&path) else {
+            continue;
+        };
+        for target in targets {
+            let target_sources: BTreeSet<&String> = provenance
+                .iter()
+                .filter(|(leaf, _)| is_within(leaf, &target))
+                .map(|(_, target_source)| target_source)
+                .filter(|target_source| *target_source != source)
+                .collect();
+            found.extend(
+                target_sources
+                    .into_iter()
+                    .map(|target_source| CrossFileReference {
+                        path: path.clone(),
+                        target: target.clone(),
+                        source: source.clone(),
+                        target_source: target_source.clone(),
+                    }),
+            );
+        }
+    }
+    Ok(found)
+}
+
+fn load_and_merge(
+    yaml_files: &[&str],
+    options: &LoadOptions,
+    provenance: Option<&mut HashMap<String, String>>,
+    on_file: &mut dyn FnMut(&str),
+) -> anyhow::Result<Value> {
+    let _span = tracing::debug_span!("load_values", files = yaml_files.len()).entered();
+    let (yaml_values, frozen) = merge_files(yaml_files, options, provenance, on_file)?;
+
+    // Raw subtrees lose their tag during resolution but are meant to keep their templates
+    let raw = if options.fail_on_unresolved {
+        raw_paths(&yaml_values)
+    } else {
+        Vec::new()
+    };
+    // Resolve value references after all files are merged
+    let resolve_options = if frozen.is_empty() {
+        Cow::Borrowed(&options.resolve)
+    } else {
+        let mut resolve_options = options.resolve.clone();
+        resolve_options.frozen_paths.extend(frozen.iter().cloned());
+        Cow::Owned(resolve_options)
+    };
+    let mut resolved_values =
+        resolve_when_referenced(yaml_values, &resolve_options, resolve_value_references_with)
+            .with_context(|| "Failed to resolve value references in YAML files")?;
+
+    if options.fail_on_unresolved {
+        let mut unresolved = find_unresolved_except(&resolved_values, &frozen)?;
+        unresolved.retain(|(path, _)| !raw.iter().any(|root| is_within(path, root)));
+        if !unresolved.is_empty() {
+            let listing = unresolved
+                .iter()
+                .map(|(path, value)| format!("  {}: {}", path, value))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(anyhow::anyhow!(
+                "{} value(s) still contain unresolved references after resolution:\n{}",
+                unresolved.len(),
+                listing
+            ));
+        }
+    }
+
+    coerce_values(&mut resolved_values, &options.coerce)?;
+    Ok(resolved_values)
+}
+
+/// Reads every values file and override in turn and merges them, without resolving
+/// references. Also returns the leaf paths whose current value came from a frozen file.
+fn merge_files(
+    yaml_files: &[&str],
+    options: &LoadOptions,
+    mut provenance: Option<&mut HashMap<String, String>>,
+    on_file: &mut dyn FnMut(&str),
+) -> anyhow::Result<(Value, HashSet<String>)> {
+    let mut yaml_values = Value::Mapping(Mapping::new());
+    let mut seen_overrides: HashMap<Vec<String>, (&str, Value)> = HashMap::new();
+    // Leaf paths whose current value came from a frozen file
+    let mut frozen: HashSet<String> = HashSet::new();
+    let mut limits = LoadLimits::default();
+
+    for yaml_file in yaml_files {
+        let is_frozen = options
+            .frozen_files
+            .iter()
+            .any(|frozen| frozen == yaml_file);
+        let yaml_file = match guarded_values_file(yaml_file)? {
+            Some((false, path)) => {
+                trace!("Guard for values file '{}' is not set, skipping.", path);
+                continue;
+            }
+            Some((true, path)) => path,
+            None => yaml_file,
+        };
+        let _file_span = tracing::debug_span!("load_values_file", file = yaml_file).entered();
+        let yaml = if let Some(path) = yaml_file.strip_prefix(OPTIONAL_FILE_PREFIX) {
+            let path = &options.values_file_path(path)?;
+            if is_remote_values_file(path) || Path::new(path).exists() {
+                limits.add_file(path, options)?;
+            }
+            match read_optional_values_file(path, options.duplicate_keys)? {
+                Some(yaml) => {
+                    on_file(path);
+                    yaml
+                }
+                None => {
+                    trace!("Optional values file '{}' does not exist, skipping.", path);
+                    continue;
+                }
+            }
+        } else if is_remote_values_file(yaml_file) {
+            limits.add_file(yaml_file, options)?;
+            let yaml = read_remote_values_file(yaml_file, options.duplicate_keys)?;
+            on_file(yaml_file);
+            yaml
+        } else if yaml_file.contains("=") {
+            let yaml = parse_yaml_string(yaml_file)?;
+            check_override_ancestors(&yaml_values, &override_keys(yaml_file)?)?;
+            // A `x.y.z:=foo` override asks for a string, so it is not a mismatch
+            if options.strict_override_types && !override_forces_string(yaml_file)? {
+                if let Some(keys) = override_key_path(yaml_file)? {
+                    check_override_type(&yaml_values, &yaml, &keys, yaml_file)?;
+                }
+            }
+            let key_path = match options.override_conflicts {
+                OverrideConflict::Allow => None,
+                _ => override_key_path(yaml_file)?,
+            };
+            if let Some(key_path) = key_path {
+                if let Some((earlier, earlier_yaml)) = seen_overrides.get(&key_path) {
+                    if *earlier_yaml != yaml {
+                        report_override_conflict(
+                            &key_path.join("."),
+                            earlier,
+                            yaml_file,
+                            options.override_conflicts,
+                        )?;
+                    }
+                }
+                seen_overrides.insert(key_path, (yaml_file, yaml.clone()));
+            }
+            yaml
+        } else {
+            let path = options.values_file_path(yaml_file)?;
+            limits.add_file(&path, options)?;
+            let yaml = read_values_file_with(&path, options.duplicate_keys)?;
+            on_file(&path);
+            yaml
+        };
+
+        // An empty (or `~`) file deserializes to null and contributes nothing, as does `{}`
+        let is_empty = yaml.is_null() || yaml.as_mapping().is_some_and(Mapping::is_empty);
+        if is_empty && options.fail_on_empty {
+            return Err(anyhow::anyhow!(
+                "Values file '{}' is empty",
+                source_name(yaml_file)
+            ));
+        }
+        if yaml.is_null() {
+            trace!("Values file '{}' is empty, skipping.", yaml_file);
+            continue;
+        }
+        if is_frozen {
+            frozen.extend(flatten(&yaml).into_keys());
+        } else if !frozen.is_empty() {
+            for path in flatten(&yaml).into_keys() {
+                frozen.remove(&path);
+            }
+        }
+        let _merge_span = tracing::debug_span!("merge_values", file = yaml_file).entered();
+        match provenance.as_deref_mut() {
+            Some(provenance) => {
+                let before = flatten(&yaml_values);
+                let set_by_file = flatten(&yaml);
+                yaml_values = merge_values_with(yaml_values, yaml, &options.merge)?;
+                record_provenance(
+                    provenance,
+                    &before,
+                    &set_by_file,
+                    &flatten(&yaml_values),
+                    &source_name(yaml_file),
+                );
+            }
+            None => yaml_values = merge_values_with(yaml_values, yaml, &options.merge)?,
+        }
+    }
+    Ok((yaml_values, frozen))
+}
+
+/// The values files read so far in one load, checked against `LoadOptions::max_files`
+/// and `LoadOptions::max_total_bytes`.
+#[derive(Default)]
+struct LoadLimits {
+    files: usize,
+    bytes: u64,
+}
+
+impl LoadLimits {
+    /// Counts `path` before it is read, failing if that takes the load over a limit. The
+    /// size of a remote file is not known until it is fetched, so only local files add
+    /// to the byte total.
+    fn add_file(&mut self, path: &str, options: &LoadOptions) -> anyhow::Result<()> {
+        self.files += 1;
+        if let Some(max) = options.max_files {
+            if self.files > max {
+                return Err(anyhow::anyhow!(
+                    "Too many values files: '{}' is file number {}, the limit is {}",
+                    path,
+                    self.files,
+                    max
+                ));
+            }
+        }
+        if let Some(max) = options.max_total_bytes {
+            if !is_remote_values_file(path) {
+                if let Ok(metadata) = std::fs::metadata(path) {
+                    self.bytes += metadata.len();
+                }
+            }
+            if self.bytes > max {
+                return Err(anyhow::anyhow!(
+                    "Values files are too large: {} bytes including '{}', the limit is {} bytes",
+                    self.bytes,
+                    path,
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn report_override_conflict(
+    path: &str,
+    earlier: &str,
+    later: &str,
+    policy: OverrideConflict,
+) -> anyhow::Result<()> {
+    let message = format!(
+        "Conflicting overrides for '{}': '{}' and '{}'",
+        path, earlier, later
+    );
+    match policy {
+        OverrideConflict::Allow => Ok(()),
+        OverrideConflict::Warn => {
+            warn!("{}, using '{}'.", message, later);
+            Ok(())
+        }
+        OverrideConflict::Error => Err(anyhow::anyhow!(
+            "{}. Pass only one override per path.",
+            message
+        )),
+    }
+}
+
+/// Runs `resolve` over the merged values only if they contain a value reference, so large
+/// reference-free configs skip collecting templates altogether.
+fn resolve_when_referenced(
+    values: Value,
+    options: &ResolveOptions,
+    resolve: impl FnOnce(Value, &ResolveOptions) -> anyhow::Result<Value>,
+) -> anyhow::Result<Value> {
+    if !contains_references_with(&values, &options.syntaxes) {
+        trace!("Values contain no references, skipping resolution.");
+        return Ok(values);
+    }
+    resolve(values, options)
+}
+
+/// The local files among the values file arguments, as they would be read. Overrides and
+/// remote files are left out. Optional and guarded files are included even when they are
+/// not currently read, so that creating a missing optional file is noticed.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+pub(crate) fn local_values_files(
+    yaml_files: &[&str],
+    options: &LoadOptions,
+) -> anyhow::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for yaml_file in yaml_files {
+        let yaml_file = match guarded_values_file(yaml_file)? {
+            Some((_, path)) => path,
+            None => yaml_file,
+        };
+        let path = match yaml_file.strip_prefix(OPTIONAL_FILE_PREFIX) {
+            Some(path) if !is_remote_values_file(path) => path,
+            Some(_) => continue,
+            None if is_remote_values_file(yaml_file) || yaml_file.contains("=") => continue,
+            None => yaml_file,
+        };
+        paths.push(options.values_file_path(path)?);
+    }
+    Ok(paths)
+}
+
+fn source_name(yaml_file: &str) -> String {
+    if let Some(path) = yaml_file.strip_prefix(OPTIONAL_FILE_PREFIX) {
+        path.to_string()
+    } else if yaml_file.contains("=") {
+        format!("{} {}", OVERRIDE_SOURCE, yaml_file)
+    } else {
+        yaml_file.to_string()
+    }
+}
+
+/// Updates `provenance` after one document has been merged, given the flattened values
+/// before and after the merge and the leaves the document itself contained.
+fn record_provenance(
+    provenance: &mut HashMap<String, String>,
+    before: &BTreeMap<String, String>,
+    set_by_file: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+    source: &str,
+) {
+    provenance.retain(|path, _| after.contains_key(path));
+    for (path, value) in after {
+        let changed = before.get(path) != Some(value);
+        // Sequence indices in the document do not line up with the merged sequence once
+        // items are appended, so only paths outside sequences count as being set again
+        let set_again = !path.contains('[') && set_by_file.get(path) == Some(value);
+        if changed || set_again {
+            provenance.insert(path.clone(), source.to_string());
+        }
+    }
+}
+
+/// Merges `overlay` into `base` with the same semantics `load_yaml_files` uses between
+/// files: mappings are merged recursively, sequences are appended and any other
+/// conflicting value is replaced by the one from `overlay`.
+///
+/// # Errors
+///
+/// Returns an error if either value is not a mapping.
+///
+/// # Examples
+///
+/// Composer has no library target, so rustdoc does not run this;
+/// `test_merge_values_example` does.
+///
+/// ```
+/// use crate::utils::load_values::merge_values;
+/// use serde_yaml::Value;
+///
+/// let base: Value = serde_yaml::from_str("foo:\n  bar: 1\nitems: [a]")?;
+/// let overlay: Value = serde_yaml::from_str("foo:\n  baz: 2\nitems: [b]")?;
+///
+/// let merged = merge_values(base, overlay)?;
+///
+/// assert_eq!(merged["foo"]["bar"], Value::from(1));
+/// assert_eq!(merged["foo"]["baz"], Value::from(2));
+/// assert_eq!(merged["items"], serde_yaml::from_str::<Value>("[a, b]")?);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn merge_values(base: Value, overlay: Value) -> anyhow::Result<Value> {
+    merge_values_with(base, overlay, &MergeOptions::default())
+}
+
+/// Same as `merge_values`, with the behaviour adjusted by `options`.
+pub fn merge_values_with(
+    base: Value,
+    overlay: Value,
+    options: &MergeOptions,
+) -> anyhow::Result<Value> {
+    if options.key_case_collisions != KeyCaseCollision::Allow {
+        check_key_case_within(&overlay, "", options)?;
+    }
+    match (base, overlay) {
+        (Value::Mapping(mut base_map), Value::Mapping(overlay_map)) => {
+            merge_maps(&mut base_map, overlay_map, options)?;
+            Ok(Value::Mapping(base_map))
+        }
+        _ => Err(anyhow::anyhow!(
+            "Expected top-level YAML structure to be a mapping."
+        )),
+    }
+}
+
+/// Borrows each path as a `&str`. Prefer `load_yaml_files_owned` when the paths are only needed
+/// for loading values.
+#[allow(dead_code)]
+pub fn get_value_files_as_refs(strings: &[String]) -> Vec<&str> {
+    strings.iter().map(|s| s.as_ref()).collect()
+}
+
+/// Formats a values file can be written in, detected from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+}
+
+/// Detects the format of a values file from its extension. Files without an extension
+/// (such as `/dev/stdin` or process substitutions) are treated as YAML. Returns `None`
+/// for any extension that is not a known values format.
+pub fn detect_format(path: &str) -> Option<Format> {
+    let Some(extension) = Path::new(path).extension() else {
+        return Some(Format::Yaml);
+    };
+    match extension.to_string_lossy().to_ascii_lowercase().as_str() {
+        "yaml" | "yml" => Some(Format::Yaml),
+        "json" => Some(Format::Json),
+        _ => None,
+    }
+}
+
+/// Reads a values file, choosing the parser from the file extension.
+#[allow(dead_code)]
+pub fn read_values_file(path: &str) -> anyhow::Result<Value> {
+    read_values_file_with(path, DuplicateKeyPolicy::Error)
+}
+
+/// Reads a values file like `read_values_file`, handling keys a YAML file repeats
+/// according to `duplicate_keys`.
+pub fn read_values_file_with(
+    path: &str,
+    duplicate_keys: DuplicateKeyPolicy,
+) -> anyhow::Result<Value> {
+    match detect_format(path) {
+        Some(Format::Yaml) => {
+            if Path::new(path).extension().is_none() {
+                trace!(
+                    "Values file '{}' has no extension, reading it as YAML.",
+                    path
+                );
+            }
+            read_yaml_file_with(path, duplicate_keys)
+        }
+        Some(Format::Json) => read_json_file(path),
+        None => {
+            let extension = Path::new(path)
+                .extension()
+                .map(|e| e.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Err(anyhow::anyhow!(
+                "Unsupported value file extension '.{}' for '{}'. Expected one of .yaml, .yml or .json.",
+                extension,
+                path
+            ))
+        }
+    }
+}
+
+/// Expands `$VAR` and `${VAR}` environment variables and a leading `~` in a values file
+/// path, e.g. `${XDG_CONFIG_HOME}/app.yaml`. A `$` not followed by a variable name is kept
+/// as it is. Unset variables are an error rather than being left in the path.
+pub(crate) fn expand_path(path: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        let home = dirs::home_dir().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Cannot expand '~' in values file path '{}': no home directory found.",
+                path
+            )
+        })?;
+        expanded.push_str(&home.to_string_lossy());
+        rest = &rest[1..];
+    }
+
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| {
+                anyhow::anyhow!("Unterminated '${{' in values file path '{}'.", path)
+            })?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = std::env::var(name).map_err(|_| {
+            anyhow::anyhow!(
+                "Cannot expand values file path '{}': environment variable '{}' is not set.",
+                path,
+                name
+            )
+        })?;
+        expanded.push_str(&value);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Returns true for `http://` and `https://` values file arguments, which are fetched
+/// rather than read from disk.
+pub(crate) fn is_remote_values_file(arg: &str) -> bool {
+    let lower = arg.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Fetches a values file over HTTP(S). The format comes from the `Content-Type` header,
+/// falling back to the extension of the URL path and then to YAML.
+#[cfg(feature = "remote")]
+fn read_remote_values_file(url: &str, duplicate_keys: DuplicateKeyPolicy) -> anyhow::Result<Value> {
+    let fetched = crate::utils::remote_values::fetch_values_file(url)?;
+    match remote_format(url, fetched.content_type.as_deref()) {
+        Format::Json => serde_json::from_str(&fetched.contents)
+            .map_err(|err| anyhow::anyhow!("Invalid JSON in values file '{}': {}", url, err)),
+        Format::Yaml => parse_values_yaml(&fetched.contents, url, duplicate_keys),
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+fn read_remote_values_file(url: &str, _: DuplicateKeyPolicy) -> anyhow::Result<Value> {
+    Err(anyhow::anyhow!(
+        "Cannot read values file '{}': composer was built without the `remote` feature.",
+        url
+    ))
+}
+
+#[cfg_attr(not(feature = "remote"), allow(dead_code))]
+fn remote_format(url: &str, content_type: Option<&str>) -> Format {
+    match content_type {
+        Some(content_type) if content_type.contains("json") => return Format::Json,
+        Some(content_type) if content_type.contains("yaml") => return Format::Yaml,
+        _ => {}
+    }
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let path = path.splitn(4, '/').nth(3).unwrap_or("");
+    detect_format(path).unwrap_or(Format::Yaml)
+}
+
+/// Reads a values file, returning `None` if it does not exist. Any other error, such as
+/// the file not being readable, is still returned.
+fn read_optional_values_file(
+    path: &str,
+    duplicate_keys: DuplicateKeyPolicy,
+) -> anyhow::Result<Option<Value>> {
+    if is_remote_values_file(path) {
+        return read_remote_values_file(path, duplicate_keys).map(Some);
+    }
+    match std::fs::metadata(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        _ => read_values_file_with(path, duplicate_keys).map(Some),
+    }
+}
+
+fn read_json_file(path: &str) -> anyhow::Result<Value> {
+    trace!("Loading file: {}", path);
+    let contents = read_values_text(path)?;
+    serde_json::from_str(&contents)
+        .map_err(|err| anyhow::anyhow!("Invalid JSON in values file '{}': {}", path, err))
+}
+
+/// Reads and parses a YAML values file.
+///
+/// Anchors and aliases are expanded while parsing: every `*alias` becomes its own copy
+/// of the anchored value, so the sharing is not visible to the merge and the merged
+/// output is written without anchors. Use `MergeOptions::dedup_sequence_items` to avoid
+/// appending the same expanded block to a sequence more than once.
+#[allow(dead_code)]
+pub fn read_yaml_file(path: &str) -> anyhow::Result<Value> {
+    read_yaml_file_with(path, DuplicateKeyPolicy::Error)
+}
+
+/// Reads and parses a YAML values file like `read_yaml_file`, handling repeated keys
+/// according to `duplicate_keys`.
+pub fn read_yaml_file_with(
+    path: &str,
+    duplicate_keys: DuplicateKeyPolicy,
+) -> anyhow::Result<Value> {
+    trace!("Loading file: {}", path);
+    let contents = read_values_text(path)?;
+    parse_values_yaml(&contents, path, duplicate_keys)
+}
+
+/// Reads a local values file, explaining the common reasons it cannot be read.
+fn read_values_text(path: &str) -> anyhow::Result<String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| describe_read_error(path, err))
+        .with_context(|| format!("Failed to read values file '{}'", path))
+}
+
+/// Turns an IO error from reading `path` into a cause a user can act on.
+fn describe_read_error(path: &str, err: std::io::Error) -> anyhow::Error {
+    let path = Path::new(path);
+    if path.is_dir() {
+        return anyhow::anyhow!("the path is a directory, expected a file");
+    }
+    let is_symlink = path.is_symlink();
+    match err.kind() {
+        std::io::ErrorKind::NotFound if is_symlink => {
+            anyhow::anyhow!("the path is a symlink to a file that does not exist")
+        }
+        std::io::ErrorKind::NotFound => anyhow::anyhow!("file not found"),
+        std::io::ErrorKind::PermissionDenied => anyhow::anyhow!("permission denied"),
+        _ if is_symlink => anyhow::anyhow!(
+            "the path is a symlink that cannot be followed, possibly a loop: {}",
+            err
+        ),
+        _ => err.into(),
+    }
+}
+
+fn parse_values_yaml(
+    contents: &str,
+    path: &str,
+    duplicate_keys: DuplicateKeyPolicy,
+) -> anyhow::Result<Value> {
+    let parsed = match duplicate_keys {
+        DuplicateKeyPolicy::Error => serde_yaml::from_str(contents),
+        DuplicateKeyPolicy::LastWins => from_str_allowing_duplicates(contents, false),
+        DuplicateKeyPolicy::FirstWins => from_str_allowing_duplicates(contents, true),
+    };
+    parsed.map_err(|err| describe_yaml_error(&err, contents, path))
+}
+
+/// Builds a self-contained parse error naming the file, the offending line and the
+/// underlying cause, so the top-level error display is actionable on its own.
+fn describe_yaml_error(err: &serde_yaml::Error, contents: &str, path: &str) -> anyhow::Error {
+    if let Some(key) = duplicate_key_in(err) {
+        return describe_duplicate_key_error(&key, contents, path);
+    }
+    let mut message = format!("Invalid YAML in values file '{}': {}", path, err);
+    if let Some(location) = err.location() {
+        if let Some(line) = contents.lines().nth(location.line().saturating_sub(1)) {
+            message.push_str(&format!("\n  line {} | {}", location.line(), line));
+        }
+    }
+    if let Some(tab_line) = first_tab_indented_line(contents) {
+        message.push_str(&format!(
+            "\nHint: YAML does not allow tabs in indentation. Replace the tab(s) on line {} with spaces.",
+            tab_line
+        ));
+    }
+    anyhow::anyhow!(message)
+}
+
+/// Extracts the key name from serde_yaml's `duplicate entry with key "x"` message.
+/// Returns None for any other kind of parse error.
+fn duplicate_key_in(err: &serde_yaml::Error) -> Option<String> {
+    let display = err.to_string();
+    let marker = "duplicate entry with key \"";
+    let start = display.find(marker)? + marker.len();
+    let rest = &display[start..];
+    let end = rest.find('"')?;
+    Some(rest[..end].to_string())
+}
+
+fn describe_duplicate_key_error(key: &str, contents: &str, path: &str) -> anyhow::Error {
+    let mut message = format!(
+        "Duplicate key \"{}\" in values file '{}': the same key appears more than once within a mapping.",
+        key, path
+    );
     let lines = lines_defining_key(contents, key);
     if lines.len() >= 2 {
         let line_list = lines
@@ -250,7 +1899,7 @@ mod tests {
         );
 
         // Merge maps
-        merge_maps(&mut existing_map, new_map);
+        merge_maps(&mut existing_map, new_map, &MergeOptions::default()).unwrap();
 
         // Check merged map
         assert_eq!(
@@ -264,32 +1913,661 @@ mod tests {
     }
 
     #[test]
-    fn test_copy_files_simple() -> anyhow::Result<()> {
-        trace!("Running test_copy_files_simple.");
-        let current_dir = current_dir()?;
-        let values_path = RelativePath::new("resources/test/test_values/values.yaml")
-            .to_logical_path(&current_dir);
-        let override_path = RelativePath::new("resources/test/test_values/override.yaml")
-            .to_logical_path(&current_dir);
-        let files = vec![
-            values_path.to_str().unwrap(),
-            override_path.to_str().unwrap(),
-        ];
-        let output = load_yaml_files(&files)?;
-        // Deserialize the expected YAML contents into a struct
-        let expected_yaml: ExpectedFullValues = from_str(
-            r#"---
-        hello: True
-        world: "notString"
-        foo:
-          bar: "hi"
-          nested:
-            map: "here""#,
-        )?;
-        // Convert the expected YAML contents into a `serde_yaml::Value` object
-        let expected_value = serde_yaml::to_value(expected_yaml)?;
-        // Test that the loaded YAML contents match the expected YAML contents
-        assert_eq!(expected_value, output);
+    fn test_merge_values_example() -> anyhow::Result<()> {
+        let base: Value = serde_yaml::from_str("foo:\n  bar: 1\nitems: [a]")?;
+        let overlay: Value = serde_yaml::from_str("foo:\n  baz: 2\nitems: [b]")?;
+
+        let merged = merge_values(base, overlay)?;
+
+        assert_eq!(merged["foo"]["bar"], Value::from(1));
+        assert_eq!(merged["foo"]["baz"], Value::from(2));
+        assert_eq!(merged["items"], serde_yaml::from_str::<Value>("[a, b]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_values_deep_merges_and_appends() -> anyhow::Result<()> {
+        let base: Value = from_str("foo:\n  bar: 1\n  keep: yes\nitems: [a]\nworld: hello")?;
+        let overlay: Value = from_str("foo:\n  bar: 2\nitems: [b]\nworld: goodbye")?;
+
+        let merged = merge_values(base, overlay)?;
+
+        let expected: Value =
+            from_str("foo:\n  bar: 2\n  keep: yes\nitems: [a, b]\nworld: goodbye")?;
+        assert_eq!(expected, merged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_values_covers_every_merge_case() -> anyhow::Result<()> {
+        let base: Value = from_str(
+            r#"
+nested:
+  deeper:
+    keep: 1
+    replace: old
+  list: [{name: a}]
+scalar_to_map: text
+map_to_scalar:
+  inner: value
+only_base: true
+"#,
+        )?;
+        let overlay: Value = from_str(
+            r#"
+nested:
+  deeper:
+    replace: new
+    added: [x]
+  list: [{name: b}]
+scalar_to_map:
+  now: mapping
+map_to_scalar: ~
+only_overlay: 2
+"#,
+        )?;
+
+        let merged = merge_values(base, overlay)?;
+
+        let expected: Value = from_str(
+            r#"
+nested:
+  deeper:
+    keep: 1
+    replace: new
+    added: [x]
+  list: [{name: a}, {name: b}]
+scalar_to_map:
+  now: mapping
+map_to_scalar: ~
+only_base: true
+only_overlay: 2
+"#,
+        )?;
+        assert_eq!(expected, merged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_values_rejects_non_mappings() -> anyhow::Result<()> {
+        let base: Value = from_str("foo: bar")?;
+        let err = merge_values(base.clone(), Value::from("scalar")).unwrap_err();
+        assert!(
+            err.to_string().contains("mapping"),
+            "unexpected error: {}",
+            err
+        );
+        assert!(merge_values(Value::Sequence(vec![]), base).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_merge_by_index_deep_merges_aligned_items() -> anyhow::Result<()> {
+        let base: Value = from_str(
+            "containers:\n  - name: web\n    env: {A: '1', B: '2'}\n  - name: worker\n    env: {A: '1'}",
+        )?;
+        let overlay: Value = from_str("containers:\n  - env: {B: '3'}")?;
+        let options = MergeOptions {
+            sequence_merge: SequenceMerge::ByIndex,
+            ..Default::default()
+        };
+
+        let merged = merge_values_with(base, overlay, &options)?;
+
+        let expected: Value = from_str(
+            "containers:\n  - name: web\n    env: {A: '1', B: '3'}\n  - name: worker\n    env: {A: '1'}",
+        )?;
+        assert_eq!(merged, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_union_scalar_sequences_sorts_and_dedups() -> anyhow::Result<()> {
+        let options = MergeOptions {
+            strategy: MergeStrategy::UnionScalarSequences,
+            ..Default::default()
+        };
+        let base: Value = from_str(
+            "tags: [web, prod, web]\nports: [443, 80]\nmixed: [b, 2, ~]\n\
+             weights: [.nan, 1.5, 9007199254740993]",
+        )?;
+        let overlay: Value = from_str(
+            "tags: [api, prod]\nports: [80, 8080]\nmixed: [true, a, 2]\n\
+             weights: [9007199254740992, 10, .nan]",
+        )?;
+
+        let merged = merge_values_with(base.clone(), overlay.clone(), &options)?;
+
+        let expected: Value = from_str(
+            "tags: [api, prod, web]\nports: [80, 443, 8080]\nmixed: [~, true, 2, a, b]\n\
+             weights: [1.5, 10, 9007199254740992, 9007199254740993, .nan]",
+        )?;
+        assert_eq!(merged, expected);
+        // The order does not depend on which file listed an item first
+        assert_eq!(
+            serde_yaml::to_string(&merge_values_with(overlay, base, &options)?)?,
+            serde_yaml::to_string(&merged)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_union_scalar_sequences_appends_lists_with_collections() -> anyhow::Result<()> {
+        let options = MergeOptions {
+            strategy: MergeStrategy::UnionScalarSequences,
+            ..Default::default()
+        };
+        let base: Value = from_str("items: [b, {name: x}]\nnested: [[z]]")?;
+        let overlay: Value = from_str("items: [a, b]\nnested: [c]")?;
+
+        let merged = merge_values_with(base, overlay, &options)?;
+
+        let expected: Value = from_str("items: [b, {name: x}, a, b]\nnested: [[z], c]")?;
+        assert_eq!(merged, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_merge_by_index_with_mismatched_lengths() -> anyhow::Result<()> {
+        let options = MergeOptions {
+            sequence_merge: SequenceMerge::ByIndex,
+            ..Default::default()
+        };
+
+        // Extra override items are appended
+        let base: Value = from_str("ports: [80, 443]\nnested: [[a, b]]")?;
+        let overlay: Value = from_str("ports: [8080, 8443, 9000]\nnested: [[c], [d]]")?;
+        let merged = merge_values_with(base, overlay, &options)?;
+        assert_eq!(
+            merged,
+            from_str::<Value>("ports: [8080, 8443, 9000]\nnested: [[c, b], [d]]")?
+        );
+
+        // Existing items past the end of a shorter override are kept
+        let base: Value = from_str("ports: [80, 443, 9000]")?;
+        let overlay: Value = from_str("ports: [8080]")?;
+        let merged = merge_values_with(base, overlay, &options)?;
+        assert_eq!(merged, from_str::<Value>("ports: [8080, 443, 9000]")?);
+
+        // The default still appends
+        let base: Value = from_str("ports: [80]")?;
+        let overlay: Value = from_str("ports: [8080]")?;
+        let merged = merge_values_with(base, overlay, &MergeOptions::default())?;
+        assert_eq!(merged, from_str::<Value>("ports: [80, 8080]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_case_collisions_across_documents() -> anyhow::Result<()> {
+        let base: Value = from_str("env:\n  Path: C:\\bin\n  HOME: /root")?;
+        let overlay: Value = from_str("env:\n  path: /usr/bin\n  HOME: /home")?;
+        let options = MergeOptions {
+            key_case_collisions: KeyCaseCollision::Error,
+            ..Default::default()
+        };
+
+        let err = merge_values_with(base.clone(), overlay.clone(), &options)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Keys 'Path' and 'path' in 'env' differ only by case. Rename one of them so they cannot shadow each other."
+        );
+
+        // Off by default, and a warning keeps both keys
+        for key_case_collisions in [KeyCaseCollision::Allow, KeyCaseCollision::Warn] {
+            let options = MergeOptions {
+                key_case_collisions,
+                ..Default::default()
+            };
+            let merged = merge_values_with(base.clone(), overlay.clone(), &options)?;
+            assert_eq!(merged["env"]["Path"], Value::from("C:\\bin"));
+            assert_eq!(merged["env"]["path"], Value::from("/usr/bin"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_case_collisions_within_one_document() -> anyhow::Result<()> {
+        let options = MergeOptions {
+            key_case_collisions: KeyCaseCollision::Error,
+            ..Default::default()
+        };
+        let empty = Value::Mapping(Mapping::new());
+
+        let nested: Value = from_str("services:\n  - env: {Path: a, PATH: b}")?;
+        let err = merge_values_with(empty.clone(), nested, &options)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Keys 'Path' and 'PATH' in 'services[0].env' differ only by case"),
+            "{}",
+            err
+        );
+
+        let top_level: Value = from_str("Name: a\nname: b")?;
+        let err = merge_values_with(empty.clone(), top_level, &options)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("at the top level"), "{}", err);
+
+        // Repeating a key with the same case is an ordinary override
+        let base: Value = from_str("env: {Path: a}")?;
+        let overlay: Value = from_str("env: {Path: b}")?;
+        assert!(merge_values_with(base, overlay, &options).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_conflict_policies() -> anyhow::Result<()> {
+        let base: Value = from_str("db:\n  host: db.internal\n  port: ~\nreplicas: 1\nname: web")?;
+        let overlay: Value = from_str("db:\n  host: ~\n  port: 5432\nreplicas: 3\nname: [a]")?;
+        let merge = |scalar_conflict| {
+            let options = MergeOptions {
+                scalar_conflict,
+                ..Default::default()
+            };
+            merge_values_with(base.clone(), overlay.clone(), &options)
+        };
+
+        let overwrite = merge(ScalarConflict::Overwrite)?;
+        assert_eq!(overwrite["db"]["host"], Value::Null);
+        assert_eq!(overwrite["db"]["port"], Value::from(5432));
+        assert_eq!(overwrite["replicas"], Value::from(3));
+
+        let keep_existing = merge(ScalarConflict::KeepExisting)?;
+        assert_eq!(keep_existing["db"]["host"], Value::from("db.internal"));
+        assert_eq!(keep_existing["db"]["port"], Value::Null);
+        assert_eq!(keep_existing["replicas"], Value::from(1));
+
+        let prefer_non_null = merge(ScalarConflict::PreferNonNull)?;
+        assert_eq!(prefer_non_null["db"]["host"], Value::from("db.internal"));
+        assert_eq!(prefer_non_null["db"]["port"], Value::from(5432));
+        assert_eq!(prefer_non_null["replicas"], Value::from(3));
+
+        // A collection replacing a scalar is not a scalar conflict
+        for merged in [overwrite, keep_existing, prefer_non_null] {
+            assert_eq!(merged["name"], from_str::<Value>("[a]")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_merge_patch_rfc_7386_examples() -> anyhow::Result<()> {
+        let options = MergeOptions {
+            strategy: MergeStrategy::JsonMergePatch,
+            ..Default::default()
+        };
+        // From RFC 7386, Appendix A, leaving out those with a non-object target or patch
+        let cases = [
+            (r#"{"a":"b"}"#, r#"{"a":"c"}"#, r#"{"a":"c"}"#),
+            (r#"{"a":"b"}"#, r#"{"b":"c"}"#, r#"{"a":"b","b":"c"}"#),
+            (r#"{"a":"b"}"#, r#"{"a":null}"#, r#"{}"#),
+            (r#"{"a":"b","b":"c"}"#, r#"{"a":null}"#, r#"{"b":"c"}"#),
+            (r#"{"a":["b"]}"#, r#"{"a":"c"}"#, r#"{"a":"c"}"#),
+            (r#"{"a":"c"}"#, r#"{"a":["b"]}"#, r#"{"a":["b"]}"#),
+            (
+                r#"{"a":{"b":"c"}}"#,
+                r#"{"a":{"b":"d","c":null}}"#,
+                r#"{"a":{"b":"d"}}"#,
+            ),
+            (r#"{"a":[{"b":"c"}]}"#, r#"{"a":[1]}"#, r#"{"a":[1]}"#),
+            (r#"{"e":null}"#, r#"{"a":1}"#, r#"{"e":null,"a":1}"#),
+            (
+                r#"{}"#,
+                r#"{"a":{"bb":{"ccc":null}}}"#,
+                r#"{"a":{"bb":{}}}"#,
+            ),
+        ];
+        for (target, patch, expected) in cases {
+            let merged = merge_values_with(from_str(target)?, from_str(patch)?, &options)?;
+            assert_eq!(
+                merged,
+                from_str::<Value>(expected)?,
+                "{} + {}",
+                target,
+                patch
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_merge_patch_rfc_7386_document_example() -> anyhow::Result<()> {
+        let target: Value = from_str(
+            "title: Goodbye!\nauthor:\n  givenName: John\n  familyName: Doe\ntags: [example, sample]\ncontent: This will be unchanged",
+        )?;
+        let patch: Value = from_str(
+            "title: Hello!\nphoneNumber: '+01-123-456-7890'\nauthor:\n  familyName: null\ntags: [example]",
+        )?;
+        let options = MergeOptions {
+            strategy: MergeStrategy::JsonMergePatch,
+            ..Default::default()
+        };
+
+        let merged = merge_values_with(target.clone(), patch.clone(), &options)?;
+        let expected: Value = from_str(
+            "title: Hello!\nauthor:\n  givenName: John\ntags: [example]\ncontent: This will be unchanged\nphoneNumber: '+01-123-456-7890'",
+        )?;
+        assert_eq!(merged, expected);
+
+        // The default strategy appends lists and keeps nulls
+        let deep = merge_values_with(target, patch, &MergeOptions::default())?;
+        assert_eq!(
+            deep["tags"],
+            from_str::<Value>("[example, sample, example]")?
+        );
+        assert_eq!(deep["author"]["familyName"], Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_merge_patch_removal_keeps_key_order() -> anyhow::Result<()> {
+        let target: Value = from_str("a: 1\nb: 2\nc: 3\nd: {x: 1, y: 2, z: 3}")?;
+        let patch: Value = from_str("b: null\nd: {x: null}")?;
+        let options = MergeOptions {
+            strategy: MergeStrategy::JsonMergePatch,
+            ..Default::default()
+        };
+
+        let merged = merge_values_with(target, patch, &options)?;
+        assert_eq!(
+            serde_yaml::to_string(&merged)?,
+            "a: 1\nc: 3\nd:\n  y: 2\n  z: 3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_precedence_later_wins_vs_earlier_wins() -> anyhow::Result<()> {
+        let specific: Value = from_str("db:\n  host: db.prod\nports: [443]\nname: web")?;
+        let defaults: Value =
+            from_str("db:\n  host: localhost\n  port: 5432\nports: [80]\nname: {first: x}")?;
+        let merge = |precedence| {
+            let options = MergeOptions {
+                precedence,
+                ..Default::default()
+            };
+            merge_values_with(specific.clone(), defaults.clone(), &options)
+        };
+
+        let later_wins = merge(Precedence::LaterWins)?;
+        assert_eq!(later_wins["db"]["host"], Value::from("localhost"));
+        assert_eq!(later_wins["ports"], from_str::<Value>("[443, 80]")?);
+        assert_eq!(later_wins["name"], from_str::<Value>("{first: x}")?);
+
+        // Only missing keys are filled in, descending into mappings to find them
+        let earlier_wins = merge(Precedence::EarlierWins)?;
+        let expected: Value =
+            from_str("db:\n  host: db.prod\n  port: 5432\nports: [443]\nname: web")?;
+        assert_eq!(earlier_wins, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_fill_defaults() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let defaults = temp_dir.path().join("defaults.yaml");
+        std::fs::write(
+            &defaults,
+            "image:\n  name: nginx\n  tag: latest\nreplicas: 1",
+        )?;
+        let files = ["image.tag:=1.25", defaults.to_str().unwrap()];
+
+        let values = load_yaml_files_fill_defaults(&files)?;
+        assert_eq!(values["image"]["name"], Value::from("nginx"));
+        assert_eq!(values["image"]["tag"], Value::from("1.25"));
+        assert_eq!(values["replicas"], Value::from(1));
+
+        let values = load_yaml_files_with(&files, &LoadOptions::default())?;
+        assert_eq!(values["image"]["tag"], Value::from("latest"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_conflict_through_load_yaml_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base = temp_dir.path().join("base.yaml");
+        std::fs::write(&base, "image: nginx\ntag: '1.25'")?;
+        let options = LoadOptions {
+            merge: MergeOptions {
+                scalar_conflict: ScalarConflict::KeepExisting,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let values =
+            load_yaml_files_with(&[base.to_str().unwrap(), "tag=latest", "port=80"], &options)?;
+        assert_eq!(values["tag"], Value::from("1.25"));
+        assert_eq!(values["port"], Value::from(80));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_render_error_keeps_value_path() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("values.yaml");
+        std::fs::write(
+            &file,
+            "name: web\nlabels:\n  app: '{{ name | nosuchfilter }}'",
+        )?;
+
+        let err = load_yaml_files(&vec![file.to_str().unwrap()]).unwrap_err();
+        let chained = format!("{:#}", err);
+        assert!(
+            chained.starts_with(
+                "Failed to resolve value references in YAML files: Failed to resolve value 'labels.app': "
+            ),
+            "{}",
+            chained
+        );
+        assert!(chained.contains("nosuchfilter"), "{}", chained);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_override_pushes_onto_existing_list() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("values.yaml");
+        std::fs::write(
+            &file,
+            "services:\n  web:\n    environment:\n      - A=1\n      - B=2",
+        )?;
+        let options = LoadOptions::new()
+            .sequence_merge(SequenceMerge::ByIndex)
+            .override_conflicts(OverrideConflict::Error);
+
+        let values = load_yaml_files_with(
+            &[
+                file.to_str().unwrap(),
+                "services.web.environment[+]=FOO=bar",
+                "services.web.environment[+]=BAZ=qux",
+            ],
+            &options,
+        )?;
+        assert_eq!(
+            values["services"]["web"]["environment"],
+            from_str::<Value>("[A=1, B=2, FOO=bar, BAZ=qux]")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_override_creates_missing_list() -> anyhow::Result<()> {
+        let values = load_yaml_files(&vec![
+            "services.web.image=nginx",
+            "services.web.ports[+]=8080",
+            "services.db.environment[+]=POSTGRES_DB=app",
+        ])?;
+        let expected: Value = from_str(
+            "services:\n  web:\n    image: nginx\n    ports: [8080]\n  db:\n    environment: [POSTGRES_DB=app]",
+        )?;
+        assert_eq!(values, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merging_deeply_nested_values_errors() {
+        let nested = |leaf: i64| {
+            (0..DEFAULT_MAX_DEPTH + 10).fold(Value::from(leaf), |inner, _| {
+                let mut map = Mapping::new();
+                map.insert(Value::from("k"), inner);
+                Value::Mapping(map)
+            })
+        };
+        let err = merge_values(nested(1), nested(2)).unwrap_err().to_string();
+        assert!(
+            err.starts_with("Values are nested too deeply at 'k.k"),
+            "{}",
+            err
+        );
+
+        let options = MergeOptions {
+            max_depth: DEFAULT_MAX_DEPTH + 20,
+            ..Default::default()
+        };
+        assert!(merge_values_with(nested(1), nested(2), &options).is_ok());
+    }
+
+    #[test]
+    fn test_pinned_path_keeps_first_value() -> anyhow::Result<()> {
+        let base: Value = from_str("database:\n  host: db.internal\n  port: 5432\nreplicas: 1")?;
+        let overlay: Value = from_str("database:\n  host: localhost\n  port: 6543\nreplicas: 3")?;
+        let options = MergeOptions {
+            pinned_paths: vec!["database.host".to_string()],
+            ..Default::default()
+        };
+
+        let merged = merge_values_with(base, overlay, &options)?;
+
+        assert_eq!(Value::from("db.internal"), merged["database"]["host"]);
+        assert_eq!(Value::from(6543), merged["database"]["port"]);
+        assert_eq!(Value::from(3), merged["replicas"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_path_override_errors_when_configured() -> anyhow::Result<()> {
+        let base: Value = from_str("database:\n  host: db.internal")?;
+        let options = MergeOptions {
+            pinned_paths: vec!["database.host".to_string()],
+            on_pinned_override: PinnedOverride::Error,
+            ..Default::default()
+        };
+
+        // Repeating the pinned value is not an override
+        let same: Value = from_str("database:\n  host: db.internal")?;
+        assert!(merge_values_with(base.clone(), same, &options).is_ok());
+
+        let changed: Value = from_str("database:\n  host: localhost")?;
+        let err = merge_values_with(base.clone(), changed, &options).unwrap_err();
+        assert!(
+            err.to_string().contains("pinned value 'database.host'"),
+            "unexpected error: {}",
+            err
+        );
+
+        // Replacing the parent mapping would drop the pinned key, so it is rejected too
+        let replaced: Value = from_str("database: sqlite")?;
+        assert!(merge_values_with(base, replaced, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_path_through_load_yaml_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base_file = temp_dir.path().join("base.yaml");
+        std::fs::write(&base_file, "policy:\n  tls: true\n  ciphers: [strong]\n")?;
+        let files = vec![
+            base_file.to_str().unwrap(),
+            "policy.tls=false",
+            "policy.ciphers=[weak]",
+        ];
+        let options = LoadOptions {
+            merge: MergeOptions {
+                pinned_paths: vec!["policy".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let output = load_yaml_files_with(&files, &options)?;
+
+        assert_eq!(Value::Bool(true), output["policy"]["tls"]);
+        assert_eq!(from_str::<Value>("[strong]")?, output["policy"]["ciphers"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchors_are_expanded_on_read() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("anchors.yaml");
+        std::fs::write(
+            &temp_file,
+            "common: &common\n  image: nginx\n  port: 80\nservices:\n  - *common\n  - name: extra\n",
+        )?;
+
+        let output = read_yaml_file(temp_file.to_str().unwrap())?;
+
+        assert_eq!(output["services"][0], output["common"]);
+        assert_eq!(output["services"][0]["image"], Value::from("nginx"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_values_dedups_appended_sequence_items() -> anyhow::Result<()> {
+        let base: Value =
+            from_str("common: &common\n  image: nginx\nservices:\n  - *common\n  - *common")?;
+        let overlay: Value = from_str(
+            "common: &common\n  image: nginx\nservices:\n  - *common\n  - image: redis\n  - image: redis",
+        )?;
+        let options = MergeOptions {
+            dedup_sequence_items: true,
+            ..Default::default()
+        };
+
+        let merged = merge_values_with(base.clone(), overlay.clone(), &options)?;
+
+        // Duplicates within either document are kept, appended copies of base items are not
+        let expected: Value = from_str(
+            "common:\n  image: nginx\nservices:\n  - image: nginx\n  - image: nginx\n  - image: redis\n  - image: redis",
+        )?;
+        assert_eq!(expected, merged);
+
+        // Without the option every appended item is kept
+        let appended = merge_values(base, overlay)?;
+        assert_eq!(5, appended["services"].as_sequence().unwrap().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_files_simple() -> anyhow::Result<()> {
+        trace!("Running test_copy_files_simple.");
+        let current_dir = current_dir()?;
+        let values_path = RelativePath::new("resources/test/test_values/values.yaml")
+            .to_logical_path(&current_dir);
+        let override_path = RelativePath::new("resources/test/test_values/override.yaml")
+            .to_logical_path(&current_dir);
+        let files = vec![
+            values_path.to_str().unwrap(),
+            override_path.to_str().unwrap(),
+        ];
+        let output = load_yaml_files(&files)?;
+        // Deserialize the expected YAML contents into a struct
+        let expected_yaml: ExpectedFullValues = from_str(
+            r#"---
+        hello: True
+        world: "notString"
+        foo:
+          bar: "hi"
+          nested:
+            map: "here""#,
+        )?;
+        // Convert the expected YAML contents into a `serde_yaml::Value` object
+        let expected_value = serde_yaml::to_value(expected_yaml)?;
+        // Test that the loaded YAML contents match the expected YAML contents
+        assert_eq!(expected_value, output);
         Ok(())
     }
 
@@ -499,195 +2777,1295 @@ mod tests {
     }
 
     #[test]
-    fn test_invalid_yaml_error_propagates_through_load_yaml_files() -> anyhow::Result<()> {
+    fn test_invalid_yaml_error_propagates_through_load_yaml_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("broken.yaml");
+        std::fs::write(&temp_file, "key: [oops\n")?;
+
+        let files = vec![temp_file.to_str().expect("utf-8 path")];
+        let err = load_yaml_files(&files).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("Invalid YAML in values file"),
+            "Full chain should keep the descriptive parse error: {}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_top_level_key_error_names_key_file_and_lines() -> anyhow::Result<()> {
+        // Exact repro from issue #33
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("dupes.yaml");
+        std::fs::write(&temp_file, "hello: world\nto: you\nhello: world\n")?;
+
+        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Duplicate key \"hello\""),
+            "Should name the duplicated key: {}",
+            message
+        );
+        assert!(
+            message.contains("dupes.yaml"),
+            "Should name the offending file: {}",
+            message
+        );
+        assert!(
+            message.contains("lines: 1, 3"),
+            "Should point at both definitions: {}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_nested_key_error_names_key() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("nested_dupes.yaml");
+        std::fs::write(&temp_file, "outer:\n  inner: 1\n  inner: 2\n")?;
+
+        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Duplicate key \"inner\""),
+            "Should name the duplicated key: {}",
+            message
+        );
+        assert!(
+            message.contains("lines: 2, 3"),
+            "Should point at both definitions: {}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_files_and_max_total_bytes() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for name in ["a", "b", "c"] {
+            let path = temp_dir.path().join(format!("{}.yaml", name));
+            std::fs::write(&path, format!("{}: 12345\n", name))?;
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let mut files: Vec<&str> = paths.iter().map(String::as_str).collect();
+        files.push("d=1");
+
+        let options = LoadOptions::new().max_files(3).max_total_bytes(30);
+        let values = load_yaml_files_with(&files, &options)?;
+        assert_eq!(
+            values,
+            from_str::<Value>("a: 12345\nb: 12345\nc: 12345\nd: 1")?
+        );
+
+        let err = load_yaml_files_with(&files, &LoadOptions::new().max_files(2)).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("c.yaml' is file number 3, the limit is 2"),
+            "{}",
+            message
+        );
+
+        let options = LoadOptions::new().max_total_bytes(20);
+        let err = load_yaml_files_with(&files, &options).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("too large: 27 bytes including")
+                && message.contains("c.yaml', the limit is 20 bytes"),
+            "{}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_key_policies() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("dupes.yaml");
+        std::fs::write(
+            &temp_file,
+            "port: 80\napp:\n  name: first\n  name: second\nport: 8080\n",
+        )?;
+        let path = temp_file.to_str().expect("utf-8 path");
+
+        let err = load_yaml_files_with(&[path], &LoadOptions::new()).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("Duplicate key \"name\"") && message.contains("lines: 3, 4"),
+            "Error should be the default, with the lines: {}",
+            message
+        );
+
+        let options = LoadOptions::new().duplicate_keys(DuplicateKeyPolicy::LastWins);
+        let values = load_yaml_files_with(&[path, "app.extra=x"], &options)?;
+        assert_eq!(
+            values,
+            from_str::<Value>("port: 8080\napp: {name: second, extra: x}")?
+        );
+
+        let options = LoadOptions::new().duplicate_keys(DuplicateKeyPolicy::FirstWins);
+        let values = load_yaml_files_with(&[path], &options)?;
+        assert_eq!(values, from_str::<Value>("port: 80\napp: {name: first}")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_key_error_propagates_through_load_yaml_files() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("dupes.yaml");
+        std::fs::write(&temp_file, "hello: world\nhello: again\n")?;
+
+        let files = vec![temp_file.to_str().expect("utf-8 path")];
+        let err = load_yaml_files(&files).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Duplicate key \"hello\""),
+            "Full chain should keep the duplicate key error: {:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_duplicate_parse_errors_keep_generic_message() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("broken.yaml");
+        std::fs::write(&temp_file, "key: [oops\n")?;
+
+        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Invalid YAML in values file"),
+            "Non-duplicate errors should use the generic parse message: {}",
+            message
+        );
+        assert!(
+            !message.contains("Duplicate key"),
+            "Should not misclassify as a duplicate key error: {}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lines_defining_key() {
+        let contents = "hello: 1\n\"hello\": 2\n  'hello': 3\nhelloworld: 4\nother: 5\n";
+        assert_eq!(lines_defining_key(contents, "hello"), vec![1, 2, 3]);
+        assert_eq!(lines_defining_key(contents, "missing"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_first_tab_indented_line() {
+        assert_eq!(first_tab_indented_line("a: 1\nb: 2\n"), None);
+        assert_eq!(first_tab_indented_line("a:\n\tb: 2\n"), Some(2));
+        assert_eq!(first_tab_indented_line("a:\n  \tb: 2\n"), Some(2));
+        assert_eq!(first_tab_indented_line("a: \"value\twith tab\"\n"), None);
+    }
+
+    #[test]
+    fn test_merge_yaml_lists() -> anyhow::Result<()> {
+        // Your inline YAML strings for the first and second YAML contents
+        let yaml1_str = r#"
+        items:
+          - apple
+          - banana
+        world: "hello""#;
+
+        let yaml2_str = r#"
+        items:
+          - orange
+          - cherry
+        world: "goodbye""#;
+
+        // Deserialize the inline YAML strings to serde_yaml::Value objects
+        let mut yaml1: Value = from_str(yaml1_str)?;
+        let yaml2: Value = from_str(yaml2_str)?;
+
+        if let (Value::Mapping(ref mut map1), Value::Mapping(map2)) = (&mut yaml1, &yaml2) {
+            merge_maps(map1, map2.clone(), &MergeOptions::default())?;
+        }
+
+        // Now, let's define the expected merged YAML result
+        let expected_str = r#"
+        items:
+          - apple
+          - banana
+          - orange
+          - cherry
+        world: "goodbye""#;
+        let expected: Value = from_str(expected_str)?;
+
+        // Test that the merged YAML content matches the expected result
+        assert_eq!(expected, yaml1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_from_strings() -> anyhow::Result<()> {
+        // Inline YAML strings for the first and second YAML contents
+        trace!("Running test_copy_files_complex_manual_override_multiple.");
+        let current_dir = current_dir()?;
+        let values_path = RelativePath::new("resources/test/merge_lists/first.yaml")
+            .to_logical_path(&current_dir);
+        let override_path = RelativePath::new("resources/test/merge_lists/second.yaml")
+            .to_logical_path(&current_dir);
+        let files = vec![
+            values_path.to_str().unwrap(),
+            override_path.to_str().unwrap(),
+            "fruit.color=red",
+        ];
+
+        // Load and merge YAML contents from strings using `load_yaml_files` function
+        let merged_yaml = load_yaml_files(&files)?;
+
+        // Now, let's define the expected merged YAML result
+        let expected_str = r#"
+        items:
+          - apple
+          - banana
+          - orange
+          - cherry
+        world: "goodbye"
+        fruit:
+          color: "red"
+    "#;
+        let expected: Value = from_str(expected_str)?;
+
+        // Test that the merged YAML content matches the expected result
+        assert_eq!(expected, merged_yaml);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_values_file_merges_as_no_op() -> anyhow::Result<()> {
+        let current_dir = current_dir()?;
+        let values_path = RelativePath::new("resources/test/test_values/values.yaml")
+            .to_logical_path(&current_dir);
+        let override_path = RelativePath::new("resources/test/test_values/override.yaml")
+            .to_logical_path(&current_dir);
+        let temp_dir = tempfile::tempdir()?;
+        let empty_file = temp_dir.path().join("empty.yaml");
+        std::fs::write(&empty_file, "")?;
+        let tilde_file = temp_dir.path().join("tilde.yaml");
+        std::fs::write(&tilde_file, "~\n")?;
+
+        let with_empty = load_yaml_files(&vec![
+            values_path.to_str().unwrap(),
+            empty_file.to_str().unwrap(),
+            override_path.to_str().unwrap(),
+            tilde_file.to_str().unwrap(),
+        ])?;
+        let without_empty = load_yaml_files(&vec![
+            values_path.to_str().unwrap(),
+            override_path.to_str().unwrap(),
+        ])?;
+        assert_eq!(without_empty, with_empty);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fail_on_empty_option() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let values_file = temp_dir.path().join("values.yaml");
+        std::fs::write(&values_file, "name: app\n")?;
+        let empty_file = temp_dir.path().join("empty.yaml");
+        std::fs::write(&empty_file, "# generated\n")?;
+        let values = values_file.to_str().unwrap();
+        let empty = empty_file.to_str().unwrap();
+
+        let lenient = load_yaml_files_with(&[values, empty], &LoadOptions::new())?;
+        assert_eq!(lenient["name"], Value::from("app"));
+
+        let strict = LoadOptions::new().fail_on_empty(true);
+        assert_eq!(load_yaml_files_with(&[values], &strict)?, lenient);
+        let err = load_yaml_files_with(&[values, empty], &strict).unwrap_err();
+        assert_eq!(err.to_string(), format!("Values file '{}' is empty", empty));
+
+        let optional = format!("?{}", empty);
+        let err = load_yaml_files_with(&[values, optional.as_str()], &strict).unwrap_err();
+        assert_eq!(err.to_string(), format!("Values file '{}' is empty", empty));
+
+        // A generator writing `{}` is caught too
+        let empty_json = temp_dir.path().join("generated.json");
+        std::fs::write(&empty_json, "{}\n")?;
+        let empty_json = empty_json.to_str().unwrap();
+        assert_eq!(
+            load_yaml_files_with(&[values, empty_json], &LoadOptions::new())?,
+            lenient
+        );
+        let err = load_yaml_files_with(&[values, empty_json], &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Values file '{}' is empty", empty_json)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_values_file_still_errors() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let scalar_file = temp_dir.path().join("scalar.yaml");
+        std::fs::write(&scalar_file, "just a string\n")?;
+
+        let err = load_yaml_files(&vec![scalar_file.to_str().unwrap()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Expected top-level YAML structure to be a mapping"),
+            "unexpected error: {}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_list_override_appends_to_existing_list() -> anyhow::Result<()> {
+        let current_dir = current_dir()?;
+        let first_path = RelativePath::new("resources/test/merge_lists/first.yaml")
+            .to_logical_path(&current_dir);
+        let files = vec![first_path.to_str().unwrap(), "items=[orange, cherry]"];
+
+        let merged_yaml = load_yaml_files(&files)?;
+
+        let expected: Value = from_str("[apple, banana, orange, cherry]")?;
+        assert_eq!(expected, merged_yaml["items"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver_skipped_without_references() -> anyhow::Result<()> {
+        let values: Value = from_str("a: 1\nb:\n  c: [plain, '{ braces }']")?;
+        let resolved =
+            resolve_when_referenced(values.clone(), &ResolveOptions::default(), |_, _| {
+                panic!("resolver should not run for reference-free values")
+            })?;
+        assert_eq!(resolved, values);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver_runs_with_dollar_only_references() -> anyhow::Result<()> {
+        let values: Value = from_str("a: 1\nb: 'port ${ a }'")?;
+        let options = ResolveOptions {
+            syntaxes: vec![crate::utils::value_resolver::ReferenceSyntax::Dollar],
+            ..ResolveOptions::default()
+        };
+        let resolved = resolve_when_referenced(values.clone(), &options, |values, options| {
+            resolve_value_references_with(values, options)
+        })?;
+        assert_eq!(resolved, from_str::<Value>("a: 1\nb: port 1")?);
+
+        // Without the syntax enabled, `${ }` is plain text
+        let resolved =
+            resolve_when_referenced(values.clone(), &ResolveOptions::default(), |_, _| {
+                panic!("resolver should not run for reference-free values")
+            })?;
+        assert_eq!(resolved, values);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver_runs_with_references() -> anyhow::Result<()> {
+        let values: Value = from_str("a: 1\nb:\n  c: ['{{ a }}']")?;
+        let mut calls = 0;
+        let resolved =
+            resolve_when_referenced(values, &ResolveOptions::default(), |values, options| {
+                calls += 1;
+                resolve_value_references_with(values, options)
+            })?;
+        assert_eq!(calls, 1);
+        assert_eq!(resolved, from_str::<Value>("a: 1\nb:\n  c: ['1']")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_path_with_set_variables() -> anyhow::Result<()> {
+        std::env::set_var("COMPOSER_TEST_EXPAND_DIR", "/etc/composer");
+        assert_eq!(
+            expand_path("$COMPOSER_TEST_EXPAND_DIR/values.yaml")?,
+            "/etc/composer/values.yaml"
+        );
+        assert_eq!(
+            expand_path("${COMPOSER_TEST_EXPAND_DIR}-prod/values.yaml")?,
+            "/etc/composer-prod/values.yaml"
+        );
+        assert_eq!(expand_path("values$.yaml")?, "values$.yaml");
+        assert_eq!(expand_path("plain/values.yaml")?, "plain/values.yaml");
+
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            expand_path("~/values.yaml")?,
+            format!("{}/values.yaml", home.display())
+        );
+        // Only a leading `~` is the home directory
+        assert_eq!(expand_path("a/~/values.yaml")?, "a/~/values.yaml");
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_path_with_unset_variable_errors() {
+        std::env::remove_var("COMPOSER_TEST_EXPAND_UNSET");
+        let err = expand_path("${COMPOSER_TEST_EXPAND_UNSET}/values.yaml")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Cannot expand values file path '${COMPOSER_TEST_EXPAND_UNSET}/values.yaml': environment variable 'COMPOSER_TEST_EXPAND_UNSET' is not set."
+        );
+        assert!(expand_path("${COMPOSER_TEST_EXPAND_UNSET").is_err());
+    }
+
+    #[test]
+    fn test_load_yaml_files_expands_paths() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(temp_dir.path().join("values.yaml"), "a: 1")?;
+        std::env::set_var("COMPOSER_TEST_LOAD_DIR", temp_dir.path());
+
+        let values = load_yaml_files(&vec![
+            "$COMPOSER_TEST_LOAD_DIR/values.yaml",
+            "?${COMPOSER_TEST_LOAD_DIR}/missing.yaml",
+            "b=$NOT_EXPANDED",
+        ])?;
+        assert_eq!(values, from_str::<Value>("a: 1\nb: $NOT_EXPANDED")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_options_builder_sets_options() {
+        let options = LoadOptions::new();
+        assert!(options.expand_env);
+        assert!(!options.resolve.fail_on_undefined);
+        assert_eq!(options.merge.sequence_merge, SequenceMerge::Append);
+        assert_eq!(options.merge.strategy, MergeStrategy::Deep);
+
+        let options = LoadOptions::new()
+            .merge_strategy(MergeStrategy::JsonMergePatch)
+            .sequence_merge(SequenceMerge::ByIndex)
+            .strict_undefined(true)
+            .fail_on_unresolved(true)
+            .override_conflicts(OverrideConflict::Error)
+            .expand_env(false);
+        assert_eq!(options.merge.strategy, MergeStrategy::JsonMergePatch);
+        assert_eq!(options.merge.sequence_merge, SequenceMerge::ByIndex);
+        assert!(options.resolve.fail_on_undefined);
+        assert!(options.fail_on_unresolved);
+        assert_eq!(options.override_conflicts, OverrideConflict::Error);
+        assert!(!options.expand_env);
+    }
+
+    #[test]
+    fn test_load_yaml_files_without_env_expansion() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let literal = temp_dir.path().join("$COMPOSER_TEST_NO_EXPAND");
+        std::fs::create_dir(&literal)?;
+        std::fs::write(literal.join("values.yaml"), "a: 1")?;
+        std::env::set_var("COMPOSER_TEST_NO_EXPAND", "elsewhere");
+        let path = format!("{}/values.yaml", literal.display());
+
+        let options = LoadOptions::new().expand_env(false);
+        let values = load_yaml_files_with(&[path.as_str()], &options)?;
+        assert_eq!(values["a"], Value::from(1));
+        assert!(load_yaml_files_with(&[path.as_str()], &LoadOptions::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_cannot_nest_under_scalar() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("values.yaml");
+        std::fs::write(&path, "foo: bar\nport: 80")?;
+        let path = path.display().to_string();
+
+        let err = load_yaml_files(&vec![&path, "foo.baz=x"]).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot set foo.baz: foo is a string");
+        let err = load_yaml_files(&vec![&path, "port.number.value=1"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot set port.number.value: port is a number"
+        );
+
+        // Replacing the scalar itself is still allowed
+        let values = load_yaml_files(&vec![&path, "foo=baz"])?;
+        assert_eq!(values["foo"], Value::from("baz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_override_types() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("values.yaml");
+        std::fs::write(
+            &path,
+            "replicas: 3
+image: app
+empty: ~
+ports: [80]",
+        )?;
+        let path = path.display().to_string();
+        let strict = LoadOptions::new().strict_override_types(true);
+
+        let err = load_yaml_files_with(&[&path, "replicas=[1,2]"], &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Override 'replicas=[1,2]' sets replicas to a sequence, but it is a number"
+        );
+        let err = load_yaml_files_with(&[&path, "image={name: app}"], &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Override 'image={name: app}' sets image to a mapping, but it is a string"
+        );
+
+        // Without the option the sequence silently replaces the scalar
+        let values = load_yaml_files(&vec![&path, "replicas=[1,2]"])?;
+        assert_eq!(values["replicas"], serde_yaml::from_str::<Value>("[1, 2]")?);
+
+        // Same types, new paths, nulls and appends are fine
+        let values = load_yaml_files_with(
+            &[
+                &path,
+                "replicas=5",
+                "image:=5",
+                "empty=[a]",
+                "new={a: b}",
+                "ports[+]=81",
+            ],
+            &strict,
+        )?;
+        assert_eq!(values["replicas"], Value::from(5));
+        assert_eq!(values["image"], Value::from("5"));
+        assert_eq!(values["ports"], serde_yaml::from_str::<Value>("[80, 81]")?);
+
+        // `:=` sets a string even over a number
+        let values = load_yaml_files_with(&[&path, "replicas:=2024"], &strict)?;
+        assert_eq!(values["replicas"], Value::from("2024"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_cannot_nest_under_sequence() -> anyhow::Result<()> {
+        let err = load_yaml_files(&vec!["a.items=[1, 2]", "a.items.first=x"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot set a.items.first: a.items is a sequence"
+        );
+
+        let values = load_yaml_files(&vec!["a.items=[1, 2]", "a.items[+]=3", "a.empty=~"])?;
+        assert_eq!(
+            values["a"]["items"],
+            serde_yaml::from_str::<Value>("[1, 2, 3]")?
+        );
+        let values = load_yaml_files(&vec!["a.empty=~", "a.empty.key=x"])?;
+        assert_eq!(values["a"]["empty"]["key"], Value::from("x"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_subtree_is_not_unresolved() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("values.yaml");
+        std::fs::write(
+            &path,
+            "name: web\nalerts: !raw\n  summary: '{{ $labels.instance }} is down'\ntitle: '{{ name }}'",
+        )?;
+        let options = LoadOptions::new().fail_on_unresolved(true);
+
+        let values = load_yaml_files_with(&[path.to_str().unwrap()], &options)?;
+        assert_eq!(values["title"], Value::from("web"));
+        assert_eq!(
+            values["alerts"]["summary"],
+            Value::from("{{ $labels.instance }} is down")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_dir_merges_value_files_in_order() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dir = temp_dir.path();
+        std::fs::write(
+            dir.join("10-base.yaml"),
+            "name: web\nreplicas: 1\nimage: nginx",
+        )?;
+        std::fs::write(dir.join("20-prod.yml"), "replicas: 3")?;
+        std::fs::write(dir.join("30-ports.json"), r#"{"port": 8080}"#)?;
+        std::fs::write(dir.join("40-url.yaml"), "url: '{{ name }}:{{ port }}'")?;
+        std::fs::write(dir.join("README.md"), "replicas: 99")?;
+        std::fs::create_dir(dir.join("nested"))?;
+        std::fs::write(dir.join("nested").join("extra.yaml"), "replicas: 5")?;
+        let dir = dir.to_str().unwrap();
+
+        let values = load_yaml_dir(dir, false)?;
+        let expected: Value =
+            from_str("name: web\nreplicas: 3\nimage: nginx\nport: 8080\nurl: web:8080")?;
+        assert_eq!(values, expected);
+
+        // `nested/` sorts after the numbered files, so it is merged last
+        let values = load_yaml_dir(dir, true)?;
+        assert_eq!(values["replicas"], Value::from(5));
+
+        let missing = format!("{}/missing", dir);
+        assert!(load_yaml_dir(&missing, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_dir_with_limits() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        for name in ["a", "b", "c"] {
+            let path = temp_dir.path().join(format!("{}.yaml", name));
+            std::fs::write(&path, format!("{}: 12345\n", name))?;
+        }
+        let dir = temp_dir.path().to_str().unwrap();
+
+        let options = LoadOptions::new().max_files(3).max_total_bytes(27);
+        let values = load_yaml_dir_with(dir, false, &options)?;
+        assert_eq!(values, from_str::<Value>("a: 12345\nb: 12345\nc: 12345")?);
+
+        let options = LoadOptions::new().max_files(2);
+        let err = format!(
+            "{:#}",
+            load_yaml_dir_with(dir, false, &options).unwrap_err()
+        );
+        assert!(err.contains("is file number 3, the limit is 2"), "{}", err);
+
+        let options = LoadOptions::new().max_total_bytes(20);
+        let err = format!(
+            "{:#}",
+            load_yaml_dir_with(dir, false, &options).unwrap_err()
+        );
+        assert!(err.contains("the limit is 20 bytes"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_with_progress_reports_each_file() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut files = Vec::new();
+        for index in 0..3 {
+            let path = temp_dir.path().join(format!("values{}.yaml", index));
+            std::fs::write(&path, format!("file{}: true\nlast: {}", index, index))?;
+            files.push(path.display().to_string());
+        }
+        let missing = format!("?{}/missing.yaml", temp_dir.path().display());
+        let mut inputs: Vec<&str> = files.iter().map(String::as_str).collect();
+        inputs.extend(["last=override", missing.as_str()]);
+
+        let mut reported = Vec::new();
+        let values =
+            load_yaml_files_with_progress(&inputs, &mut |path| reported.push(path.to_string()))?;
+        assert_eq!(reported, files);
+        assert_eq!(
+            values,
+            load_yaml_files_with(&inputs, &LoadOptions::default())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_frozen_files_are_not_resolved_again() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plugin = temp_dir.path().join("plugin.yaml");
+        std::fs::write(
+            &plugin,
+            "plugin:\n  url: http://plugin.internal\n  template: 'Hello {{ name }}'\n  id: '{{ uuid() }}'",
+        )?;
+        let app = temp_dir.path().join("app.yaml");
+        std::fs::write(
+            &app,
+            "name: web\nendpoint: '{{ plugin.url }}/api'\ngreeting: '{{ plugin.template }}'",
+        )?;
+        let plugin = plugin.display().to_string();
+        let app = app.display().to_string();
+        let options = LoadOptions::new().frozen_file(&plugin);
+
+        let values = load_yaml_files_with(&[&plugin, &app], &options)?;
+        assert_eq!(
+            values["plugin"]["template"],
+            Value::from("Hello {{ name }}")
+        );
+        assert_eq!(values["plugin"]["id"], Value::from("{{ uuid() }}"));
+        assert_eq!(
+            values["endpoint"],
+            Value::from("http://plugin.internal/api")
+        );
+        assert_eq!(values["greeting"], Value::from("Hello {{ name }}"));
+        // Frozen values are not reported as unresolved, but copies of them are
+        let strict = options.clone().fail_on_unresolved(true);
+        assert!(load_yaml_files_with(&[&plugin], &strict).is_ok());
+        assert!(load_yaml_files_with(&[&plugin, &app], &strict).is_err());
+
+        // Without freezing, the plugin's templates are rendered
+        let values = load_yaml_files_with(&[&plugin, &app], &LoadOptions::default())?;
+        assert_eq!(values["plugin"]["template"], Value::from("Hello web"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_frozen_files_may_hold_unterminated_expressions() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let frozen = temp_dir.path().join("frozen.yaml");
+        std::fs::write(
+            &frozen,
+            "literal: 'starts with {{ and never ends'
+name: web",
+        )?;
+        let app = temp_dir.path().join("app.yaml");
+        std::fs::write(&app, "greeting: 'Hello {{ name }}'")?;
+        let frozen = frozen.display().to_string();
+        let app = app.display().to_string();
+        let options = LoadOptions::new()
+            .frozen_file(&frozen)
+            .fail_on_unresolved(true);
+
+        let values = load_yaml_files_with(&[&frozen, &app], &options)?;
+        assert_eq!(
+            values["literal"],
+            Value::from("starts with {{ and never ends")
+        );
+        assert_eq!(values["greeting"], Value::from("Hello web"));
+
+        // Unfrozen, the same value is still rejected
+        let err = load_yaml_files_with(&[&frozen, &app], &LoadOptions::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unterminated"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unterminated_expression_fails_without_other_templates() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let values_file = temp_dir.path().join("values.yaml");
+        std::fs::write(&values_file, "literal: 'starts with {{ and never ends'")?;
+
+        let err = load_yaml_files(&vec![values_file.to_str().unwrap()]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unterminated"), "{:#}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frozen_value_overridden_later_is_resolved() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let frozen = temp_dir.path().join("frozen.yaml");
+        std::fs::write(&frozen, "a: '{{ b }}'\nc: '{{ b }}'")?;
+        let frozen = frozen.display().to_string();
+        let options = LoadOptions::new().frozen_file(&frozen);
+
+        let values = load_yaml_files_with(&[&frozen, "b=1", "c={{ b }}"], &options)?;
+        assert_eq!(values["a"], Value::from("{{ b }}"));
+        assert_eq!(values["c"], Value::from("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_owned() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("values.yaml");
+        std::fs::write(&path, "a: 1\nb: 2")?;
+
+        let files = vec![path.display().to_string(), "b=3".to_string()];
+        let values = load_yaml_files_owned(&files)?;
+        assert_eq!(values["a"], Value::from(1));
+        assert_eq!(values["b"], Value::from(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_values_files_skips_overrides_and_urls() -> anyhow::Result<()> {
+        let files = [
+            "values.yaml",
+            "?local.yaml",
+            "if:COMPOSER_TEST_UNSET_GUARD:debug.yaml",
+            "?https://example.com/values.yaml",
+            "https://example.com/values.yaml",
+            "a.b=c",
+        ];
+
+        let paths = local_values_files(&files, &LoadOptions::new())?;
+
+        assert_eq!(paths, vec!["values.yaml", "local.yaml", "debug.yaml"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_guarded_values_file_is_merged_when_truthy() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base = temp_dir.path().join("values.yaml");
+        std::fs::write(&base, "debug: false\nlevel: info")?;
+        let debug = temp_dir.path().join("values.debug.yaml");
+        std::fs::write(&debug, "debug: true")?;
+        let guarded = format!("if:COMPOSER_TEST_GUARD_TRUTHY:{}", debug.display());
+
+        for value in ["1", "true", "yes", "anything"] {
+            std::env::set_var("COMPOSER_TEST_GUARD_TRUTHY", value);
+            let values = load_yaml_files(&vec![base.to_str().unwrap(), guarded.as_str()])?;
+            assert_eq!(values["debug"], Value::Bool(true), "guard value {}", value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_guarded_values_file_is_skipped_when_falsey() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base = temp_dir.path().join("values.yaml");
+        std::fs::write(&base, "debug: false")?;
+        let debug = temp_dir.path().join("values.debug.yaml");
+        std::fs::write(&debug, "debug: true")?;
+        let guarded = format!("if:COMPOSER_TEST_GUARD_FALSEY:{}", debug.display());
+
+        std::env::remove_var("COMPOSER_TEST_GUARD_FALSEY");
+        let values = load_yaml_files(&vec![base.to_str().unwrap(), guarded.as_str()])?;
+        assert_eq!(values["debug"], Value::Bool(false));
+
+        for value in ["", "0", "false", "No", "OFF"] {
+            std::env::set_var("COMPOSER_TEST_GUARD_FALSEY", value);
+            let values = load_yaml_files(&vec![base.to_str().unwrap(), guarded.as_str()])?;
+            assert_eq!(
+                values["debug"],
+                Value::Bool(false),
+                "guard value {:?}",
+                value
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_guarded_values_file_missing_errors_only_when_truthy() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let missing = temp_dir.path().join("values.debug.yaml");
+        let guarded = format!("if:COMPOSER_TEST_GUARD_MISSING:{}", missing.display());
+
+        std::env::remove_var("COMPOSER_TEST_GUARD_MISSING");
+        assert!(load_yaml_files(&vec![guarded.as_str()]).is_ok());
+
+        std::env::set_var("COMPOSER_TEST_GUARD_MISSING", "1");
+        let err = load_yaml_files(&vec![guarded.as_str()]).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(missing.to_str().unwrap()),
+            "{:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_guarded_values_file_syntax_errors() {
+        for arg in ["if:values.yaml", "if::values.yaml", "if:DEBUG:"] {
+            let err = guarded_values_file(arg).unwrap_err().to_string();
+            assert!(err.contains("Expected if:VARIABLE:path"), "{}", err);
+        }
+        assert!(guarded_values_file("values.yaml").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_is_remote_values_file() {
+        assert!(is_remote_values_file("http://example.com/values.yaml"));
+        assert!(is_remote_values_file("HTTPS://example.com/values"));
+        assert!(!is_remote_values_file("values.yaml"));
+        assert!(!is_remote_values_file("url=https://example.com"));
+    }
+
+    #[test]
+    fn test_remote_format_prefers_content_type() {
+        assert_eq!(
+            remote_format("https://host/values.yaml", Some("application/json")),
+            Format::Json
+        );
+        assert_eq!(
+            remote_format("https://host/values.json", Some("application/yaml")),
+            Format::Yaml
+        );
+        assert_eq!(
+            remote_format("https://host/values.json?ref=main", Some("text/plain")),
+            Format::Json
+        );
+        assert_eq!(remote_format("https://host/defaults", None), Format::Yaml);
+        assert_eq!(remote_format("https://host.json", None), Format::Yaml);
+    }
+
+    #[cfg(feature = "remote")]
+    #[test]
+    fn test_remote_values_file_is_merged_in_position() -> anyhow::Result<()> {
+        use crate::utils::remote_values::test_server::serve_once;
+
+        let temp_dir = tempfile::tempdir()?;
+        let base = temp_dir.path().join("base.yaml");
+        std::fs::write(&base, "a: 1\nb: 1\nc: 1\n")?;
+        let local = temp_dir.path().join("local.yaml");
+        std::fs::write(&local, "c: 3\n")?;
+        let url = format!(
+            "{}/shared/defaults.json",
+            serve_once("200 OK", "application/octet-stream", "{\"b\": 2, \"c\": 2}")
+        );
+
+        let values = load_yaml_files(&vec![
+            base.to_str().unwrap(),
+            url.as_str(),
+            local.to_str().unwrap(),
+        ])?;
+        assert_eq!(values, serde_yaml::from_str::<Value>("a: 1\nb: 2\nc: 3")?);
+        Ok(())
+    }
+
+    #[cfg(feature = "remote")]
+    #[test]
+    fn test_remote_values_file_error_names_url() {
+        use crate::utils::remote_values::test_server::serve_once;
+
+        let url = format!(
+            "{}/values.yaml",
+            serve_once("500 Internal Server Error", "text/plain", "")
+        );
+        let err = load_yaml_files(&vec![url.as_str()])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Failed to fetch values file '{}': server responded with 500 Internal Server Error",
+                url
+            )
+        );
+    }
+
+    #[test]
+    fn test_optional_values_file_present_is_merged() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let local_file = temp_dir.path().join("values.local.yaml");
+        std::fs::write(&local_file, "foo: local")?;
+        let optional = format!("?{}", local_file.to_str().unwrap());
+
+        let output = load_yaml_files(&vec!["foo=base", optional.as_str()])?;
+
+        assert_eq!(Value::from("local"), output["foo"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_values_file_absent_is_skipped() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let missing = temp_dir.path().join("values.local.yaml");
+        let optional = format!("?{}", missing.to_str().unwrap());
+
+        let output = load_yaml_files(&vec!["foo=base", optional.as_str()])?;
+        assert_eq!(Value::from("base"), output["foo"]);
+
+        // The same file without the prefix is still required
+        assert!(load_yaml_files(&vec![missing.to_str().unwrap()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_values_file_explains_unreadable_paths() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let cause = |path: &Path| {
+            let err = read_values_file(path.to_str().unwrap()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Failed to read values file '{}'", path.display())
+            );
+            err.root_cause().to_string()
+        };
+
+        let dir = temp_dir.path().join("values.yaml");
+        std::fs::create_dir(&dir)?;
+        assert_eq!(cause(&dir), "the path is a directory, expected a file");
+
+        let missing = temp_dir.path().join("missing.yaml");
+        assert_eq!(cause(&missing), "file not found");
+
+        #[cfg(unix)]
+        {
+            let broken = temp_dir.path().join("broken.yaml");
+            std::os::unix::fs::symlink(&missing, &broken)?;
+            assert_eq!(
+                cause(&broken),
+                "the path is a symlink to a file that does not exist"
+            );
+
+            let looped = temp_dir.path().join("loop.yaml");
+            std::os::unix::fs::symlink(&looped, &looped)?;
+            assert!(
+                cause(&looped).starts_with("the path is a symlink that cannot be followed"),
+                "{}",
+                cause(&looped)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_values_file_unreadable_errors() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+
+        // A directory exists but cannot be read as a values file
+        let optional_dir = format!("?{}", temp_dir.path().to_str().unwrap());
+        assert!(load_yaml_files(&vec![optional_dir.as_str()]).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = temp_dir.path().join("locked.yaml");
+            std::fs::write(&locked, "foo: bar")?;
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+            // Permissions are not enforced for root, so only check when they apply
+            if std::fs::read(&locked).is_err() {
+                let optional = format!("?{}", locked.to_str().unwrap());
+                let err = load_yaml_files(&vec![optional.as_str()]).unwrap_err();
+                assert!(
+                    format!("{:#}", err).contains("Failed to read values file"),
+                    "unexpected error: {:#}",
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cross_file_references_flags_targets_set_elsewhere() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base_file = temp_dir.path().join("base.yaml");
+        let prod_file = temp_dir.path().join("prod.yaml");
+        std::fs::write(
+            &base_file,
+            "config:\n  greeting: hello\n  name: base\nmessage: '{{ config.greeting }}'\n\
+             local: '{{ config.name }}'\nsummary: '{{ config | tojson }}'\n",
+        )?;
+        std::fs::write(&prod_file, "config:\n  greeting: bonjour\n")?;
+        let base = base_file.to_str().unwrap();
+        let prod = prod_file.to_str().unwrap();
+
+        let found = cross_file_references(&[base, prod, "shout={{ message | upper }}"])?;
+
+        let expected = vec![
+            CrossFileReference {
+                path: "message".to_string(),
+                target: "config.greeting".to_string(),
+                source: base.to_string(),
+                target_source: prod.to_string(),
+            },
+            CrossFileReference {
+                path: "shout".to_string(),
+                target: "message".to_string(),
+                source: "--set shout={{ message | upper }}".to_string(),
+                target_source: base.to_string(),
+            },
+            CrossFileReference {
+                path: "summary".to_string(),
+                target: "config".to_string(),
+                source: base.to_string(),
+                target_source: prod.to_string(),
+            },
+        ];
+        assert_eq!(expected, found);
+        assert_eq!(
+            found[0].to_string(),
+            format!(
+                "'message' (set in {}) references 'config.greeting', which was last set in {}",
+                base, prod
+            )
+        );
+
+        assert!(cross_file_references(&[base])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_provenance_records_last_source_per_leaf() -> anyhow::Result<()> {
         let temp_dir = tempfile::tempdir()?;
-        let temp_file = temp_dir.path().join("broken.yaml");
-        std::fs::write(&temp_file, "key: [oops\n")?;
+        let base_file = temp_dir.path().join("base.yaml");
+        let env_file = temp_dir.path().join("prod.yaml");
+        std::fs::write(
+            &base_file,
+            "image:\n  name: nginx\n  tag: latest\nports: [80]\nreplicas: 1\n",
+        )?;
+        std::fs::write(
+            &env_file,
+            "image:\n  tag: '1.25'\nports: [443]\nreplicas: 1\n",
+        )?;
+        let base = base_file.to_str().unwrap();
+        let env = env_file.to_str().unwrap();
+        let optional_env = format!("?{}", env);
 
-        let files = vec![temp_file.to_str().expect("utf-8 path")];
-        let err = load_yaml_files(&files).unwrap_err();
-        let message = format!("{:#}", err);
-        assert!(
-            message.contains("Invalid YAML in values file"),
-            "Full chain should keep the descriptive parse error: {}",
-            message
-        );
+        let (values, provenance) =
+            load_yaml_files_with_provenance(&[base, optional_env.as_str(), "image.name=httpd"])?;
+
+        assert_eq!(Value::from("httpd"), values["image"]["name"]);
+        assert_eq!(provenance["image.name"], "--set image.name=httpd");
+        assert_eq!(provenance["image.tag"], env);
+        assert_eq!(provenance["ports[0]"], base);
+        assert_eq!(provenance["ports[1]"], env);
+        // Setting the same value again still counts as the later file setting it
+        assert_eq!(provenance["replicas"], env);
+        assert_eq!(5, provenance.len());
         Ok(())
     }
 
     #[test]
-    fn test_duplicate_top_level_key_error_names_key_file_and_lines() -> anyhow::Result<()> {
-        // Exact repro from issue #33
-        let temp_dir = tempfile::tempdir()?;
-        let temp_file = temp_dir.path().join("dupes.yaml");
-        std::fs::write(&temp_file, "hello: world\nto: you\nhello: world\n")?;
+    fn test_provenance_drops_replaced_leaves() -> anyhow::Result<()> {
+        let (values, provenance) =
+            load_yaml_files_with_provenance(&["db.host=localhost", "db.port=5432", "db:=sqlite"])?;
 
-        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
-        let message = err.to_string();
-        assert!(
-            message.contains("Duplicate key \"hello\""),
-            "Should name the duplicated key: {}",
-            message
-        );
-        assert!(
-            message.contains("dupes.yaml"),
-            "Should name the offending file: {}",
-            message
-        );
-        assert!(
-            message.contains("lines: 1, 3"),
-            "Should point at both definitions: {}",
-            message
-        );
+        assert_eq!(Value::from("sqlite"), values["db"]);
+        assert_eq!(1, provenance.len());
+        assert_eq!(provenance["db"], "--set db:=sqlite");
         Ok(())
     }
 
     #[test]
-    fn test_duplicate_nested_key_error_names_key() -> anyhow::Result<()> {
-        let temp_dir = tempfile::tempdir()?;
-        let temp_file = temp_dir.path().join("nested_dupes.yaml");
-        std::fs::write(&temp_file, "outer:\n  inner: 1\n  inner: 2\n")?;
+    fn test_conflicting_overrides_error_when_configured() {
+        let options = LoadOptions {
+            override_conflicts: OverrideConflict::Error,
+            ..Default::default()
+        };
 
-        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
-        let message = err.to_string();
-        assert!(
-            message.contains("Duplicate key \"inner\""),
-            "Should name the duplicated key: {}",
-            message
-        );
-        assert!(
-            message.contains("lines: 2, 3"),
-            "Should point at both definitions: {}",
-            message
+        let err =
+            load_yaml_files_with(&["foo.bar=a", "other=1", "/foo/bar=b"], &options).unwrap_err();
+        assert_eq!(
+            "Conflicting overrides for 'foo.bar': 'foo.bar=a' and '/foo/bar=b'. Pass only one override per path.",
+            err.to_string()
         );
+
+        // Repeating the same value, or setting different paths, is fine
+        assert!(load_yaml_files_with(&["foo=a", "foo:=a", "bar.baz=b"], &options).is_ok());
+    }
+
+    #[test]
+    fn test_conflicting_overrides_allowed_by_default() -> anyhow::Result<()> {
+        let output = load_yaml_files(&vec!["foo=a", "foo=b"])?;
+        assert_eq!(Value::from("b"), output["foo"]);
+
+        let options = LoadOptions {
+            override_conflicts: OverrideConflict::Warn,
+            ..Default::default()
+        };
+        let output = load_yaml_files_with(&["foo=a", "foo=b"], &options)?;
+        assert_eq!(Value::from("b"), output["foo"]);
         Ok(())
     }
 
+    fn env_values_dir() -> anyhow::Result<String> {
+        let dir = RelativePath::new("resources/test/env_values").to_logical_path(current_dir()?);
+        Ok(dir.to_string_lossy().into_owned())
+    }
+
     #[test]
-    fn test_duplicate_key_error_propagates_through_load_yaml_files() -> anyhow::Result<()> {
-        let temp_dir = tempfile::tempdir()?;
-        let temp_file = temp_dir.path().join("dupes.yaml");
-        std::fs::write(&temp_file, "hello: world\nhello: again\n")?;
+    fn test_load_for_env_merges_matching_env_file() -> anyhow::Result<()> {
+        let dir = env_values_dir()?;
 
-        let files = vec![temp_file.to_str().expect("utf-8 path")];
-        let err = load_yaml_files(&files).unwrap_err();
-        assert!(
-            format!("{:#}", err).contains("Duplicate key \"hello\""),
-            "Full chain should keep the duplicate key error: {:#}",
-            err
-        );
+        let dev = load_yaml_files_for_env(&dir, "dev")?;
+        assert_eq!(Value::Bool(true), dev["app"]["debug"]);
+        assert_eq!(Value::from(1), dev["app"]["replicas"]);
+        assert_eq!(Value::from("shop:shop-latest"), dev["image"]);
+
+        let prod = load_yaml_files_for_env(&dir, "prod")?;
+        assert_eq!(Value::Bool(false), prod["app"]["debug"]);
+        assert_eq!(Value::from(3), prod["app"]["replicas"]);
+        assert_eq!(Value::from("shop:1.4.2"), prod["image"]);
         Ok(())
     }
 
     #[test]
-    fn test_non_duplicate_parse_errors_keep_generic_message() -> anyhow::Result<()> {
+    fn test_load_for_env_without_env_file_uses_base() -> anyhow::Result<()> {
+        let dir = env_values_dir()?;
+        let staging = load_yaml_files_for_env(&dir, "staging")?;
+        let base = load_yaml_files(&vec![Path::new(&dir).join("values.yaml").to_str().unwrap()])?;
+        assert_eq!(base, staging);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_for_env_requires_base_file() -> anyhow::Result<()> {
         let temp_dir = tempfile::tempdir()?;
-        let temp_file = temp_dir.path().join("broken.yaml");
-        std::fs::write(&temp_file, "key: [oops\n")?;
+        std::fs::write(temp_dir.path().join("values.dev.yaml"), "a: 1")?;
+        // A base file in a subdirectory does not count
+        std::fs::create_dir(temp_dir.path().join("nested"))?;
+        std::fs::write(temp_dir.path().join("nested/values.yaml"), "a: 0")?;
 
-        let err = read_yaml_file(temp_file.to_str().expect("utf-8 path")).unwrap_err();
-        let message = err.to_string();
-        assert!(
-            message.contains("Invalid YAML in values file"),
-            "Non-duplicate errors should use the generic parse message: {}",
-            message
-        );
+        let err = load_yaml_files_for_env(temp_dir.path().to_str().unwrap(), "dev").unwrap_err();
         assert!(
-            !message.contains("Duplicate key"),
-            "Should not misclassify as a duplicate key error: {}",
-            message
+            err.to_string()
+                .contains("No base values file 'values.yaml'"),
+            "{}",
+            err
         );
         Ok(())
     }
 
     #[test]
-    fn test_lines_defining_key() {
-        let contents = "hello: 1\n\"hello\": 2\n  'hello': 3\nhelloworld: 4\nother: 5\n";
-        assert_eq!(lines_defining_key(contents, "hello"), vec![1, 2, 3]);
-        assert_eq!(lines_defining_key(contents, "missing"), Vec::<usize>::new());
+    fn test_detect_format() {
+        assert_eq!(Some(Format::Yaml), detect_format("values.yaml"));
+        assert_eq!(Some(Format::Yaml), detect_format("dir/values.YML"));
+        assert_eq!(Some(Format::Json), detect_format("values.json"));
+        assert_eq!(Some(Format::Yaml), detect_format("/dev/stdin"));
+        assert_eq!(None, detect_format("notes.txt"));
     }
 
     #[test]
-    fn test_first_tab_indented_line() {
-        assert_eq!(first_tab_indented_line("a: 1\nb: 2\n"), None);
-        assert_eq!(first_tab_indented_line("a:\n\tb: 2\n"), Some(2));
-        assert_eq!(first_tab_indented_line("a:\n  \tb: 2\n"), Some(2));
-        assert_eq!(first_tab_indented_line("a: \"value\twith tab\"\n"), None);
+    fn test_unsupported_extension_errors_before_reading() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("values.txt");
+        std::fs::write(&temp_file, "hello: world\n")?;
+
+        let files = vec![temp_file.to_str().unwrap()];
+        let err = load_yaml_files(&files).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unsupported value file extension '.txt'"),
+            "unexpected error: {}",
+            err
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_merge_yaml_lists() -> anyhow::Result<()> {
-        // Your inline YAML strings for the first and second YAML contents
-        let yaml1_str = r#"
-        items:
-          - apple
-          - banana
-        world: "hello""#;
-
-        let yaml2_str = r#"
-        items:
-          - orange
-          - cherry
-        world: "goodbye""#;
-
-        // Deserialize the inline YAML strings to serde_yaml::Value objects
-        let mut yaml1: Value = from_str(yaml1_str)?;
-        let yaml2: Value = from_str(yaml2_str)?;
-
-        if let (Value::Mapping(ref mut map1), Value::Mapping(map2)) = (&mut yaml1, &yaml2) {
-            merge_maps(map1, map2.clone());
-        }
-
-        // Now, let's define the expected merged YAML result
-        let expected_str = r#"
-        items:
-          - apple
-          - banana
-          - orange
-          - cherry
-        world: "goodbye""#;
-        let expected: Value = from_str(expected_str)?;
-
-        // Test that the merged YAML content matches the expected result
-        assert_eq!(expected, yaml1);
+    fn test_extensionless_file_is_read_as_yaml() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("values");
+        std::fs::write(&temp_file, "hello: world\n")?;
 
+        let output = load_yaml_files(&vec![temp_file.to_str().unwrap()])?;
+        assert_eq!(Value::String("world".to_string()), output["hello"]);
         Ok(())
     }
 
     #[test]
-    fn test_load_yaml_from_strings() -> anyhow::Result<()> {
-        // Inline YAML strings for the first and second YAML contents
-        trace!("Running test_copy_files_complex_manual_override_multiple.");
+    fn test_json_values_file_merges_with_yaml() -> anyhow::Result<()> {
         let current_dir = current_dir()?;
-        let values_path = RelativePath::new("resources/test/merge_lists/first.yaml")
-            .to_logical_path(&current_dir);
-        let override_path = RelativePath::new("resources/test/merge_lists/second.yaml")
+        let values_path = RelativePath::new("resources/test/test_values/values.yaml")
             .to_logical_path(&current_dir);
-        let files = vec![
-            values_path.to_str().unwrap(),
-            override_path.to_str().unwrap(),
-            "fruit.color=red",
-        ];
-
-        // Load and merge YAML contents from strings using `load_yaml_files` function
-        let merged_yaml = load_yaml_files(&files)?;
+        let temp_dir = tempfile::tempdir()?;
+        let json_file = temp_dir.path().join("override.json");
+        std::fs::write(
+            &json_file,
+            r#"{"world": "from json", "foo": {"extra": [1, 2]}}"#,
+        )?;
 
-        // Now, let's define the expected merged YAML result
-        let expected_str = r#"
-        items:
-          - apple
-          - banana
-          - orange
-          - cherry
-        world: "goodbye"
-        fruit:
-          color: "red"
-    "#;
-        let expected: Value = from_str(expected_str)?;
+        let output = load_yaml_files(&vec![
+            values_path.to_str().unwrap(),
+            json_file.to_str().unwrap(),
+        ])?;
+        assert_eq!(Value::String("from json".to_string()), output["world"]);
+        assert_eq!(Value::String("hi".to_string()), output["foo"]["bar"]);
+        assert_eq!(Value::from(2), output["foo"]["extra"][1]);
+        Ok(())
+    }
 
-        // Test that the merged YAML content matches the expected result
-        assert_eq!(expected, merged_yaml);
+    #[test]
+    fn test_invalid_json_error_names_file() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let json_file = temp_dir.path().join("broken.json");
+        std::fs::write(&json_file, "{\"unclosed\": ")?;
 
+        let err = read_values_file(json_file.to_str().unwrap()).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("Invalid JSON in values file") && message.contains("broken.json"),
+            "unexpected error: {}",
+            message
+        );
         Ok(())
     }
 
@@ -728,6 +4106,144 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fail_on_unresolved_option() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_file = temp_dir.path().join("leftover.yaml");
+        std::fs::write(&temp_file, "b: value\na: \"{{ '{{' }} b }}\"\n")?;
+        let files = vec![temp_file.to_str().unwrap()];
+
+        // Lenient by default, the rendered template is passed through
+        let output = load_yaml_files(&files)?;
+        assert_eq!(output["a"], Value::String("{{ b }}".to_string()));
+
+        let options = LoadOptions {
+            fail_on_unresolved: true,
+            ..Default::default()
+        };
+        let err = load_yaml_files_with(&files, &options).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("unresolved references") && message.contains("a: {{ b }}"),
+            "Error should list the unresolved value: {}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_as_with_coerces_rendered_strings() -> anyhow::Result<()> {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Server {
+            debug: bool,
+            port: u16,
+        }
+        let files = [
+            "env=dev",
+            "base_port=8000",
+            "debug={{ env == 'dev' }}",
+            "port={{ base_port + 80 }}",
+        ];
+
+        assert!(load_yaml_files_as::<Server>(&files).is_err());
+
+        let options = LoadOptions::new()
+            .coerce("debug", ScalarType::Bool)
+            .coerce("port", ScalarType::Int);
+        let server: Server = load_yaml_files_as_with(&files, &options)?;
+        assert_eq!(
+            Server {
+                debug: true,
+                port: 8080
+            },
+            server
+        );
+
+        let options = LoadOptions::new().coerce("env", ScalarType::Bool);
+        let err = load_yaml_files_with(&files, &options).unwrap_err();
+        assert!(
+            err.to_string().contains("env: dev is not a valid bool"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_as_example() -> anyhow::Result<()> {
+        #[derive(Deserialize)]
+        struct Database {
+            host: String,
+            port: u16,
+        }
+
+        let database: Database = load_yaml_files_as(&["host=localhost", "port=5432"])?;
+
+        assert_eq!(database.host, "localhost");
+        assert_eq!(database.port, 5432);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_as_deserializes_resolved_values() -> anyhow::Result<()> {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Image {
+            name: String,
+            tag: String,
+        }
+
+        // References are resolved before deserializing, and unknown keys are ignored
+        let image: Image =
+            load_yaml_files_as(&["name=nginx", "version:=1.25", "tag={{ version }}-alpine"])?;
+
+        assert_eq!(
+            Image {
+                name: "nginx".to_string(),
+                tag: "1.25-alpine".to_string()
+            },
+            image
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_yaml_files_as_error_names_files() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Ports {
+            port: u16,
+        }
+
+        let err = load_yaml_files_as::<Ports>(&["port=not-a-number"]).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("[port=not-a-number]") && message.contains("port"),
+            "unexpected error: {}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_forced_string_override_merges_and_resolves() -> anyhow::Result<()> {
+        let current_dir = current_dir()?;
+        let values_path = RelativePath::new("resources/test/test_values/values.yaml")
+            .to_logical_path(&current_dir);
+        let files = vec![
+            values_path.to_str().unwrap(),
+            "foo.bar:=2024",
+            "image:={{ foo.bar }}-alpine",
+        ];
+        let output = load_yaml_files(&files)?;
+
+        assert_eq!(Value::String("2024".to_string()), output["foo"]["bar"]);
+        assert_eq!(
+            Value::String("here".to_string()),
+            output["foo"]["nested"]["map"]
+        );
+        assert_eq!(Value::String("2024-alpine".to_string()), output["image"]);
+        Ok(())
+    }
+
     #[test]
     fn test_value_reference_across_files() -> anyhow::Result<()> {
         trace!("Running test_value_reference_across_files.");
@@ -1,6 +1,13 @@
 pub mod copy_file_utils;
+pub mod diff;
+pub mod digest;
 pub mod docker_compose;
+mod duplicate_keys;
+pub mod flatten;
+mod json_pointer;
 pub mod load_values;
+#[cfg(feature = "remote")]
+mod remote_values;
 pub mod self_updater;
 pub mod update_notifier;
 pub mod storage;
@@ -9,4 +16,7 @@ pub mod template;
 pub mod test_utils;
 pub mod value_resolver;
 pub(crate) mod walk;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod yaml_output;
 mod yaml_string_parser;
@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Context};
+use std::time::Duration;
+
+/// How long a remote values file may take to download before giving up.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The body of a values file fetched over HTTP(S).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RemoteValuesFile {
+    pub contents: String,
+    /// The `Content-Type` header, without parameters such as `charset`.
+    pub content_type: Option<String>,
+}
+
+/// Fetches a values file with a blocking GET. Network failures and non-2xx responses are
+/// returned as errors naming the URL.
+pub(crate) fn fetch_values_file(url: &str) -> anyhow::Result<RemoteValuesFile> {
+    trace!("Fetching values file: {}", url);
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(FETCH_TIMEOUT))
+        .build()
+        .into();
+    let mut response = agent
+        .get(url)
+        .header("User-Agent", "composer")
+        .call()
+        .with_context(|| format!("Failed to fetch values file '{}'", url))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Failed to fetch values file '{}': server responded with {}",
+            url,
+            status
+        ));
+    }
+
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty());
+    let contents = response
+        .body_mut()
+        .read_to_string()
+        .with_context(|| format!("Failed to read values file '{}'", url))?;
+    Ok(RemoteValuesFile {
+        contents,
+        content_type,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves a single canned HTTP response on a local port and returns the base URL.
+    pub(crate) fn serve_once(status: &str, content_type: &str, body: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Drain the request headers before answering
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}", address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::serve_once;
+    use super::*;
+
+    #[test]
+    fn test_fetch_values_file_returns_body_and_content_type() {
+        let url = serve_once("200 OK", "application/json; charset=utf-8", "{\"a\": 1}");
+        let fetched = fetch_values_file(&format!("{}/defaults", url)).unwrap();
+        assert_eq!(fetched.contents, "{\"a\": 1}");
+        assert_eq!(fetched.content_type.as_deref(), Some("application/json"));
+    }
+
+    #[test]
+    fn test_fetch_values_file_rejects_non_success_status() {
+        let url = format!(
+            "{}/missing.yaml",
+            serve_once("404 Not Found", "text/plain", "nope")
+        );
+        let err = fetch_values_file(&url).unwrap_err().to_string();
+        assert!(err.contains(&url), "{}", err);
+        assert!(err.contains("404"), "{}", err);
+    }
+
+    #[test]
+    fn test_fetch_values_file_reports_connection_errors() {
+        // Bind then drop a listener so nothing is serving on the port
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/values.yaml", port);
+        let err = fetch_values_file(&url).unwrap_err().to_string();
+        assert_eq!(err, format!("Failed to fetch values file '{}'", url));
+    }
+}
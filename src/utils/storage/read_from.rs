@@ -2,6 +2,8 @@ use crate::utils::copy_file_utils::get_composer_directory;
 use crate::utils::storage::models::PersistedApplication;
 use anyhow::anyhow;
 use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Read};
 use std::path::PathBuf;
@@ -50,10 +52,47 @@ pub fn if_application_exists(id: &str) -> bool {
     get_application_by_id(id).is_ok()
 }
 
+/// Compares the per-application directories in the composer directory with the records
+/// in `config.json`. Returns the ids of directories without a record, then the ids of
+/// records without a directory, each sorted. Files and hidden directories are ignored.
+/// Nothing is changed on disk.
+#[allow(dead_code)]
+pub fn find_orphaned_applications() -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let composer_directory = get_composer_directory()?;
+    let recorded: BTreeSet<String> = get_all_from_storage()?
+        .into_iter()
+        .map(|application| application.id)
+        .collect();
+
+    let mut directories = BTreeSet::new();
+    let entries = match fs::read_dir(&composer_directory) {
+        Ok(entries) => Some(entries),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Could not read directory '{:?}'", composer_directory))
+        }
+    };
+    for entry in entries.into_iter().flatten() {
+        let entry = entry
+            .with_context(|| format!("Could not read directory '{:?}'", composer_directory))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            directories.insert(name);
+        }
+    }
+
+    let without_records = directories.difference(&recorded).cloned().collect();
+    let without_directories = recorded.difference(&directories).cloned().collect();
+    Ok((without_records, without_directories))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::utils::storage::models::{ApplicationState, PersistedApplication};
-    use crate::utils::storage::read_from::{get_all_from_storage, get_application_by_id};
+    use crate::utils::storage::read_from::{
+        find_orphaned_applications, get_all_from_storage, get_application_by_id,
+    };
     use crate::utils::storage::write_to_storage::append_to_storage;
     use crate::utils::test_utils::{
         backup_composer_config, create_file_with_contents, move_file_if_exists,
@@ -194,4 +233,40 @@ mod tests {
         assert_eq!(expected_err, actual_err);
         Ok(())
     }
+
+    #[test]
+    #[serial]
+    fn test_find_orphaned_applications() -> anyhow::Result<()> {
+        use crate::utils::test_utils::ComposerHomeGuard;
+        let home = ComposerHomeGuard::new()?;
+        assert_eq!(find_orphaned_applications()?, (vec![], vec![]));
+
+        for id in ["installed", "record_missing_b", "record_missing_a"] {
+            std::fs::create_dir(home.path().join(id))?;
+        }
+        std::fs::create_dir(home.path().join(".cache"))?;
+        create_file_with_contents(&home.path().join("update-check.json"), "{}")?;
+        for id in ["installed", "directory_missing"] {
+            append_to_storage(&PersistedApplication {
+                id: id.to_string(),
+                version: "1.0.0".to_string(),
+                timestamp: 0,
+                state: ApplicationState::Running,
+                app_name: id.to_string(),
+                compose_path: id.to_string(),
+                value_files: vec![],
+            })?;
+        }
+
+        let (without_records, without_directories) = find_orphaned_applications()?;
+        assert_eq!(
+            without_records,
+            vec!["record_missing_a", "record_missing_b"]
+        );
+        assert_eq!(without_directories, vec!["directory_missing"]);
+        // Only reads
+        assert!(home.path().join("record_missing_a").exists());
+        assert_eq!(get_all_from_storage()?.len(), 2);
+        Ok(())
+    }
 }
@@ -6,15 +6,20 @@ use std::path::Path;
 
 /// A custom filter for required
 /// Hello {{ world_variable | required }}
-/// an error will be thrown in the example above is world_variable is empty
-fn required(value: String) -> Result<String, minijinja::Error> {
-    if value.is_empty() {
+/// an error will be thrown in the example above if world_variable is undefined, null or
+/// empty. `{{ db_host | required('db_host must be set') }}` replaces the message. Value
+/// references use the same filter, so both behave alike.
+pub(crate) fn required(
+    value: minijinja::Value,
+    message: Option<String>,
+) -> Result<minijinja::Value, minijinja::Error> {
+    if value.is_undefined() || value.is_none() || value.as_str() == Some("") {
         return Err(
             minijinja::Error::new(
-                ErrorKind::InvalidOperation, 
-                "Failed to render template. Value marked as 'required' must be present. Check your values file to ensure it exists.")
+                ErrorKind::InvalidOperation,
+                message.unwrap_or_else(|| "Failed to render template. Value marked as 'required' must be present. Check your values file to ensure it exists.".to_string()))
         )
-    } 
+    }
     Ok(value)
 }
 
@@ -65,14 +70,46 @@ pub fn render_template(path: &str, values_yaml: Value) -> anyhow::Result<String>
 
     // Render the template with the input data
     let rendered = template.render(&ctx).map_err(|e| {
-        anyhow::anyhow!(
+        let mut message = format!(
             "Failed to render template {}: due to an error in the template. Error: {}",
+            path, e
+        );
+        // Point at the construct that failed
+        if let Some(line_number) = e.line() {
+            if let Some(line) = template_string.lines().nth(line_number.saturating_sub(1)) {
+                message.push_str(&format!("\n  line {} | {}", line_number, line.trim_end()));
+            }
+        }
+        anyhow::anyhow!(message)
+    })?;
+
+    // Return the rendered string
+    Ok(rendered)
+}
+
+/// Renders a docker compose template the same way install and upgrade do, but only returns
+/// the result: nothing is written and docker is not called. Useful for previewing the final
+/// compose file before bringing an app up, much like `helm template`.
+///
+/// # Arguments
+///
+/// * `path` - The file path to the docker compose Jinja2 template.
+/// * `values_yaml` - The merged and resolved values to render it with.
+///
+/// # Returns
+///
+/// * `Ok(String)` containing the rendered compose file.
+/// * `Err(anyhow::Error)` naming the template if it fails to render or does not render to
+///   valid YAML.
+pub fn render_compose_dry_run(path: &str, values_yaml: Value) -> anyhow::Result<String> {
+    let rendered = render_template(path, values_yaml)?;
+    serde_yaml::from_str::<Value>(&rendered).map_err(|e| {
+        anyhow::anyhow!(
+            "Compose template {} did not render to valid YAML: {}",
             path,
             e
         )
     })?;
-
-    // Return the rendered string
     Ok(rendered)
 }
 
@@ -162,7 +199,7 @@ fn merge_yaml(base: Value, override_val: Value) -> anyhow::Result<Value> {
 
 #[cfg(test)]
 mod tests {
-    use super::render_template;
+    use super::{render_compose_dry_run, render_template};
     use relative_path::RelativePath;
     use serde_yaml::Value;
     use std::env::current_dir;
@@ -224,6 +261,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_render_template_required_rejects_null() -> anyhow::Result<()> {
+        let current_dir = current_dir()?;
+        let template = RelativePath::new("resources/test/templates/required.jinja2")
+            .to_logical_path(&current_dir);
+        // A null value is as missing as an empty one, the same as in value references.
+        let value_file: Value = serde_yaml::from_str("val: ~")?;
+        let result = render_template(template.to_str().unwrap(), value_file);
+        assert!(
+            result.is_err(),
+            "Template rendering should have failed for a null required value."
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_render_nested_template_with_default() -> anyhow::Result<()> {
@@ -263,6 +314,69 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_render_compose_dry_run_returns_rendered_yaml() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let template = dir.path().join("docker-compose.jinja2");
+        std::fs::write(
+            &template,
+            "services:\n  web:\n    image: nginx:{{ nginx.tag }}\n",
+        )?;
+        let values: Value = serde_yaml::from_str("nginx:\n  tag: '1.25'")?;
+
+        let rendered = render_compose_dry_run(template.to_str().unwrap(), values)?;
+
+        assert_eq!("services:\n  web:\n    image: nginx:1.25", rendered);
+        // Nothing is written next to the template
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_compose_dry_run_names_template_and_failing_line() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let template = dir.path().join("docker-compose.jinja2");
+        std::fs::write(
+            &template,
+            "services:\n  web:\n    image: {{ image | required }}\n",
+        )?;
+        let values: Value = serde_yaml::from_str("image: ''")?;
+
+        let err = render_compose_dry_run(template.to_str().unwrap(), values)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains(template.to_str().unwrap()), "{}", err);
+        assert!(
+            err.contains("line 3 |     image: {{ image | required }}"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_compose_dry_run_rejects_invalid_yaml() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let template = dir.path().join("docker-compose.jinja2");
+        std::fs::write(&template, "services:\n  web: {{ web }}\n")?;
+        let values: Value = serde_yaml::from_str("web: '[unclosed'")?;
+
+        let err = render_compose_dry_run(template.to_str().unwrap(), values)
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            err.starts_with(&format!(
+                "Compose template {} did not render to valid YAML",
+                template.display()
+            )),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
     fn get_current_directory(template: PathBuf) -> anyhow::Result<String> {
         Ok(template
             .parent()
@@ -5,10 +5,18 @@ use anyhow::anyhow;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
 use crate::utils::storage::read_from::if_application_exists;
 use crate::utils::storage::write_to_storage::delete_application_by_id;
 
+/// How many times cleanup retries removing an application directory, and the delay
+/// before the first retry (doubled after each attempt). Kept small so a genuinely
+/// stuck directory fails the test quickly.
+const CLEANUP_REMOVE_ATTEMPTS: u32 = 5;
+const CLEANUP_INITIAL_RETRY_DELAY: Duration = Duration::from_millis(50);
+
 /// Points COMPOSER_HOME at a fresh temporary directory so tests read and
 /// write an isolated storage location instead of ~/.composer. The override
 /// is removed and the directory deleted when the guard drops. Tests using
@@ -86,7 +94,7 @@ pub fn clean_up_test_folder(id: &str) -> anyhow::Result<()> {
         for compose_file in compose_files {
             compose_down_with(&RealCommandRunner, &compose_file, id);
         }
-        fs::remove_dir_all(composer_id_directory)?;
+        remove_dir_with_retry(&composer_id_directory)?;
     }
 
     // Remove the persisted application from config.json if it exists
@@ -95,3 +103,37 @@ pub fn clean_up_test_folder(id: &str) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// Docker can keep hold of files in a bind-mounted directory for a moment after
+/// `compose down` returns, so removal is retried with a backoff before giving up.
+fn remove_dir_with_retry(directory: &Path) -> anyhow::Result<()> {
+    let mut delay = CLEANUP_INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match fs::remove_dir_all(directory) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) if attempt >= CLEANUP_REMOVE_ATTEMPTS => {
+                return Err(anyhow!(
+                    "Could not remove test directory {:?} after {} attempts: {}",
+                    directory,
+                    attempt,
+                    e
+                ));
+            }
+            Err(e) => {
+                trace!(
+                    "Removing {:?} failed (attempt {}/{}): {}. Retrying in {:?}.",
+                    directory,
+                    attempt,
+                    CLEANUP_REMOVE_ATTEMPTS,
+                    e,
+                    delay
+                );
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use super::dependency_graph::{PathSegment, ValuePath};
+use super::set_value_at_path;
+use anyhow::Result;
+use serde_yaml::Value;
+
+/// The values being resolved, together with the MiniJinja form templates are rendered
+/// against.
+///
+/// Converting the whole tree for every render makes resolution O(references × tree
+/// size), so it is converted once when the context is created. Each write-back then only
+/// rebuilds the mappings and sequences along the written path, sharing everything else
+/// with the previous version.
+pub struct RenderContext {
+    values: Value,
+    prepared: minijinja::Value,
+}
+
+impl RenderContext {
+    pub fn new(values: Value) -> Self {
+        let prepared = minijinja::Value::from_serialize(&values);
+        Self { values, prepared }
+    }
+
+    /// The values as they currently are.
+    pub fn values(&self) -> &Value {
+        &self.values
+    }
+
+    /// The values converted for MiniJinja, always in step with `values`.
+    pub fn prepared(&self) -> &minijinja::Value {
+        &self.prepared
+    }
+
+    pub fn into_values(self) -> Value {
+        self.values
+    }
+
+    /// Sets the value at `path` in both forms, like `set_value_at_path`.
+    pub fn set(&mut self, path: &str, new_val: Value) -> Result<()> {
+        let segments = ValuePath::new(path).segments()?;
+        let prepared = minijinja::Value::from_serialize(&new_val);
+        set_value_at_path(&mut self.values, path, new_val)?;
+        self.prepared = with_item(&self.prepared, &segments, prepared);
+        Ok(())
+    }
+}
+
+/// Returns a copy of `container` with the item at `segments` replaced by `new_item`.
+/// Only the mappings and sequences along the way are copied, and only shallowly.
+fn with_item(
+    container: &minijinja::Value,
+    segments: &[PathSegment],
+    new_item: minijinja::Value,
+) -> minijinja::Value {
+    let Some((segment, rest)) = segments.split_first() else {
+        return new_item;
+    };
+    let items = container.try_iter().into_iter().flatten();
+    match segment {
+        PathSegment::Key(key) => {
+            let key = minijinja::Value::from(key.as_str());
+            let mut new_item = Some(new_item);
+            let mut entries: Vec<(minijinja::Value, minijinja::Value)> = items
+                .map(|item_key| {
+                    let item = container.get_item(&item_key).unwrap_or_default();
+                    match new_item.take_if(|_| item_key == key) {
+                        Some(new_item) => (item_key, with_item(&item, rest, new_item)),
+                        None => (item_key, item),
+                    }
+                })
+                .collect();
+            // `set_value_at_path` adds a missing last key, so the prepared form does too
+            if let Some(new_item) = new_item {
+                entries.push((key, new_item));
+            }
+            entries.into_iter().collect()
+        }
+        PathSegment::Index(index) => {
+            let mut new_item = Some(new_item);
+            items
+                .enumerate()
+                .map(
+                    |(position, item)| match new_item.take_if(|_| position == *index) {
+                        Some(new_item) => with_item(&item, rest, new_item),
+                        None => item,
+                    },
+                )
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml::from_str;
+
+    #[test]
+    fn test_set_keeps_prepared_in_step_with_values() -> anyhow::Result<()> {
+        let mut context = RenderContext::new(from_str(
+            "a: 1\nnested:\n  b: x\n  list: [p, {q: r}, s]\nother: {c: 2}",
+        )?);
+
+        context.set("nested.b", Value::from("y"))?;
+        context.set("nested.list[1].q", Value::from("t"))?;
+        context.set("nested.list[2]", Value::from("u"))?;
+        context.set("other.d", Value::from("v"))?;
+
+        let expected: Value =
+            from_str("a: 1\nnested:\n  b: y\n  list: [p, {q: t}, u]\nother: {c: 2, d: v}")?;
+        assert_eq!(context.values(), &expected);
+        assert_eq!(
+            context.prepared(),
+            &minijinja::Value::from_serialize(&expected)
+        );
+        assert!(context.set("missing.path", Value::from("z")).is_err());
+        Ok(())
+    }
+}
@@ -1,13 +1,24 @@
+use super::error::ResolveError;
+use crate::utils::json_pointer::{is_index_token, parse_json_pointer};
 use anyhow::{anyhow, Result};
-use petgraph::algo::toposort;
 use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::visit::EdgeRef;
-use std::collections::HashMap;
+use petgraph::visit::{Dfs, EdgeRef, Reversed};
+use petgraph::Direction;
+use std::collections::{BTreeSet, HashMap};
 
-/// Represents a path to a value in the YAML structure (e.g., "parent.child.grandchild")
+/// Represents a path to a value in the YAML structure (e.g., "parent.child.grandchild").
+/// Sequence elements are always written as `[n]` (e.g., "items[0].name"), both by the
+/// collector and the extractor, so equal locations compare equal as strings.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ValuePath(pub String);
 
+/// One step of a `ValuePath`: a mapping key or a sequence index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
 impl ValuePath {
     pub fn new(path: &str) -> Self {
         ValuePath(path.to_string())
@@ -16,9 +27,85 @@ impl ValuePath {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Converts an RFC 6901 JSON Pointer into the dotted form, so `/items/0/name` becomes
+    /// `items[0].name`. Tokens that look like array indices become `[n]`.
+    ///
+    /// Keys that are empty or contain `.`, `[` or `]` cannot be written in the dotted form
+    /// and are rejected, as is the empty pointer.
+    pub fn from_json_pointer(pointer: &str) -> Result<Self> {
+        let tokens = parse_json_pointer(pointer)?;
+        if tokens.is_empty() {
+            return Err(anyhow!(
+                "JSON Pointer '{}' refers to the whole document, not a value",
+                pointer
+            ));
+        }
+        let mut path = String::new();
+        for token in &tokens {
+            if is_index_token(token) {
+                path.push_str(&format!("[{}]", token));
+            } else if token.is_empty() || token.contains(['.', '[', ']']) {
+                return Err(anyhow!(
+                    "JSON Pointer '{}': key '{}' cannot be written as a value path",
+                    pointer,
+                    token
+                ));
+            } else {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(token);
+            }
+        }
+        Ok(ValuePath(path))
+    }
+
+    /// Splits the path into mapping keys and sequence indices, so `a.b[0]` becomes
+    /// `[Key("a"), Key("b"), Index(0)]` while `a.0` stays `[Key("a"), Key("0")]`.
+    pub fn segments(&self) -> Result<Vec<PathSegment>> {
+        let mut segments = Vec::new();
+        for (part_idx, part) in self.0.split('.').enumerate() {
+            let (key, mut indices) = match part.find('[') {
+                Some(pos) => (&part[..pos], &part[pos..]),
+                None => (part, ""),
+            };
+            if key.contains(']') {
+                return Err(anyhow!(
+                    "Invalid value path '{}': unbalanced brackets",
+                    self.0
+                ));
+            }
+            // Only the very first segment may start with an index (a top-level sequence)
+            if !key.is_empty() {
+                segments.push(PathSegment::Key(key.to_string()));
+            } else if part_idx > 0 || indices.is_empty() {
+                return Err(anyhow!("Invalid value path '{}': empty key", self.0));
+            }
+            while !indices.is_empty() {
+                let close = indices
+                    .find(']')
+                    .filter(|_| indices.starts_with('['))
+                    .ok_or_else(|| {
+                        anyhow!("Invalid value path '{}': unbalanced brackets", self.0)
+                    })?;
+                let index = indices[1..close].parse::<usize>().map_err(|_| {
+                    anyhow!(
+                        "Invalid value path '{}': '{}' is not a sequence index",
+                        self.0,
+                        &indices[1..close]
+                    )
+                })?;
+                segments.push(PathSegment::Index(index));
+                indices = &indices[close + 1..];
+            }
+        }
+        Ok(segments)
+    }
 }
 
 /// Dependency graph for value references using petgraph.
+#[derive(Clone)]
 pub struct DependencyGraph {
     graph: DiGraph<String, ()>,
     node_indices: HashMap<String, NodeIndex>,
@@ -59,29 +146,116 @@ impl DependencyGraph {
 
     /// Returns topological order for resolution, or error with cycle description.
     /// The returned order has dependencies first (values that don't depend on others).
+    /// Among values that could go next, the smallest path comes first, so the order only
+    /// depends on the paths and dependencies, not on the order they were added in.
     pub fn topological_sort(&self) -> Result<Vec<ValuePath>> {
-        match toposort(&self.graph, None) {
-            Ok(sorted) => {
-                let paths: Vec<ValuePath> = sorted
-                    .into_iter()
-                    .map(|idx| ValuePath::new(&self.graph[idx]))
-                    .collect();
-                Ok(paths)
+        let _span =
+            tracing::debug_span!("topological_sort", nodes = self.graph.node_count()).entered();
+        // Kahn's algorithm, always taking the smallest ready path
+        let mut in_degree: Vec<usize> = self
+            .graph
+            .node_indices()
+            .map(|idx| {
+                self.graph
+                    .neighbors_directed(idx, Direction::Incoming)
+                    .count()
+            })
+            .collect();
+        let mut ready: BTreeSet<(&str, NodeIndex)> = self
+            .graph
+            .node_indices()
+            .filter(|idx| in_degree[idx.index()] == 0)
+            .map(|idx| (self.graph[idx].as_str(), idx))
+            .collect();
+
+        let mut sorted = Vec::with_capacity(self.graph.node_count());
+        while let Some((path, idx)) = ready.pop_first() {
+            sorted.push(ValuePath::new(path));
+            for dependent in self.graph.neighbors_directed(idx, Direction::Outgoing) {
+                in_degree[dependent.index()] -= 1;
+                if in_degree[dependent.index()] == 0 {
+                    ready.insert((self.graph[dependent].as_str(), dependent));
+                }
+            }
+        }
+        if sorted.len() == self.graph.node_count() {
+            return Ok(sorted);
+        }
+
+        // Whatever is left is on a cycle or depends on one; report the first cycle found
+        let mut remaining: Vec<NodeIndex> = self
+            .graph
+            .node_indices()
+            .filter(|idx| in_degree[idx.index()] > 0)
+            .collect();
+        remaining.sort_by(|a, b| self.graph[*a].cmp(&self.graph[*b]));
+        let cycle = remaining
+            .iter()
+            .find_map(|&idx| self.find_cycle_path(idx))
+            .unwrap_or_else(|| vec![self.graph[remaining[0]].clone()]);
+        Err(ResolveError::Cycle { cycle }.into())
+    }
+
+    /// Returns every path `path` depends on, directly or transitively, excluding itself.
+    /// An unknown path has no dependencies.
+    pub fn transitive_dependencies(&self, path: &ValuePath) -> Vec<ValuePath> {
+        let Some(&start) = self.node_indices.get(path.as_str()) else {
+            return Vec::new();
+        };
+        // Edges point from a dependency to its dependent, so walk them backwards
+        let reversed = Reversed(&self.graph);
+        let mut dfs = Dfs::new(reversed, start);
+        let mut dependencies = Vec::new();
+        while let Some(idx) = dfs.next(reversed) {
+            if idx != start {
+                dependencies.push(ValuePath::new(&self.graph[idx]));
             }
-            Err(cycle) => {
-                // Extract cycle information for the error message
-                let cycle_node = &self.graph[cycle.node_id()];
-                let cycle_path = self.find_cycle_path(cycle.node_id());
-                Err(anyhow!(
-                    "Circular dependency detected in values. Cycle involves: {}",
-                    cycle_path.unwrap_or_else(|| cycle_node.clone())
-                ))
+        }
+        dependencies
+    }
+
+    /// Returns every path that depends on `path`, directly or transitively, excluding
+    /// itself. An unknown path has no dependents.
+    #[allow(dead_code)]
+    pub fn transitive_dependents(&self, path: &ValuePath) -> Vec<ValuePath> {
+        let Some(&start) = self.node_indices.get(path.as_str()) else {
+            return Vec::new();
+        };
+        let mut dfs = Dfs::new(&self.graph, start);
+        let mut dependents = Vec::new();
+        while let Some(idx) = dfs.next(&self.graph) {
+            if idx != start {
+                dependents.push(ValuePath::new(&self.graph[idx]));
             }
         }
+        dependents
+    }
+
+    /// Returns the paths `path` depends on directly, sorted. An unknown path has no
+    /// dependencies.
+    pub fn dependencies(&self, path: &ValuePath) -> Vec<ValuePath> {
+        let Some(&idx) = self.node_indices.get(path.as_str()) else {
+            return Vec::new();
+        };
+        let mut dependencies: Vec<&str> = self
+            .graph
+            .neighbors_directed(idx, Direction::Incoming)
+            .map(|dependency| self.graph[dependency].as_str())
+            .collect();
+        dependencies.sort();
+        dependencies.dedup();
+        dependencies.into_iter().map(ValuePath::new).collect()
+    }
+
+    /// Iterates over every path in the graph, in no particular order.
+    #[allow(dead_code)]
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.node_indices.keys().map(String::as_str)
     }
 
-    /// Finds a cycle path starting from the given node for error reporting
-    fn find_cycle_path(&self, start: NodeIndex) -> Option<String> {
+    /// Finds a cycle path starting from the given node for error reporting. The first path
+    /// in the cycle is repeated at the end.
+    fn find_cycle_path(&self, start: NodeIndex) -> Option<Vec<String>> {
         let mut visited = HashMap::new();
         let mut path = Vec::new();
         self.dfs_find_cycle(start, &mut visited, &mut path)
@@ -92,14 +266,15 @@ impl DependencyGraph {
         node: NodeIndex,
         visited: &mut HashMap<NodeIndex, bool>,
         path: &mut Vec<String>,
-    ) -> Option<String> {
+    ) -> Option<Vec<String>> {
         if let Some(&in_stack) = visited.get(&node) {
             if in_stack {
                 // Found cycle - find where it starts in path
                 let node_name = &self.graph[node];
                 if let Some(pos) = path.iter().position(|p| p == node_name) {
-                    let cycle: Vec<_> = path[pos..].to_vec();
-                    return Some(format!("{} -> {}", cycle.join(" -> "), node_name));
+                    let mut cycle: Vec<_> = path[pos..].to_vec();
+                    cycle.push(node_name.clone());
+                    return Some(cycle);
                 }
             }
             return None;
@@ -119,12 +294,16 @@ impl DependencyGraph {
         None
     }
 
-    /// Returns the number of nodes in the graph (test utility)
-    #[cfg(test)]
+    /// Returns the number of nodes in the graph
     pub fn node_count(&self) -> usize {
         self.graph.node_count()
     }
 
+    /// Returns the number of dependency edges in the graph
+    pub fn edge_count(&self) -> usize {
+        self.graph.edge_count()
+    }
+
     /// Checks if the graph is empty (test utility)
     #[cfg(test)]
     pub fn is_empty(&self) -> bool {
@@ -142,6 +321,32 @@ impl Default for DependencyGraph {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_from_json_pointer() {
+        let cases = [
+            ("/config/greeting", "config.greeting"),
+            ("/items/0/name", "items[0].name"),
+            ("/0/a", "[0].a"),
+            ("/a~1b/c~0d", "a/b.c~d"),
+            ("/versions/01", "versions.01"),
+        ];
+        for (pointer, expected) in cases {
+            assert_eq!(
+                ValuePath::from_json_pointer(pointer).unwrap(),
+                ValuePath::new(expected),
+                "pointer {}",
+                pointer
+            );
+        }
+        for pointer in ["", "no/slash", "/a.b", "/a/", "/a~2"] {
+            assert!(
+                ValuePath::from_json_pointer(pointer).is_err(),
+                "pointer '{}' should be rejected",
+                pointer
+            );
+        }
+    }
+
     #[test]
     fn test_topological_sort_simple() {
         let mut graph = DependencyGraph::new();
@@ -162,6 +367,21 @@ mod tests {
         assert!(b_pos < c_pos, "b should come before c");
     }
 
+    #[test]
+    fn test_dependencies_are_direct_and_sorted() {
+        let mut graph = DependencyGraph::new();
+        graph.add_dependency(&ValuePath::new("c"), &ValuePath::new("b"));
+        graph.add_dependency(&ValuePath::new("c"), &ValuePath::new("a"));
+        graph.add_dependency(&ValuePath::new("c"), &ValuePath::new("b"));
+        graph.add_dependency(&ValuePath::new("b"), &ValuePath::new("x"));
+
+        let dependencies = graph.dependencies(&ValuePath::new("c"));
+        let paths: Vec<_> = dependencies.iter().map(|p| p.as_str()).collect();
+
+        assert_eq!(paths, vec!["a", "b"]);
+        assert!(graph.dependencies(&ValuePath::new("missing")).is_empty());
+    }
+
     #[test]
     fn test_topological_sort_independent_nodes() {
         let mut graph = DependencyGraph::new();
@@ -173,6 +393,47 @@ mod tests {
         assert_eq!(order.len(), 3);
     }
 
+    #[test]
+    fn test_topological_sort_is_independent_of_insertion_order() {
+        let dependencies = [
+            ("web.url", "host"),
+            ("web.url", "port"),
+            ("api.url", "host"),
+            ("b", "a"),
+            ("z", "web.url"),
+        ];
+        let build = |edges: &[(&str, &str)]| {
+            let mut graph = DependencyGraph::new();
+            graph.add_node(&ValuePath::new("standalone"));
+            for (from, to) in edges {
+                graph.add_dependency(&ValuePath::new(from), &ValuePath::new(to));
+            }
+            graph
+                .topological_sort()
+                .unwrap()
+                .into_iter()
+                .map(|path| path.0)
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            "a",
+            "b",
+            "host",
+            "api.url",
+            "port",
+            "standalone",
+            "web.url",
+            "z",
+        ];
+        assert_eq!(build(&dependencies), expected);
+        let mut reversed = dependencies;
+        reversed.reverse();
+        for _ in 0..5 {
+            assert_eq!(build(&reversed), expected);
+        }
+    }
+
     #[test]
     fn test_topological_sort_diamond_dependency() {
         let mut graph = DependencyGraph::new();
@@ -278,6 +539,91 @@ mod tests {
         assert_eq!(graph.node_count(), 1);
     }
 
+    #[test]
+    fn test_segments_distinguish_index_from_numeric_key() {
+        assert_eq!(
+            ValuePath::new("a[0]").segments().unwrap(),
+            vec![PathSegment::Key("a".to_string()), PathSegment::Index(0)]
+        );
+        assert_eq!(
+            ValuePath::new("a.0").segments().unwrap(),
+            vec![
+                PathSegment::Key("a".to_string()),
+                PathSegment::Key("0".to_string())
+            ]
+        );
+        assert_ne!(ValuePath::new("a[0]"), ValuePath::new("a.0"));
+    }
+
+    #[test]
+    fn test_segments_nested_indices() {
+        assert_eq!(
+            ValuePath::new("a.b[1][2].c").segments().unwrap(),
+            vec![
+                PathSegment::Key("a".to_string()),
+                PathSegment::Key("b".to_string()),
+                PathSegment::Index(1),
+                PathSegment::Index(2),
+                PathSegment::Key("c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_segments_invalid_paths() {
+        for invalid in ["a..b", "a[x]", "a[0", "a]", "a]0[", ".a", "a.[0]"] {
+            assert!(
+                ValuePath::new(invalid).segments().is_err(),
+                "'{}' should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_transitive_dependencies() {
+        let mut graph = DependencyGraph::new();
+        // c depends on b, b depends on a, d is unrelated
+        graph.add_dependency(&ValuePath::new("c"), &ValuePath::new("b"));
+        graph.add_dependency(&ValuePath::new("b"), &ValuePath::new("a"));
+        graph.add_dependency(&ValuePath::new("d"), &ValuePath::new("e"));
+
+        let mut deps: Vec<_> = graph
+            .transitive_dependencies(&ValuePath::new("c"))
+            .into_iter()
+            .map(|p| p.0)
+            .collect();
+        deps.sort();
+        assert_eq!(deps, vec!["a", "b"]);
+        assert!(graph
+            .transitive_dependencies(&ValuePath::new("a"))
+            .is_empty());
+        assert!(graph
+            .transitive_dependencies(&ValuePath::new("unknown"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_transitive_dependents() {
+        let mut graph = DependencyGraph::new();
+        // c depends on b, b depends on a, d is unrelated
+        graph.add_dependency(&ValuePath::new("c"), &ValuePath::new("b"));
+        graph.add_dependency(&ValuePath::new("b"), &ValuePath::new("a"));
+        graph.add_dependency(&ValuePath::new("d"), &ValuePath::new("e"));
+
+        let mut dependents: Vec<_> = graph
+            .transitive_dependents(&ValuePath::new("a"))
+            .into_iter()
+            .map(|p| p.0)
+            .collect();
+        dependents.sort();
+        assert_eq!(dependents, vec!["b", "c"]);
+        assert!(graph.transitive_dependents(&ValuePath::new("c")).is_empty());
+        assert!(graph
+            .transitive_dependents(&ValuePath::new("unknown"))
+            .is_empty());
+    }
+
     #[test]
     fn test_nested_paths() {
         let mut graph = DependencyGraph::new();
@@ -0,0 +1,230 @@
+use serde_json::json;
+use std::fmt;
+
+/// Why value references could not be resolved. The resolver's functions return
+/// `anyhow::Error`, which can be downcast to this for structured handling, e.g.
+/// `err.downcast_ref::<ResolveError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// References form a cycle. `cycle` lists the value paths in order and repeats the
+    /// first one at the end, e.g. `["a", "b", "a"]`.
+    Cycle { cycle: Vec<String> },
+    /// References point at values that do not exist, as `(template path, reference)`.
+    UndefinedReferences { references: Vec<(String, String)> },
+    /// The template at `path` references an element past the end of a sequence, e.g.
+    /// `items[2]` when the merged `items` has length 2.
+    IndexOutOfRange {
+        path: String,
+        reference: String,
+        sequence: String,
+        length: usize,
+    },
+    /// The template at `path` failed to parse or render.
+    Render {
+        path: String,
+        template: String,
+        message: String,
+    },
+    /// Resolution failed for another reason, such as values nesting too deeply.
+    /// `message` describes the whole error chain.
+    Other { message: String },
+}
+
+impl ResolveError {
+    /// The value path the error is about; for several undefined references, the first.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            ResolveError::Cycle { cycle } => cycle.first().map(String::as_str),
+            ResolveError::UndefinedReferences { references } => {
+                references.first().map(|(path, _)| path.as_str())
+            }
+            ResolveError::IndexOutOfRange { path, .. } | ResolveError::Render { path, .. } => {
+                Some(path)
+            }
+            ResolveError::Other { .. } => None,
+        }
+    }
+
+    /// A stable JSON description for tools that parse composer's output. Every object has
+    /// `kind`, `path` and `message`, plus fields specific to the kind:
+    ///
+    /// * `cycle`: `cycle`, the array of value paths.
+    /// * `undefined_references`: `references`, an array of `{path, reference}` objects.
+    /// * `index_out_of_range`: `reference`, plus the `sequence` path and its `length`.
+    /// * `render`: `template`, the template that failed.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut object = json!({
+            "kind": self.kind(),
+            "path": self.path(),
+            "message": self.to_string(),
+        });
+        let fields = match self {
+            ResolveError::Cycle { cycle } => json!({ "cycle": cycle }),
+            ResolveError::UndefinedReferences { references } => json!({
+                "references": references
+                    .iter()
+                    .map(|(path, reference)| json!({ "path": path, "reference": reference }))
+                    .collect::<Vec<_>>(),
+            }),
+            ResolveError::IndexOutOfRange {
+                reference,
+                sequence,
+                length,
+                ..
+            } => json!({ "reference": reference, "sequence": sequence, "length": length }),
+            ResolveError::Render { template, .. } => json!({ "template": template }),
+            ResolveError::Other { .. } => json!({}),
+        };
+        if let (Some(object), serde_json::Value::Object(fields)) = (object.as_object_mut(), fields)
+        {
+            object.extend(fields);
+        }
+        object
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            ResolveError::Cycle { .. } => "cycle",
+            ResolveError::UndefinedReferences { .. } => "undefined_references",
+            ResolveError::IndexOutOfRange { .. } => "index_out_of_range",
+            ResolveError::Render { .. } => "render",
+            ResolveError::Other { .. } => "other",
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Cycle { cycle } => write!(
+                f,
+                "Circular dependency detected in values. Cycle involves: {}",
+                cycle.join(" -> ")
+            ),
+            ResolveError::UndefinedReferences { references } => {
+                write!(
+                    f,
+                    "{} value reference(s) point at values that do not exist:",
+                    references.len()
+                )?;
+                for (path, reference) in references {
+                    write!(f, "\n  {}: {}", path, reference)?;
+                }
+                Ok(())
+            }
+            ResolveError::IndexOutOfRange {
+                path,
+                reference,
+                sequence,
+                length,
+            } => write!(
+                f,
+                "Value '{}' references {} but {} has length {}",
+                path, reference, sequence, length
+            ),
+            // Renderer errors already quote the template and where it failed
+            ResolveError::Render { message, .. } | ResolveError::Other { message } => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_to_json() {
+        let err = ResolveError::Cycle {
+            cycle: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+        };
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "kind": "cycle",
+                "path": "a",
+                "message": "Circular dependency detected in values. Cycle involves: a -> b -> a",
+                "cycle": ["a", "b", "a"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_undefined_references_to_json() {
+        let err = ResolveError::UndefinedReferences {
+            references: vec![
+                ("url".to_string(), "hots".to_string()),
+                ("url".to_string(), "prot".to_string()),
+            ],
+        };
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "kind": "undefined_references",
+                "path": "url",
+                "message": "2 value reference(s) point at values that do not exist:\n  url: hots\n  url: prot",
+                "references": [
+                    { "path": "url", "reference": "hots" },
+                    { "path": "url", "reference": "prot" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_index_out_of_range_to_json() {
+        let err = ResolveError::IndexOutOfRange {
+            path: "first_extra".to_string(),
+            reference: "items[2].name".to_string(),
+            sequence: "items".to_string(),
+            length: 2,
+        };
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "kind": "index_out_of_range",
+                "path": "first_extra",
+                "message": "Value 'first_extra' references items[2].name but items has length 2",
+                "reference": "items[2].name",
+                "sequence": "items",
+                "length": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_render_to_json() {
+        let err = ResolveError::Render {
+            path: "config.name".to_string(),
+            template: "{{ name | nope }}".to_string(),
+            message: "unknown filter".to_string(),
+        };
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "kind": "render",
+                "path": "config.name",
+                "message": "unknown filter",
+                "template": "{{ name | nope }}",
+            })
+        );
+    }
+
+    #[test]
+    fn test_other_to_json() {
+        let err = ResolveError::Other {
+            message: "Values are nested too deeply".to_string(),
+        };
+        assert_eq!(
+            err.to_json(),
+            json!({
+                "kind": "other",
+                "path": null,
+                "message": "Values are nested too deeply",
+            })
+        );
+    }
+}
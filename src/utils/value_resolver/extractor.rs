@@ -1,27 +1,325 @@
+use super::dependency_graph::{PathSegment, ValuePath};
 use super::traits::ReferenceExtractor;
+use crate::utils::json_pointer::{is_index_token, parse_json_pointer};
+use anyhow::{anyhow, Result};
+use minijinja::Environment;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
 /// Regex to match Jinja2 variable expressions and extract the variable name.
-/// Matches: {{ variable }}, {{ var.nested }}, {{ var | filter }}, etc.
-/// Captures only the variable name (group 1), ignoring filters.
+/// Matches: {{ variable }}, {{ var.nested }}, {{ list[0].name }}, {{ var['my-key'] }},
+/// {{ var | filter }}, etc. Captures only the variable path (group 1), ignoring filters.
+/// Sequence indices are captured in the same `[n]` form the resolver uses for collected
+/// value paths; quoted subscripts are turned into dotted keys by `subscripts_to_dotted`.
 static TEMPLATE_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(
-        r"\{\{\s*([a-zA-Z_][a-zA-Z0-9_]*(?:\.[a-zA-Z_][a-zA-Z0-9_]*)*)(?:\s*\|[^}]*)?\s*\}\}",
+        r#"\{\{\s*([a-zA-Z_][a-zA-Z0-9_]*(?:\.[a-zA-Z_][a-zA-Z0-9_]*|\[\d+\]|\[\s*'[^'.\[\]]+'\s*\]|\[\s*"[^".\[\]]+"\s*\])*)(?:\s*\|[^}]*)?\s*\}\}"#,
     )
     .expect("Invalid regex pattern")
 });
 
-/// Regex to check if string contains any template syntax
+/// Regex to match a quoted subscript such as `['my-key']` or `["my-key"]`, capturing the key.
+static SUBSCRIPT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"\[\s*(?:'([^']*)'|"([^"]*)")\s*\]"#).expect("Invalid regex pattern")
+});
+
+/// Writes quoted subscripts as dotted keys, so `services['my-service'].port` becomes
+/// `services.my-service.port`, the form the resolver collects value paths in.
+fn subscripts_to_dotted(reference: &str) -> Cow<'_, str> {
+    SUBSCRIPT_REGEX.replace_all(reference, |cap: &regex::Captures| {
+        let key = cap
+            .get(1)
+            .or_else(|| cap.get(2))
+            .map_or("", |key| key.as_str());
+        format!(".{}", key)
+    })
+}
+
+/// Rules for which keys a dotted reference may name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractorConfig {
+    /// Characters allowed in keys besides ASCII letters, digits and `_`, such as `-` for
+    /// the `my-service` in `{{ services.my-service.port }}`. MiniJinja would read that as
+    /// a subtraction, so references using these characters are rewritten to subscripts
+    /// (`services['my-service'].port`) before rendering. This only applies to plain
+    /// `{{ path | filter }}` expressions, and not to the first key, which MiniJinja can
+    /// only look up by name. Quoted subscripts work without any configuration. Defaults
+    /// to none, so `{{ a-b }}` stays a subtraction.
+    pub extra_identifier_chars: Vec<char>,
+}
+
+impl ExtractorConfig {
+    /// Rewrites dotted keys that use `extra_identifier_chars` into quoted subscripts, e.g.
+    /// `{{ services.my-service.port }}` into `{{ services['my-service'].port }}`.
+    pub fn rewrite_identifiers<'a>(&self, template_str: &'a str) -> Cow<'a, str> {
+        if self.extra_identifier_chars.is_empty()
+            || !template_str.contains(self.extra_identifier_chars.as_slice())
+        {
+            return Cow::Borrowed(template_str);
+        }
+        let regex = extended_reference_regex(&self.extra_identifier_chars);
+        let rewritten = regex.replace_all(template_str, |cap: &regex::Captures| {
+            let mut parts = cap[2].split('.');
+            let mut path = parts.next().unwrap_or_default().to_string();
+            for part in parts {
+                let (key, indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+                if IDENTIFIER_REGEX.is_match(key) {
+                    path.push_str(&format!(".{}", key));
+                } else {
+                    path.push_str(&format!("['{}']", key));
+                }
+                path.push_str(indices);
+            }
+            format!("{}{}{}", &cap[1], path, &cap[3])
+        });
+        Cow::Owned(rewritten.into_owned())
+    }
+}
+
+/// Regex to match a key MiniJinja can look up with dot syntax.
+static IDENTIFIER_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").expect("Invalid regex pattern"));
+
+/// Regexes matching `{{ path | filter }}` expressions whose keys may also use the given
+/// characters, built once per set of characters. Groups are the opening braces, the path
+/// and the rest of the expression.
+static EXTENDED_REFERENCE_REGEXES: Lazy<Mutex<HashMap<Vec<char>, Regex>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn extended_reference_regex(extra_chars: &[char]) -> Regex {
+    let mut regexes = EXTENDED_REFERENCE_REGEXES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    regexes
+        .entry(extra_chars.to_vec())
+        .or_insert_with(|| {
+            let extra: String = extra_chars
+                .iter()
+                .map(|c| format!("\\x{{{:x}}}", *c as u32))
+                .collect();
+            Regex::new(&format!(
+                r"(\{{\{{\s*)([a-zA-Z_][a-zA-Z0-9_]*(?:\.[a-zA-Z0-9_{}]+|\[\d+\])*)((?:\s*\|[^}}]*)?\s*\}}\}})",
+                extra
+            ))
+            .expect("Invalid regex pattern")
+        })
+        .clone()
+}
+
+/// Regex to match the alternate JSON Pointer reference form, e.g. {{ /config/greeting }}.
+/// Captures the pointer (group 1), ignoring filters.
+static POINTER_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\{\s*(/[^\s|}]*)(?:\s*\|[^}]*)?\s*\}\}").expect("Invalid regex pattern")
+});
+
+/// Regex to check if string contains any template syntax: a `{{` followed, possibly on a
+/// later line, by a `}}`. A `}}` before the first `{{` does not count.
 static HAS_TEMPLATE_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"\{\{.*?\}\}").expect("Invalid regex pattern"));
+    Lazy::new(|| Regex::new(r"(?s)\{\{.*?\}\}").expect("Invalid regex pattern"));
+
+/// Regex to match the legacy `${ path }` reference form. The body may not contain braces,
+/// so `${{ path }}` is left alone and reads as a literal `$` before a `{{ }}` reference.
+static DOLLAR_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$\{([^{}]*)\}").expect("Invalid regex pattern"));
+
+/// Regex to match MiniJinja comment blocks, which may span lines
+static COMMENT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)\{#.*?#\}").expect("Invalid regex pattern"));
+
+/// Removes `{# ... #}` comments so references inside them are ignored.
+fn strip_comments(template_str: &str) -> Cow<'_, str> {
+    COMMENT_REGEX.replace_all(template_str, "")
+}
+
+/// Globals the resolver injects into each render: the dotted path of the value being
+/// rendered and its own key (or index, inside a sequence). They are not values, so they
+/// are never reported as references.
+pub(super) const PATH_GLOBAL: &str = "__path__";
+pub(super) const KEY_GLOBAL: &str = "__key__";
+
+fn is_injected_global(reference: &str) -> bool {
+    let root = reference.split(['.', '[']).next().unwrap_or(reference);
+    root == PATH_GLOBAL || root == KEY_GLOBAL
+}
+
+/// A syntax value references can be written in. `{{ }}` is MiniJinja's own syntax and is
+/// always understood; other syntaxes are rewritten to it before extraction and rendering,
+/// so files written in different syntaxes can be mixed during a migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(dead_code)]
+pub enum ReferenceSyntax {
+    /// `{{ path }}`
+    Jinja,
+    /// `${ path }`, as used by shell-style and legacy values files.
+    Dollar,
+}
+
+/// Rewrites references in any of `syntaxes` into `{{ }}` form, e.g. `${ db.host }`
+/// becomes `{{ db.host }}`. A string may mix syntaxes; where they overlap the `{{ }}`
+/// reading wins, so `${{ a }}` is a literal `$` followed by a reference to `a`.
+pub fn normalize_syntax<'a>(template_str: &'a str, syntaxes: &[ReferenceSyntax]) -> Cow<'a, str> {
+    if !syntaxes.contains(&ReferenceSyntax::Dollar) || !template_str.contains("${") {
+        return Cow::Borrowed(template_str);
+    }
+    DOLLAR_REGEX.replace_all(template_str, |cap: &regex::Captures| {
+        format!("{{{{{}}}}}", &cap[1])
+    })
+}
+
+/// Rewrites JSON Pointer references into MiniJinja subscript syntax so they can be
+/// rendered, e.g. `{{ /config/web.app/0 | upper }}` becomes
+/// `{{ config["web.app"][0] | upper }}`. The first token must be a plain identifier;
+/// pointers that cannot be rewritten are left as they are.
+pub fn rewrite_json_pointers(template_str: &str) -> Cow<'_, str> {
+    POINTER_REGEX.replace_all(template_str, |cap: &regex::Captures| {
+        let whole = cap.get(0).expect("match always has group 0");
+        let pointer = cap.get(1).expect("pointer group always participates");
+        match pointer_to_expression(pointer.as_str()) {
+            Some(expression) => format!(
+                "{}{}{}",
+                &template_str[whole.start()..pointer.start()],
+                expression,
+                &template_str[pointer.end()..whole.end()]
+            ),
+            None => whole.as_str().to_string(),
+        }
+    })
+}
+
+fn pointer_to_expression(pointer: &str) -> Option<String> {
+    let tokens = parse_json_pointer(pointer).ok()?;
+    let (first, rest) = tokens.split_first()?;
+    let is_identifier = first
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && first.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_identifier {
+        return None;
+    }
+    let mut expression = first.clone();
+    for token in rest {
+        if is_index_token(token) {
+            expression.push_str(&format!("[{}]", token));
+        } else {
+            let escaped = token.replace('\\', "\\\\").replace('"', "\\\"");
+            expression.push_str(&format!("[\"{}\"]", escaped));
+        }
+    }
+    Some(expression)
+}
+
+/// Regex to match a relative reference at the start of an expression, e.g. `{{ ../port`.
+/// Captures the opening braces (group 1), the `../` steps (group 2) and the first key
+/// after them (group 3); anything after that key is left as written.
+static RELATIVE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(\{\{\s*)((?:\.\./)+)([a-zA-Z_][a-zA-Z0-9_]*)").expect("Invalid regex pattern")
+});
+
+/// Rewrites the relative references in the template at `value_path` into absolute ones.
+/// The first `../` stands for the value's parent and each further one goes up a level, so
+/// at `services.web.url`, `{{ ../port }}` becomes `{{ services.web.port }}` and
+/// `{{ ../../db.host }}` becomes `{{ services.db.host }}`.
+///
+/// # Errors
+///
+/// Returns an error if a reference steps above the root of the values, or if the path
+/// it resolves to starts with a key MiniJinja cannot look up by name.
+pub(super) fn resolve_relative_references<'a>(
+    template_str: &'a str,
+    value_path: &str,
+) -> Result<Cow<'a, str>> {
+    if !template_str.contains("../") {
+        return Ok(Cow::Borrowed(template_str));
+    }
+    let segments = ValuePath::new(value_path).segments()?;
+    let mut rewritten = String::with_capacity(template_str.len());
+    let mut last = 0;
+    for cap in RELATIVE_REGEX.captures_iter(template_str) {
+        let whole = cap.get(0).expect("match always has group 0");
+        let steps = cap[2].len() / "../".len();
+        let parent_len = segments.len().checked_sub(steps).ok_or_else(|| {
+            anyhow!(
+                "Relative reference '{}{}' in '{}' goes above the root of the values",
+                &cap[2],
+                &cap[3],
+                value_path
+            )
+        })?;
+        let mut expression = String::new();
+        for segment in &segments[..parent_len] {
+            match segment {
+                PathSegment::Index(index) => expression.push_str(&format!("[{}]", index)),
+                PathSegment::Key(key) if expression.is_empty() => {
+                    if !IDENTIFIER_REGEX.is_match(key) {
+                        return Err(anyhow!(
+                            "Relative reference in '{}' cannot be resolved: '{}' is not a plain name",
+                            value_path,
+                            key
+                        ));
+                    }
+                    expression.push_str(key);
+                }
+                PathSegment::Key(key) if IDENTIFIER_REGEX.is_match(key) => {
+                    expression.push_str(&format!(".{}", key))
+                }
+                PathSegment::Key(key) => expression.push_str(&format!("['{}']", key)),
+            }
+        }
+        if !expression.is_empty() {
+            expression.push('.');
+        }
+        expression.push_str(&cap[3]);
+
+        rewritten.push_str(&template_str[last..whole.start()]);
+        rewritten.push_str(&cap[1]);
+        rewritten.push_str(&expression);
+        last = whole.end();
+    }
+    rewritten.push_str(&template_str[last..]);
+    Ok(Cow::Owned(rewritten))
+}
 
 /// Reference extractor implementation using regex to parse MiniJinja/Jinja2 syntax.
-pub struct MiniJinjaReferenceExtractor;
+pub struct MiniJinjaReferenceExtractor {
+    pub(super) syntaxes: Vec<ReferenceSyntax>,
+    pub(super) config: ExtractorConfig,
+}
 
 impl MiniJinjaReferenceExtractor {
     pub fn new() -> Self {
-        Self
+        Self {
+            syntaxes: vec![ReferenceSyntax::Jinja],
+            config: ExtractorConfig::default(),
+        }
+    }
+
+    /// Also recognises references written in any of `syntaxes`.
+    pub fn with_syntaxes(syntaxes: &[ReferenceSyntax]) -> Self {
+        Self {
+            syntaxes: syntaxes.to_vec(),
+            ..Self::new()
+        }
+    }
+
+    /// Recognises keys following the rules in `config`.
+    #[allow(dead_code)]
+    pub fn with_config(config: ExtractorConfig) -> Self {
+        Self {
+            config,
+            ..Self::new()
+        }
+    }
+
+    /// Rewrites `template_str` into the plain `{{ }}` form references are extracted from.
+    fn normalize<'a>(&self, template_str: &'a str) -> Cow<'a, str> {
+        match normalize_syntax(template_str, &self.syntaxes) {
+            Cow::Borrowed(borrowed) => self.config.rewrite_identifiers(borrowed),
+            Cow::Owned(owned) => Cow::Owned(self.config.rewrite_identifiers(&owned).into_owned()),
+        }
     }
 }
 
@@ -31,17 +329,208 @@ impl Default for MiniJinjaReferenceExtractor {
     }
 }
 
+/// Functions MiniJinja provides as globals. Calling one is not a reference to a value.
+/// `debug` is left out, as it is far more likely to name a value.
+const BUILTIN_GLOBALS: [&str; 3] = ["range", "dict", "namespace"];
+
+/// Regex to match calls to the functions the renderer provides (see `TemplateFunctions`),
+/// capturing the function name (group 1).
+static FUNCTION_CALL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[^\w.])(now|uuid|env)\s*\(").expect("Invalid regex pattern"));
+
+/// Whether `template_str` calls the function `variable`. Calling one is not a reference,
+/// but a value of the same name shadows the function, so only calls are skipped.
+fn is_function_call(template_str: &str, variable: &str) -> bool {
+    FUNCTION_CALL_REGEX
+        .captures_iter(template_str)
+        .any(|cap| &cap[1] == variable)
+}
+
+/// Whether `variable`, a dotted attribute chain, is called as a method somewhere in
+/// `template_str`, e.g. `mymap.keys` in `{{ mymap.keys() }}`.
+fn is_method_call(template_str: &str, variable: &str) -> bool {
+    template_str.match_indices(variable).any(|(start, _)| {
+        let before = template_str[..start].chars().next_back();
+        let continues_name = before.is_some_and(|c| c.is_ascii_alphanumeric() || "_.".contains(c));
+        !continues_name
+            && template_str[start + variable.len()..]
+                .trim_start()
+                .starts_with('(')
+    })
+}
+
+/// Regex to match what makes a missing value safe to use within one expression: the
+/// `default` filter, an `is defined` style test, or an inline `if` or `or`.
+static GUARD_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\|\s*(?:default|d)\b|\bis\s+(?:not\s+)?(?:defined|undefined|none)\b|\b(?:if|or)\b")
+        .expect("Invalid regex pattern")
+});
+
+/// Regex to match the opening and closing tags of `{% if %}` blocks, capturing which.
+static IF_BLOCK_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{%-?\s*(if|endif)\b").expect("Invalid regex pattern"));
+
+/// Whether every use of `reference` in `template_str` copes with it being missing: inside
+/// an `{% if %}` block, or in an expression with a guard such as `| default('x')`,
+/// `is defined` or an inline `if`. A reference not written out as it is extracted, such
+/// as one through a quoted subscript, counts as unguarded.
+pub fn reference_is_guarded(template_str: &str, reference: &str) -> bool {
+    let mut uses = template_str
+        .match_indices(reference)
+        .filter(|(start, _)| {
+            let before = template_str[..*start].chars().next_back();
+            !before.is_some_and(|c| c.is_ascii_alphanumeric() || "_.".contains(c))
+        })
+        .peekable();
+    if uses.peek().is_none() {
+        return false;
+    }
+    uses.all(|(start, _)| {
+        let before = &template_str[..start];
+        let open_ifs = IF_BLOCK_REGEX
+            .captures_iter(before)
+            .fold(0i32, |depth, cap| match &cap[1] {
+                "if" => depth + 1,
+                _ => depth - 1,
+            });
+        if open_ifs > 0 {
+            return true;
+        }
+        let expression_start = before.rfind("{{").max(before.rfind("{%")).unwrap_or(0);
+        let after = &template_str[start + reference.len()..];
+        let expression_end = after
+            .find("}}")
+            .into_iter()
+            .chain(after.find("%}"))
+            .min()
+            .unwrap_or(after.len());
+        GUARD_REGEX.is_match(&before[expression_start..])
+            || GUARD_REGEX.is_match(&after[..expression_end])
+    })
+}
+
+/// Returns the variables `template_str` reads, as MiniJinja's parser sees them, or `None`
+/// if it does not parse. Attribute chains come back dotted (`a.b`), but subscripts stop the
+/// chain, so `items[0].name` is just `items`. Variables set by the template itself, such
+/// as loop variables and `{% set %}` targets, are left out.
+fn parsed_variables(template_str: &str) -> Option<HashSet<String>> {
+    let env = Environment::empty();
+    let source = rewrite_json_pointers(template_str);
+    let template = env.template_from_str(&source).ok()?;
+    Some(template.undeclared_variables(true))
+}
+
+/// The first key of a dotted value path, e.g. `items` for `items[0].name`.
+fn root_of(path: &str) -> &str {
+    path.split(['.', '[']).next().unwrap_or(path)
+}
+
+/// Whether `path` is `root` or lies beneath it.
+fn is_under(path: &str, root: &str) -> bool {
+    path.strip_prefix(root)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+}
+
 impl ReferenceExtractor for MiniJinjaReferenceExtractor {
+    /// Dotted references and JSON Pointer references are both returned as dotted value
+    /// paths, in the order they appear. Pointers that cannot be written as a dotted path
+    /// are skipped.
+    ///
+    /// Which variables a template reads is decided by MiniJinja's parser, so references
+    /// anywhere in an expression count, e.g. both `a` and `b` in `{{ (a or b) ~ '-x' }}`,
+    /// while loop variables and `{% set %}` targets do not. Simple `{{ path | filter }}`
+    /// expressions also keep their full path including sequence indices (`items[0].name`),
+    /// which the parser does not report.
     fn extract_references(&self, template_str: &str) -> Vec<String> {
-        TEMPLATE_REGEX
-            .captures_iter(template_str)
-            .map(|cap| cap[1].to_string())
+        let template_str = self.normalize(template_str);
+        let uncommented = strip_comments(&template_str);
+        let dotted = TEMPLATE_REGEX.captures_iter(&uncommented).map(|cap| {
+            let path = subscripts_to_dotted(&cap[1]).into_owned();
+            (cap.get(1).unwrap().start(), path)
+        });
+        let pointers = POINTER_REGEX.captures_iter(&uncommented).filter_map(|cap| {
+            let path = ValuePath::from_json_pointer(&cap[1]).ok()?;
+            Some((cap.get(1).unwrap().start(), path.0))
+        });
+        let mut references: Vec<(usize, String)> = dotted.chain(pointers).collect();
+
+        // Templates that do not parse keep the pattern matches; rendering reports the error
+        if let Some(variables) = parsed_variables(&template_str) {
+            references.retain(|(_, path)| {
+                variables
+                    .iter()
+                    .any(|variable| root_of(variable) == root_of(path))
+            });
+            let mut missed: Vec<(usize, String)> = variables
+                .into_iter()
+                .filter(|variable| !BUILTIN_GLOBALS.contains(&variable.as_str()))
+                .filter(|variable| !is_function_call(&uncommented, variable))
+                // A method call such as `mymap.keys()` reads the whole of `mymap`
+                .map(|variable| match variable.rsplit_once('.') {
+                    Some((receiver, _)) if is_method_call(&uncommented, &variable) => {
+                        (receiver.to_string(), true)
+                    }
+                    _ => (variable, false),
+                })
+                .filter(|(variable, is_receiver)| {
+                    if *is_receiver {
+                        !references.iter().any(|(_, path)| path == variable)
+                    } else {
+                        !references.iter().any(|(_, path)| is_under(path, variable))
+                    }
+                })
+                .map(|(variable, _)| {
+                    let position = uncommented.find(&variable).unwrap_or(usize::MAX);
+                    (position, variable)
+                })
+                .collect();
+            missed.sort();
+            references.extend(missed);
+        }
+
+        references.sort_by_key(|(position, _)| *position);
+        references
+            .into_iter()
+            .map(|(_, path)| path)
+            .filter(|path| !is_injected_global(path))
             .collect()
     }
 
+    fn references_path(&self, template_str: &str, path: &str) -> bool {
+        let template_str = &*self.normalize(template_str);
+        // Cheap substring check first so most templates are never parsed. Pointers and
+        // subscripts are spelled differently from the path, so they always need full
+        // extraction.
+        if !template_str.contains(path) && !template_str.contains(['/', '[']) {
+            return false;
+        }
+        self.extract_references(template_str)
+            .iter()
+            .any(|reference| reference == path)
+    }
+
     fn contains_template(&self, s: &str) -> bool {
-        HAS_TEMPLATE_REGEX.is_match(s)
+        contains_template_syntax(&self.normalize(s))
+    }
+}
+
+/// Checks if a string contains a `{{ }}` expression outside of comments.
+pub(super) fn contains_template_syntax(s: &str) -> bool {
+    HAS_TEMPLATE_REGEX.is_match(&strip_comments(s))
+}
+
+/// Finds the first `{{` outside of comments that has no `}}` after it, returning the text
+/// from there on, e.g. `{{ db.host` for `postgres://{{ db.host`.
+pub(super) fn unterminated_expression(s: &str) -> Option<String> {
+    let uncommented = strip_comments(s);
+    let mut rest = &*uncommented;
+    while let Some(open) = rest.find("{{") {
+        match rest[open + 2..].find("}}") {
+            Some(close) => rest = &rest[open + 2 + close + 2..],
+            None => return Some(rest[open..].to_string()),
+        }
     }
+    None
 }
 
 #[cfg(test)]
@@ -55,6 +544,117 @@ mod tests {
         assert_eq!(refs, vec!["foo"]);
     }
 
+    #[test]
+    fn test_injected_globals_are_not_references() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor.extract_references("{{ __path__ }}/{{ __key__ | upper }}/{{ name }}");
+        assert_eq!(refs, vec!["name"]);
+    }
+
+    #[test]
+    fn test_function_calls_are_not_references() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor
+            .extract_references("{{ now() }}-{{ uuid() }}-{{ env('HOME', home) }}-{{ name }}");
+        assert_eq!(refs, vec!["home", "name"]);
+
+        // Without a call, these names are ordinary references
+        let refs = extractor.extract_references("{{ env }}/{{ now | upper }}/{{ config.uuid }}");
+        assert_eq!(refs, vec!["env", "now", "config.uuid"]);
+    }
+
+    #[test]
+    fn test_filtered_variables_and_filter_arguments_are_references() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor.extract_references("{{ servers | length }}");
+        assert_eq!(refs, vec!["servers"]);
+
+        let refs = extractor.extract_references("{{ name | default(fallback.name) | upper }}");
+        assert_eq!(refs, vec!["name", "fallback.name"]);
+    }
+
+    #[test]
+    fn test_method_calls_reference_their_receiver() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor.extract_references("{{ mymap.keys() | join(',') }}");
+        assert_eq!(refs, vec!["mymap"]);
+
+        let refs = extractor.extract_references(
+            "{% for k, v in config.ports.items() %}{{ k }}={{ v }}{% endfor %}",
+        );
+        assert_eq!(refs, vec!["config.ports"]);
+
+        // Only a call turns the last segment into a method
+        let refs = extractor.extract_references("{{ mymap.keys }} {{ other.keys ( ) }}");
+        assert_eq!(refs, vec!["mymap.keys", "other"]);
+    }
+
+    #[test]
+    fn test_reference_is_guarded() {
+        let guarded = [
+            "{{ xs[5] | default('x') }}",
+            "{{ xs[5]|d('x') }}",
+            "{{ xs[5] if xs | length > 5 else 'x' }}",
+            "{{ xs[5] or 'x' }}",
+            "{% if xs[5] is defined %}{{ xs[5] }}{% endif %}",
+            "{% if xs | length > 5 %}{{ xs[5] }}{% else %}none{% endif %}",
+        ];
+        for template in guarded {
+            assert!(reference_is_guarded(template, "xs[5]"), "{}", template);
+        }
+
+        let unguarded = [
+            "{{ xs[5] }}",
+            "{{ xs[5] | upper }} {{ xs[5] | default('x') }}",
+            "{% if enabled %}on{% endif %}{{ xs[5] }}",
+            "{{ other.xs[5] | default('x') }}",
+            "{{ ys[0] | default('x') }}",
+        ];
+        for template in unguarded {
+            assert!(!reference_is_guarded(template, "xs[5]"), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_extract_quoted_subscripts_as_dotted_keys() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor.extract_references(
+            r#"{{ services['my-service'].port }} {{ services["db-main"] | upper }}"#,
+        );
+        assert_eq!(refs, vec!["services.my-service.port", "services.db-main"]);
+        assert!(extractor.references_path(
+            "{{ services['my-service'].port }}",
+            "services.my-service.port"
+        ));
+
+        // Keys containing dots cannot be written as a value path, so the root is used
+        let refs = extractor.extract_references("{{ config['web.app'] }}");
+        assert_eq!(refs, vec!["config"]);
+    }
+
+    #[test]
+    fn test_extra_identifier_chars_in_dotted_references() {
+        let plain = MiniJinjaReferenceExtractor::new();
+        assert_eq!(
+            plain.extract_references("{{ services.my-service.port }}"),
+            vec!["services.my", "service.port"]
+        );
+
+        let hyphens = MiniJinjaReferenceExtractor::with_config(ExtractorConfig {
+            extra_identifier_chars: vec!['-'],
+        });
+        assert_eq!(
+            hyphens.extract_references("{{ services.my-service.port }}-{{ a-b }}"),
+            vec!["services.my-service.port", "a", "b"]
+        );
+        assert_eq!(
+            hyphens
+                .config
+                .rewrite_identifiers("{{ services.my-service.ports[0] | int }} {{ x.y }}"),
+            "{{ services['my-service'].ports[0] | int }} {{ x.y }}"
+        );
+    }
+
     #[test]
     fn test_extract_nested_reference() {
         let extractor = MiniJinjaReferenceExtractor::new();
@@ -104,6 +704,159 @@ mod tests {
         assert_eq!(refs, vec!["foo", "bar", "baz"]);
     }
 
+    #[test]
+    fn test_extract_sequence_index_reference() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs =
+            extractor.extract_references("{{ items[0] }} and {{ servers[1].ports[2] | upper }}");
+        assert_eq!(refs, vec!["items[0]", "servers[1].ports[2]"]);
+    }
+
+    #[test]
+    fn test_extract_references_from_concatenation() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor.extract_references("{{ 'x-' ~ foo ~ db.host | upper }}");
+        assert_eq!(refs, vec!["foo", "db.host"]);
+    }
+
+    #[test]
+    fn test_extract_references_from_logical_and_parenthesized_expressions() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        assert_eq!(
+            extractor.extract_references("{{ (a or b) }}"),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            extractor.extract_references("{{ port if (tls.enabled and not debug) else 80 }}"),
+            vec!["port", "tls.enabled", "debug"]
+        );
+        assert_eq!(
+            extractor.extract_references("{% if a.b %}{{ c }}{% endif %}"),
+            vec!["a.b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_extract_references_skips_template_locals_and_builtins() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let template =
+            "{% for item in items %}{{ item.name }}{% endfor %}{% set x = y %}{{ x }}{{ range(3) | list }}";
+        assert_eq!(extractor.extract_references(template), vec!["items", "y"]);
+    }
+
+    #[test]
+    fn test_references_path() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let template = "{{ foo.bar | upper }} and {{ items[0] }}";
+        assert!(extractor.references_path(template, "foo.bar"));
+        assert!(extractor.references_path(template, "items[0]"));
+        assert!(!extractor.references_path(template, "foo"));
+        assert!(!extractor.references_path(template, "items"));
+        assert!(!extractor.references_path("foo.bar", "foo.bar"));
+    }
+
+    #[test]
+    fn test_references_path_default_matches_override() {
+        struct ListOnlyExtractor;
+
+        impl ReferenceExtractor for ListOnlyExtractor {
+            fn extract_references(&self, template_str: &str) -> Vec<String> {
+                MiniJinjaReferenceExtractor::new().extract_references(template_str)
+            }
+
+            fn contains_template(&self, s: &str) -> bool {
+                MiniJinjaReferenceExtractor::new().contains_template(s)
+            }
+        }
+
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let template = "{{ a }} {{ a.b }} {{ list[1].c | lower }}";
+        for path in ["a", "a.b", "list[1].c", "list[1]", "b", ""] {
+            assert_eq!(
+                extractor.references_path(template, path),
+                ListOnlyExtractor.references_path(template, path),
+                "mismatch for path '{}'",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_references_inside_comments_are_ignored() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let template = "{{ live }}{# was {{ old.value }} #} and {#\n {{ other }}\n#}";
+        assert_eq!(extractor.extract_references(template), vec!["live"]);
+        assert!(!extractor.references_path(template, "old.value"));
+        assert!(extractor.contains_template(template));
+        assert!(!extractor.contains_template("{# {{ commented }} #}"));
+    }
+
+    #[test]
+    fn test_extract_json_pointer_references() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let refs = extractor
+            .extract_references("{{ /config/greeting }} {{ name }} {{ /items/0/a~1b | upper }}");
+        assert_eq!(refs, vec!["config.greeting", "name", "items[0].a/b"]);
+        assert!(extractor.references_path("{{ /config/greeting }}", "config.greeting"));
+        // Keys containing dots have no dotted form, so they create no reference
+        assert!(extractor.extract_references("{{ /a.b/c }}").is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_json_pointers() {
+        assert_eq!(
+            rewrite_json_pointers("{{ /config/web.app/0 | upper }}!"),
+            r#"{{ config["web.app"][0] | upper }}!"#
+        );
+        assert_eq!(
+            rewrite_json_pointers("{{ /a/x~1y~0z }} {{ plain }}"),
+            r#"{{ a["x/y~z"] }} {{ plain }}"#
+        );
+        // Escaped characters in keys stay valid string literals
+        assert_eq!(
+            rewrite_json_pointers(r#"{{ /a/say"hi" }}"#),
+            r#"{{ a["say\"hi\""] }}"#
+        );
+        // A first token that is not an identifier is left alone
+        assert_eq!(rewrite_json_pointers("{{ /0/a }}"), "{{ /0/a }}");
+    }
+
+    #[test]
+    fn test_normalize_syntax() {
+        let both = [ReferenceSyntax::Jinja, ReferenceSyntax::Dollar];
+        assert_eq!(normalize_syntax("${ a.b }", &both), "{{ a.b }}");
+        assert_eq!(
+            normalize_syntax("${a} and {{ b }}", &both),
+            "{{a}} and {{ b }}"
+        );
+        assert_eq!(normalize_syntax("${{ a }}", &both), "${{ a }}");
+        assert_eq!(normalize_syntax("plain $ and {}", &both), "plain $ and {}");
+        // Dollar references are literal text unless enabled
+        assert_eq!(
+            normalize_syntax("${ a }", &[ReferenceSyntax::Jinja]),
+            "${ a }"
+        );
+    }
+
+    #[test]
+    fn test_extract_mixed_syntaxes() {
+        let extractor = MiniJinjaReferenceExtractor::with_syntaxes(&[
+            ReferenceSyntax::Jinja,
+            ReferenceSyntax::Dollar,
+        ]);
+        let template = "${ db.host }:{{ db.port }}/${db.name | lower}";
+        assert!(extractor.contains_template("${ db.host }"));
+        assert_eq!(
+            extractor.extract_references(template),
+            vec!["db.host", "db.port", "db.name"]
+        );
+        assert!(extractor.references_path(template, "db.name"));
+
+        let default = MiniJinjaReferenceExtractor::new();
+        assert!(!default.contains_template("${ db.host }"));
+        assert_eq!(default.extract_references(template), vec!["db.port"]);
+    }
+
     #[test]
     fn test_contains_template_true() {
         let extractor = MiniJinjaReferenceExtractor::new();
@@ -122,6 +875,58 @@ mod tests {
         assert!(extractor.contains_template("Hello {{}} world"));
     }
 
+    #[test]
+    fn test_contains_template_requires_opening_before_closing() {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        assert!(!extractor.contains_template("a }} b {{ c"));
+        assert!(!extractor.contains_template("cat <<EOF {{"));
+        assert!(extractor.contains_template("{{\n  host\n}}"));
+        assert_eq!(
+            unterminated_expression("a }} b {{ c"),
+            Some("{{ c".to_string())
+        );
+        assert_eq!(
+            unterminated_expression("{{ a }} and {{ b"),
+            Some("{{ b".to_string())
+        );
+        assert_eq!(unterminated_expression("{# {{ #} {{ a }}"), None);
+    }
+
+    #[test]
+    fn test_resolve_relative_references() -> anyhow::Result<()> {
+        let resolve = |template: &str, path: &str| {
+            resolve_relative_references(template, path).map(Cow::into_owned)
+        };
+        assert_eq!(
+            resolve("{{ ../port }}", "services.web.url")?,
+            "{{ services.web.port }}"
+        );
+        assert_eq!(
+            resolve("{{ ../../db.host }}", "services.web.url")?,
+            "{{ services.db.host }}"
+        );
+        assert_eq!(
+            resolve("{{ ../name | upper }}", "app")?,
+            "{{ name | upper }}"
+        );
+        assert_eq!(
+            resolve("{{ ../../port }}", "services.my-web.hosts[1]")?,
+            "{{ services['my-web'].port }}"
+        );
+        assert_eq!(
+            resolve("{{ ../host }}:{{ db.port }}", "apps[0].url")?,
+            "{{ apps[0].host }}:{{ db.port }}"
+        );
+        assert_eq!(resolve("a/../b {{ x }}", "p")?, "a/../b {{ x }}");
+
+        let err = resolve("{{ ../../name }}", "app").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Relative reference '../../name' in 'app' goes above the root of the values"
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_extract_underscore_in_name() {
         let extractor = MiniJinjaReferenceExtractor::new();
@@ -1,22 +1,90 @@
+mod context;
 mod dependency_graph;
+mod error;
 mod extractor;
 pub mod traits;
 
-use anyhow::{anyhow, Context, Result};
-use minijinja::Environment;
-use serde_yaml::Value;
-use std::collections::HashMap;
-
-use dependency_graph::{DependencyGraph, ValuePath};
-use extractor::MiniJinjaReferenceExtractor;
-use traits::{ReferenceExtractor, TemplateRenderer};
+use anyhow::{anyhow, Result};
+use minijinja::value::ValueKind;
+use minijinja::{Environment, UndefinedBehavior};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use context::RenderContext;
+use dependency_graph::{DependencyGraph, PathSegment, ValuePath};
+pub use error::ResolveError;
+use extractor::{
+    contains_template_syntax, normalize_syntax, reference_is_guarded, resolve_relative_references,
+    rewrite_json_pointers, unterminated_expression, MiniJinjaReferenceExtractor, KEY_GLOBAL,
+    PATH_GLOBAL,
+};
+pub use extractor::{ExtractorConfig, ReferenceSyntax};
+use traits::{ReferenceExtractor, Scalar, ScalarFormatter, TemplateRenderer};
 
 /// Production implementation of TemplateRenderer using MiniJinja
-pub struct MiniJinjaRenderer;
+pub struct MiniJinjaRenderer {
+    scalar_formatter: Option<Arc<dyn ScalarFormatter>>,
+    syntaxes: Vec<ReferenceSyntax>,
+    functions: TemplateFunctions,
+    identifiers: ExtractorConfig,
+}
+
+/// Which of composer's own functions templates may call, on top of MiniJinja's builtins:
+///
+/// - `now()`: the current time in UTC as RFC 3339, e.g. `2024-05-01T12:00:00Z`.
+/// - `uuid()`: a random version 4 UUID.
+/// - `env(name, default)`: the environment variable `name`, or `default` (undefined if
+///   not given) when it is not set.
+///
+/// A value with the same name as a function shadows it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum TemplateFunctions {
+    /// All of them.
+    #[default]
+    All,
+    /// Only `env`, leaving out functions that give a different result on every render.
+    Deterministic,
+    /// None of them.
+    None,
+}
 
 impl MiniJinjaRenderer {
     pub fn new() -> Self {
-        Self
+        Self {
+            scalar_formatter: None,
+            syntaxes: vec![ReferenceSyntax::Jinja],
+            functions: TemplateFunctions::default(),
+            identifiers: ExtractorConfig::default(),
+        }
+    }
+
+    /// Only lets templates call the given set of functions.
+    #[allow(dead_code)]
+    pub fn with_functions(functions: TemplateFunctions) -> Self {
+        Self {
+            functions,
+            ..Self::new()
+        }
+    }
+
+    /// Uses `formatter` to write numbers, booleans and nulls interpolated into templates.
+    #[allow(dead_code)]
+    pub fn with_scalar_formatter(formatter: impl ScalarFormatter + 'static) -> Self {
+        Self {
+            scalar_formatter: Some(Arc::new(formatter)),
+            ..Self::new()
+        }
+    }
+
+    /// Also renders references written in any of `syntaxes`.
+    pub fn with_syntaxes(syntaxes: &[ReferenceSyntax]) -> Self {
+        Self {
+            syntaxes: syntaxes.to_vec(),
+            ..Self::new()
+        }
     }
 }
 
@@ -28,42 +96,398 @@ impl Default for MiniJinjaRenderer {
 
 impl TemplateRenderer for MiniJinjaRenderer {
     fn render(&self, template_str: &str, context: &Value) -> Result<String> {
-        let mut env = Environment::new();
+        let mut rendered = String::new();
+        self.render_into(template_str, context, &mut rendered)?;
+        Ok(rendered)
+    }
+
+    fn render_into(&self, template_str: &str, context: &Value, buf: &mut String) -> Result<()> {
+        let ctx = minijinja::Value::from_serialize(context);
+        self.render_prepared(INLINE_TEMPLATE_NAME, template_str, &ctx, buf)
+    }
+
+    fn render_with_context(
+        &self,
+        template_str: &str,
+        context: &RenderContext,
+        buf: &mut String,
+    ) -> Result<()> {
+        self.render_prepared(INLINE_TEMPLATE_NAME, template_str, context.prepared(), buf)
+    }
+
+    fn render_named(
+        &self,
+        name: &str,
+        template_str: &str,
+        context: &RenderContext,
+        buf: &mut String,
+    ) -> Result<()> {
+        self.render_prepared(name, template_str, context.prepared(), buf)
+    }
+}
+
+impl MiniJinjaRenderer {
+    fn render_prepared(
+        &self,
+        name: &str,
+        template_str: &str,
+        ctx: &minijinja::Value,
+        buf: &mut String,
+    ) -> Result<()> {
+        let template_str = normalize_syntax(template_str, &self.syntaxes);
+        render_inline_into(
+            name,
+            &self.identifiers.rewrite_identifiers(&template_str),
+            ctx,
+            UndefinedBehavior::Lenient,
+            self.scalar_formatter.as_ref(),
+            self.functions,
+            buf,
+        )
+    }
+}
+
+/// The name MiniJinja gives a template rendered without a value path.
+const INLINE_TEMPLATE_NAME: &str = "inline";
+
+/// Renders a single template string against `ctx` with the given undefined behaviour,
+/// appending the output to `buf`. On error `buf` is left as it was. The template is
+/// named `name` and the MiniJinja error is kept as the source of the returned error, so
+/// its `name()` says which template failed.
+fn render_inline_into(
+    name: &str,
+    template_str: &str,
+    ctx: &minijinja::Value,
+    undefined_behavior: UndefinedBehavior,
+    scalar_formatter: Option<&Arc<dyn ScalarFormatter>>,
+    functions: TemplateFunctions,
+    buf: &mut String,
+) -> Result<()> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(undefined_behavior);
+    env.add_filter("tojson", to_json_filter);
+    env.add_filter("toyaml", to_yaml_filter);
+    env.add_filter("required", crate::utils::template::required);
+    env.set_unknown_method_callback(map_method);
+    if functions != TemplateFunctions::None {
+        env.add_function("env", env_function);
+    }
+    if functions == TemplateFunctions::All {
+        env.add_function("now", now_function);
+        env.add_function("uuid", uuid_function);
+    }
+    if let Some(formatter) = scalar_formatter {
+        let formatter = Arc::clone(formatter);
+        env.set_formatter(move |out, state, value| {
+            let formatted = to_scalar(value).and_then(|scalar| formatter.format(&scalar));
+            match formatted {
+                Some(formatted) => Ok(out.write_str(&formatted)?),
+                None => minijinja::escape_formatter(out, state, value),
+            }
+        });
+    }
+    // Errors quote the rewritten template so their locations line up; it only
+    // differs from the original inside JSON Pointer references
+    let source = rewrite_json_pointers(template_str);
+
+    env.add_template(name, &source).map_err(|e| {
+        let description = describe_render_error(&source, &e);
+        anyhow::Error::new(e).context(format!("Failed to parse template {}", description))
+    })?;
+
+    let template = env.get_template(name)?;
+
+    // MiniJinja only streams output to an `io::Write`, so it renders onto the bytes of
+    // `buf`, which are checked to still be UTF-8 once rendering is done
+    let mut bytes = std::mem::take(buf).into_bytes();
+    let start = bytes.len();
+    let result = template.render_captured_to(ctx, &mut bytes);
+    if result.is_err() {
+        bytes.truncate(start);
+    }
+    *buf = String::from_utf8(bytes)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+    result.map(|_| ()).map_err(|e| {
+        let description = describe_render_error(&source, &e);
+        anyhow::Error::new(e).context(format!("Failed to render value reference {}", description))
+    })
+}
+
+/// `mymap.keys()`, `mymap.values()` and `mymap.items()` as in Jinja2, which MiniJinja
+/// otherwise only offers as the `list` and `items` filters.
+fn map_method(
+    state: &minijinja::State,
+    value: &minijinja::Value,
+    method: &str,
+    args: &[minijinja::Value],
+) -> std::result::Result<minijinja::Value, minijinja::Error> {
+    let unknown = || minijinja::Error::from(minijinja::ErrorKind::UnknownMethod);
+    if value.kind() != ValueKind::Map || !matches!(method, "keys" | "values" | "items") {
+        return Err(unknown());
+    }
+    let _: () = minijinja::value::from_args(args)?;
+    match method {
+        "keys" => state.apply_filter("list", std::slice::from_ref(value)),
+        "items" => state.apply_filter("items", std::slice::from_ref(value)),
+        _ => Ok(value
+            .try_iter()?
+            .map(|key| value.get_item(&key).unwrap_or_default())
+            .collect()),
+    }
+}
+
+/// `{{ now() }}`: the current time in UTC, to the second.
+fn now_function() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// `{{ uuid() }}`: a random version 4 UUID.
+fn uuid_function() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// `{{ env('HOME', '/root') }}`: an environment variable, or `default` when it is not set.
+fn env_function(name: String, default: Option<minijinja::Value>) -> minijinja::Value {
+    match std::env::var(&name) {
+        Ok(value) => minijinja::Value::from(value),
+        Err(_) => default.unwrap_or(minijinja::Value::UNDEFINED),
+    }
+}
+
+/// `{{ labels | tojson }}`: serializes a value (usually a mapping or sequence) as compact
+/// JSON, so it can be embedded in a string. Strings inside are escaped as JSON strings.
+fn to_json_filter(value: minijinja::Value) -> std::result::Result<String, minijinja::Error> {
+    serde_json::to_string(&value).map_err(|e| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::BadSerialization,
+            format!("Cannot convert value to JSON: {}", e),
+        )
+    })
+}
+
+/// `{{ labels | toyaml }}`: serializes a value as a YAML document without the trailing
+/// newline.
+fn to_yaml_filter(value: minijinja::Value) -> std::result::Result<String, minijinja::Error> {
+    serde_yaml::to_string(&value)
+        .map(|yaml| yaml.trim_end().to_string())
+        .map_err(|e| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::BadSerialization,
+                format!("Cannot convert value to YAML: {}", e),
+            )
+        })
+}
 
-        env.add_template("inline", template_str)
-            .with_context(|| format!("Failed to parse template: {}", template_str))?;
+/// Converts a MiniJinja value to a `Scalar`, or `None` for strings, collections and
+/// numbers that do not fit an `i64` or `f64`.
+fn to_scalar(value: &minijinja::Value) -> Option<Scalar> {
+    match value.kind() {
+        ValueKind::None => Some(Scalar::Null),
+        ValueKind::Bool => Some(Scalar::Bool(value.is_true())),
+        ValueKind::Number if value.is_integer() => value.as_i64().map(Scalar::Integer),
+        ValueKind::Number => f64::try_from(value.clone()).ok().map(Scalar::Float),
+        _ => None,
+    }
+}
 
-        let template = env.get_template("inline")?;
-        let ctx = minijinja::value::Value::from_serialize(context);
+/// Templates longer than this many characters are shortened in error messages.
+const MAX_TEMPLATE_DISPLAY_CHARS: usize = 80;
+
+/// Describes a MiniJinja error as `'template' at line L, column C near `expr`: cause`,
+/// leaving out whichever location details MiniJinja did not report.
+fn describe_render_error(template_str: &str, err: &minijinja::Error) -> String {
+    let mut description = format!("'{}'", truncate_template(template_str));
+    if let Some(line) = err.line() {
+        description.push_str(&format!(" at line {}", line));
+    }
+    if let Some(range) = err.range() {
+        if let Some(before) = template_str.get(..range.start) {
+            let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            let column = before[line_start..].chars().count() + 1;
+            description.push_str(&format!(", column {}", column));
+        }
+        if let Some(expr) = template_str.get(range).map(str::trim) {
+            if !expr.is_empty() {
+                description.push_str(&format!(" near `{}`", expr));
+            }
+        }
+    }
+    match err.detail() {
+        Some(detail) => description.push_str(&format!(": {}: {}", err.kind(), detail)),
+        None => description.push_str(&format!(": {}", err.kind())),
+    }
+    description
+}
 
-        template
-            .render(&ctx)
-            .map_err(|e| anyhow!("Failed to render value reference '{}': {}", template_str, e))
+fn truncate_template(template_str: &str) -> String {
+    if template_str.chars().count() <= MAX_TEMPLATE_DISPLAY_CHARS {
+        return template_str.to_string();
     }
+    let shortened: String = template_str
+        .chars()
+        .take(MAX_TEMPLATE_DISPLAY_CHARS)
+        .collect();
+    format!("{}...", shortened)
 }
 
 /// Resolves all value references in the given YAML structure using default implementations.
 /// This is the main public entry point for value resolution.
+#[allow(dead_code)]
 pub fn resolve_value_references(values: Value) -> Result<Value> {
-    let extractor = MiniJinjaReferenceExtractor::new();
-    let renderer = MiniJinjaRenderer::new();
-    resolve_with(values, &extractor, &renderer)
+    resolve_value_references_with(values, &ResolveOptions::default())
+}
+
+/// Same as `resolve_value_references`, with the behaviour adjusted by `options`.
+pub fn resolve_value_references_with(values: Value, options: &ResolveOptions) -> Result<Value> {
+    let (extractor, renderer) = default_implementations(options);
+    resolve_with_options(values, &extractor, &renderer, options)
+}
+
+/// Same as `resolve_value_references_with`, but a value that fails to render does not
+/// stop resolution: every other value is still rendered, and all the failures are
+/// returned together, so a file with many broken references can be fixed in one pass.
+/// Values that depend on a failed value are skipped rather than reported, as their
+/// failure would only repeat the earlier one.
+///
+/// # Errors
+///
+/// Lists every `ResolveError::Render`, in the order the values were rendered. A problem
+/// that stops resolution, such as circular references (no order to render in exists
+/// then), ends the list; one that is not a `ResolveError` is given as
+/// `ResolveError::Other`.
+#[allow(dead_code)]
+pub fn resolve_collecting_errors(
+    values: Value,
+    options: &ResolveOptions,
+) -> std::result::Result<Value, Vec<ResolveError>> {
+    let (extractor, renderer) = default_implementations(options);
+    let mut failures = Vec::new();
+    match resolve_inner(values, &extractor, &renderer, options, Some(&mut failures)) {
+        Ok(values) if failures.is_empty() => Ok(values),
+        Ok(_) => Err(failures),
+        Err(err) => {
+            failures.push(err.downcast::<ResolveError>().unwrap_or_else(|err| {
+                ResolveError::Other {
+                    message: format!("{:#}", err),
+                }
+            }));
+            Err(failures)
+        }
+    }
+}
+
+/// The extractor and renderer `resolve_value_references_with` uses for `options`.
+fn default_implementations(
+    options: &ResolveOptions,
+) -> (MiniJinjaReferenceExtractor, MiniJinjaRenderer) {
+    let extractor = MiniJinjaReferenceExtractor {
+        config: options.identifiers.clone(),
+        ..MiniJinjaReferenceExtractor::with_syntaxes(&options.syntaxes)
+    };
+    let renderer = MiniJinjaRenderer {
+        functions: options.functions,
+        identifiers: options.identifiers.clone(),
+        ..MiniJinjaRenderer::with_syntaxes(&options.syntaxes)
+    };
+    (extractor, renderer)
+}
+
+/// How deeply values may nest before resolving or merging them is refused. Real configs
+/// stay far below this; anything deeper is treated as malformed rather than risking a
+/// stack overflow in the recursive walks.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Options controlling how value references are resolved. The defaults match
+/// `resolve_with`.
+#[derive(Debug, Clone)]
+pub struct ResolveOptions {
+    /// Fail before rendering anything if a reference points at a path that does not
+    /// exist in the values. This is strict: a reference guarded by `default` still
+    /// counts as undefined. Defaults to `false`, where missing values render as empty.
+    pub fail_on_undefined: bool,
+    /// Trim leading and trailing whitespace from rendered strings before they are
+    /// written back, so a template spread over lines of `{% if %}` blocks yields a
+    /// clean scalar. Defaults to `false` to keep intentional whitespace.
+    pub trim_rendered: bool,
+    /// Syntaxes references may be written in. `{{ }}` is always understood, so this only
+    /// needs listing extra ones such as `ReferenceSyntax::Dollar` for `${ }`.
+    pub syntaxes: Vec<ReferenceSyntax>,
+    /// Fail if mappings and sequences nest more than this many levels deep. Defaults to
+    /// `DEFAULT_MAX_DEPTH`.
+    pub max_depth: usize,
+    /// Which of composer's functions, such as `now()`, templates may call. Defaults to
+    /// `TemplateFunctions::All`.
+    pub functions: TemplateFunctions,
+    /// Which keys references may name. Defaults to plain identifiers, with other keys
+    /// reachable through quoted subscripts such as `{{ services['my-service'] }}`.
+    pub identifiers: ExtractorConfig,
+    /// Value paths (e.g. `db.url` or `hosts[0]`) that were resolved elsewhere. Their strings
+    /// are kept exactly as they are, even if they contain `{{ }}`, and references to them
+    /// read them as plain values. Defaults to none.
+    pub frozen_paths: HashSet<String>,
+    /// Remove mapping keys whose template rendered to an empty string, as if each were
+    /// tagged `!omitempty`. An empty string written in the values themselves is kept.
+    /// Defaults to `false`.
+    pub omit_empty: bool,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        ResolveOptions {
+            fail_on_undefined: false,
+            trim_rendered: false,
+            syntaxes: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            functions: TemplateFunctions::default(),
+            identifiers: ExtractorConfig::default(),
+            frozen_paths: HashSet::new(),
+            omit_empty: false,
+        }
+    }
 }
 
 /// Resolves all value references using provided extractor and renderer.
 /// Uses `&impl Trait` syntax for testability with mock implementations.
+#[allow(dead_code)]
 pub fn resolve_with(
-    mut values: Value,
+    values: Value,
+    extractor: &impl ReferenceExtractor,
+    renderer: &impl TemplateRenderer,
+) -> Result<Value> {
+    resolve_with_options(values, extractor, renderer, &ResolveOptions::default())
+}
+
+/// Same as `resolve_with`, with the behaviour adjusted by `options`.
+pub fn resolve_with_options(
+    values: Value,
+    extractor: &impl ReferenceExtractor,
+    renderer: &impl TemplateRenderer,
+    options: &ResolveOptions,
+) -> Result<Value> {
+    resolve_inner(values, extractor, renderer, options, None)
+}
+
+/// Resolves `values`, stopping at the first render failure unless `failures` is given,
+/// in which case render failures are added to it and values depending on them skipped.
+fn resolve_inner(
+    values: Value,
     extractor: &impl ReferenceExtractor,
     renderer: &impl TemplateRenderer,
+    options: &ResolveOptions,
+    mut failures: Option<&mut Vec<ResolveError>>,
 ) -> Result<Value> {
     // Step 1: Collect all template values (string values containing {{ }})
-    let mut templates = HashMap::new();
-    collect_template_values(&values, "", &mut templates, extractor);
+    let PreparedValues {
+        mut values,
+        templates,
+        omit_paths,
+    } = prepare_values(values, extractor, options)?;
 
     if templates.is_empty() {
+        omit_empty_keys(&mut values, &omit_paths)?;
         return Ok(values);
     }
+    let _span = tracing::debug_span!("resolve", templates = templates.len()).entered();
 
     // Step 2: Build dependency graph
     let graph = build_dependency_graph(&templates, extractor);
@@ -71,27 +495,764 @@ pub fn resolve_with(
     // Step 3: Topological sort (detects cycles)
     let resolution_order = graph.topological_sort()?;
 
-    // Step 4: Resolve in order
+    // Step 4: Resolve in order, writing each rendered string back without copying it
+    let mut context = RenderContext::new(values);
+    // Values that failed to render, or were skipped because they depend on one that did
+    let mut failed: HashSet<String> = HashSet::new();
+    for path in resolution_order {
+        if let Some(template_str) = templates.get(path.as_str()) {
+            let depends_on_failure = !failed.is_empty()
+                && graph
+                    .dependencies(&path)
+                    .iter()
+                    .any(|dependency| failed.contains(dependency.as_str()));
+            if depends_on_failure {
+                trace!(
+                    "Skipping '{}', a value it depends on failed.",
+                    path.as_str()
+                );
+                failed.insert(path.as_str().to_string());
+                continue;
+            }
+            let mut rendered = match render_at(renderer, path.as_str(), template_str, &context) {
+                Ok(rendered) => rendered,
+                Err(err) => {
+                    let Some(failures) = failures.as_deref_mut() else {
+                        return Err(err);
+                    };
+                    // `render_at` reports failures as `ResolveError::Render`
+                    failures.push(err.downcast::<ResolveError>().unwrap_or_else(|err| {
+                        ResolveError::Render {
+                            path: path.as_str().to_string(),
+                            template: template_str.clone(),
+                            message: format!("{:#}", err),
+                        }
+                    }));
+                    failed.insert(path.as_str().to_string());
+                    continue;
+                }
+            };
+            if options.trim_rendered {
+                trim_in_place(&mut rendered);
+            }
+            context.set(path.as_str(), Value::String(rendered))?;
+        }
+    }
+
+    let mut values = context.into_values();
+    omit_empty_keys(&mut values, &omit_paths)?;
+    Ok(values)
+}
+
+/// Values ready to render, as every way of resolving prepares them.
+struct PreparedValues {
+    /// The values with their `!raw` and `!omitempty` tags removed.
+    values: Value,
+    /// The template of every value to render, by value path.
+    templates: HashMap<String, String>,
+    /// Mapping keys to remove once resolved if they are empty, see `omit_empty_keys`.
+    omit_paths: Vec<String>,
+}
+
+/// The steps before rendering that every way of resolving shares, so they all give the
+/// same result: takes the `!omitempty` tags, collects the templates outside `!raw` nodes
+/// and frozen paths, strips the `!raw` tags and checks the references before anything
+/// is rendered.
+fn prepare_values(
+    mut values: Value,
+    extractor: &impl ReferenceExtractor,
+    options: &ResolveOptions,
+) -> Result<PreparedValues> {
+    let mut omit_paths = Vec::new();
+    take_omit_empty_tags(&mut values, "", &mut omit_paths);
+    let mut templates = HashMap::new();
+    collect_unfrozen_template_values(
+        &values,
+        "",
+        &mut templates,
+        extractor,
+        options.max_depth,
+        &options.frozen_paths,
+    )?;
+    strip_raw_tags(&mut values);
+    if options.omit_empty {
+        omit_paths.extend(templates.keys().cloned());
+    }
+
+    if options.fail_on_undefined {
+        let references = undefined_references(&values, &templates, extractor);
+        if !references.is_empty() {
+            return Err(ResolveError::UndefinedReferences { references }.into());
+        }
+    }
+    check_sequence_bounds(&values, &templates, extractor)?;
+    Ok(PreparedValues {
+        values,
+        templates,
+        omit_paths,
+    })
+}
+
+/// Renders the template at `path`, reporting a failure as a `ResolveError::Render` with
+/// the value path as context, so the first value that fails to render is named.
+///
+/// Templates that mention `__path__` or `__key__` see them as globals holding `path` and
+/// its last segment (an integer inside a sequence), so `metadata.label: "{{ __path__ }}"`
+/// renders `metadata.label`. They shadow top-level values of the same name.
+fn render_at(
+    renderer: &impl TemplateRenderer,
+    path: &str,
+    template_str: &str,
+    context: &RenderContext,
+) -> Result<String> {
+    let mut rendered = String::new();
+    render_at_into(renderer, path, template_str, context, &mut rendered)?;
+    Ok(rendered)
+}
+
+/// Removes leading and trailing whitespace from `s` without reallocating it.
+fn trim_in_place(s: &mut String) {
+    s.truncate(s.trim_end().len());
+    let leading = s.len() - s.trim_start().len();
+    s.drain(..leading);
+}
+
+/// Same as `render_at`, appending the output to `buf`.
+fn render_at_into(
+    renderer: &impl TemplateRenderer,
+    path: &str,
+    template_str: &str,
+    context: &RenderContext,
+    buf: &mut String,
+) -> Result<()> {
+    // Per-value spans are trace level so that they cost nothing unless asked for
+    let _span = tracing::trace_span!("render", path, template = template_str).entered();
+    let mut with_globals = None;
+    if template_str.contains(PATH_GLOBAL) || template_str.contains(KEY_GLOBAL) {
+        if let Value::Mapping(map) = context.values() {
+            let mut map = map.clone();
+            map.insert(Value::from(PATH_GLOBAL), Value::from(path));
+            let key = match ValuePath::new(path).segments()?.pop() {
+                Some(PathSegment::Index(index)) => Value::from(index),
+                Some(PathSegment::Key(key)) => Value::from(key),
+                None => Value::Null,
+            };
+            map.insert(Value::from(KEY_GLOBAL), key);
+            with_globals = Some(Value::Mapping(map));
+        }
+    }
+    // Globals are rare enough that converting the whole tree for them is fine
+    let with_globals = with_globals.map(RenderContext::new);
+    let context = with_globals.as_ref().unwrap_or(context);
+    renderer
+        .render_named(path, template_str, context, buf)
+        .map_err(|e| {
+            // The context names the value; the message adds the template and the cause
+            anyhow::Error::new(ResolveError::Render {
+                path: path.to_string(),
+                template: template_str.to_string(),
+                message: render_error_message(&e),
+            })
+            .context(format!("Failed to resolve value '{}'", path))
+        })
+}
+
+/// Formats a render error like `{:#}`, leaving out the MiniJinja error kept as its
+/// source, as the description before it already says the same.
+fn render_error_message(err: &anyhow::Error) -> String {
+    err.chain()
+        .filter(|cause| !cause.is::<minijinja::Error>())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Same as `resolve_value_references`, but on failure returns the error as a JSON object
+/// (see `ResolveError::to_json`) for tools that parse composer's output. Errors that are
+/// not a `ResolveError` have kind `other`.
+#[allow(dead_code)]
+pub fn resolve_value_references_json_err(values: Value) -> std::result::Result<Value, String> {
+    resolve_value_references(values).map_err(|err| {
+        let json = match err.downcast_ref::<ResolveError>() {
+            Some(resolve_error) => resolve_error.to_json(),
+            None => serde_json::json!({
+                "kind": "other",
+                "path": null,
+                "message": format!("{:#}", err),
+            }),
+        };
+        json.to_string()
+    })
+}
+
+/// Counts gathered while resolving value references, for diagnosing slow resolution.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolveStats {
+    /// Number of string values containing template syntax.
+    pub templates: usize,
+    /// Number of value paths in the dependency graph, including referenced non-templates.
+    pub graph_nodes: usize,
+    /// Number of references between value paths.
+    pub graph_edges: usize,
+    /// Number of times the renderer was called.
+    pub render_calls: usize,
+    /// Wall-clock time spent resolving.
+    pub elapsed: Duration,
+}
+
+/// Same as `resolve_with`, additionally returning statistics about the resolution.
+/// Kept separate so `resolve_with` does not pay for the bookkeeping.
+#[allow(dead_code)]
+pub fn resolve_with_stats(
+    values: Value,
+    extractor: &impl ReferenceExtractor,
+    renderer: &impl TemplateRenderer,
+) -> Result<(Value, ResolveStats)> {
+    let start = Instant::now();
+    let mut stats = ResolveStats::default();
+
+    let PreparedValues {
+        mut values,
+        templates,
+        omit_paths,
+    } = prepare_values(values, extractor, &ResolveOptions::default())?;
+    stats.templates = templates.len();
+
+    if !templates.is_empty() {
+        let graph = build_dependency_graph(&templates, extractor);
+        stats.graph_nodes = graph.node_count();
+        stats.graph_edges = graph.edge_count();
+
+        let mut context = RenderContext::new(values);
+        for path in graph.topological_sort()? {
+            if let Some(template_str) = templates.get(path.as_str()) {
+                stats.render_calls += 1;
+                let rendered = render_at(renderer, path.as_str(), template_str, &context)?;
+                context.set(path.as_str(), Value::String(rendered))?;
+            }
+        }
+        values = context.into_values();
+    }
+    omit_empty_keys(&mut values, &omit_paths)?;
+
+    stats.elapsed = start.elapsed();
+    Ok((values, stats))
+}
+
+/// Resolved values kept together with the unresolved source and dependency graph, so a
+/// few overrides can be applied later without resolving everything again.
+#[allow(dead_code)]
+pub struct ResolvedValues {
+    source: Value,
+    resolved: Value,
+    templates: HashMap<String, String>,
+    graph: DependencyGraph,
+    resolution_order: Vec<ValuePath>,
+}
+
+impl ResolvedValues {
+    /// The fully resolved values.
+    #[allow(dead_code)]
+    pub fn values(&self) -> &Value {
+        &self.resolved
+    }
+
+    #[allow(dead_code)]
+    pub fn into_values(self) -> Value {
+        self.resolved
+    }
+
+    /// Same as `apply_overrides_with`, using the default extractor and renderer.
+    #[allow(dead_code)]
+    pub fn apply_overrides(&self, overrides: &[(&str, Value)]) -> Result<ResolvedValues> {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let renderer = MiniJinjaRenderer::new();
+        self.apply_overrides_with(overrides, &extractor, &renderer)
+    }
+
+    /// Sets each `(path, value)` override on the unresolved values and re-renders only the
+    /// templates affected by them: those referencing an overridden path, a path beneath
+    /// it or one of its parents, and everything depending on those in turn. The result
+    /// is the same as resolving the overridden values from scratch.
+    ///
+    /// Overrides that add, remove or change a template invalidate the dependency graph,
+    /// so those fall back to a full resolution. `self` is left untouched and can be reused
+    /// with other overrides.
+    #[allow(dead_code)]
+    pub fn apply_overrides_with(
+        &self,
+        overrides: &[(&str, Value)],
+        extractor: &impl ReferenceExtractor,
+        renderer: &impl TemplateRenderer,
+    ) -> Result<ResolvedValues> {
+        let mut source = self.source.clone();
+        for (path, value) in overrides {
+            set_value_at_path_creating(&mut source, path, value.clone())?;
+        }
+
+        let PreparedValues {
+            values,
+            templates,
+            omit_paths,
+        } = prepare_values(source.clone(), extractor, &ResolveOptions::default())?;
+        if templates != self.templates {
+            trace!("Overrides changed the templates in the values, resolving from scratch.");
+            return resolve_cached_with(source, extractor, renderer);
+        }
+
+        // A path is affected when it overlaps an overridden or affected path, since
+        // referencing a mapping reads every template inside it
+        let mut affected: HashSet<String> = HashSet::new();
+        let mut changed: Vec<String> = overrides.iter().map(|(path, _)| path.to_string()).collect();
+        while let Some(changed_path) = changed.pop() {
+            let overlapping: Vec<&str> = self
+                .graph
+                .paths()
+                .filter(|node| !affected.contains(*node))
+                .filter(|node| is_within(node, &changed_path) || is_within(&changed_path, node))
+                .collect();
+            for node in overlapping {
+                let node = ValuePath::new(node);
+                let dependents = self.graph.transitive_dependents(&node);
+                for path in std::iter::once(node).chain(dependents) {
+                    if affected.insert(path.0.clone()) {
+                        changed.push(path.0);
+                    }
+                }
+            }
+        }
+
+        // Replay the resolution order so every template sees exactly what a full
+        // resolution would show it, reusing the earlier output where nothing changed
+        let mut resolved = RenderContext::new(values);
+        for path in &self.resolution_order {
+            let Some(template_str) = templates.get(path.as_str()) else {
+                continue;
+            };
+            let previous = get_value_at_path(&self.resolved, path.as_str());
+            let value = match previous {
+                Some(previous) if !affected.contains(path.as_str()) => previous.clone(),
+                _ => Value::String(render_at(renderer, path.as_str(), template_str, &resolved)?),
+            };
+            resolved.set(path.as_str(), value)?;
+        }
+        let mut resolved = resolved.into_values();
+        omit_empty_keys(&mut resolved, &omit_paths)?;
+
+        Ok(ResolvedValues {
+            source,
+            resolved,
+            templates,
+            graph: self.graph.clone(),
+            resolution_order: self.resolution_order.clone(),
+        })
+    }
+}
+
+/// Resolves all value references like `resolve_value_references`, keeping what is needed
+/// to apply overrides to the result cheaply afterwards.
+#[allow(dead_code)]
+pub fn resolve_cached(values: Value) -> Result<ResolvedValues> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let renderer = MiniJinjaRenderer::new();
+    resolve_cached_with(values, &extractor, &renderer)
+}
+
+/// Resolves all value references like `resolve_with`, keeping what is needed to apply
+/// overrides to the result cheaply afterwards.
+#[allow(dead_code)]
+pub fn resolve_cached_with(
+    values: Value,
+    extractor: &impl ReferenceExtractor,
+    renderer: &impl TemplateRenderer,
+) -> Result<ResolvedValues> {
+    let PreparedValues {
+        values: prepared,
+        templates,
+        omit_paths,
+    } = prepare_values(values.clone(), extractor, &ResolveOptions::default())?;
+    let graph = build_dependency_graph(&templates, extractor);
+    let resolution_order = graph.topological_sort()?;
+
+    let mut context = RenderContext::new(prepared);
+    for path in &resolution_order {
+        if let Some(template_str) = templates.get(path.as_str()) {
+            let rendered = render_at(renderer, path.as_str(), template_str, &context)?;
+            context.set(path.as_str(), Value::String(rendered))?;
+        }
+    }
+    let mut resolved = context.into_values();
+    omit_empty_keys(&mut resolved, &omit_paths)?;
+
+    Ok(ResolvedValues {
+        source: values,
+        resolved,
+        templates,
+        graph,
+        resolution_order,
+    })
+}
+
+/// Checks that every value reference in `values` would resolve, without resolving them.
+///
+/// Reports reference cycles, references to values that do not exist and templates that
+/// fail to parse or render. References guarded by `default` or `is defined` are allowed
+/// to be missing. The input is only read, so this is cheap to run over large trees.
+///
+/// # Errors
+///
+/// Returns the cycle error if there is one, otherwise a single error listing every
+/// failing value path.
+#[allow(dead_code)]
+pub fn validate_references(values: &Value) -> Result<()> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let mut templates = HashMap::new();
+    collect_template_values(values, "", &mut templates, &extractor, DEFAULT_MAX_DEPTH)?;
+
+    let graph = build_dependency_graph(&templates, &extractor);
+    graph.topological_sort()?;
+
+    // Referenced values are rendered unresolved, which is enough to find missing
+    // references and template errors. The context is converted once for all templates
+    let ctx = minijinja::Value::from_serialize(values);
+    let mut rendered = String::new();
+    let mut problems: Vec<String> = templates
+        .iter()
+        .filter_map(|(path, template_str)| {
+            rendered.clear();
+            render_inline_into(
+                INLINE_TEMPLATE_NAME,
+                template_str,
+                &ctx,
+                UndefinedBehavior::Strict,
+                None,
+                TemplateFunctions::default(),
+                &mut rendered,
+            )
+            .err()
+            .map(|err| format!("  {}: {}", path, err))
+        })
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    problems.sort();
+    Err(anyhow!(
+        "{} value reference(s) failed validation:\n{}",
+        problems.len(),
+        problems.join("\n")
+    ))
+}
+
+/// Resolves only the references whose value path lies under `root_path` (the path itself
+/// or anything nested beneath it) using default implementations.
+#[allow(dead_code)]
+pub fn resolve_subtree(values: Value, root_path: &str) -> Result<Value> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let renderer = MiniJinjaRenderer::new();
+    resolve_subtree_with(values, root_path, &extractor, &renderer)
+}
+
+/// Resolves only the references under `root_path`. Templates outside the subtree that
+/// those references depend on, directly or transitively, are rendered so the subtree
+/// sees their resolved values, but they are left untouched in the returned tree.
+/// A cycle anywhere in the values is still reported as an error.
+pub fn resolve_subtree_with(
+    mut values: Value,
+    root_path: &str,
+    extractor: &impl ReferenceExtractor,
+    renderer: &impl TemplateRenderer,
+) -> Result<Value> {
+    let mut templates = HashMap::new();
+    collect_template_values(&values, "", &mut templates, extractor, DEFAULT_MAX_DEPTH)?;
+
+    let in_subtree: HashSet<&str> = templates
+        .keys()
+        .map(String::as_str)
+        .filter(|path| is_within(path, root_path))
+        .collect();
+    if in_subtree.is_empty() {
+        return Ok(values);
+    }
+
+    let graph = build_dependency_graph(&templates, extractor);
+    let mut needed: HashSet<String> = HashSet::new();
+    for path in &in_subtree {
+        needed.insert(path.to_string());
+        needed.extend(
+            graph
+                .transitive_dependencies(&ValuePath::new(path))
+                .into_iter()
+                .map(|dependency| dependency.0),
+        );
+    }
+    let resolution_order = graph.topological_sort()?;
+
+    // Dependencies are rendered into a scratch copy so they are read resolved but never
+    // written back outside the subtree
+    let mut scratch = RenderContext::new(values.clone());
     for path in resolution_order {
+        if !needed.contains(path.as_str()) {
+            continue;
+        }
         if let Some(template_str) = templates.get(path.as_str()) {
-            let rendered = renderer.render(template_str, &values)?;
-            set_value_at_path(&mut values, path.as_str(), Value::String(rendered))?;
+            let rendered = render_at(renderer, path.as_str(), template_str, &scratch)?;
+            if in_subtree.contains(path.as_str()) {
+                set_value_at_path(&mut values, path.as_str(), Value::String(rendered.clone()))?;
+            }
+            scratch.set(path.as_str(), Value::String(rendered))?;
         }
     }
 
     Ok(values)
 }
 
-/// Recursively collects all value paths and their template strings
+/// True when `path` is `root` or nested beneath it as a key or sequence element.
+pub(crate) fn is_within(path: &str, root: &str) -> bool {
+    if root.is_empty() {
+        return true;
+    }
+    match path.strip_prefix(root) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with('['),
+        None => false,
+    }
+}
+
+/// Renders one ad-hoc template against an already merged values tree, exactly as the
+/// resolver would render a value holding it. The template is not added to the tree and
+/// no dependency graph is built, so references to other templates see them unresolved;
+/// resolve the values first to preview against final values.
+///
+/// Run by `test_render_against_example`, as rustdoc skips examples in a binary crate:
+///
+/// ```
+/// use crate::utils::value_resolver::render_against;
+/// use serde_yaml::Value;
+///
+/// let values: Value = serde_yaml::from_str("foo:\n  bar: hello").unwrap();
+/// assert_eq!(render_against(&values, "{{ foo.bar | upper }}").unwrap(), "HELLO");
+/// ```
+#[allow(dead_code)]
+pub fn render_against(values: &Value, template: &str) -> Result<String> {
+    MiniJinjaRenderer::new().render(template, values)
+}
+
+/// Checks if a string contains a value reference (`{{ }}` syntax), without constructing
+/// an extractor. Useful as a quick guard, e.g. to skip resolution entirely.
+///
+/// ```
+/// assert!(has_references("http://{{ host }}:80"));
+/// assert!(!has_references("plain value"));
+/// ```
+pub fn has_references(s: &str) -> bool {
+    contains_template_syntax(s)
+}
+
+/// Checks if any string anywhere in `values` contains a value reference, stopping at the
+/// first one found. Much cheaper than collecting templates when there are none.
+/// Values nested deeper than `DEFAULT_MAX_DEPTH` count as containing references, so the
+/// resolver gets to report them, as do `!raw` subtrees, so it gets to strip their tag,
+/// and strings with an unterminated `{{`, so it gets to reject them.
+#[allow(dead_code)]
+pub fn contains_references(values: &Value) -> bool {
+    contains_references_with(values, &[])
+}
+
+/// Like `contains_references`, also counting references written in any of `syntaxes`,
+/// such as `${ path }` for `ReferenceSyntax::Dollar`.
+pub fn contains_references_with(values: &Value, syntaxes: &[ReferenceSyntax]) -> bool {
+    contains_references_within(values, syntaxes, DEFAULT_MAX_DEPTH)
+}
+
+fn contains_references_within(
+    values: &Value,
+    syntaxes: &[ReferenceSyntax],
+    max_depth: usize,
+) -> bool {
+    let within = |value: &Value| {
+        max_depth == 0 || contains_references_within(value, syntaxes, max_depth - 1)
+    };
+    match values {
+        Value::String(s) => {
+            has_references(&normalize_syntax(s, syntaxes)) || unterminated_expression(s).is_some()
+        }
+        Value::Mapping(map) => map.values().any(within),
+        Value::Sequence(seq) => seq.iter().any(within),
+        // The resolver still has to strip the tag from a raw subtree
+        Value::Tagged(tagged) if tagged.tag == RAW_TAG || tagged.tag == OMIT_EMPTY_TAG => true,
+        Value::Tagged(tagged) => contains_references_within(&tagged.value, syntaxes, max_depth),
+        _ => false,
+    }
+}
+
+/// Returns the `(path, value)` pairs of every string that still contains template
+/// syntax, sorted by path. Run after resolution this catches values that rendered to
+/// another template expression, which a single resolution pass leaves untouched.
+#[allow(dead_code)]
+pub fn find_unresolved(values: &Value) -> Result<Vec<(String, String)>> {
+    find_unresolved_except(values, &HashSet::new())
+}
+
+/// Same as `find_unresolved`, leaving out the strings at `frozen_paths`, which are kept
+/// as written, `{{ }}` included.
+pub fn find_unresolved_except(
+    values: &Value,
+    frozen_paths: &HashSet<String>,
+) -> Result<Vec<(String, String)>> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let mut templates = HashMap::new();
+    collect_unfrozen_template_values(
+        values,
+        "",
+        &mut templates,
+        &extractor,
+        DEFAULT_MAX_DEPTH,
+        frozen_paths,
+    )?;
+    let mut unresolved: Vec<(String, String)> = templates.into_iter().collect();
+    unresolved.sort();
+    Ok(unresolved)
+}
+
+/// Groups every reference to a path that is not defined in `values` by that path, listing
+/// the value paths that reference it in order. This is what `fail_on_undefined` would
+/// reject, so references guarded by `default` are included.
+///
+/// ```
+/// let values: Value = serde_yaml::from_str("web: '{{ db_host }}'\napi: '{{ db_host }}'")?;
+///
+/// let missing = missing_references(&values)?;
+/// assert_eq!(missing["db_host"], vec!["api", "web"]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn missing_references(values: &Value) -> Result<BTreeMap<String, Vec<String>>> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let mut templates = HashMap::new();
+    collect_template_values(values, "", &mut templates, &extractor, DEFAULT_MAX_DEPTH)?;
+
+    let mut missing: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut stripped;
+    let mut defined = values;
+    if !raw_paths(values).is_empty() {
+        stripped = values.clone();
+        strip_raw_tags(&mut stripped);
+        defined = &stripped;
+    }
+    for (path, reference) in undefined_references(defined, &templates, &extractor) {
+        missing.entry(reference).or_default().push(path);
+    }
+    Ok(missing)
+}
+
+/// Lists the value paths each template in `values` references, keyed by the path of the
+/// template, e.g. `{"url": ["db.host", "db.port"]}`. References are in the order they
+/// appear in the template, and values under a `!raw` tag are not templates.
+#[allow(dead_code)]
+pub fn value_references(values: &Value) -> Result<BTreeMap<String, Vec<String>>> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let mut templates = HashMap::new();
+    collect_template_values(values, "", &mut templates, &extractor, DEFAULT_MAX_DEPTH)?;
+    Ok(templates
+        .into_iter()
+        .map(|(path, template_str)| {
+            let mut references = extractor.extract_references(&template_str);
+            let mut seen = HashSet::new();
+            references.retain(|reference| seen.insert(reference.clone()));
+            (path, references)
+        })
+        .collect())
+}
+
+/// Describes how `values` would be resolved, without resolving them, as a JSON array for
+/// external tools. Each template is listed in the order it would be rendered, with the
+/// paths it waits for:
+///
+/// ```json
+/// [
+///   { "path": "db.url", "template": "{{ db.host }}:{{ db.port }}", "depends_on": ["db.host", "db.port"] }
+/// ]
+/// ```
+///
+/// `depends_on` includes the templates inside a mapping or sequence a template
+/// references, as those are rendered first.
+///
+/// # Errors
+///
+/// Fails like resolution does before rendering, e.g. on circular references,
+/// unterminated templates and values nested too deeply.
+#[allow(dead_code)]
+pub fn resolution_plan_json(values: &Value) -> Result<String> {
+    let extractor = MiniJinjaReferenceExtractor::new();
+    let PreparedValues { templates, .. } =
+        prepare_values(values.clone(), &extractor, &ResolveOptions::default())?;
+    let graph = build_dependency_graph(&templates, &extractor);
+    let plan: Vec<serde_json::Value> = graph
+        .topological_sort()?
+        .into_iter()
+        .filter_map(|path| {
+            let template = templates.get(path.as_str())?;
+            let depends_on: Vec<String> = graph
+                .dependencies(&path)
+                .iter()
+                .map(|dependency| dependency.as_str().to_string())
+                .collect();
+            Some(serde_json::json!({
+                "path": path.as_str(),
+                "template": template,
+                "depends_on": depends_on,
+            }))
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&plan)?)
+}
+
+/// Recursively collects all value paths and their template strings, failing if
+/// mappings and sequences nest more than `max_depth` levels below `value`.
 fn collect_template_values(
     value: &Value,
     current_path: &str,
     templates: &mut HashMap<String, String>,
     extractor: &impl ReferenceExtractor,
-) {
+    max_depth: usize,
+) -> Result<()> {
+    collect_unfrozen_template_values(
+        value,
+        current_path,
+        templates,
+        extractor,
+        max_depth,
+        &HashSet::new(),
+    )
+}
+
+/// Same as `collect_template_values`, skipping the strings at `frozen_paths` without
+/// checking them, as they are kept exactly as written.
+fn collect_unfrozen_template_values(
+    value: &Value,
+    current_path: &str,
+    templates: &mut HashMap<String, String>,
+    extractor: &impl ReferenceExtractor,
+    max_depth: usize,
+    frozen_paths: &HashSet<String>,
+) -> Result<()> {
+    if max_depth == 0 && (value.is_mapping() || value.is_sequence()) {
+        return Err(too_deep(current_path));
+    }
     match value {
-        Value::String(s) if extractor.contains_template(s) => {
-            templates.insert(current_path.to_string(), s.clone());
+        Value::String(_) if frozen_paths.contains(current_path) => {}
+        Value::String(s) => {
+            if let Some(expression) = unterminated_expression(s) {
+                return Err(anyhow!(
+                    "Unterminated template expression at '{}': '{}' has no closing '}}}}'",
+                    current_path,
+                    expression.chars().take(40).collect::<String>()
+                ));
+            }
+            if extractor.contains_template(s) {
+                let template_str = resolve_relative_references(s, current_path)?;
+                templates.insert(current_path.to_string(), template_str.into_owned());
+            }
         }
         Value::Mapping(map) => {
             for (key, val) in map {
@@ -101,82 +1262,504 @@ fn collect_template_values(
                     } else {
                         format!("{}.{}", current_path, key_str)
                     };
-                    collect_template_values(val, &new_path, templates, extractor);
+                    collect_unfrozen_template_values(
+                        val,
+                        &new_path,
+                        templates,
+                        extractor,
+                        max_depth - 1,
+                        frozen_paths,
+                    )?;
                 }
             }
         }
         Value::Sequence(seq) => {
             for (idx, val) in seq.iter().enumerate() {
                 let new_path = format!("{}[{}]", current_path, idx);
-                collect_template_values(val, &new_path, templates, extractor);
+                collect_unfrozen_template_values(
+                    val,
+                    &new_path,
+                    templates,
+                    extractor,
+                    max_depth - 1,
+                    frozen_paths,
+                )?;
             }
         }
+        // Everything under a `!raw` node is kept as written
+        Value::Tagged(tagged) if tagged.tag == RAW_TAG => {}
         _ => {}
     }
+    Ok(())
 }
 
-/// Builds the dependency graph from template values
-fn build_dependency_graph(
-    templates: &HashMap<String, String>,
-    extractor: &impl ReferenceExtractor,
-) -> DependencyGraph {
-    let mut graph = DependencyGraph::new();
-
-    for (path, template_str) in templates {
-        let from = ValuePath::new(path);
-        graph.add_node(&from);
+/// Tag marking a mapping or sequence whose strings are meant for another renderer, e.g.
+/// `alerts: !raw { summary: "{{ $labels.instance }} is down" }`. The resolver leaves
+/// everything under it as written and removes the tag from its output.
+pub const RAW_TAG: &str = "raw";
 
-        let refs = extractor.extract_references(template_str);
-        for ref_path in refs {
-            let to = ValuePath::new(&ref_path);
-            graph.add_dependency(&from, &to);
+/// Replaces every `!raw` node in `value` with its untouched contents.
+fn strip_raw_tags(value: &mut Value) {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == RAW_TAG => {
+            let inner = std::mem::take(&mut tagged.value);
+            *value = inner;
         }
+        Value::Mapping(map) => map.values_mut().for_each(strip_raw_tags),
+        Value::Sequence(seq) => seq.iter_mut().for_each(strip_raw_tags),
+        _ => {}
     }
-
-    graph
 }
 
-/// Sets a value at a given path (supports nested paths like "a.b.c")
-fn set_value_at_path(value: &mut Value, path: &str, new_val: Value) -> Result<()> {
-    let parts: Vec<&str> = path.split('.').collect();
+/// Tag marking a value whose key is removed from the resolved values when it resolves to
+/// an empty string or null, e.g. `hostname: !omitempty "{{ host }}"`, for optional
+/// fields that should be left out rather than set to `""`. The tag is always removed;
+/// a tagged item of a sequence is kept, as removing it would shift the items after it.
+pub const OMIT_EMPTY_TAG: &str = "omitempty";
 
-    if parts.is_empty() {
-        return Err(anyhow!("Empty path"));
+/// Replaces every `!omitempty` node in `value` with its contents, adding its path to
+/// `paths`. Nodes under `!raw` are left as written.
+fn take_omit_empty_tags(value: &mut Value, current_path: &str, paths: &mut Vec<String>) {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == OMIT_EMPTY_TAG => {
+            let inner = std::mem::take(&mut tagged.value);
+            *value = inner;
+            paths.push(current_path.to_string());
+            take_omit_empty_tags(value, current_path, paths);
+        }
+        Value::Mapping(map) => {
+            for (key, val) in map.iter_mut() {
+                if let Value::String(key_str) = key {
+                    let new_path = if current_path.is_empty() {
+                        key_str.clone()
+                    } else {
+                        format!("{}.{}", current_path, key_str)
+                    };
+                    take_omit_empty_tags(val, &new_path, paths);
+                }
+            }
+        }
+        Value::Sequence(seq) => {
+            for (idx, val) in seq.iter_mut().enumerate() {
+                take_omit_empty_tags(val, &format!("{}[{}]", current_path, idx), paths);
+            }
+        }
+        _ => {}
     }
+}
 
-    if parts.len() == 1 {
-        if let Value::Mapping(map) = value {
-            map.insert(Value::String(parts[0].to_string()), new_val);
-            return Ok(());
+/// Removes the mapping key at each of `paths` whose value is an empty string or null,
+/// keeping the order of the keys around it.
+fn omit_empty_keys(values: &mut Value, paths: &[String]) -> Result<()> {
+    for path in paths {
+        let segments = ValuePath::new(path).segments()?;
+        let Some((PathSegment::Key(key), parents)) = segments.split_last() else {
+            continue;
+        };
+        let parent =
+            parents
+                .iter()
+                .try_fold(&mut *values, |current, segment| match (segment, current) {
+                    (PathSegment::Key(key), Value::Mapping(map)) => map.get_mut(key.as_str()),
+                    (PathSegment::Index(index), Value::Sequence(seq)) => seq.get_mut(*index),
+                    _ => None,
+                });
+        if let Some(Value::Mapping(map)) = parent {
+            let is_empty = match map.get(key.as_str()) {
+                Some(Value::Null) => true,
+                Some(value) => value.as_str() == Some(""),
+                None => false,
+            };
+            if is_empty {
+                trace!("Omitting empty value '{}'.", path);
+                map.shift_remove(key.as_str());
+            }
         }
-        return Err(anyhow!("Cannot set value at path '{}': not a mapping", path));
     }
+    Ok(())
+}
 
-    let mut current = value;
-    for (i, part) in parts.iter().enumerate() {
-        if i == parts.len() - 1 {
-            // Last part - set the value
-            if let Value::Mapping(map) = current {
-                map.insert(Value::String(part.to_string()), new_val);
-                return Ok(());
+/// Returns the value path of every `!raw` node in `values`, which keep their template
+/// syntax through resolution.
+pub fn raw_paths(values: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_raw_paths(values, "", &mut paths);
+    paths
+}
+
+fn collect_raw_paths(value: &Value, current_path: &str, paths: &mut Vec<String>) {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == RAW_TAG => paths.push(current_path.to_string()),
+        Value::Mapping(map) => {
+            for (key, val) in map {
+                if let Value::String(key_str) = key {
+                    let new_path = if current_path.is_empty() {
+                        key_str.clone()
+                    } else {
+                        format!("{}.{}", current_path, key_str)
+                    };
+                    collect_raw_paths(val, &new_path, paths);
+                }
+            }
+        }
+        Value::Sequence(seq) => {
+            for (idx, val) in seq.iter().enumerate() {
+                collect_raw_paths(val, &format!("{}[{}]", current_path, idx), paths);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The error for values nested deeper than the configured maximum, at `path`.
+pub(crate) fn too_deep(path: &str) -> anyhow::Error {
+    anyhow!(
+        "Values are nested too deeply at '{}'. Check for a runaway structure, or raise the maximum depth.",
+        path
+    )
+}
+
+/// Builds the dependency graph from template values
+fn build_dependency_graph(
+    templates: &HashMap<String, String>,
+    extractor: &impl ReferenceExtractor,
+) -> DependencyGraph {
+    let _span =
+        tracing::debug_span!("build_dependency_graph", templates = templates.len()).entered();
+    let mut graph = DependencyGraph::new();
+    let mut sorted_paths: Vec<&String> = templates.keys().collect();
+    sorted_paths.sort();
+
+    for (path, template_str) in templates {
+        let from = ValuePath::new(path);
+        graph.add_node(&from);
+
+        let refs = extractor.extract_references(template_str);
+        for ref_path in refs {
+            // A reference to a mapping or sequence (e.g. `{{ config | tojson }}`) sees
+            // every template inside it, so those render first. Templates inside the
+            // container they reference see their siblings unresolved instead, as
+            // ordering them would make the siblings depend on each other.
+            if !is_within(path, &ref_path) {
+                let start = sorted_paths.partition_point(|p| p.as_str() <= ref_path.as_str());
+                for inner in sorted_paths[start..]
+                    .iter()
+                    .take_while(|p| p.starts_with(ref_path.as_str()))
+                    .filter(|p| is_within(p, &ref_path))
+                {
+                    graph.add_dependency(&from, &ValuePath::new(inner));
+                }
+            }
+            let to = ValuePath::new(&ref_path);
+            graph.add_dependency(&from, &to);
+        }
+    }
+
+    graph
+}
+
+/// Returns `(template path, reference)` for every reference to a path that is not defined
+/// in `values`, sorted by template path. The dependency graph cannot tell these apart,
+/// since it creates a node for every referenced path.
+fn undefined_references(
+    values: &Value,
+    templates: &HashMap<String, String>,
+    extractor: &impl ReferenceExtractor,
+) -> Vec<(String, String)> {
+    let mut undefined: Vec<(String, String)> = templates
+        .iter()
+        .flat_map(|(path, template_str)| {
+            extractor
+                .extract_references(template_str)
+                .into_iter()
+                .filter(|reference| get_value_at_path(values, reference).is_none())
+                .map(move |reference| (path.clone(), reference))
+        })
+        .collect();
+    undefined.sort();
+    undefined.dedup();
+    undefined
+}
+
+/// Fails on the first reference (by template path) that indexes past the end of a
+/// sequence. Sequences only reach their final length once every file is merged, so an
+/// index can be valid or not depending on file order; this names the culprit instead of
+/// leaving it to render as empty or fail further in. References the template guards, as
+/// in `{{ items[2] | default('x') }}` or inside an `{% if %}` block, are left to it.
+fn check_sequence_bounds(
+    values: &Value,
+    templates: &HashMap<String, String>,
+    extractor: &impl ReferenceExtractor,
+) -> Result<()> {
+    let mut paths: Vec<&String> = templates.keys().collect();
+    paths.sort();
+    for path in paths {
+        for reference in extractor.extract_references(&templates[path]) {
+            if !reference.contains('[') || reference_is_guarded(&templates[path], &reference) {
+                continue;
             }
-            return Err(anyhow!(
-                "Cannot set value at path '{}': parent is not a mapping",
-                path
-            ));
-        } else {
-            // Navigate deeper
-            if let Value::Mapping(map) = current {
-                current = map
-                    .get_mut(Value::String(part.to_string()))
-                    .ok_or_else(|| anyhow!("Path not found: {}", path))?;
-            } else {
-                return Err(anyhow!("Cannot navigate path '{}': not a mapping", path));
+            let Ok(segments) = ValuePath::new(&reference).segments() else {
+                continue;
+            };
+            let mut current = values;
+            let mut walked = String::new();
+            for segment in segments {
+                let next = match (segment, current) {
+                    (PathSegment::Key(key), Value::Mapping(map)) => {
+                        if !walked.is_empty() {
+                            walked.push('.');
+                        }
+                        walked.push_str(&key);
+                        map.get(key.as_str())
+                    }
+                    (PathSegment::Index(index), Value::Sequence(seq)) => {
+                        if index >= seq.len() {
+                            return Err(ResolveError::IndexOutOfRange {
+                                path: path.clone(),
+                                reference,
+                                sequence: walked,
+                                length: seq.len(),
+                            }
+                            .into());
+                        }
+                        walked.push_str(&format!("[{}]", index));
+                        seq.get(index)
+                    }
+                    _ => None,
+                };
+                match next {
+                    Some(next) => current = next,
+                    None => break,
+                }
             }
         }
     }
+    Ok(())
+}
+
+/// Looks up the value at a path like "a.b[0].c", navigating the same way
+/// `set_value_at_path` does. Returns `None` if the path does not exist, passes through a
+/// value of the wrong type (such as an index into a mapping), or is not a valid path.
+pub fn get_value_at_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let segments = ValuePath::new(path).segments().ok()?;
+    segments
+        .iter()
+        .try_fold(value, |current, segment| match (segment, current) {
+            (PathSegment::Key(key), Value::Mapping(map)) => map.get(key.as_str()),
+            (PathSegment::Index(index), Value::Sequence(seq)) => seq.get(*index),
+            _ => None,
+        })
+}
+
+/// Looks up the value at a path like "a.b[0].c" for writing, if there is one.
+fn value_at_path_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    let segments = ValuePath::new(path).segments().ok()?;
+    segments
+        .iter()
+        .try_fold(value, |current, segment| match (segment, current) {
+            (PathSegment::Key(key), Value::Mapping(map)) => map.get_mut(key.as_str()),
+            (PathSegment::Index(index), Value::Sequence(seq)) => seq.get_mut(*index),
+            _ => None,
+        })
+}
+
+/// The type `coerce_values` converts a resolved value to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ScalarType {
+    /// `true` or `false`, in any case.
+    Bool,
+    /// A whole number that fits in an `i64`.
+    Int,
+    /// Any finite number.
+    Float,
+}
+
+impl ScalarType {
+    fn name(self) -> &'static str {
+        match self {
+            ScalarType::Bool => "bool",
+            ScalarType::Int => "int",
+            ScalarType::Float => "float",
+        }
+    }
+
+    /// Converts `value` to this type, or returns `None` if it cannot be read as one.
+    fn coerce(self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (ScalarType::Bool, Value::Bool(_)) => Some(value.clone()),
+            (ScalarType::Bool, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (ScalarType::Int, Value::Number(n)) if n.is_i64() || n.is_u64() => Some(value.clone()),
+            (ScalarType::Int, Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+            (ScalarType::Float, Value::Number(n)) => n.as_f64().map(Value::from),
+            (ScalarType::Float, Value::String(s)) => s
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|f| f.is_finite())
+                .map(Value::from),
+            _ => None,
+        }
+    }
+}
+
+/// Converts the resolved values at the given paths to the requested types, so a reference
+/// that renders `"true"` or `"8080"` can feed a struct expecting a `bool` or a number.
+/// Values that already have the type are kept, and paths that do not exist or hold `null`
+/// are skipped, so optional settings can be listed too.
+///
+/// # Errors
+///
+/// Returns an error listing every value that cannot be read as its type.
+#[allow(dead_code)]
+pub fn coerce_values(values: &mut Value, types: &BTreeMap<String, ScalarType>) -> Result<()> {
+    let mut failures = Vec::new();
+    for (path, scalar_type) in types {
+        let Some(value) = value_at_path_mut(values, path) else {
+            continue;
+        };
+        if value.is_null() {
+            continue;
+        }
+        match scalar_type.coerce(value) {
+            Some(coerced) => *value = coerced,
+            None => failures.push(format!(
+                "  {}: {} is not a valid {}",
+                path,
+                serde_yaml::to_string(value)
+                    .map(|s| s.trim_end().to_string())
+                    .unwrap_or_default(),
+                scalar_type.name()
+            )),
+        }
+    }
+    if failures.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{} value(s) cannot be coerced to the expected type:\n{}",
+        failures.len(),
+        failures.join("\n")
+    ))
+}
+
+/// Sets a value at a given path (supports nested paths like "a.b.c" and indices like "a[0].b")
+fn set_value_at_path(value: &mut Value, path: &str, new_val: Value) -> Result<()> {
+    set_value_at_path_with(value, path, new_val, false)
+}
+
+/// Sets the value at `path`, creating any missing intermediate mappings first, like
+/// `mkdir -p`. An intermediate `null` is replaced by a mapping too. Sequence elements
+/// are never created, so every `[n]` segment must already exist.
+///
+/// The resolver's own write-back uses the strict variant, since the paths it writes were
+/// collected from the same tree; this one is for applying overrides to arbitrary paths.
+#[allow(dead_code)]
+pub fn set_value_at_path_creating(value: &mut Value, path: &str, new_val: Value) -> Result<()> {
+    set_value_at_path_with(value, path, new_val, true)
+}
+
+/// Removes the value at `path` along with everything beneath it, returning whether
+/// anything was removed. Removing a sequence item shifts the later items down. A path
+/// that does not exist, including one that runs through a scalar, is not an error.
+#[allow(dead_code)]
+pub fn remove_value_at_path(value: &mut Value, path: &str) -> Result<bool> {
+    let segments = ValuePath::new(path).segments()?;
+    let (last, parents) = segments.split_last().ok_or_else(|| anyhow!("Empty path"))?;
+
+    let mut current = value;
+    for segment in parents {
+        let next = match (segment, current) {
+            (PathSegment::Key(key), Value::Mapping(map)) => map.get_mut(key.as_str()),
+            (PathSegment::Index(index), Value::Sequence(seq)) => seq.get_mut(*index),
+            _ => None,
+        };
+        match next {
+            Some(next) => current = next,
+            None => return Ok(false),
+        }
+    }
+
+    Ok(match (last, current) {
+        (PathSegment::Key(key), Value::Mapping(map)) => map.shift_remove(key.as_str()).is_some(),
+        (PathSegment::Index(index), Value::Sequence(seq)) if *index < seq.len() => {
+            seq.remove(*index);
+            true
+        }
+        _ => false,
+    })
+}
+
+fn set_value_at_path_with(
+    value: &mut Value,
+    path: &str,
+    new_val: Value,
+    create_missing: bool,
+) -> Result<()> {
+    let segments = ValuePath::new(path).segments()?;
+    if segments.len() > DEFAULT_MAX_DEPTH {
+        return Err(too_deep(path));
+    }
+    let (last, parents) = segments.split_last().ok_or_else(|| anyhow!("Empty path"))?;
+
+    let mut current = value;
+    for segment in parents {
+        if create_missing && current.is_null() && matches!(segment, PathSegment::Key(_)) {
+            *current = Value::Mapping(Mapping::new());
+        }
+        current = match (segment, current) {
+            (PathSegment::Key(key), Value::Mapping(map)) => {
+                if create_missing && !map.contains_key(key.as_str()) {
+                    map.insert(Value::String(key.clone()), Value::Mapping(Mapping::new()));
+                }
+                map.get_mut(key.as_str())
+                    .ok_or_else(|| anyhow!("Path not found: {}", path))?
+            }
+            (PathSegment::Index(index), Value::Sequence(seq)) => seq
+                .get_mut(*index)
+                .ok_or_else(|| anyhow!("Path not found: {}", path))?,
+            (PathSegment::Key(_), _) => {
+                return Err(anyhow!("Cannot navigate path '{}': not a mapping", path))
+            }
+            (PathSegment::Index(_), _) => {
+                return Err(anyhow!("Cannot navigate path '{}': not a sequence", path))
+            }
+        };
+    }
 
-    Err(anyhow!("Failed to set value at path: {}", path))
+    if create_missing && current.is_null() && matches!(last, PathSegment::Key(_)) {
+        *current = Value::Mapping(Mapping::new());
+    }
+    match (last, current) {
+        (PathSegment::Key(key), Value::Mapping(map)) => {
+            map.insert(Value::String(key.clone()), new_val);
+            Ok(())
+        }
+        (PathSegment::Index(index), Value::Sequence(seq)) => match seq.get_mut(*index) {
+            Some(slot) => {
+                *slot = new_val;
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "Cannot set value at path '{}': index {} is out of range for a sequence of length {}",
+                path,
+                index,
+                seq.len()
+            )),
+        },
+        (PathSegment::Key(_), _) => Err(anyhow!(
+            "Cannot set value at path '{}': parent is not a mapping",
+            path
+        )),
+        (PathSegment::Index(_), _) => Err(anyhow!(
+            "Cannot set value at path '{}': parent is not a sequence",
+            path
+        )),
+    }
 }
 
 #[cfg(test)]
@@ -211,236 +1794,2131 @@ result: "{{ parent.child.value }}"
         let resolved = resolve_value_references(values).unwrap();
 
         assert_eq!(
-            resolved.get("result").unwrap(),
-            &Value::String("nested".to_string())
+            resolved.get("result").unwrap(),
+            &Value::String("nested".to_string())
+        );
+    }
+
+    #[test]
+    fn test_chained_references() {
+        let yaml = r#"
+a: "base"
+b: "{{ a }}-extended"
+c: "{{ b }}-final"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("c").unwrap(),
+            &Value::String("base-extended-final".to_string())
+        );
+    }
+
+    #[test]
+    fn test_filter_support_upper() {
+        let yaml = r#"
+name: "world"
+greeting: "{{ name | upper }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("greeting").unwrap(),
+            &Value::String("WORLD".to_string())
+        );
+    }
+
+    #[test]
+    fn test_filter_support_lower() {
+        let yaml = r#"
+name: "HELLO"
+greeting: "{{ name | lower }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("greeting").unwrap(),
+            &Value::String("hello".to_string())
+        );
+    }
+
+    #[test]
+    fn test_default_filter() {
+        let yaml = r#"
+message: "{{ undefined_var | default('fallback') }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("message").unwrap(),
+            &Value::String("fallback".to_string())
+        );
+    }
+
+    #[test]
+    fn test_circular_dependency_error() {
+        let yaml = r#"
+a: "{{ b }}"
+b: "{{ c }}"
+c: "{{ a }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let result = resolve_value_references(values);
+
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("Circular dependency"),
+            "Error should mention circular dependency: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_self_reference_error() {
+        let yaml = r#"
+a: "{{ a }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let result = resolve_value_references(values);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_non_string_values_unchanged() {
+        let yaml = r#"
+number: 42
+boolean: true
+list:
+  - item1
+  - item2
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values.clone()).unwrap();
+
+        assert_eq!(
+            resolved.get("number").unwrap(),
+            values.get("number").unwrap()
+        );
+        assert_eq!(
+            resolved.get("boolean").unwrap(),
+            values.get("boolean").unwrap()
+        );
+        assert_eq!(resolved.get("list").unwrap(), values.get("list").unwrap());
+    }
+
+    #[test]
+    fn test_multiple_references_in_one_value() {
+        let yaml = r#"
+part1: "world"
+part2: "hello"
+message: "{{ part2 }} {{ part1 }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("message").unwrap(),
+            &Value::String("hello world".to_string())
+        );
+    }
+
+    #[test]
+    fn test_no_templates_passthrough() {
+        let yaml = r#"
+simple: "value"
+another: "plain text"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values.clone()).unwrap();
+
+        assert_eq!(resolved, values);
+    }
+
+    #[test]
+    fn test_nested_target_value() {
+        let yaml = r#"
+source: "hello"
+target:
+  nested:
+    value: "{{ source }} world"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        let target = resolved.get("target").unwrap();
+        let nested = target.get("nested").unwrap();
+        let value = nested.get("value").unwrap();
+
+        assert_eq!(value, &Value::String("hello world".to_string()));
+    }
+
+    #[test]
+    fn test_deeply_nested_reference() {
+        let yaml = r#"
+level1:
+  level2:
+    level3:
+      source: "deep"
+result: "{{ level1.level2.level3.source }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("result").unwrap(),
+            &Value::String("deep".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mixed_static_and_dynamic_text() {
+        let yaml = r#"
+name: "Alice"
+greeting: "Hello, {{ name }}! Welcome to the system."
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("greeting").unwrap(),
+            &Value::String("Hello, Alice! Welcome to the system.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_complex_dependency_chain() {
+        let yaml = r#"
+base: "foundation"
+layer1: "{{ base }}-layer1"
+layer2: "{{ layer1 }}-layer2"
+final: "{{ layer2 }}-complete"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("final").unwrap(),
+            &Value::String("foundation-layer1-layer2-complete".to_string())
+        );
+    }
+
+    #[test]
+    fn test_diamond_dependency() {
+        // Both branch1 and branch2 depend on root
+        // final depends on both branches
+        let yaml = r#"
+root: "base"
+branch1: "{{ root }}-b1"
+branch2: "{{ root }}-b2"
+final: "{{ branch1 }} and {{ branch2 }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            resolved.get("final").unwrap(),
+            &Value::String("base-b1 and base-b2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_find_unresolved_reports_remaining_templates() {
+        let yaml = r#"
+plain: "value"
+nested:
+  leftover: "{{ missing }}"
+list:
+  - "{{ other }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let unresolved = find_unresolved(&values).unwrap();
+
+        assert_eq!(
+            unresolved,
+            vec![
+                ("list[0]".to_string(), "{{ other }}".to_string()),
+                ("nested.leftover".to_string(), "{{ missing }}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_unresolved_after_template_renders_to_template() {
+        let yaml = r#"
+b: "value"
+a: "{{ '{{' }} b }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+
+        assert_eq!(
+            find_unresolved(&resolved).unwrap(),
+            vec![("a".to_string(), "{{ b }}".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_find_unresolved_empty_when_fully_resolved() {
+        let yaml = r#"
+greeting: "hello"
+message: "{{ greeting }} world"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_value_references(values).unwrap();
+        assert!(find_unresolved(&resolved).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resolve_subtree_only_rewrites_subtree() {
+        let yaml = r#"
+base: "hello"
+outside: "{{ base }} outside"
+app:
+  greeting: "{{ outside }} and in"
+  name: "{{ base | upper }}"
+other:
+  untouched: "{{ base }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_subtree(values, "app").unwrap();
+
+        assert_eq!(
+            resolved["app"]["greeting"],
+            Value::String("hello outside and in".to_string())
+        );
+        assert_eq!(resolved["app"]["name"], Value::String("HELLO".to_string()));
+        // The dependency was read resolved but is not rewritten
+        assert_eq!(
+            resolved["outside"],
+            Value::String("{{ base }} outside".to_string())
+        );
+        assert_eq!(
+            resolved["other"]["untouched"],
+            Value::String("{{ base }}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_subtree_matches_full_resolution_within_subtree() {
+        let yaml = r#"
+a: "x"
+b: "{{ a }}-b"
+nested:
+  c: "{{ b }}-c"
+  deeper:
+    d: "{{ nested.c }}-d"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let partial = resolve_subtree(values.clone(), "nested").unwrap();
+        let full = resolve_value_references(values).unwrap();
+
+        assert_eq!(partial["nested"], full["nested"]);
+        assert_eq!(partial["b"], Value::String("{{ a }}-b".to_string()));
+    }
+
+    #[test]
+    fn test_resolve_subtree_does_not_match_key_prefixes() {
+        let yaml = r#"
+base: "v"
+app: "{{ base }}"
+apple: "{{ base }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let resolved = resolve_subtree(values, "app").unwrap();
+
+        assert_eq!(resolved["app"], Value::String("v".to_string()));
+        assert_eq!(resolved["apple"], Value::String("{{ base }}".to_string()));
+    }
+
+    #[test]
+    fn test_render_into_appends_and_keeps_buffer_on_error() {
+        let renderer = MiniJinjaRenderer::new();
+        let context: Value = from_str("name: world\ncount: 3").unwrap();
+        let mut buf = String::from("> ");
+
+        renderer
+            .render_into("hello {{ name }}", &context, &mut buf)
+            .unwrap();
+        renderer
+            .render_into(", {{ count * 2 }}", &context, &mut buf)
+            .unwrap();
+        renderer
+            .render_into(" → {{ 'héllo' | upper }} ✓", &context, &mut buf)
+            .unwrap();
+        assert_eq!(buf, "> hello world, 6 → HÉLLO ✓");
+
+        let err = renderer.render_into("{{ name | nosuchfilter }}", &context, &mut buf);
+        assert!(err.is_err());
+        assert_eq!(buf, "> hello world, 6 → HÉLLO ✓");
+        assert_eq!(
+            renderer.render("hello {{ name }}", &context).unwrap(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn test_render_error_includes_location_and_expression() {
+        let renderer = MiniJinjaRenderer::new();
+        let context: Value = from_str("name: world").unwrap();
+
+        let err = renderer
+            .render("first line\nhello {{ name | nosuchfilter }}", &context)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("at line 2"), "missing line: {}", err);
+        assert!(err.contains("column"), "missing column: {}", err);
+        assert!(err.contains("nosuchfilter"), "missing expression: {}", err);
+    }
+
+    #[test]
+    fn test_parse_error_includes_line() {
+        let renderer = MiniJinjaRenderer::new();
+        let err = renderer
+            .render("ok\n{{ name ", &Value::Null)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.starts_with("Failed to parse template"), "{}", err);
+        assert!(err.contains("at line 2"), "missing line: {}", err);
+    }
+
+    #[test]
+    fn test_render_named_errors_carry_the_name() {
+        let renderer = MiniJinjaRenderer::new();
+        let context = RenderContext::new(from_str("name: world").unwrap());
+        let template_name = |err: &anyhow::Error| {
+            err.chain()
+                .find_map(|cause| cause.downcast_ref::<minijinja::Error>())
+                .and_then(|err| err.name().map(str::to_string))
+        };
+
+        let mut buf = String::new();
+        let err = renderer
+            .render_named(
+                "services.web.url",
+                "{{ name | nosuchfilter }}",
+                &context,
+                &mut buf,
+            )
+            .unwrap_err();
+        assert_eq!(template_name(&err).as_deref(), Some("services.web.url"));
+
+        let err = renderer
+            .render_named("services.web.url", "{{ name ", &context, &mut buf)
+            .unwrap_err();
+        assert_eq!(template_name(&err).as_deref(), Some("services.web.url"));
+
+        let err = renderer
+            .render("{{ name | nosuchfilter }}", context.values())
+            .unwrap_err();
+        assert_eq!(template_name(&err).as_deref(), Some("inline"));
+    }
+
+    #[test]
+    fn test_render_error_truncates_long_templates() {
+        let renderer = MiniJinjaRenderer::new();
+        let template = format!("{}{{{{ name | nosuchfilter }}}}", "x".repeat(200));
+
+        let err = renderer
+            .render(&template, &Value::Null)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains(&format!("'{}...'", "x".repeat(80))), "{}", err);
+        assert!(!err.contains(&"x".repeat(81)), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_with_stats_counts_steps() {
+        let yaml = r#"
+host: "localhost"
+port: 8080
+url: "http://{{ host }}:{{ port }}"
+endpoint: "{{ url }}/api"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let renderer = MiniJinjaRenderer::new();
+
+        let (resolved, stats) = resolve_with_stats(values.clone(), &extractor, &renderer).unwrap();
+
+        assert_eq!(resolve_value_references(values).unwrap(), resolved);
+        assert_eq!(2, stats.templates);
+        assert_eq!(4, stats.graph_nodes);
+        assert_eq!(3, stats.graph_edges);
+        assert_eq!(2, stats.render_calls);
+    }
+
+    #[test]
+    fn test_cached_and_stats_match_resolution_with_tags() {
+        let values: Value = from_str(
+            r#"
+host: ""
+name: web
+hostname: !omitempty "{{ host }}"
+label: !omitempty "{{ name }}"
+alerts: !raw
+  summary: "{{ $labels.instance }} is down"
+"#,
+        )
+        .unwrap();
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let renderer = MiniJinjaRenderer::new();
+        let expected = resolve_value_references(values.clone()).unwrap();
+        assert!(expected.get("hostname").is_none());
+        assert_eq!(expected["label"], Value::from("web"));
+
+        let (resolved, stats) = resolve_with_stats(values.clone(), &extractor, &renderer).unwrap();
+        assert_eq!(resolved, expected);
+        assert_eq!(2, stats.templates);
+        assert_eq!(resolve_cached(values.clone()).unwrap().values(), &expected);
+
+        let out_of_range: Value = from_str(
+            "items: [a]
+b: '{{ items[3] }}'",
+        )
+        .unwrap();
+        assert!(resolve_with_stats(out_of_range.clone(), &extractor, &renderer).is_err());
+        assert!(resolve_cached(out_of_range).is_err());
+    }
+
+    #[test]
+    fn test_resolve_with_stats_no_templates() {
+        let values: Value = from_str("a: 1\nb: two").unwrap();
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let renderer = MiniJinjaRenderer::new();
+
+        let (resolved, stats) = resolve_with_stats(values.clone(), &extractor, &renderer).unwrap();
+
+        assert_eq!(values, resolved);
+        assert_eq!(0, stats.templates);
+        assert_eq!(0, stats.render_calls);
+    }
+
+    #[test]
+    fn test_commented_out_reference_does_not_create_dependency() {
+        // `b` would form a cycle with `a` if the commented reference were followed
+        let yaml = r#"
+a: "{{ c }}{# previously {{ b }} #}"
+b: "{{ a }}"
+c: "value"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let result = resolve_value_references(values).unwrap();
+
+        assert_eq!(result["a"], Value::String("value".to_string()));
+        assert_eq!(result["b"], Value::String("value".to_string()));
+    }
+
+    #[test]
+    fn test_json_pointer_references_resolve_in_order() {
+        let yaml = r#"
+config:
+  greeting: "hi"
+  "a/b": "slash"
+message: "{{ /shout }} world"
+shout: "{{ /config/greeting | upper }}"
+items:
+  - name: "{{ /config/a~1b }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let result = resolve_value_references(values).unwrap();
+
+        assert_eq!(result["shout"], Value::String("HI".to_string()));
+        assert_eq!(result["message"], Value::String("HI world".to_string()));
+        assert_eq!(
+            result["items"][0]["name"],
+            Value::String("slash".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_references_accepts_resolvable_values() {
+        let yaml = r#"
+host: "localhost"
+url: "http://{{ host }}"
+endpoint: "{{ url }}/api"
+fallback: "{{ missing | default('none') }}"
+guarded: "{% if missing is defined %}{{ missing }}{% endif %}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        assert!(validate_references(&values).is_ok());
+    }
+
+    #[test]
+    fn test_validate_references_reports_every_problem() {
+        let yaml = r#"
+name: "app"
+a: "{{ nothere }}"
+b: "{{ name | nosuchfilter }}"
+c: "{{ name }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let err = validate_references(&values).unwrap_err().to_string();
+
+        assert!(
+            err.starts_with("2 value reference(s) failed validation"),
+            "{}",
+            err
+        );
+        assert!(err.contains("  a: ") && err.contains("nothere"), "{}", err);
+        assert!(
+            err.contains("  b: ") && err.contains("nosuchfilter"),
+            "{}",
+            err
+        );
+        assert!(!err.contains("  c: "), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_references_reports_cycles() {
+        let values: Value = from_str("a: \"{{ b }}\"\nb: \"{{ a }}\"").unwrap();
+        let err = validate_references(&values).unwrap_err().to_string();
+        assert!(err.to_lowercase().contains("circular"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolution_plan_json_lists_templates_in_order() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+db:
+  host: localhost
+  port: 5432
+url: "{{ db.host }}:{{ db.port }}"
+api: "{{ url }}/api"
+ports:
+  - "{{ db.port }}"
+all_ports: "{{ ports | join(',') }}"
+"#,
+        )?;
+
+        let plan: serde_json::Value = serde_json::from_str(&resolution_plan_json(&values)?)?;
+
+        let expected = serde_json::json!([
+            {"path": "ports[0]", "template": "{{ db.port }}", "depends_on": ["db.port"]},
+            {"path": "all_ports", "template": "{{ ports | join(',') }}", "depends_on": ["ports", "ports[0]"]},
+            {"path": "url", "template": "{{ db.host }}:{{ db.port }}", "depends_on": ["db.host", "db.port"]},
+            {"path": "api", "template": "{{ url }}/api", "depends_on": ["url"]},
+        ]);
+        assert_eq!(plan, expected);
+
+        // Templates under `!omitempty` are rendered too, those under `!raw` are not
+        let tagged: Value = from_str(
+            "host: db\nhostname: !omitempty \"{{ host }}\"\nraw: !raw {a: \"{{ host }}\"}",
+        )?;
+        let plan: serde_json::Value = serde_json::from_str(&resolution_plan_json(&tagged)?)?;
+        let expected = serde_json::json!([
+            {"path": "hostname", "template": "{{ host }}", "depends_on": ["host"]},
+        ]);
+        assert_eq!(plan, expected);
+
+        let cycle: Value = from_str("a: \"{{ b }}\"\nb: \"{{ a }}\"")?;
+        let err = resolution_plan_json(&cycle).unwrap_err().to_string();
+        assert!(err.to_lowercase().contains("circular"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_omitempty_tag_removes_keys_that_resolve_empty() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+tag: ""
+service:
+  image: "app:{{ tag }}"
+  hostname: !omitempty "{{ missing }}"
+  user: !omitempty "{{ tag }}"
+  domainname: !omitempty "{{ 'example.com' }}"
+  entrypoint: !omitempty ~
+  restart: always
+ports:
+  - !omitempty "{{ missing }}"
+"#,
+        )?;
+
+        let resolved = resolve_value_references(values)?;
+
+        let expected: Value = from_str(
+            r#"
+tag: ""
+service:
+  image: "app:"
+  domainname: example.com
+  restart: always
+ports:
+  - ""
+"#,
+        )?;
+        assert_eq!(resolved, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_omit_empty_option_keeps_empty_strings_that_are_not_templates() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+suffix: ""
+label: "{{ missing }}"
+name: "web{{ suffix }}"
+command: "{{ suffix }}"
+"#,
+        )?;
+        let options = ResolveOptions {
+            omit_empty: true,
+            ..ResolveOptions::default()
+        };
+
+        let resolved = resolve_value_references_with(values.clone(), &options)?;
+
+        assert_eq!(resolved, from_str::<Value>("suffix: ''\nname: web")?);
+        let kept = resolve_value_references(values)?;
+        assert_eq!(kept["label"], Value::from(""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_collecting_errors_reports_every_failure() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+name: web
+first: "{{ name | nosuchfilter }}"
+second: "{{ name | alsomissing }}"
+third: "{{ missing | required }}"
+depends_on_first: "{{ first }}-x"
+fine: "{{ name }}-ok"
+"#,
+        )?;
+
+        let failures = resolve_collecting_errors(values, &ResolveOptions::default())
+            .expect_err("broken references should fail");
+
+        let paths: Vec<_> = failures.iter().filter_map(ResolveError::path).collect();
+        assert_eq!(paths, vec!["first", "second", "third"]);
+        assert!(failures
+            .iter()
+            .all(|failure| matches!(failure, ResolveError::Render { .. })));
+        assert!(
+            failures[0].to_string().contains("nosuchfilter"),
+            "{}",
+            failures[0]
+        );
+
+        let values: Value = from_str("name: web\nurl: '{{ name }}.local'")?;
+        let resolved = resolve_collecting_errors(values, &ResolveOptions::default());
+        assert_eq!(resolved, Ok(from_str("name: web\nurl: web.local")?));
+
+        let cycle: Value = from_str("a: '{{ b }}'\nb: '{{ a }}'")?;
+        let failures = resolve_collecting_errors(cycle, &ResolveOptions::default()).unwrap_err();
+        assert!(matches!(failures[..], [ResolveError::Cycle { .. }]));
+
+        let deep = nested_mapping(4, Value::from("{{ name }}"));
+        let shallow = ResolveOptions {
+            max_depth: 3,
+            ..Default::default()
+        };
+        let failures = resolve_collecting_errors(deep, &shallow).unwrap_err();
+        assert!(
+            matches!(&failures[..], [ResolveError::Other { message }] if message.contains("nested too deeply")),
+            "{:?}",
+            failures
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_renderer_scalar_formatting_unchanged() {
+        let renderer = MiniJinjaRenderer::new();
+        let context: Value = from_str("version: 2.50\nenabled: true\ncount: 3").unwrap();
+
+        let rendered = renderer
+            .render("v{{ version }} {{ enabled }} {{ count }}", &context)
+            .unwrap();
+
+        assert_eq!("v2.5 true 3", rendered);
+    }
+
+    #[test]
+    fn test_scalar_formatter_controls_interpolated_scalars() {
+        struct PythonStyle;
+
+        impl ScalarFormatter for PythonStyle {
+            fn format(&self, scalar: &Scalar) -> Option<String> {
+                match scalar {
+                    Scalar::Float(f) => Some(format!("{:.2}", f)),
+                    Scalar::Bool(true) => Some("True".to_string()),
+                    Scalar::Bool(false) => Some("False".to_string()),
+                    Scalar::Null => Some("None".to_string()),
+                    Scalar::Integer(_) => None,
+                }
+            }
+        }
+
+        let renderer = MiniJinjaRenderer::with_scalar_formatter(PythonStyle);
+        let context: Value =
+            from_str("version: 2.50\nenabled: true\ncount: 3\nunset: ~\nname: app").unwrap();
+
+        let rendered = renderer
+            .render(
+                "v{{ version }} {{ enabled }} {{ count }} {{ unset }} {{ name }}",
+                &context,
+            )
+            .unwrap();
+
+        assert_eq!("v2.50 True 3 None app", rendered);
+    }
+
+    #[test]
+    fn test_fail_on_undefined_lists_missing_references() {
+        let yaml = r#"
+name: "app"
+items: [first]
+ok: "{{ name }} {{ items[0] }}"
+broken: "{{ nmae }} and {{ items[3] }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let options = ResolveOptions {
+            fail_on_undefined: true,
+            ..Default::default()
+        };
+
+        let err = resolve_value_references_with(values.clone(), &options)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            "2 value reference(s) point at values that do not exist:\n  broken: items[3]\n  broken: nmae",
+            err
+        );
+
+        // Lenient by default: missing keys render as empty, but an index past the end of
+        // an existing sequence is always an error
+        let err = resolve_value_references(values).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Value 'broken' references items[3] but items has length 1"
+        );
+    }
+
+    #[test]
+    fn test_fail_on_undefined_allows_references_to_templates() {
+        let yaml = r#"
+base: "{{ host }}:80"
+host: "localhost"
+url: "http://{{ base }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let options = ResolveOptions {
+            fail_on_undefined: true,
+            ..Default::default()
+        };
+        let resolved = resolve_value_references_with(values, &options).unwrap();
+        assert_eq!(
+            resolved["url"],
+            Value::String("http://localhost:80".to_string())
+        );
+    }
+
+    #[test]
+    fn test_trim_rendered_collapses_multiline_template() {
+        let yaml = r#"
+env: "prod"
+prod_replicas: 3
+replicas: |
+  {% if env == "prod" %}
+    {{ prod_replicas }}
+  {% else %}
+    1
+  {% endif %}
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let options = ResolveOptions {
+            trim_rendered: true,
+            ..Default::default()
+        };
+        let resolved = resolve_value_references_with(values.clone(), &options).unwrap();
+        assert_eq!(resolved["replicas"], Value::String("3".to_string()));
+
+        // Whitespace is kept unless trimming is asked for
+        let resolved = resolve_value_references(values).unwrap();
+        let untrimmed = resolved["replicas"].as_str().unwrap();
+        assert_ne!(untrimmed, "3");
+        assert_eq!(untrimmed.trim(), "3");
+    }
+
+    #[test]
+    fn test_trim_rendered_applies_before_dependents_render() {
+        let yaml = r#"
+host: "  db.internal\n"
+padded: "{{ host }}\n"
+url: "postgres://{{ padded }}:5432"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let options = ResolveOptions {
+            trim_rendered: true,
+            ..Default::default()
+        };
+        let resolved = resolve_value_references_with(values, &options).unwrap();
+        assert_eq!(resolved["padded"], Value::String("db.internal".to_string()));
+        assert_eq!(
+            resolved["url"],
+            Value::String("postgres://db.internal:5432".to_string())
+        );
+        // Plain values are not templates and are left alone
+        assert_eq!(
+            resolved["host"],
+            Value::String("  db.internal\n".to_string())
+        );
+    }
+
+    /// Counts render calls while delegating to the real renderer.
+    struct CountingRenderer {
+        inner: MiniJinjaRenderer,
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl CountingRenderer {
+        fn new() -> Self {
+            Self {
+                inner: MiniJinjaRenderer::new(),
+                calls: std::cell::Cell::new(0),
+            }
+        }
+    }
+
+    impl TemplateRenderer for CountingRenderer {
+        fn render(&self, template_str: &str, context: &Value) -> Result<String> {
+            self.calls.set(self.calls.get() + 1);
+            self.inner.render(template_str, context)
+        }
+    }
+
+    const CACHED_VALUES: &str = r#"
+env: "dev"
+db:
+  host: "db.{{ env }}"
+  port: 5432
+  url: "postgres://{{ db.host }}:{{ db.port }}"
+api:
+  url: "https://api.{{ env }}"
+  timeout: 30
+summary: "{{ api.url }} -> {{ db.url }}"
+labels:
+  team: ops
+  tag: "{{ labels }}"
+"#;
+
+    #[test]
+    fn test_apply_overrides_matches_full_resolution() {
+        let values: Value = from_str(CACHED_VALUES).unwrap();
+        let cached = resolve_cached(values.clone()).unwrap();
+        assert_eq!(
+            cached.values(),
+            &resolve_value_references(values.clone()).unwrap()
+        );
+
+        let cases: Vec<Vec<(&str, Value)>> = vec![
+            vec![("env", Value::from("prod"))],
+            vec![("db.port", Value::from(6432))],
+            vec![
+                ("api.timeout", Value::from(60)),
+                ("db.port", Value::from(1)),
+            ],
+            vec![("labels.team", Value::from("web"))],
+            vec![(
+                "db",
+                from_str("{host: \"{{ env }}-db\", port: 1, url: x}").unwrap(),
+            )],
+            vec![("api.url", Value::from("http://localhost"))],
+            vec![("extra.new", Value::from("{{ env }}"))],
+        ];
+        for overrides in cases {
+            let mut expected = values.clone();
+            for (path, value) in &overrides {
+                set_value_at_path_creating(&mut expected, path, value.clone()).unwrap();
+            }
+            let expected = resolve_value_references(expected).unwrap();
+            let incremental = cached.apply_overrides(&overrides).unwrap();
+            assert_eq!(
+                incremental.values(),
+                &expected,
+                "overrides: {:?}",
+                overrides
+            );
+        }
+        // The cached result is reusable after applying overrides
+        assert_eq!(cached.values(), &resolve_value_references(values).unwrap());
+    }
+
+    #[test]
+    fn test_apply_overrides_handles_raw_and_omit_empty_tags() {
+        let values: Value = from_str(
+            r#"
+env: dev
+host: ""
+hostname: !omitempty "{{ host }}"
+alerts: !raw
+  summary: "{{ $labels.instance }} is down in {{ env }}"
+label: "{{ env }}"
+"#,
+        )
+        .unwrap();
+        let cached = resolve_cached(values.clone()).unwrap();
+        assert_eq!(
+            cached.values(),
+            &resolve_value_references(values.clone()).unwrap()
+        );
+
+        let cases: Vec<Vec<(&str, Value)>> = vec![
+            vec![("env", Value::from("prod"))],
+            vec![("host", Value::from("db.internal"))],
+            vec![
+                ("host", Value::from("db.internal")),
+                ("env", Value::from("prod")),
+            ],
+        ];
+        for overrides in cases {
+            let mut expected = values.clone();
+            for (path, value) in &overrides {
+                set_value_at_path_creating(&mut expected, path, value.clone()).unwrap();
+            }
+            let expected = resolve_value_references(expected).unwrap();
+            let incremental = cached.apply_overrides(&overrides).unwrap();
+            assert_eq!(
+                incremental.values(),
+                &expected,
+                "overrides: {:?}",
+                overrides
+            );
+            assert!(!serde_yaml::to_string(incremental.values())
+                .unwrap()
+                .contains('!'));
+        }
+
+        let updated = cached
+            .apply_overrides(&[("host", Value::from("db.internal"))])
+            .unwrap();
+        assert_eq!(updated.values()["hostname"], Value::from("db.internal"));
+        assert_eq!(
+            updated.values()["alerts"]["summary"],
+            Value::from("{{ $labels.instance }} is down in {{ env }}")
+        );
+        assert!(cached.values().get("hostname").is_none());
+    }
+
+    #[test]
+    fn test_apply_overrides_only_renders_affected_templates() {
+        let values: Value = from_str(CACHED_VALUES).unwrap();
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let renderer = CountingRenderer::new();
+        let cached = resolve_cached_with(values, &extractor, &renderer).unwrap();
+        assert_eq!(renderer.calls.get(), 5);
+
+        // db.url and summary depend on db.port
+        renderer.calls.set(0);
+        let updated = cached
+            .apply_overrides_with(&[("db.port", Value::from(6432))], &extractor, &renderer)
+            .unwrap();
+        assert_eq!(renderer.calls.get(), 2);
+        assert_eq!(
+            updated.values()["summary"],
+            Value::from("https://api.dev -> postgres://db.dev:6432")
+        );
+
+        // Nothing references api.timeout
+        renderer.calls.set(0);
+        cached
+            .apply_overrides_with(&[("api.timeout", Value::from(60))], &extractor, &renderer)
+            .unwrap();
+        assert_eq!(renderer.calls.get(), 0);
+
+        // Overriding a parent of a referenced path re-renders its dependents
+        renderer.calls.set(0);
+        let updated = cached
+            .apply_overrides_with(
+                &[(
+                    "labels",
+                    from_str("{team: web, tag: \"{{ labels }}\"}").unwrap(),
+                )],
+                &extractor,
+                &renderer,
+            )
+            .unwrap();
+        assert_eq!(renderer.calls.get(), 1);
+        assert!(updated.values()["labels"]["tag"]
+            .as_str()
+            .unwrap()
+            .contains("web"));
+    }
+
+    #[test]
+    fn test_render_against_example() {
+        let values: Value = serde_yaml::from_str("foo:\n  bar: hello").unwrap();
+        assert_eq!(
+            render_against(&values, "{{ foo.bar | upper }}").unwrap(),
+            "HELLO"
+        );
+    }
+
+    #[test]
+    fn test_render_against_matches_resolution() {
+        let values: Value =
+            from_str("name: web\nports: [80, 443]\nurl: 'http://{{ name }}'").unwrap();
+        let resolved = resolve_value_references(values.clone()).unwrap();
+
+        assert_eq!(
+            render_against(&resolved, "{{ url }}:{{ ports[1] }}").unwrap(),
+            "http://web:443"
+        );
+        assert_eq!(
+            render_against(&values, "{{ url }}").unwrap(),
+            "http://{{ name }}"
+        );
+        assert_eq!(render_against(&values, "{{ /ports/0 }}").unwrap(), "80");
+        assert_eq!(render_against(&values, "{{ missing }}").unwrap(), "");
+
+        let err = render_against(&values, "{{ name | nope }}")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown filter"), "{}", err);
+    }
+
+    #[test]
+    fn test_render_error_chain_names_path_template_and_cause() {
+        let values: Value =
+            from_str("name: web\nconfig:\n  label: '{{ name | nosuchfilter }}'").unwrap();
+        let err = resolve_value_references(values).unwrap_err();
+
+        assert_eq!(err.to_string(), "Failed to resolve value 'config.label'");
+        let chained = format!("{:#}", err);
+        assert!(
+            chained.starts_with("Failed to resolve value 'config.label': Failed to render"),
+            "{}",
+            chained
+        );
+        assert!(chained.contains("{{ name | nosuchfilter }}"), "{}", chained);
+        assert!(chained.contains("unknown filter"), "{}", chained);
+        assert_eq!(
+            chained.matches("unknown filter").count(),
+            1,
+            "The cause should be given once: {}",
+            chained
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors_downcast_to_resolve_error() {
+        let values: Value = from_str("a: '{{ b }}'\nb: '{{ a }}'").unwrap();
+        let err = resolve_value_references(values).unwrap_err();
+        match err.downcast_ref::<ResolveError>() {
+            Some(ResolveError::Cycle { cycle }) => {
+                assert_eq!(cycle.len(), 3);
+                assert_eq!(cycle.first(), cycle.last());
+            }
+            other => panic!("expected a cycle error, got {:?}", other),
+        }
+
+        let values: Value = from_str("name: web\nbad: '{{ name | nope }}'").unwrap();
+        let err = resolve_value_references(values).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<ResolveError>(),
+                Some(ResolveError::Render { path, .. }) if path == "bad"
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_resolve_value_references_json_err() {
+        let values: Value = from_str("a: '{{ b }}'\nb: '{{ a }}'").unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&resolve_value_references_json_err(values).unwrap_err()).unwrap();
+        assert_eq!(json["kind"], "cycle");
+        assert_eq!(json["cycle"].as_array().unwrap().len(), 3);
+        assert!(json["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Circular dependency detected"));
+
+        let values: Value = from_str("name: web\nbad: '{{ name | nope }}'").unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&resolve_value_references_json_err(values).unwrap_err()).unwrap();
+        assert_eq!(json["kind"], "render");
+        assert_eq!(json["path"], "bad");
+        assert_eq!(json["template"], "{{ name | nope }}");
+
+        let values: Value = from_str("name: web\ngood: '{{ name }}'").unwrap();
+        let resolved = resolve_value_references_json_err(values).unwrap();
+        assert_eq!(resolved["good"], Value::from("web"));
+    }
+
+    #[test]
+    fn test_reference_past_end_of_sequence_errors_before_rendering() {
+        let yaml = r#"
+items:
+  - name: first
+  - name: second
+ok: "{{ items[1].name }}"
+extra: "{{ items[2].name }}"
+"#;
+        let values: Value = from_str(yaml).unwrap();
+        let err = resolve_value_references(values).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Value 'extra' references items[2].name but items has length 2"
+        );
+        assert!(matches!(
+            err.downcast_ref::<ResolveError>(),
+            Some(ResolveError::IndexOutOfRange { length: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_guarded_references_past_end_of_sequence_resolve() -> anyhow::Result<()> {
+        let yaml = r#"
+xs: [a, b]
+fallback: "{{ xs[5] | default('x') }}"
+conditional: "{% if xs | length > 5 %}{{ xs[5] }}{% else %}short{% endif %}"
+"#;
+        let resolved = resolve_value_references(from_str(yaml)?)?;
+        assert_eq!(resolved["fallback"], Value::from("x"));
+        assert_eq!(resolved["conditional"], Value::from("short"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reference_within_appended_sequence_resolves() -> anyhow::Result<()> {
+        // The third item only exists once the later document is merged
+        let base: Value = from_str("items: [a, b]\nthird: '{{ items[2] }}'")?;
+        let overlay: Value = from_str("items: [c]")?;
+        let merged = crate::utils::load_values::merge_values(base, overlay)?;
+        let resolved = resolve_value_references(merged)?;
+        assert_eq!(resolved["third"], Value::from("c"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_sequence_bounds_name_the_inner_sequence() {
+        let values: Value =
+            from_str("matrix:\n  rows: [[1, 2], [3]]\ncell: '{{ matrix.rows[1][1] }}'").unwrap();
+        let err = resolve_value_references(values).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Value 'cell' references matrix.rows[1][1] but matrix.rows[1] has length 1"
+        );
+    }
+
+    #[test]
+    fn test_path_and_key_globals_render_the_value_location() -> anyhow::Result<()> {
+        let yaml = r#"
+metadata:
+  label: "{{ __path__ }}"
+  name: "app-{{ __key__ }}"
+ports:
+  - "{{ __path__ }}:{{ __key__ }}"
+"#;
+        let values: Value = from_str(yaml)?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved["metadata"]["label"], Value::from("metadata.label"));
+        assert_eq!(resolved["metadata"]["name"], Value::from("app-name"));
+        assert_eq!(resolved["ports"][0], Value::from("ports[0]:0"));
+        // Only the rendered values change; the globals are not left behind
+        assert!(resolved.get(PATH_GLOBAL).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_global_is_not_an_undefined_reference() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            "name: app
+label: '{{ name }}@{{ __path__ }}'",
+        )?;
+        let options = ResolveOptions {
+            fail_on_undefined: true,
+            ..Default::default()
+        };
+        let resolved = resolve_value_references_with(values, &options)?;
+        assert_eq!(resolved["label"], Value::from("app@label"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tojson_and_toyaml_embed_structures() -> anyhow::Result<()> {
+        let yaml = r#"
+labels:
+  app: web
+  note: 'say "hi"'
+  ports: [80, 443]
+json_label: "{{ labels | tojson }}"
+yaml_label: "{{ labels | toyaml }}"
+"#;
+        let values: Value = from_str(yaml)?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(
+            resolved["json_label"],
+            Value::from(r#"{"app":"web","note":"say \"hi\"","ports":[80,443]}"#)
+        );
+        assert_eq!(
+            resolved["yaml_label"],
+            Value::from("app: web\nnote: say \"hi\"\nports:\n- 80\n- 443")
+        );
+        // The embedded JSON reads back as the original mapping
+        let parsed: serde_json::Value =
+            serde_json::from_str(resolved["json_label"].as_str().unwrap())?;
+        assert_eq!(parsed["note"], serde_json::json!("say \"hi\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tojson_orders_after_the_referenced_value() -> anyhow::Result<()> {
+        let yaml = r#"
+embedded: "{{ config | tojson }}"
+host: db
+config:
+  url: "postgres://{{ host }}"
+"#;
+        let values: Value = from_str(yaml)?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(
+            resolved["embedded"],
+            Value::from(r#"{"url":"postgres://db"}"#)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_coerce_values_converts_strings() -> anyhow::Result<()> {
+        let mut values: Value = from_str(
+            "debug: 'True'\nport: '8080'\nratio: ' 0.5 '\nreplicas: 3\nhosts: [{weight: '2'}]\ntimeout: ~",
+        )?;
+        let types = BTreeMap::from([
+            ("debug".to_string(), ScalarType::Bool),
+            ("port".to_string(), ScalarType::Int),
+            ("ratio".to_string(), ScalarType::Float),
+            ("replicas".to_string(), ScalarType::Float),
+            ("hosts[0].weight".to_string(), ScalarType::Int),
+            ("timeout".to_string(), ScalarType::Int),
+            ("missing.path".to_string(), ScalarType::Bool),
+        ]);
+
+        coerce_values(&mut values, &types)?;
+
+        let expected: Value = from_str(
+            "debug: true\nport: 8080\nratio: 0.5\nreplicas: 3.0\nhosts: [{weight: 2}]\ntimeout: ~",
+        )?;
+        assert_eq!(values, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_coerce_values_lists_every_failure() -> anyhow::Result<()> {
+        let mut values: Value =
+            from_str("debug: 'yes please'\nport: '80.5'\nratio: nan\nlabels: {a: b}\nok: 'false'")?;
+        let types = BTreeMap::from([
+            ("debug".to_string(), ScalarType::Bool),
+            ("port".to_string(), ScalarType::Int),
+            ("ratio".to_string(), ScalarType::Float),
+            ("labels".to_string(), ScalarType::Bool),
+            ("ok".to_string(), ScalarType::Bool),
+        ]);
+
+        let err = coerce_values(&mut values, &types).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "4 value(s) cannot be coerced to the expected type:\n  \
+             debug: yes please is not a valid bool\n  \
+             labels: a: b is not a valid bool\n  \
+             port: '80.5' is not a valid int\n  \
+             ratio: nan is not a valid float"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_value_at_path() -> anyhow::Result<()> {
+        let mut values: Value =
+            from_str("name: app\ndatabase:\n  host: db\n  port: 5432\nports: [80, 443, 8080]")?;
+
+        assert!(remove_value_at_path(&mut values, "name")?);
+        assert!(remove_value_at_path(&mut values, "database.port")?);
+        assert!(remove_value_at_path(&mut values, "ports[1]")?);
+        assert_eq!(
+            values,
+            from_str::<Value>("database:\n  host: db\nports: [80, 8080]")?
+        );
+
+        // Whole subtrees go too
+        assert!(remove_value_at_path(&mut values, "database")?);
+        assert_eq!(values, from_str::<Value>("ports: [80, 8080]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_value_at_path_keeps_key_order() -> anyhow::Result<()> {
+        let mut values: Value = from_str("a: 1\nb: 2\nc: 3\nd: 4")?;
+        assert!(remove_value_at_path(&mut values, "b")?);
+        assert_eq!(serde_yaml::to_string(&values)?, "a: 1\nc: 3\nd: 4\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_missing_value_at_path() -> anyhow::Result<()> {
+        let mut values: Value = from_str("name: app\nports: [80]")?;
+        let before = values.clone();
+
+        assert!(!remove_value_at_path(&mut values, "missing")?);
+        assert!(!remove_value_at_path(&mut values, "missing.nested")?);
+        assert!(!remove_value_at_path(&mut values, "name.nested")?);
+        assert!(!remove_value_at_path(&mut values, "ports[3]")?);
+        assert!(!remove_value_at_path(&mut values, "ports.key")?);
+        assert_eq!(values, before);
+        assert!(remove_value_at_path(&mut values, "").is_err());
+        Ok(())
+    }
+
+    /// Renders like `MiniJinjaRenderer`, but through `render` alone, so the whole context
+    /// is converted for every value as it was before `RenderContext`.
+    struct ReconvertingRenderer(MiniJinjaRenderer);
+
+    impl TemplateRenderer for ReconvertingRenderer {
+        fn render(&self, template_str: &str, context: &Value) -> Result<String> {
+            self.0.render(template_str, context)
+        }
+    }
+
+    /// `services: {svc0: {host, port, url: "{{ services.svcN.host }}:..."}, ...}`, with
+    /// one plain value and one reference for every service.
+    fn services_values(count: usize) -> Value {
+        let mut services = Mapping::new();
+        for i in 0..count {
+            let mut service = Mapping::new();
+            service.insert(
+                Value::from("host"),
+                Value::from(format!("svc{}.internal", i)),
+            );
+            service.insert(Value::from("port"), Value::from(8000 + i));
+            service.insert(
+                Value::from("url"),
+                Value::from(format!(
+                    "http://{{{{ services.svc{0}.host }}}}:{{{{ services.svc{0}.port }}}}",
+                    i
+                )),
+            );
+            services.insert(Value::from(format!("svc{}", i)), Value::Mapping(service));
+        }
+        let mut root = Mapping::new();
+        root.insert(Value::from("services"), Value::Mapping(services));
+        Value::Mapping(root)
+    }
+
+    #[test]
+    fn test_prepared_context_matches_reconverting_every_render() -> anyhow::Result<()> {
+        let values = services_values(20);
+        let extractor = MiniJinjaReferenceExtractor::new();
+        let prepared = resolve_with(values.clone(), &extractor, &MiniJinjaRenderer::new())?;
+        let reconverted = resolve_with(
+            values,
+            &extractor,
+            &ReconvertingRenderer(MiniJinjaRenderer::new()),
+        )?;
+        assert_eq!(prepared, reconverted);
+        assert_eq!(
+            prepared["services"]["svc7"]["url"],
+            Value::from("http://svc7.internal:8007")
         );
+        Ok(())
     }
 
+    /// Checks that resolving with the prepared context is faster than converting the
+    /// context for every render once there are a few hundred references. Timing based,
+    /// so it only runs when asked: `cargo test --release bench_ -- --ignored`.
     #[test]
-    fn test_chained_references() {
-        let yaml = r#"
-a: "base"
-b: "{{ a }}-extended"
-c: "{{ b }}-final"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+    #[ignore = "timing based, run with --release"]
+    fn bench_prepared_context() -> anyhow::Result<()> {
+        let extractor = MiniJinjaReferenceExtractor::new();
+        for count in [500, 2_000] {
+            let values = services_values(count);
+
+            let start = Instant::now();
+            let prepared = resolve_with(values.clone(), &extractor, &MiniJinjaRenderer::new())?;
+            let prepared_time = start.elapsed();
+
+            let start = Instant::now();
+            let reconverted = resolve_with(
+                values,
+                &extractor,
+                &ReconvertingRenderer(MiniJinjaRenderer::new()),
+            )?;
+            let reconverted_time = start.elapsed();
+
+            assert_eq!(prepared, reconverted);
+            assert!(
+                prepared_time < reconverted_time,
+                "{} references: prepared {:?}, reconverted {:?}",
+                count,
+                prepared_time,
+                reconverted_time
+            );
+        }
+        Ok(())
+    }
 
-        assert_eq!(
-            resolved.get("c").unwrap(),
-            &Value::String("base-extended-final".to_string())
+    /// `{ k: { k: ... { k: leaf } } }`, `depth` mappings deep.
+    fn nested_mapping(depth: usize, leaf: Value) -> Value {
+        (0..depth).fold(leaf, |inner, _| {
+            let mut map = Mapping::new();
+            map.insert(Value::from("k"), inner);
+            Value::Mapping(map)
+        })
+    }
+
+    #[test]
+    fn test_deeply_nested_values_error_instead_of_overflowing() {
+        let values = nested_mapping(DEFAULT_MAX_DEPTH + 10, Value::from("{{ other }}"));
+        let err = resolve_value_references(values).unwrap_err().to_string();
+        assert!(
+            err.starts_with("Values are nested too deeply at 'k.k.k"),
+            "{}",
+            err
         );
+
+        let path = vec!["k"; DEFAULT_MAX_DEPTH + 1].join(".");
+        let mut values = Value::Mapping(Mapping::new());
+        let err = set_value_at_path_creating(&mut values, &path, Value::from(1)).unwrap_err();
+        assert!(err.to_string().contains("nested too deeply"), "{}", err);
     }
 
     #[test]
-    fn test_filter_support_upper() {
+    fn test_relative_references_resolve_against_parent() -> anyhow::Result<()> {
         let yaml = r#"
-name: "world"
-greeting: "{{ name | upper }}"
+domain: example.com
+services:
+  web:
+    host: web.internal
+    port: 8080
+    url: "http://{{ ../host }}:{{ ../port }}"
+    public: "https://{{ ../../../domain }}/{{ ../../api.path }}"
+  api:
+    path: v1
+    upstream: "{{ ../../web.url }}/{{ ../path }}"
 "#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+        let resolved = resolve_value_references(from_str(yaml)?)?;
 
         assert_eq!(
-            resolved.get("greeting").unwrap(),
-            &Value::String("WORLD".to_string())
+            resolved["services"]["web"]["url"],
+            Value::from("http://web.internal:8080")
+        );
+        assert_eq!(
+            resolved["services"]["web"]["public"],
+            Value::from("https://example.com/v1")
+        );
+        assert_eq!(
+            resolved["services"]["api"]["upstream"],
+            Value::from("http://web.internal:8080/v1")
         );
+        Ok(())
     }
 
     #[test]
-    fn test_filter_support_lower() {
-        let yaml = r#"
-name: "HELLO"
-greeting: "{{ name | lower }}"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+    fn test_relative_reference_above_root_errors() -> anyhow::Result<()> {
+        let values: Value = from_str("name: app\nlabel: '{{ ../../name }}'")?;
+        let err = resolve_value_references(values).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Relative reference '../../name' in 'label' goes above the root"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
 
+    #[test]
+    fn test_unterminated_expression_errors() {
+        let values: Value =
+            serde_yaml::from_str("db:\n  host: localhost\n  url: 'postgres://{{ db.host'\n")
+                .unwrap();
+        let err = resolve_value_references(values).unwrap_err().to_string();
         assert_eq!(
-            resolved.get("greeting").unwrap(),
-            &Value::String("hello".to_string())
+            err,
+            "Unterminated template expression at 'db.url': '{{ db.host' has no closing '}}'"
         );
+
+        let values: Value = serde_yaml::from_str("greeting: 'a }} b {{ c'\n").unwrap();
+        let err = resolve_value_references(values).unwrap_err().to_string();
+        assert!(err.contains("at 'greeting': '{{ c'"), "{}", err);
+
+        let values: Value = serde_yaml::from_str("closing: 'a }} b'\n").unwrap();
+        assert_eq!(resolve_value_references(values.clone()).unwrap(), values);
     }
 
     #[test]
-    fn test_default_filter() {
+    fn test_max_depth_is_configurable() -> anyhow::Result<()> {
+        let mut values = nested_mapping(4, Value::from("{{ name }}"));
+        values
+            .as_mapping_mut()
+            .unwrap()
+            .insert(Value::from("name"), Value::from("app"));
+        let shallow = ResolveOptions {
+            max_depth: 3,
+            ..Default::default()
+        };
+        assert!(resolve_value_references_with(values.clone(), &shallow).is_err());
+
+        let resolved = resolve_value_references_with(values, &ResolveOptions::default())?;
+        assert_eq!(resolved["k"]["k"]["k"]["k"], Value::from("app"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_references_inside_expressions_are_resolved_first() -> anyhow::Result<()> {
         let yaml = r#"
-message: "{{ undefined_var | default('fallback') }}"
+label: "{{ 'app-' ~ name }}"
+enabled: "{{ (tls or debug) }}"
+name: "{{ base }}-web"
+base: shop
+tls: "{{ base == 'shop' }}"
+debug: false
 "#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+        let values: Value = from_str(yaml)?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved["label"], Value::from("app-shop-web"));
+        assert_eq!(resolved["enabled"], Value::from("true"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_of_a_list_resolves_its_items_first() -> anyhow::Result<()> {
+        // `replica_count` sorts before `servers`, and only counts an item once `{{ extra }}`
+        // in it has been resolved
+        let values: Value = from_str(
+            "replica_count: \"{{ servers | select('eq', 'c') | list | length }}\"\n\
+             servers: [a, \"{{ extra }}\"]\nextra: c",
+        )?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved["replica_count"], Value::from("1"));
+        assert_eq!(resolved["servers"][1], Value::from("c"));
+        Ok(())
+    }
 
+    #[test]
+    fn test_map_key_iteration() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+names: "{{ mymap.keys() | join(',') }}"
+loop: "{% for k in mymap %}{{ k }}={{ mymap[k] }};{% endfor %}"
+pairs: "{% for k, v in mymap.items() %}{{ k }}:{{ v }} {% endfor %}"
+hosts: "{{ mymap.values() | join(',') }}"
+mymap:
+  web: "{{ domain }}"
+  db: db.internal
+domain: example.com
+"#,
+        )?;
+        let resolved = resolve_value_references(values)?;
+        // Templates see map keys in sorted order
+        assert_eq!(resolved["names"], Value::from("db,web"));
         assert_eq!(
-            resolved.get("message").unwrap(),
-            &Value::String("fallback".to_string())
+            resolved["loop"],
+            Value::from("db=db.internal;web=example.com;")
+        );
+        assert_eq!(
+            resolved["pairs"],
+            Value::from("db:db.internal web:example.com ")
         );
+        assert_eq!(resolved["hosts"], Value::from("db.internal,example.com"));
+
+        let values: Value = from_str("a: \"{{ mymap.keys(1) }}\"\nmymap: {k: v}")?;
+        assert!(resolve_value_references(values).is_err());
+        Ok(())
     }
 
     #[test]
-    fn test_circular_dependency_error() {
-        let yaml = r#"
-a: "{{ b }}"
-b: "{{ c }}"
-c: "{{ a }}"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let result = resolve_value_references(values);
+    fn test_required_filter_passes_present_values() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            "db_host: db\nport: 0\nurl: \"{{ db_host | required('db_host must be set') }}:{{ port | required }}\"",
+        )?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved["url"], Value::from("db:0"));
+        Ok(())
+    }
 
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
+    #[test]
+    fn test_required_filter_fails_on_empty_and_undefined_values() {
+        let cases = [
+            "db_host: ''\nconfig:\n  url: \"{{ db_host | required('db_host must be set') }}\"",
+            "config:\n  url: \"{{ db_host | required('db_host must be set') }}\"",
+            "db_host: ~\nconfig:\n  url: \"{{ db_host | required('db_host must be set') }}\"",
+        ];
+        for yaml in cases {
+            let values: Value = from_str(yaml).unwrap();
+            let err = resolve_value_references(values).unwrap_err();
+            let chained = format!("{:#}", err);
+            assert!(
+                chained.starts_with("Failed to resolve value 'config.url'"),
+                "{}",
+                chained
+            );
+            assert!(chained.contains("db_host must be set"), "{}", chained);
+        }
+
+        let values: Value = from_str("url: '{{ missing | required }}'").unwrap();
+        let err = format!("{:#}", resolve_value_references(values).unwrap_err());
         assert!(
-            err.contains("Circular dependency"),
-            "Error should mention circular dependency: {}",
+            err.contains("Value marked as 'required' must be present"),
+            "{}",
             err
         );
     }
 
     #[test]
-    fn test_self_reference_error() {
-        let yaml = r#"
-a: "{{ a }}"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let result = resolve_value_references(values);
+    fn test_now_function_renders_utc_timestamp() -> anyhow::Result<()> {
+        let values: Value = from_str("built_at: '{{ now() }}'")?;
+        let resolved = resolve_value_references(values)?;
+        let built_at = resolved["built_at"].as_str().unwrap();
+        let parsed = chrono::DateTime::parse_from_rfc3339(built_at)?;
+        assert_eq!(parsed.offset().local_minus_utc(), 0);
+        assert!(built_at.ends_with('Z'), "{}", built_at);
+        Ok(())
+    }
 
-        assert!(result.is_err());
+    #[test]
+    fn test_uuid_function_renders_distinct_uuids() -> anyhow::Result<()> {
+        let values: Value = from_str("a: '{{ uuid() }}'\nb: '{{ uuid() }}'")?;
+        let resolved = resolve_value_references(values)?;
+        let a = uuid::Uuid::parse_str(resolved["a"].as_str().unwrap())?;
+        let b = uuid::Uuid::parse_str(resolved["b"].as_str().unwrap())?;
+        assert_eq!(a.get_version_num(), 4);
+        assert_ne!(a, b);
+        Ok(())
     }
 
     #[test]
-    fn test_non_string_values_unchanged() {
-        let yaml = r#"
-number: 42
-boolean: true
-list:
-  - item1
-  - item2
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values.clone()).unwrap();
+    fn test_env_function_reads_variable_or_default() -> anyhow::Result<()> {
+        std::env::set_var("COMPOSER_TEST_ENV_FUNCTION", "from-env");
+        std::env::remove_var("COMPOSER_TEST_ENV_FUNCTION_UNSET");
+        let values: Value = from_str(
+            "fallback: local\nset: \"{{ env('COMPOSER_TEST_ENV_FUNCTION', 'x') }}\"\nunset: \"{{ env('COMPOSER_TEST_ENV_FUNCTION_UNSET', fallback) }}\"\nbare: \"{{ env('COMPOSER_TEST_ENV_FUNCTION_UNSET') }}\"",
+        )?;
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved["set"], Value::from("from-env"));
+        assert_eq!(resolved["unset"], Value::from("local"));
+        assert_eq!(resolved["bare"], Value::from(""));
+        Ok(())
+    }
 
-        assert_eq!(
-            resolved.get("number").unwrap(),
-            values.get("number").unwrap()
+    #[test]
+    fn test_template_functions_can_be_restricted() -> anyhow::Result<()> {
+        std::env::set_var("COMPOSER_TEST_ENV_RESTRICTED", "yes");
+        let resolve = |yaml: &str, functions| {
+            let options = ResolveOptions {
+                functions,
+                ..Default::default()
+            };
+            resolve_value_references_with(from_str(yaml)?, &options)
+        };
+        let flag = "flag: \"{{ env('COMPOSER_TEST_ENV_RESTRICTED') }}\"";
+        let built_at = "built_at: '{{ now() }}'";
+
+        let resolved = resolve(flag, TemplateFunctions::Deterministic)?;
+        assert_eq!(resolved["flag"], Value::from("yes"));
+        let err = resolve(built_at, TemplateFunctions::Deterministic).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown function"),
+            "{:#}",
+            err
         );
-        assert_eq!(
-            resolved.get("boolean").unwrap(),
-            values.get("boolean").unwrap()
+        let err = resolve(flag, TemplateFunctions::None).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown function"),
+            "{:#}",
+            err
         );
-        assert_eq!(resolved.get("list").unwrap(), values.get("list").unwrap());
+        Ok(())
     }
 
     #[test]
-    fn test_multiple_references_in_one_value() {
-        let yaml = r#"
-part1: "world"
-part2: "hello"
-message: "{{ part2 }} {{ part1 }}"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+    fn test_raw_subtree_is_left_untouched() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+host: db
+resolved:
+  url: "{{ host }}:5432"
+  items: ["{{ host }}"]
+raw: !raw
+  url: "{{ host }}:5432"
+  items: ["{{ host | upper }}", "{{ missing.deeply }}"]
+copy: "{{ raw.url }}"
+"#,
+        )?;
+        assert_eq!(raw_paths(&values), vec!["raw"]);
+
+        let resolved = resolve_value_references(values)?;
+        let expected: Value = from_str(
+            r#"
+host: db
+resolved:
+  url: "db:5432"
+  items: ["db"]
+raw:
+  url: "{{ host }}:5432"
+  items: ["{{ host | upper }}", "{{ missing.deeply }}"]
+copy: "{{ host }}:5432"
+"#,
+        )?;
+        assert_eq!(resolved, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_tag_is_stripped_without_references() -> anyhow::Result<()> {
+        let values: Value = from_str("list: !raw [a, b]\nname: plain")?;
+        assert!(contains_references(&values));
 
+        let resolved = resolve_value_references(values)?;
+        assert_eq!(resolved, from_str::<Value>("list: [a, b]\nname: plain")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hyphenated_keys_resolve_through_subscripts() -> anyhow::Result<()> {
+        // `api-gateway` sorts before `db-main`, so it only resolves if its reference is found
+        let values: Value = from_str(
+            r#"
+services:
+  api-gateway:
+    host: "{{ services['db-main'].host }}"
+  db-main:
+    host: "{{ domain }}"
+domain: db.internal
+"#,
+        )?;
+        let resolved = resolve_value_references(values)?;
         assert_eq!(
-            resolved.get("message").unwrap(),
-            &Value::String("hello world".to_string())
+            resolved["services"]["api-gateway"]["host"],
+            Value::from("db.internal")
         );
+        Ok(())
     }
 
     #[test]
-    fn test_no_templates_passthrough() {
-        let yaml = r#"
-simple: "value"
-another: "plain text"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values.clone()).unwrap();
+    fn test_hyphenated_keys_resolve_with_extra_identifier_chars() -> anyhow::Result<()> {
+        // As above, `api-gateway` sorts before the `db-main` value it depends on
+        let values: Value = from_str(
+            r#"
+services:
+  api-gateway:
+    url: "http://{{ services.db-main.host }}:{{ port - 1 }}"
+  db-main:
+    host: "{{ domain }}"
+domain: db.internal
+port: 5433
+"#,
+        )?;
+        let options = ResolveOptions {
+            identifiers: ExtractorConfig {
+                extra_identifier_chars: vec!['-'],
+            },
+            ..Default::default()
+        };
+        let resolved = resolve_value_references_with(values, &options)?;
+        assert_eq!(
+            resolved["services"]["api-gateway"]["url"],
+            Value::from("http://db.internal:5432")
+        );
+        Ok(())
+    }
 
-        assert_eq!(resolved, values);
+    #[test]
+    fn test_missing_references_grouped_by_target() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+domain: example.com
+services:
+  web:
+    env: "{{ db_host }}:{{ db_port }}"
+  api:
+    env: "{{ db_host }}"
+    url: "https://{{ domain }}/{{ services.web.path | default('') }}"
+  worker:
+    env: "{{ db_host }}"
+"#,
+        )?;
+        let missing = missing_references(&values)?;
+
+        let expected: BTreeMap<String, Vec<String>> = [
+            (
+                "db_host",
+                vec![
+                    "services.api.env",
+                    "services.web.env",
+                    "services.worker.env",
+                ],
+            ),
+            ("db_port", vec!["services.web.env"]),
+            ("services.web.path", vec!["services.api.url"]),
+        ]
+        .into_iter()
+        .map(|(target, paths)| {
+            let paths = paths.into_iter().map(String::from).collect();
+            (target.to_string(), paths)
+        })
+        .collect();
+        assert_eq!(missing, expected);
+        Ok(())
     }
 
     #[test]
-    fn test_nested_target_value() {
-        let yaml = r#"
-source: "hello"
-target:
-  nested:
-    value: "{{ source }} world"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+    fn test_missing_references_empty_when_all_defined() -> anyhow::Result<()> {
+        let values: Value = from_str("a: 1\nraw: !raw {x: 1}\nb: '{{ a }}-{{ raw.x }}'")?;
+        assert!(missing_references(&values)?.is_empty());
+        Ok(())
+    }
 
-        let target = resolved.get("target").unwrap();
-        let nested = target.get("nested").unwrap();
-        let value = nested.get("value").unwrap();
+    #[test]
+    fn test_has_references() {
+        assert!(has_references("{{ name }}"));
+        assert!(has_references("http://{{ host }}:{{ port }}"));
+        assert!(!has_references("plain value"));
+        assert!(!has_references("{# {{ commented }} #}"));
+        assert!(!has_references("{{ unclosed"));
+    }
 
-        assert_eq!(value, &Value::String("hello world".to_string()));
+    #[test]
+    fn test_contains_references() {
+        let templated: Value = from_str("a:\n  b: [1, {c: \"{{ a.b[0] }}\"}]").unwrap();
+        assert!(contains_references(&templated));
+        let plain: Value =
+            from_str("a:\n  b: [1, {c: \"{ not a template }\"}]\n'{{ key }}': 1").unwrap();
+        assert!(!contains_references(&plain));
+        assert!(!contains_references(&Value::Null));
     }
 
     #[test]
-    fn test_deeply_nested_reference() {
+    fn test_mixed_reference_syntaxes_resolve_together() {
+        // As if merged from a migrated file and a legacy one
         let yaml = r#"
-level1:
-  level2:
-    level3:
-      source: "deep"
-result: "{{ level1.level2.level3.source }}"
+db:
+  host: "db.internal"
+  port: 5432
+url: "postgres://${ db.host }:{{ db.port }}"
+legacy: "${url}/app"
+overlap: "${{ db.port }}"
 "#;
         let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+        let options = ResolveOptions {
+            syntaxes: vec![ReferenceSyntax::Jinja, ReferenceSyntax::Dollar],
+            ..Default::default()
+        };
 
+        let resolved = resolve_value_references_with(values.clone(), &options).unwrap();
+        assert_eq!(resolved["url"], Value::from("postgres://db.internal:5432"));
         assert_eq!(
-            resolved.get("result").unwrap(),
-            &Value::String("deep".to_string())
+            resolved["legacy"],
+            Value::from("postgres://db.internal:5432/app")
         );
-    }
+        // `{{ }}` wins where the two overlap
+        assert_eq!(resolved["overlap"], Value::from("$5432"));
 
-    #[test]
-    fn test_mixed_static_and_dynamic_text() {
-        let yaml = r#"
-name: "Alice"
-greeting: "Hello, {{ name }}! Welcome to the system."
-"#;
-        let values: Value = from_str(yaml).unwrap();
+        // Without the option `${ }` is plain text
         let resolved = resolve_value_references(values).unwrap();
+        assert_eq!(resolved["url"], Value::from("postgres://${ db.host }:5432"));
+        assert_eq!(resolved["legacy"], Value::from("${url}/app"));
+    }
 
+    #[test]
+    fn test_get_value_at_path() {
+        let values: Value = from_str("a:\n  b: [x, {c: d}]\nmatrix: [[1, 2], [3, 4]]").unwrap();
+        assert_eq!(get_value_at_path(&values, "a"), values.get("a"));
         assert_eq!(
-            resolved.get("greeting").unwrap(),
-            &Value::String("Hello, Alice! Welcome to the system.".to_string())
+            get_value_at_path(&values, "matrix[1][0]"),
+            Some(&Value::from(3))
+        );
+        assert!(get_value_at_path(&values, "missing").is_none());
+        assert!(get_value_at_path(&values, "a.b[x]").is_none());
+        assert_eq!(
+            get_value_at_path(&values, "a.b[1].c"),
+            Some(&Value::from("d"))
+        );
+        assert_eq!(
+            get_value_at_path(&values, "a.b[0]"),
+            Some(&Value::from("x"))
         );
+        assert!(get_value_at_path(&values, "a.b[2]").is_none());
+        assert!(get_value_at_path(&values, "a.c").is_none());
+        assert!(get_value_at_path(&values, "a.b.c").is_none());
     }
 
     #[test]
-    fn test_complex_dependency_chain() {
+    fn test_is_within() {
+        assert!(is_within("app", "app"));
+        assert!(is_within("app.name", "app"));
+        assert!(is_within("app[0]", "app"));
+        assert!(!is_within("apple", "app"));
+        assert!(is_within("anything", ""));
+    }
+
+    #[test]
+    fn test_sequence_element_references_sequence_element() {
         let yaml = r#"
-base: "foundation"
-layer1: "{{ base }}-layer1"
-layer2: "{{ layer1 }}-layer2"
-final: "{{ layer2 }}-complete"
+items:
+  - "first"
+  - "{{ items[0] }}-second"
+  - "{{ items[1] }}-third"
 "#;
         let values: Value = from_str(yaml).unwrap();
         let resolved = resolve_value_references(values).unwrap();
 
-        assert_eq!(
-            resolved.get("final").unwrap(),
-            &Value::String("foundation-layer1-layer2-complete".to_string())
+        let expected: Value =
+            from_str(r#"["first", "first-second", "first-second-third"]"#).unwrap();
+        assert_eq!(resolved["items"], expected);
+        // The resolved value is written back into the sequence, not as a new key
+        assert!(resolved.get("items[1]").is_none());
+    }
+
+    #[test]
+    fn test_templates_at_every_list_position_stay_in_the_list() -> anyhow::Result<()> {
+        let values: Value = from_str(
+            r#"
+env: prod
+tags: ["{{ env }}-web", plain, "{{ env }}-api"]
+matrix:
+  - ["{{ env }}", fixed]
+  - [fixed, ["{{ env }}-deep"]]
+services:
+  - name: web
+    args: ["--env={{ env }}"]
+"#,
+        )?;
+
+        let resolved = resolve_value_references(values)?;
+
+        let expected: Value = from_str(
+            r#"
+env: prod
+tags: [prod-web, plain, prod-api]
+matrix:
+  - [prod, fixed]
+  - [fixed, [prod-deep]]
+services:
+  - name: web
+    args: ["--env=prod"]
+"#,
+        )?;
+        assert_eq!(resolved, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_value_at_path_with_indices() {
+        let mut values: Value = from_str("a:\n  - b: old\n  - c").unwrap();
+        set_value_at_path(&mut values, "a[0].b", Value::String("new".to_string())).unwrap();
+        set_value_at_path(&mut values, "a[1]", Value::String("d".to_string())).unwrap();
+
+        let expected: Value = from_str("a:\n  - b: new\n  - d").unwrap();
+        assert_eq!(values, expected);
+
+        let err = set_value_at_path(&mut values, "a[5]", Value::Null).unwrap_err();
+        assert!(
+            err.to_string().contains("out of range"),
+            "unexpected error: {}",
+            err
         );
     }
 
     #[test]
-    fn test_diamond_dependency() {
-        // Both branch1 and branch2 depend on root
-        // final depends on both branches
-        let yaml = r#"
-root: "base"
-branch1: "{{ root }}-b1"
-branch2: "{{ root }}-b2"
-final: "{{ branch1 }} and {{ branch2 }}"
-"#;
-        let values: Value = from_str(yaml).unwrap();
-        let resolved = resolve_value_references(values).unwrap();
+    fn test_set_value_at_path_creating_builds_missing_mappings() {
+        let mut values: Value = from_str("existing:\n  keep: 1\nempty: ~").unwrap();
+
+        set_value_at_path_creating(&mut values, "a.b.c.d", Value::from("deep")).unwrap();
+        set_value_at_path_creating(&mut values, "existing.new.leaf", Value::from(2)).unwrap();
+        set_value_at_path_creating(&mut values, "empty.inner", Value::Bool(true)).unwrap();
+
+        let expected: Value = from_str(
+            "existing:\n  keep: 1\n  new:\n    leaf: 2\nempty:\n  inner: true\na:\n  b:\n    c:\n      d: deep",
+        )
+        .unwrap();
+        assert_eq!(expected, values);
+    }
 
+    #[test]
+    fn test_set_value_at_path_creating_does_not_create_sequence_elements() {
+        let mut values: Value = from_str("list: [{name: a}]\nscalar: text").unwrap();
+
+        set_value_at_path_creating(&mut values, "list[0].extra.flag", Value::Bool(true)).unwrap();
+        assert_eq!(values["list"][0]["extra"]["flag"], Value::Bool(true));
+
+        assert!(set_value_at_path_creating(&mut values, "list[3].name", Value::Null).is_err());
+        assert!(set_value_at_path_creating(&mut values, "missing[0]", Value::Null).is_err());
+        // Existing scalars are not replaced to make room for a mapping
+        assert!(set_value_at_path_creating(&mut values, "scalar.inner", Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_set_value_at_path_strict_still_requires_existing_path() {
+        let mut values: Value = from_str("a: {}").unwrap();
+        let err = set_value_at_path(&mut values, "a.b.c", Value::Null).unwrap_err();
+        assert!(err.to_string().contains("Path not found: a.b.c"), "{}", err);
+    }
+
+    /// Records the name and fields of every span created while it is the default subscriber.
+    struct SpanRecorder {
+        spans: std::sync::Mutex<Vec<String>>,
+    }
+
+    struct FieldRecorder<'a>(&'a mut String);
+
+    impl tracing::field::Visit for FieldRecorder<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut line = span.metadata().name().to_string();
+            span.record(&mut FieldRecorder(&mut line));
+            spans.push(line);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {}
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn test_resolution_emits_spans() {
+        let recorder = std::sync::Arc::new(SpanRecorder {
+            spans: std::sync::Mutex::new(Vec::new()),
+        });
+        let values: Value = from_str("a: x\nb: '{{ a }}'\nc: '{{ b }}'").unwrap();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            resolve_value_references(values).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
         assert_eq!(
-            resolved.get("final").unwrap(),
-            &Value::String("base-b1 and base-b2".to_string())
+            *spans,
+            vec![
+                "resolve templates=2",
+                "build_dependency_graph templates=2",
+                "topological_sort nodes=3",
+                r#"render path="b" template="{{ a }}""#,
+                r#"render path="c" template="{{ b }}""#,
+            ]
         );
     }
 
@@ -448,7 +3926,9 @@ final: "{{ branch1 }} and {{ branch2 }}"
     #[cfg(test)]
     mod mock_tests {
         use super::*;
-        use crate::utils::value_resolver::traits::{MockReferenceExtractor, MockTemplateRenderer};
+        use crate::utils::value_resolver::traits::{
+            MockReferenceExtractor, MockScalarFormatter, MockTemplateRenderer,
+        };
 
         #[test]
         fn test_resolver_with_mock_extractor() {
@@ -462,8 +3942,11 @@ final: "{{ branch1 }} and {{ branch2 }}"
 
             let mut mock_renderer = MockTemplateRenderer::new();
             mock_renderer
-                .expect_render()
-                .returning(|_, _| Ok("rendered".to_string()));
+                .expect_render_named()
+                .returning(|_, _, _, buf| {
+                    buf.push_str("rendered");
+                    Ok(())
+                });
 
             let yaml = r#"
 foo: "value"
@@ -475,6 +3958,34 @@ bar: "{{ foo }}"
             assert!(result.is_ok());
         }
 
+        #[test]
+        fn test_mock_references_path() {
+            let mut mock_extractor = MockReferenceExtractor::new();
+            mock_extractor
+                .expect_references_path()
+                .withf(|_, path| path == "foo")
+                .returning(|template, _| template.contains("foo"));
+
+            assert!(mock_extractor.references_path("{{ foo }}", "foo"));
+            assert!(!mock_extractor.references_path("{{ bar }}", "foo"));
+        }
+
+        #[test]
+        fn test_mock_scalar_formatter_is_called_for_scalars_only() {
+            let mut formatter = MockScalarFormatter::new();
+            formatter
+                .expect_format()
+                .withf(|scalar| *scalar == Scalar::Integer(8080))
+                .times(1)
+                .returning(|_| Some("8,080".to_string()));
+
+            let renderer = MiniJinjaRenderer::with_scalar_formatter(formatter);
+            let context: Value = from_str("port: 8080\nhost: localhost").unwrap();
+
+            let rendered = renderer.render("{{ host }}:{{ port }}", &context).unwrap();
+            assert_eq!("localhost:8,080", rendered);
+        }
+
         #[test]
         fn test_renderer_error_propagates() {
             let mut mock_extractor = MockReferenceExtractor::new();
@@ -487,8 +3998,8 @@ bar: "{{ foo }}"
 
             let mut mock_renderer = MockTemplateRenderer::new();
             mock_renderer
-                .expect_render()
-                .returning(|_, _| Err(anyhow!("Render error")));
+                .expect_render_named()
+                .returning(|_, _, _, _| Err(anyhow!("Render error")));
 
             let yaml = r#"
 bar: "{{ foo }}"
@@ -496,8 +4007,8 @@ bar: "{{ foo }}"
             let values: Value = from_str(yaml).unwrap();
             let result = resolve_with(values, &mock_extractor, &mock_renderer);
 
-            assert!(result.is_err());
-            assert!(result.unwrap_err().to_string().contains("Render error"));
+            let err = format!("{:#}", result.unwrap_err());
+            assert_eq!(err, "Failed to resolve value 'bar': Render error");
         }
     }
 }
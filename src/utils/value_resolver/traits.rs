@@ -1,3 +1,4 @@
+use super::RenderContext;
 use anyhow::Result;
 use serde_yaml::Value;
 
@@ -13,6 +14,15 @@ pub trait ReferenceExtractor {
     /// E.g., "{{ name | upper }}" returns vec!["name"]
     fn extract_references(&self, template_str: &str) -> Vec<String>;
 
+    /// Checks if a template string references exactly the given value path.
+    /// E.g., "{{ foo.bar | upper }}" references "foo.bar" but not "foo"
+    #[allow(dead_code)]
+    fn references_path(&self, template_str: &str, path: &str) -> bool {
+        self.extract_references(template_str)
+            .iter()
+            .any(|reference| reference == path)
+    }
+
     /// Checks if a string contains template syntax
     fn contains_template(&self, s: &str) -> bool;
 }
@@ -23,4 +33,51 @@ pub trait ReferenceExtractor {
 pub trait TemplateRenderer {
     /// Renders a template string with the given context values.
     fn render(&self, template_str: &str, context: &Value) -> Result<String>;
+
+    /// Renders a template string into `buf`, after anything it already holds, so one
+    /// buffer can be reused across many renders. On error `buf` is left as it was.
+    fn render_into(&self, template_str: &str, context: &Value, buf: &mut String) -> Result<()> {
+        buf.push_str(&self.render(template_str, context)?);
+        Ok(())
+    }
+
+    /// Same as `render_into`, against the values held by `context`. The resolver renders
+    /// through this so renderers can reuse the form `context` has already prepared.
+    fn render_with_context(
+        &self,
+        template_str: &str,
+        context: &RenderContext,
+        buf: &mut String,
+    ) -> Result<()> {
+        self.render_into(template_str, context.values(), buf)
+    }
+
+    /// Same as `render_with_context`, naming the template `name` so that errors point at
+    /// it. The resolver passes the path of the value being rendered.
+    fn render_named(
+        &self,
+        name: &str,
+        template_str: &str,
+        context: &RenderContext,
+        buf: &mut String,
+    ) -> Result<()> {
+        let _ = name;
+        self.render_with_context(template_str, context, buf)
+    }
+}
+
+/// A non-string scalar about to be written into a rendered template value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+}
+
+/// Hook controlling how non-string scalars are written when a template like
+/// "v{{ version }}" interpolates them. Returning `None` keeps MiniJinja's formatting.
+#[cfg_attr(test, automock)]
+pub trait ScalarFormatter: Send + Sync {
+    fn format(&self, scalar: &Scalar) -> Option<String>;
 }
@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
 /// Recursively searches a directory for files with any of the specified file extensions.
@@ -28,6 +30,97 @@ pub fn get_files_with_extensions(dir: &str, extensions: &[&str]) -> Vec<String>
         .collect()
 }
 
+/// Same as `get_files_with_extensions`, searching each of several directories.
+///
+/// A file reachable from more than one directory, because the directories overlap or one
+/// is a symlink into another, is listed once, under the first directory it was found in.
+///
+/// # Arguments
+///
+/// * `dirs` - The directories to search for files in.
+/// * `extensions` - A slice of file extensions to search for, without the leading dot (e.g. &["jinja2", "j2"]).
+///
+/// # Returns
+///
+/// A sorted vector of the file paths of all files in any of the directory trees with any of the given file extensions.
+#[allow(dead_code)]
+pub fn get_files_with_extensions_multi(dirs: &[&str], extensions: &[&str]) -> Vec<String> {
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut files: Vec<String> = dirs
+        .iter()
+        .flat_map(|dir| get_files_with_extensions(dir, extensions))
+        .filter(|file| {
+            let path = Path::new(file);
+            seen.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Recursively searches a directory for files whose name ends with any of the specified suffixes.
+///
+/// Unlike `get_files_with_extensions`, this matches on the whole file name, so compound suffixes
+/// such as `.tar.gz` or `.override.yaml` work. A leading `*` is ignored, so `*.secret.env` and
+/// `.secret.env` are equivalent. Empty patterns, including a bare `*`, match nothing.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to search for files in.
+/// * `suffixes` - A slice of file name endings to search for (e.g. &[".override.yaml", "*.tar.gz"]).
+///
+/// # Returns
+///
+/// A vector of strings representing the file paths of all files in the directory tree ending with any of the given suffixes.
+#[allow(dead_code)]
+pub fn get_files_with_suffixes(dir: &str, suffixes: &[&str]) -> Vec<String> {
+    let suffixes: Vec<&str> = suffixes
+        .iter()
+        .map(|suffix| suffix.trim_start_matches('*'))
+        .filter(|suffix| !suffix.is_empty())
+        .collect();
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| {
+            if let Ok(entry) = entry {
+                if entry.file_type().is_file() {
+                    let file_name = entry.file_name().to_string_lossy();
+                    if suffixes.iter().any(|s| file_name.ends_with(s)) {
+                        return Some(entry.path().to_string_lossy().into_owned());
+                    }
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+/// Same as `get_files_with_extensions`, but returns each path relative to `base`.
+///
+/// Files that are not under `base` are skipped.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to search for files in.
+/// * `extensions` - A slice of file extensions to search for, without the leading dot (e.g. &["jinja2", "j2"]).
+/// * `base` - The directory the returned paths are made relative to.
+#[allow(dead_code)]
+pub fn get_files_with_extensions_relative(
+    dir: &str,
+    extensions: &[&str],
+    base: &Path,
+) -> Vec<String> {
+    get_files_with_extensions(dir, extensions)
+        .into_iter()
+        .filter_map(|file| {
+            Path::new(&file)
+                .strip_prefix(base)
+                .ok()
+                .map(|relative| relative.to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
 /// Recursively searches a directory for files with any of the specified file names.
 pub fn get_files_with_names(dir: &str, names: &[&str]) -> Vec<String> {
     WalkDir::new(dir)
@@ -48,7 +141,10 @@ pub fn get_files_with_names(dir: &str, names: &[&str]) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::walk::{get_files_with_extensions, get_files_with_names};
+    use crate::utils::walk::{
+        get_files_with_extensions_multi, get_files_with_extensions_relative, get_files_with_names,
+        get_files_with_suffixes,
+    };
 
     use relative_path::RelativePath;
     use std::env::current_dir;
@@ -66,9 +162,9 @@ mod tests {
             "resources/test/walk_test/subfolder/file3.jinja2",
         ];
         let target_dir_str = target_dir.to_str().unwrap();
-        let actual = get_files_with_extensions(target_dir_str, &["jinja2"]);
-        // We need to remove the base path for our tests so they are generic
-        let actual_relative = get_relative_files(actual, &current_dir);
+        // Paths relative to the working directory keep the test generic
+        let actual_relative =
+            get_files_with_extensions_relative(target_dir_str, &["jinja2"], &current_dir);
         // Sort both vectors so order doesn't matter.
         let mut expected_sorted = expected.clone();
         expected_sorted.sort();
@@ -91,8 +187,8 @@ mod tests {
             "resources/test/walk_test/subfolder/file3.jinja2",
         ];
         let target_dir_str = target_dir.to_str().unwrap();
-        let actual = get_files_with_extensions(target_dir_str, &["jinja2", "j2"]);
-        let actual_relative = get_relative_files(actual, &current_dir);
+        let actual_relative =
+            get_files_with_extensions_relative(target_dir_str, &["jinja2", "j2"], &current_dir);
         let mut expected_sorted = expected.clone();
         expected_sorted.sort();
         let mut actual_sorted = actual_relative.clone();
@@ -101,6 +197,108 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_walk_relative_skips_files_outside_base() -> anyhow::Result<()> {
+        let current_dir = current_dir()?;
+        let target_dir =
+            RelativePath::new("resources/test/walk_test").to_logical_path(&current_dir);
+        let target_dir_str = target_dir.to_str().unwrap();
+
+        let mut relative_to_target =
+            get_files_with_extensions_relative(target_dir_str, &["jinja2"], &target_dir);
+        relative_to_target.sort();
+        assert_eq!(
+            vec!["file1.jinja2", "file2.jinja2", "subfolder/file3.jinja2"],
+            relative_to_target
+        );
+
+        let subfolder = target_dir.join("subfolder");
+        let only_subfolder =
+            get_files_with_extensions_relative(target_dir_str, &["jinja2"], &subfolder);
+        assert_eq!(vec!["file3.jinja2"], only_subfolder);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_get_files_with_extensions_multi_dedups_overlapping_roots() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let base = temp_dir.path().join("base");
+        let shared = base.join("shared");
+        let extra = temp_dir.path().join("extra");
+        std::fs::create_dir_all(&shared)?;
+        std::fs::create_dir(&extra)?;
+        for file in [
+            "base/values.yaml",
+            "base/shared/common.yaml",
+            "base/shared/notes.txt",
+            "extra/app.yaml",
+        ] {
+            std::fs::write(temp_dir.path().join(file), "")?;
+        }
+        let linked = temp_dir.path().join("linked");
+        std::os::unix::fs::symlink(&shared, &linked)?;
+
+        let dirs = [
+            base.to_str().unwrap(),
+            linked.to_str().unwrap(),
+            extra.to_str().unwrap(),
+            shared.to_str().unwrap(),
+        ];
+        let actual = get_files_with_extensions_multi(&dirs, &["yaml"]);
+        let expected: Vec<String> = [
+            "base/shared/common.yaml",
+            "base/values.yaml",
+            "extra/app.yaml",
+        ]
+        .iter()
+        .map(|file| temp_dir.path().join(file).to_string_lossy().into_owned())
+        .collect();
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_files_with_suffixes_matches_multi_dot_suffixes() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let nested = temp_dir.path().join("nested");
+        std::fs::create_dir(&nested)?;
+        for file in [
+            "backup.tar.gz",
+            "archive.gz",
+            "docker-compose.override.yaml",
+            "docker-compose.yaml",
+            "nested/db.secret.env",
+            "nested/plain.env",
+        ] {
+            std::fs::write(temp_dir.path().join(file), "")?;
+        }
+
+        let dir = temp_dir.path().to_str().unwrap();
+        let mut actual =
+            get_files_with_suffixes(dir, &["*.tar.gz", ".override.yaml", ".secret.env"]);
+        actual.sort();
+        let expected: Vec<String> = [
+            "backup.tar.gz",
+            "docker-compose.override.yaml",
+            "nested/db.secret.env",
+        ]
+        .iter()
+        .map(|file| temp_dir.path().join(file).to_string_lossy().into_owned())
+        .collect();
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_files_with_suffixes_ignores_empty_patterns() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        std::fs::write(temp_dir.path().join("file.txt"), "")?;
+        let actual = get_files_with_suffixes(temp_dir.path().to_str().unwrap(), &["*", ""]);
+        assert!(actual.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_get_files_with_names_finds_nested_files() -> anyhow::Result<()> {
         let current_dir = current_dir()?;
@@ -0,0 +1,186 @@
+use crate::utils::load_values::{load_yaml_files_with, local_values_files, LoadOptions};
+use anyhow::{anyhow, Context};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde_yaml::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the values files must go unchanged before they are reloaded, so an editor
+/// saving several files, or one file in several writes, causes a single reload.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Loads and resolves `files` like `load_yaml_files`, then again every time one of them
+/// changes, passing each result to `callback`. See `watch_and_resolve_with`.
+#[allow(dead_code)]
+pub fn watch_and_resolve<F>(files: &[&str], callback: F) -> anyhow::Result<()>
+where
+    F: FnMut(anyhow::Result<Value>) -> ControlFlow<()>,
+{
+    watch_and_resolve_with(files, &LoadOptions::default(), DEFAULT_DEBOUNCE, callback)
+}
+
+/// Loads and resolves `files` with `options`, then watches the local values files among
+/// them and reloads whenever one is written, created, renamed or removed. `callback` is
+/// called with the first result straight away and with each reload after that, and the
+/// watch runs until it returns `ControlFlow::Break`.
+///
+/// Changes are debounced: a reload only starts once no change has been seen for
+/// `debounce`. A reload that fails, for example because a file is half written or no
+/// longer valid YAML, is passed to `callback` as an error and the watch carries on.
+///
+/// The directories holding the files are watched rather than the files themselves, as
+/// many editors save by replacing the file.
+///
+/// # Errors
+///
+/// Returns an error if none of `files` is a local file, or if a directory cannot be
+/// watched.
+#[allow(dead_code)]
+pub fn watch_and_resolve_with<F>(
+    files: &[&str],
+    options: &LoadOptions,
+    debounce: Duration,
+    mut callback: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(anyhow::Result<Value>) -> ControlFlow<()>,
+{
+    let paths = local_values_files(files, options)?;
+    if paths.is_empty() {
+        return Err(anyhow!("None of the values files are local files to watch"));
+    }
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let mut watched = HashSet::new();
+    let mut directories = BTreeSet::new();
+    for path in &paths {
+        let (directory, file) = watched_location(Path::new(path))?;
+        watched.insert(directory.join(file));
+        directories.insert(directory);
+    }
+    for directory in &directories {
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {}", directory.display()))?;
+    }
+
+    if callback(load_yaml_files_with(files, options)).is_break() {
+        return Ok(());
+    }
+    loop {
+        match events.recv() {
+            Ok(Ok(event)) if affects(&event, &watched) => {}
+            Ok(Ok(_)) => continue,
+            Ok(Err(err)) => {
+                if callback(Err(anyhow!(err).context("File watcher error"))).is_break() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(_) => return Err(anyhow!("File watcher stopped unexpectedly")),
+        }
+        loop {
+            match events.recv_timeout(debounce) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("File watcher stopped unexpectedly"))
+                }
+            }
+        }
+        trace!("Values files changed, reloading.");
+        if callback(load_yaml_files_with(files, options)).is_break() {
+            return Ok(());
+        }
+    }
+}
+
+/// Splits a values file path into its canonical directory and file name, which is how
+/// the watcher reports events in that directory.
+fn watched_location(path: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let file = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Cannot watch values file '{}'", path.display()))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = directory.canonicalize().with_context(|| {
+        format!(
+            "Cannot watch values file '{}', its directory does not exist",
+            path.display()
+        )
+    })?;
+    Ok((directory, PathBuf::from(file)))
+}
+
+fn affects(event: &Event, watched: &HashSet<PathBuf>) -> bool {
+    !matches!(event.kind, EventKind::Access(_))
+        && event.paths.iter().any(|path| watched.contains(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml::from_str;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_reloads_on_change_and_survives_errors() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let base = dir.path().join("values.yaml");
+        let other = dir.path().join("other.yaml");
+        std::fs::write(&base, "name: app\nport: 80\n")?;
+        std::fs::write(&other, "unrelated: true\n")?;
+
+        let (sender, results) = mpsc::channel();
+        let path = base.to_string_lossy().into_owned();
+        std::thread::spawn(move || {
+            let mut reloads = 0;
+            watch_and_resolve(&[path.as_str()], |result| {
+                let _ = sender.send(result.map_err(|err| format!("{:#}", err)));
+                reloads += 1;
+                if reloads == 4 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+        });
+
+        let first = results.recv_timeout(TIMEOUT)?;
+        assert_eq!(first, Ok(from_str::<Value>("name: app\nport: 80")?));
+
+        // Successive writes are debounced into one reload, and other files are ignored
+        std::fs::write(&other, "unrelated: false\n")?;
+        std::fs::write(&base, "name: app\nport: 81\n")?;
+        std::fs::write(&base, "name: app\nport: 82\n")?;
+        let second = results.recv_timeout(TIMEOUT)?;
+        assert_eq!(second, Ok(from_str::<Value>("name: app\nport: 82")?));
+
+        std::fs::write(&base, "name: [unclosed\n")?;
+        let third = results.recv_timeout(TIMEOUT)?;
+        assert!(
+            third
+                .as_ref()
+                .is_err_and(|err| err.contains("Invalid YAML")),
+            "Reload errors should be passed on: {:?}",
+            third
+        );
+
+        std::fs::write(&base, "name: fixed\n")?;
+        let fourth = results.recv_timeout(TIMEOUT)?;
+        assert_eq!(fourth, Ok(from_str::<Value>("name: fixed")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides_only_is_an_error() {
+        let err = watch_and_resolve(&["a=b"], |_| ControlFlow::Break(())).unwrap_err();
+        assert!(err.to_string().contains("None of the values files"));
+    }
+}
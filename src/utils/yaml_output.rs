@@ -0,0 +1,275 @@
+use serde_yaml::Value;
+
+/// Serializes values to YAML with `header` written above them as a comment block, e.g. to
+/// note that a file was generated and from what.
+///
+/// serde_yaml drops comments when loading, so only the header survives. Mapping keys keep
+/// the order they have in `value`; for merged values that is the order of the first file
+/// setting each key, followed by keys added by later files, so output is stable across
+/// runs. Each header line gets a `# ` prefix, and an empty header adds nothing.
+///
+/// # Examples
+///
+/// ```
+/// let values: serde_yaml::Value = serde_yaml::from_str("b: 1\na: 2")?;
+/// let yaml = to_yaml_string_with_header(&values, "Generated by composer")?;
+/// assert_eq!(yaml, "# Generated by composer\nb: 1\na: 2\n");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[allow(dead_code)]
+pub fn to_yaml_string_with_header(value: &Value, header: &str) -> anyhow::Result<String> {
+    let body = serde_yaml::to_string(value)
+        .map_err(|err| anyhow::anyhow!("Failed to serialize values to YAML: {}", err))?;
+    let mut output = String::new();
+    for line in header.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            output.push_str("#\n");
+        } else {
+            output.push_str(&format!("# {}\n", line));
+        }
+    }
+    output.push_str(&body);
+    Ok(output)
+}
+
+/// Serializes `resolved` to YAML with a trailing comment on every value that resolution
+/// changed, giving the value it had in `original`, e.g.
+/// `greeting: hello world  # was: {{ hi }} world`. Meant for debugging templates, so
+/// pass the merged values from before and after resolving references.
+///
+/// Values are compared by path; one missing from `original` is not annotated. Line
+/// breaks in the original are written as `\n` to keep each comment on one line. The
+/// comments do not change what the output parses back to.
+#[allow(dead_code)]
+pub fn annotate_resolved(original: &Value, resolved: &Value) -> String {
+    let mut output = String::new();
+    match resolved {
+        Value::Mapping(mapping) if !mapping.is_empty() => {
+            write_collection(&mut output, Some(original), resolved, 0)
+        }
+        Value::Sequence(sequence) if !sequence.is_empty() => {
+            write_collection(&mut output, Some(original), resolved, 0)
+        }
+        _ => {
+            let note = annotation(Some(original), resolved).unwrap_or_default();
+            let scalar = scalar_yaml(resolved);
+            let mut lines = scalar.lines();
+            output.push_str(&format!("{}{}\n", lines.next().unwrap_or_default(), note));
+            for line in lines {
+                output.push_str(&format!("{}\n", line));
+            }
+        }
+    }
+    output
+}
+
+/// Writes the entries of a non-empty mapping or sequence, one per line at `indent`.
+fn write_collection(output: &mut String, original: Option<&Value>, value: &Value, indent: usize) {
+    let padding = " ".repeat(indent);
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, child) in mapping {
+                let original = original
+                    .and_then(Value::as_mapping)
+                    .and_then(|mapping| mapping.get(key));
+                let prefix = format!("{}{}:", padding, scalar_yaml(key));
+                write_entry(output, &prefix, original, child, indent, false);
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (index, child) in sequence.iter().enumerate() {
+                let original = original
+                    .and_then(Value::as_sequence)
+                    .and_then(|sequence| sequence.get(index));
+                let prefix = format!("{}-", padding);
+                write_entry(output, &prefix, original, child, indent, true);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Writes one mapping entry or sequence item, starting with `prefix` (`key:` or `-`).
+fn write_entry(
+    output: &mut String,
+    prefix: &str,
+    original: Option<&Value>,
+    value: &Value,
+    indent: usize,
+    in_sequence: bool,
+) {
+    let note = annotation(original, value);
+    let is_collection = match value {
+        Value::Mapping(mapping) => !mapping.is_empty(),
+        Value::Sequence(sequence) => !sequence.is_empty(),
+        _ => false,
+    };
+    if is_collection {
+        // Sequences under a key stay at the key's indent, as serde_yaml writes them
+        let child_indent = match value {
+            Value::Sequence(_) if !in_sequence => indent,
+            _ => indent + 2,
+        };
+        let mut nested = String::new();
+        write_collection(&mut nested, original, value, child_indent);
+        match note {
+            // An item's first entry goes on the `-` line: `- name: web`
+            None if in_sequence => {
+                output.push_str(prefix);
+                output.push(' ');
+                output.push_str(&nested[child_indent..]);
+            }
+            _ => {
+                output.push_str(&format!("{}{}\n", prefix, note.unwrap_or_default()));
+                output.push_str(&nested);
+            }
+        }
+        return;
+    }
+    let scalar = scalar_yaml(value);
+    let mut lines = scalar.lines();
+    let first = lines.next().unwrap_or_default();
+    output.push_str(&format!(
+        "{} {}{}\n",
+        prefix,
+        first,
+        note.unwrap_or_default()
+    ));
+    // Block scalar lines, indented past the key or `-`
+    for line in lines {
+        output.push_str(&format!("{}{}\n", " ".repeat(indent), line));
+    }
+}
+
+/// The trailing comment for a value that differs from its `original` scalar. Tags such
+/// as `!raw` on the original are ignored, as resolution removes them.
+fn annotation(original: Option<&Value>, resolved: &Value) -> Option<String> {
+    let mut original = original?;
+    if original == resolved {
+        return None;
+    }
+    while let Value::Tagged(tagged) = original {
+        original = &tagged.value;
+    }
+    if original == resolved || original.is_mapping() || original.is_sequence() {
+        return None;
+    }
+    let was = match original {
+        Value::String(string) => string.clone(),
+        other => scalar_yaml(other),
+    };
+    Some(format!("  # was: {}", was.replace('\n', "\\n")))
+}
+
+/// A scalar, or an empty mapping or sequence, as serde_yaml writes it, without the
+/// trailing newline.
+fn scalar_yaml(value: &Value) -> String {
+    serde_yaml::to_string(value)
+        .map(|yaml| yaml.trim_end_matches('\n').to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_is_emitted_as_comment_block() -> anyhow::Result<()> {
+        let values: Value = serde_yaml::from_str("name: web\nports: [80]")?;
+        let yaml = to_yaml_string_with_header(
+            &values,
+            "Merged values for app web\n\nSources: values.yaml, values.prod.yaml",
+        )?;
+
+        assert!(
+            yaml.starts_with(
+                "# Merged values for app web\n#\n# Sources: values.yaml, values.prod.yaml\nname: web\n"
+            ),
+            "{}",
+            yaml
+        );
+        // The header does not change what the values parse back to
+        assert_eq!(serde_yaml::from_str::<Value>(&yaml)?, values);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_order_is_kept() -> anyhow::Result<()> {
+        let values: Value = serde_yaml::from_str("zeta: 1\nalpha:\n  y: 2\n  b: 3\nmid: 4")?;
+        let yaml = to_yaml_string_with_header(&values, "")?;
+        assert_eq!(yaml, "zeta: 1\nalpha:\n  y: 2\n  b: 3\nmid: 4\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_resolved_marks_changed_values() -> anyhow::Result<()> {
+        let original: Value = serde_yaml::from_str(
+            r#"
+hi: hello
+greeting: "{{ hi }} world"
+port: 80
+servers:
+  - name: web
+    url: "http://{{ hi }}:{{ port }}"
+  - "{{ hi }}"
+script: "echo {{ hi }}\necho done"
+"#,
+        )?;
+        let resolved: Value = serde_yaml::from_str(
+            r#"
+hi: hello
+greeting: hello world
+port: 80
+servers:
+  - name: web
+    url: "http://hello:80"
+  - hello
+script: "echo hello\necho done"
+"#,
+        )?;
+
+        let yaml = annotate_resolved(&original, &resolved);
+        assert!(
+            yaml.contains("\ngreeting: hello world  # was: {{ hi }} world\n"),
+            "{}",
+            yaml
+        );
+        assert!(yaml.starts_with("hi: hello\n"), "{}", yaml);
+        assert!(yaml.contains("\nport: 80\n"), "{}", yaml);
+        assert!(
+            yaml.contains(
+                "\n- name: web\n  url: http://hello:80  # was: http://{{ hi }}:{{ port }}\n"
+            ),
+            "{}",
+            yaml
+        );
+        assert!(yaml.contains("\n- hello  # was: {{ hi }}\n"), "{}", yaml);
+        assert!(
+            yaml.contains("script: |-  # was: echo {{ hi }}\\necho done\n"),
+            "{}",
+            yaml
+        );
+        assert_eq!(serde_yaml::from_str::<Value>(&yaml)?, resolved);
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_resolved_ignores_removed_tags() -> anyhow::Result<()> {
+        let original: Value = serde_yaml::from_str(
+            "b: 1\ny: !raw '{{ b }}'\nname: !omitempty '{{ b }}-web'\nkept: !custom x",
+        )?;
+        let resolved: Value =
+            serde_yaml::from_str("b: 1\ny: '{{ b }}'\nname: 1-web\nkept: !custom x")?;
+
+        let yaml = annotate_resolved(&original, &resolved);
+        assert!(yaml.contains("\ny: '{{ b }}'\n"), "{}", yaml);
+        assert!(
+            yaml.contains("\nname: 1-web  # was: {{ b }}-web\n"),
+            "{}",
+            yaml
+        );
+        assert!(yaml.ends_with("\nkept: !custom x\n"), "{}", yaml);
+        Ok(())
+    }
+}
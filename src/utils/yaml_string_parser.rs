@@ -1,4 +1,6 @@
+use crate::utils::json_pointer::parse_json_pointer;
 use anyhow::anyhow;
+use serde_yaml::value::{Tag, TaggedValue};
 use serde_yaml::{Mapping, Value};
 use std::borrow::Cow;
 
@@ -6,7 +8,15 @@ use std::borrow::Cow;
 ///
 /// This function takes a string in the format of a dot-separated key path, followed by an equals sign and a value.
 /// It then creates a YAML mapping with nested mappings for each key in the path, with the final key-value pair being
-/// added as a string value to the nested mapping.
+/// added to the nested mapping. The value is parsed as YAML, so `ports=[80, 443]` and `labels={a: 1}` produce a
+/// sequence and a mapping and `replicas=3` produces a number; anything else is kept as the literal string.
+/// Writing `x.y.z:=foo` instead skips the inference and always stores the value as a string, so
+/// `tag:=1.0` stays `"1.0"` rather than becoming a float. A key path starting with `/` is read
+/// as a JSON Pointer instead, so `/config/web.app/port=80` sets the `port` of a key literally
+/// named `web.app`; every token becomes a mapping key, including numeric ones.
+/// Ending a dotted key path with `[+]`, as in `services.web.environment[+]=FOO=bar`, appends
+/// the value to the sequence at that path instead of replacing it. The value is returned as
+/// a one-item sequence tagged `!append`, which the merge recognises; see `APPEND_TAG`.
 ///
 /// # Errors
 ///
@@ -25,6 +35,9 @@ use std::borrow::Cow;
 ///
 ///     assert_eq!(yaml_value["abc"]["bcd"]["dge"], Value::String("xyz".to_owned()));
 ///
+///     let yaml_value = parse_yaml_string("ports=[80, 443]")?;
+///     assert_eq!(yaml_value["ports"][1], Value::from(443));
+///
 ///     Ok(())
 /// }
 /// ```
@@ -42,6 +55,148 @@ use std::borrow::Cow;
 ///
 /// This function may panic if there is an internal error when accessing nested mappings.
 pub(crate) fn parse_yaml_string(yaml_str: &str) -> anyhow::Result<Value> {
+    let Override {
+        keys,
+        value,
+        force_string,
+        append,
+    } = split_override(yaml_str)?;
+    let mut map = Mapping::new();
+    let mut nested_map = &mut map;
+
+    for key in &keys[..keys.len() - 1] {
+        let new_map = Mapping::new();
+        nested_map.insert(Value::from(key.clone()), Value::Mapping(new_map));
+        nested_map = match nested_map.get_mut(key.as_ref()).unwrap() {
+            Value::Mapping(map) => map,
+            _ => unreachable!(),
+        };
+    }
+
+    let last_key = keys.last().unwrap();
+    let mut value = if force_string {
+        Value::String(value.to_owned())
+    } else {
+        parse_override_value(value)
+    };
+    if append {
+        value = Value::Tagged(Box::new(TaggedValue {
+            tag: Tag::new(APPEND_TAG),
+            value: Value::Sequence(vec![value]),
+        }));
+    }
+    nested_map.insert(Value::from(last_key.clone()), value);
+
+    Ok(Value::Mapping(map))
+}
+
+/// Tag marking a sequence whose items are appended to the sequence it is merged into,
+/// whatever the merge options say. Produced by `x.y.z[+]=foo` overrides.
+pub(crate) const APPEND_TAG: &str = "append";
+
+/// Suffix of a dotted key path that appends to the sequence at that path.
+const APPEND_SUFFIX: &str = "[+]";
+
+/// The parts of a `x.y.z=foo` override: the keys it sets and the raw value.
+struct Override<'a> {
+    keys: Vec<Cow<'a, str>>,
+    value: &'a str,
+    force_string: bool,
+    append: bool,
+}
+
+/// Returns the keys an override sets, so `a.b=1`, `a.b:=1` and `/a/b=2` all give
+/// `["a", "b"]`. Used to spot several overrides targeting the same path. Appending
+/// overrides (`a.b[+]=1`) do not set their path, so they give `None`.
+pub(crate) fn override_key_path(yaml_str: &str) -> anyhow::Result<Option<Vec<String>>> {
+    let parsed = split_override(yaml_str)?;
+    if parsed.append {
+        return Ok(None);
+    }
+    Ok(Some(parsed.keys.into_iter().map(Cow::into_owned).collect()))
+}
+
+/// Returns whether an override keeps its value a string (`a.b:=1`), so the value is
+/// meant as a string whatever type the path held before.
+pub(crate) fn override_forces_string(yaml_str: &str) -> anyhow::Result<bool> {
+    Ok(split_override(yaml_str)?.force_string)
+}
+
+/// Returns every key along the path an override writes to, including for appending
+/// overrides, so `a.b[+]=1` gives `["a", "b"]`.
+pub(crate) fn override_keys(yaml_str: &str) -> anyhow::Result<Vec<String>> {
+    let parsed = split_override(yaml_str)?;
+    Ok(parsed.keys.into_iter().map(Cow::into_owned).collect())
+}
+
+/// Checks the key path of every `x.y.z=foo` override without applying any, so a CLI can
+/// report all the malformed `--set` arguments at once. A dotted key path must have no
+/// empty segments and balanced brackets in each one; a JSON Pointer key path must be a
+/// valid pointer.
+///
+/// # Errors
+///
+/// Returns one message per invalid argument, in order, each starting with the argument,
+/// e.g. `a..b=1: key path 'a..b' has an empty segment`.
+#[allow(dead_code)]
+pub(crate) fn validate_overrides(args: &[&str]) -> Result<(), Vec<String>> {
+    let invalid: Vec<String> = args
+        .iter()
+        .filter_map(|arg| key_path_error(arg).map(|reason| format!("{}: {}", arg, reason)))
+        .collect();
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid)
+    }
+}
+
+/// Describes what is wrong with the key path of an override, if anything.
+fn key_path_error(arg: &str) -> Option<String> {
+    let Some((key_path, _)) = arg.split_once('=') else {
+        return Some("missing '=' between key and value".to_string());
+    };
+    let parsed = match split_override(arg) {
+        Ok(parsed) => parsed,
+        Err(_)
+            if key_path
+                .trim_end_matches(':')
+                .trim_end_matches(APPEND_SUFFIX)
+                .is_empty() =>
+        {
+            return Some("key is empty".to_string());
+        }
+        Err(e) => return Some(e.to_string()),
+    };
+    if key_path.starts_with('/') {
+        return None;
+    }
+    let dotted = parsed.keys.join(".");
+    if parsed.keys.iter().any(|key| key.is_empty()) {
+        return Some(format!("key path '{}' has an empty segment", dotted));
+    }
+    parsed
+        .keys
+        .iter()
+        .find(|key| !brackets_balanced(key))
+        .map(|key| format!("key '{}' has unbalanced brackets", key))
+}
+
+/// Checks that every `[` in a key is closed by a later `]` and every `]` closes one.
+fn brackets_balanced(key: &str) -> bool {
+    let mut depth = 0usize;
+    for c in key.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return false,
+            ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+fn split_override(yaml_str: &str) -> anyhow::Result<Override<'_>> {
     let (key_path, value) = yaml_str.split_once("=").ok_or_else(|| {
         anyhow!(
             "Failed to split YAML string: {}, must be the format x.y.z=foo",
@@ -49,6 +204,18 @@ pub(crate) fn parse_yaml_string(yaml_str: &str) -> anyhow::Result<Value> {
         )
     })?;
 
+    // A trailing ':' on the key (`key:=value`) forces the value to stay a string
+    let (key_path, force_string) = match key_path.strip_suffix(':') {
+        Some(stripped) => (stripped, true),
+        None => (key_path, false),
+    };
+
+    // A trailing `[+]` appends to the sequence rather than replacing it
+    let (key_path, append) = match key_path.strip_suffix(APPEND_SUFFIX) {
+        Some(stripped) if !key_path.starts_with('/') => (stripped, true),
+        _ => (key_path, false),
+    };
+
     if key_path.is_empty() {
         return Err(anyhow!(
             "Failed to find yaml key for string {}, must be the format x.y.z=foo.",
@@ -56,26 +223,43 @@ pub(crate) fn parse_yaml_string(yaml_str: &str) -> anyhow::Result<Value> {
         ));
     }
 
-    let keys = key_path.split(".");
-    let mut map = Mapping::new();
-    let mut nested_map = &mut map;
+    // A leading '/' makes the key an RFC 6901 JSON Pointer, which allows keys containing dots
+    let keys: Vec<Cow<str>> = if key_path.starts_with('/') {
+        parse_json_pointer(key_path)?
+            .into_iter()
+            .map(Cow::Owned)
+            .collect()
+    } else {
+        key_path.split(".").map(Cow::Borrowed).collect()
+    };
 
-    for key in keys.clone().take(keys.clone().count().saturating_sub(1)) {
-        let new_map = Mapping::new();
-        nested_map.insert(Value::from(Cow::Borrowed(key)), Value::Mapping(new_map));
-        nested_map = match nested_map.get_mut(key).unwrap() {
-            Value::Mapping(map) => map,
-            _ => unreachable!(),
-        };
-    }
-
-    let last_key = keys.last().unwrap();
-    nested_map.insert(
-        Value::from(Cow::Borrowed(last_key)),
-        Value::String(value.to_owned()),
-    );
+    Ok(Override {
+        keys,
+        value,
+        force_string,
+        append,
+    })
+}
 
-    Ok(Value::Mapping(map))
+/// Parses the right-hand side of a key-value override as YAML. Collections are only
+/// accepted in flow syntax (`[..]` / `{..}`) and anything that does not parse cleanly
+/// to a scalar is kept verbatim, so plain strings behave exactly as before.
+fn parse_override_value(raw: &str) -> Value {
+    // `{{ x }}` is valid flow syntax too, but references must stay strings for the resolver
+    if raw.trim().is_empty() || raw.contains("{{") {
+        return Value::String(raw.to_owned());
+    }
+    let is_flow_collection = raw.trim_start().starts_with(['[', '{']);
+    match serde_yaml::from_str::<Value>(raw) {
+        Ok(value @ (Value::Sequence(_) | Value::Mapping(_))) if is_flow_collection => value,
+        // Quoting is how a user forces a string, so honour the unquoted content
+        Ok(Value::String(parsed)) if raw.trim_start().starts_with(['"', '\'']) => {
+            Value::String(parsed)
+        }
+        Ok(value @ (Value::Bool(_) | Value::Number(_))) => value,
+        Ok(Value::Null) if matches!(raw.trim(), "~" | "null" | "Null" | "NULL") => Value::Null,
+        _ => Value::String(raw.to_owned()),
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -134,6 +318,163 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_yaml_string_inline_list() -> anyhow::Result<()> {
+        let actual = parse_yaml_string("service.ports=[80, 443]")?;
+        let expected: Value = from_str("service:\n  ports: [80, 443]")?;
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_string_inline_map() -> anyhow::Result<()> {
+        let actual = parse_yaml_string("labels={a: 1, b: two}")?;
+        let expected: Value = from_str("labels:\n  a: 1\n  b: two")?;
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_string_infers_scalars() -> anyhow::Result<()> {
+        assert_eq!(Value::from(3), parse_yaml_string("replicas=3")?["replicas"]);
+        assert_eq!(
+            Value::Bool(true),
+            parse_yaml_string("enabled=true")?["enabled"]
+        );
+        assert_eq!(Value::Null, parse_yaml_string("unset=~")?["unset"]);
+        assert_eq!(
+            Value::String("3".to_string()),
+            parse_yaml_string("replicas='3'")?["replicas"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_string_keeps_plain_strings_verbatim() -> anyhow::Result<()> {
+        let cases = [
+            ("foo= padded ", " padded "),
+            ("foo=# not a comment", "# not a comment"),
+            ("foo=a: b", "a: b"),
+            ("foo=- item", "- item"),
+            ("foo={{ world }}", "{{ world }}"),
+            ("foo=[unclosed", "[unclosed"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                Value::String(expected.to_string()),
+                parse_yaml_string(input)?["foo"],
+                "input: {}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_string_forced_string() -> anyhow::Result<()> {
+        let inferred = parse_yaml_string("image.tag=1.0")?;
+        assert_eq!(Value::from(1.0), inferred["image"]["tag"]);
+
+        let forced = parse_yaml_string("image.tag:=1.0")?;
+        assert_eq!(Value::String("1.0".to_string()), forced["image"]["tag"]);
+
+        let forced_list = parse_yaml_string("ports:=[80]")?;
+        assert_eq!(Value::String("[80]".to_string()), forced_list["ports"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_forced_string_missing_key() {
+        let err = parse_yaml_string(":=1.0").unwrap_err();
+        assert_eq!(
+            "Failed to find yaml key for string :=1.0, must be the format x.y.z=foo.",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_yaml_string_append() -> anyhow::Result<()> {
+        let actual = parse_yaml_string("services.web.environment[+]=FOO=bar")?;
+        let expected: Value = from_str("services:\n  web:\n    environment: !append [FOO=bar]")?;
+        assert_eq!(expected, actual);
+
+        let forced = parse_yaml_string("ports[+]:=8080")?;
+        assert_eq!(from_str::<Value>("ports: !append ['8080']")?, forced);
+
+        assert_eq!(override_key_path("ports[+]=80")?, None);
+        assert_eq!(
+            override_key_path("a.b=1")?,
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert!(parse_yaml_string("[+]=1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_yaml_json_pointer_key() -> anyhow::Result<()> {
+        let result = parse_yaml_string("/config/web.app/port=80")?;
+        let expected: Value = from_str("config:\n  web.app:\n    port: 80")?;
+        assert_eq!(expected, result);
+
+        let escaped = parse_yaml_string("/paths/~1api~1v1/owner~0team:=ops")?;
+        assert_eq!(
+            Value::String("ops".to_string()),
+            escaped["paths"]["/api/v1"]["owner~team"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_overrides_accepts_valid_args() {
+        let args = [
+            "a.b.c=1",
+            "tag:=1.0",
+            "env[+]=FOO=bar",
+            "/config/web.app/port=80",
+            "list[0]=x",
+        ];
+        assert_eq!(validate_overrides(&args), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_overrides_reports_every_invalid_arg() {
+        let args = [
+            "a.b=1",
+            "a..b=1",
+            "missing",
+            "=value",
+            "ports]=80",
+            "c.d=2",
+            "list[0=x",
+            ".a=1",
+            "/bad~2=1",
+        ];
+        let errors = validate_overrides(&args).unwrap_err();
+        assert_eq!(errors.len(), 7);
+        assert_eq!(
+            errors[..6],
+            [
+                "a..b=1: key path 'a..b' has an empty segment",
+                "missing: missing '=' between key and value",
+                "=value: key is empty",
+                "ports]=80: key 'ports]' has unbalanced brackets",
+                "list[0=x: key 'list[0' has unbalanced brackets",
+                ".a=1: key path '.a' has an empty segment",
+            ]
+        );
+        assert!(
+            errors[6].starts_with("/bad~2=1: Invalid JSON Pointer"),
+            "{}",
+            errors[6]
+        );
+    }
+
+    #[test]
+    fn test_parse_yaml_invalid_json_pointer_key() {
+        let err = parse_yaml_string("/config/bad~2=1").unwrap_err();
+        assert!(err.to_string().contains("Invalid JSON Pointer"), "{}", err);
+    }
+
     #[test]
     fn test_parse_yaml_invalid_string() -> anyhow::Result<()> {
         let err = parse_yaml_string("invalid").unwrap_err();